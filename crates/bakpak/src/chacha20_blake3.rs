@@ -1,3 +1,18 @@
+//! ChaCha20 encryption authenticated with a keyed BLAKE3 MAC (encrypt-then-MAC).
+//!
+//! Both subkeys are derived from the 32-byte key `K`:
+//!
+//! ```text
+//! Ke = BLAKE3-derive_key("ChaCha20.Encrypt()", K)
+//! Km = BLAKE3-derive_key("BLAKE3-256.KeyedHash()", K)
+//!
+//! C  = ChaCha20(Ke, N) ^ P
+//! T  = BLAKE3-keyed(Km, N || A || C || LE64(len(A)) || LE64(len(C)))
+//! ```
+//!
+//! The nonce has a fixed size, so the trailing lengths make the MAC input uniquely decodable:
+//! moving bytes between the associated data `A` and the ciphertext `C` changes the tag.
+
 use std::marker::PhantomData;
 
 use aead::{
@@ -24,7 +39,15 @@ const BLOCK_SIZE: usize = 64;
 
 /// Maximum number of blocks that can be encrypted with ChaCha20 before the
 /// counter overflows.
-const MAX_BLOCKS: usize = core::u32::MAX as usize;
+const MAX_BLOCKS: usize = u32::MAX as usize;
+
+/// Fails if a message of `len` bytes would overflow the block counter.
+fn check_length(len: usize) -> aead::Result<()> {
+    if len / BLOCK_SIZE >= MAX_BLOCKS {
+        return Err(aead::Error);
+    }
+    Ok(())
+}
 
 pub type ChaCha20Blake3 = ChaChaBlake3<ChaCha20, U12>;
 
@@ -35,6 +58,12 @@ pub struct ChaChaBlake3<C, N: ArrayLength<u8> = U12> {
     nonce_size: PhantomData<N>,
 }
 
+// Ciphers hold the key, so callers rely on them zeroizing it when dropped.
+const _: fn() = || {
+    fn assert_zeroize_on_drop<T: ZeroizeOnDrop>() {}
+    assert_zeroize_on_drop::<ChaCha20Blake3>();
+};
+
 trait KeyDerivationCtx {
     const KEY_DERIVATION_CTX: &str;
 }
@@ -82,9 +111,7 @@ where
         associated_data: &[u8],
         buffer: &mut [u8],
     ) -> aead::Result<Tag> {
-        if buffer.len() / BLOCK_SIZE >= MAX_BLOCKS {
-            return Err(aead::Error);
-        }
+        check_length(buffer.len())?;
 
         let mut encryption_key = blake3::derive_key(C::KEY_DERIVATION_CTX, &self.key);
         let mut cipher = C::new_from_slices(&encryption_key, nonce).unwrap();
@@ -105,9 +132,7 @@ where
         buffer: &mut [u8],
         tag: &Tag,
     ) -> aead::Result<()> {
-        if buffer.len() / BLOCK_SIZE >= MAX_BLOCKS {
-            return Err(aead::Error);
-        }
+        check_length(buffer.len())?;
 
         let computed_tag = self.compute_tag(nonce, associated_data, buffer)?;
         if computed_tag == **tag {
@@ -155,3 +180,134 @@ where
         Ok(mac.finalize())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn cipher() -> ChaCha20Blake3 {
+        let key = Key::from_exact_iter(0..32).unwrap();
+        ChaCha20Blake3::new(&key)
+    }
+
+    fn nonce() -> Nonce {
+        Nonce::from_exact_iter(100..112).unwrap()
+    }
+
+    /// Encryptions of `(associated data, plaintext)` under `cipher()` and `nonce()`, as hex
+    /// `(ciphertext, tag)`. Any change of the key derivation or the tag breaks these.
+    const VECTORS: [(&[u8], &[u8], &str, &str); 3] = [
+        (
+            b"",
+            b"",
+            "",
+            "a1f021c2b237d74e1d4d13e428d6061611054812f29a1df5c95c61753b04f742",
+        ),
+        (
+            b"bakpak",
+            b"The quick brown fox jumps over the lazy dog",
+            "05d0a04441922f0c62195e5354d1c84154451437138468320a79cca38546cd19\
+             4f956fac1b3b3cbf6cbe2f",
+            "046d530e57124fd236155bc4ca20fa7b6c0bd71da69565c0fd102a9418b5fcb2",
+        ),
+        (
+            b"",
+            &[0x42; 130],
+            "13fa872672a5042d4b7b7e6379e4e42370682e553bb347003b1be197a276af2f\
+             65b20d82380307dd4a930a2c127e955afe9ba0078cee31d936b3f66763c7f483\
+             14895e7ce55323f1d3319e5b14fa2edfe15b52996df142c37d46ada0a5589f9d\
+             c4435de12fab9d5efd7f2b58ded239ffd699d7a31b8df91593876ee21bdbba18\
+             416d",
+            "47751835364dbdcc6f4e3925ba47aa2c0aed63e5d2d6462e285a499a49536bdf",
+        ),
+    ];
+
+    #[test]
+    fn test_vectors() {
+        for (ad, plaintext, ciphertext, tag) in VECTORS {
+            let mut buffer = plaintext.to_vec();
+            let computed_tag = cipher()
+                .encrypt_in_place_detached(&nonce(), ad, &mut buffer)
+                .unwrap();
+            assert_eq!(buffer, hex(ciphertext));
+            assert_eq!(computed_tag.to_vec(), hex(tag));
+
+            cipher()
+                .decrypt_in_place_detached(&nonce(), ad, &mut buffer, &computed_tag)
+                .unwrap();
+            assert_eq!(buffer, plaintext);
+        }
+    }
+
+    #[test]
+    fn test_max_length() {
+        assert!(check_length(0).is_ok());
+        assert!(check_length(MAX_BLOCKS * BLOCK_SIZE - 1).is_ok());
+        assert!(check_length(MAX_BLOCKS * BLOCK_SIZE).is_err());
+        assert!(check_length(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_tag_binds_split() {
+        let tag =
+            |ad: &[u8], ciphertext: &[u8]| cipher().compute_tag(&nonce(), ad, ciphertext).unwrap();
+        assert_ne!(tag(b"ab", b"c"), tag(b"a", b"bc"));
+        assert_ne!(tag(b"abc", b""), tag(b"", b"abc"));
+        assert_ne!(tag(b"", b""), tag(b"\0", b""));
+
+        // Moving a byte of the associated data into the ciphertext does not verify.
+        let mut buffer = b"c".to_vec();
+        let tag = cipher()
+            .encrypt_in_place_detached(&nonce(), b"ab", &mut buffer)
+            .unwrap();
+        assert!(decrypt(b"a", &[b"b".as_slice(), &buffer].concat(), &tag).is_err());
+    }
+
+    fn decrypt(ad: &[u8], ciphertext: &[u8], tag: &Tag) -> aead::Result<Vec<u8>> {
+        let mut buffer = ciphertext.to_vec();
+        cipher().decrypt_in_place_detached(&nonce(), ad, &mut buffer, tag)?;
+        Ok(buffer)
+    }
+
+    proptest! {
+        #[test]
+        fn test_roundtrip(
+            ad: Vec<u8>,
+            plaintext: Vec<u8>,
+            flip: prop::sample::Index,
+            bit in 0..8u8,
+        ) {
+            let mut ciphertext = plaintext.clone();
+            let tag = cipher()
+                .encrypt_in_place_detached(&nonce(), &ad, &mut ciphertext)
+                .unwrap();
+            prop_assert_eq!(decrypt(&ad, &ciphertext, &tag).unwrap(), plaintext);
+
+            // Flipping any bit of the ciphertext, associated data or tag fails authentication.
+            if !ciphertext.is_empty() {
+                let mut ciphertext = ciphertext.clone();
+                let i = flip.index(ciphertext.len());
+                ciphertext[i] ^= 1 << bit;
+                prop_assert!(decrypt(&ad, &ciphertext, &tag).is_err());
+            }
+            if !ad.is_empty() {
+                let mut ad = ad.clone();
+                let i = flip.index(ad.len());
+                ad[i] ^= 1 << bit;
+                prop_assert!(decrypt(&ad, &ciphertext, &tag).is_err());
+            }
+            let mut tag = tag;
+            tag[flip.index(32)] ^= 1 << bit;
+            prop_assert!(decrypt(&ad, &ciphertext, &tag).is_err());
+        }
+    }
+}
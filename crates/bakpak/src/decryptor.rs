@@ -0,0 +1,339 @@
+use std::io::{Read, Write};
+
+use aead::{AeadInPlace, KeyInit};
+use x25519_dalek::StaticSecret;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::{
+    chacha20_blake3::{ChaCha20Blake3, Tag},
+    common,
+    encryptor::{self, EncryptionKey},
+    stream_reader::read_exact,
+    StreamReader,
+};
+
+/// Decryptor for reading bakpak files.
+pub struct Decryptor {
+    identity: StaticSecret,
+}
+
+impl ZeroizeOnDrop for Decryptor {}
+
+impl Decryptor {
+    /// Creates a decryptor for files encrypted to the public key of `identity`.
+    pub fn new(identity: &StaticSecret) -> Decryptor {
+        Decryptor {
+            identity: identity.clone(),
+        }
+    }
+
+    /// Reads and verifies the bakpak header from `reader`, and returns a wrapper around it that
+    /// decrypts the payload.
+    ///
+    /// Returns [`Error::NotARecipient`](crate::Error::NotARecipient) if the file is not encrypted
+    /// for this identity.
+    pub fn wrap_input<R: Read>(self, mut reader: R) -> Result<StreamReader<R>, crate::Error> {
+        let (file_key, sender) = self.read_header(&mut reader)?;
+        let payload_encryption_key = Zeroizing::new(EncryptionKey::from(blake3::derive_key(
+            common::PAYLOAD_ENCRYPTION_KEY_CTX,
+            file_key.as_ref(),
+        )));
+
+        Ok(StreamReader::new(reader, sender, &payload_encryption_key))
+    }
+
+    /// Reads and verifies the bakpak header from `reader`, and returns a new header that wraps the
+    /// same file key for `recipients` instead.
+    ///
+    /// `reader` is left at the start of the payload, which can be appended to the new header as
+    /// is, without re-encrypting it. The payload stays signed by the original sender, so the new
+    /// header names the same sender. Rewrapping doesn't revoke access of former recipients that
+    /// kept the file key or the file itself.
+    pub fn rewrap<R: Read>(
+        self,
+        mut reader: R,
+        recipients: &[x25519_dalek::PublicKey],
+    ) -> Result<Vec<u8>, crate::Error> {
+        let (file_key, sender) = self.read_header(&mut reader)?;
+        encryptor::write_header(rand_core::OsRng, &file_key, &sender, recipients)
+    }
+
+    /// Reads and verifies the bakpak header from `reader`, and returns the file key and the
+    /// sender.
+    fn read_header(
+        &self,
+        mut reader: impl Read,
+    ) -> Result<(Zeroizing<[u8; 32]>, ed25519_dalek::VerifyingKey), crate::Error> {
+        let mut header = Vec::new();
+
+        let magic = read_array::<4>(&mut reader, &mut header)?;
+        if magic != common::BAKPAK_MAGIC {
+            return Err(crate::Error::InvalidMagic);
+        }
+
+        let recipient_count = u32::from_le_bytes(read_array(&mut reader, &mut header)?);
+        let ephemeral_share =
+            x25519_dalek::PublicKey::from(read_array::<32>(&mut reader, &mut header)?);
+
+        let mut shared_secret = self.identity.diffie_hellman(&ephemeral_share);
+        let mut recipient_mac_key =
+            blake3::derive_key(common::RECIPIENT_MAC_KEY_CTX, shared_secret.as_bytes());
+        let recipient_id = blake3::keyed_hash(
+            &recipient_mac_key,
+            x25519_dalek::PublicKey::from(&self.identity).as_bytes(),
+        );
+        recipient_mac_key.zeroize();
+
+        let mut wrap_key = blake3::derive_key(common::WRAP_KEY_CTX, shared_secret.as_bytes());
+        shared_secret.zeroize();
+        let cipher = ChaCha20Blake3::new((&wrap_key).into());
+        wrap_key.zeroize();
+
+        // Read all recipients, as the header MAC covers the whole header.
+        let mut file_key = Zeroizing::new(None);
+        for _ in 0..recipient_count {
+            let id = read_array::<32>(&mut reader, &mut header)?;
+            let mut wrapped_key = Zeroizing::new(read_array::<32>(&mut reader, &mut header)?);
+            let tag = Tag::from(read_array::<32>(&mut reader, &mut header)?);
+
+            if file_key.is_none()
+                && blake3::Hash::from(id) == recipient_id
+                && cipher
+                    .decrypt_in_place_detached(&Default::default(), &[], wrapped_key.as_mut(), &tag)
+                    .is_ok()
+            {
+                *file_key = Some(*wrapped_key);
+            }
+        }
+
+        let mut sender_id = read_array::<32>(&mut reader, &mut header)?;
+        let sender_id_tag = Tag::from(read_array::<32>(&mut reader, &mut header)?);
+        let header_len = header.len();
+        let header_mac = blake3::Hash::from(read_array::<32>(&mut reader, &mut header)?);
+
+        let Some(file_key) = *file_key else {
+            return Err(crate::Error::NotARecipient);
+        };
+        let file_key = Zeroizing::new(file_key);
+
+        let header_mac_key = Zeroizing::new(blake3::derive_key(
+            common::HEADER_MAC_KEY_CTX,
+            file_key.as_ref(),
+        ));
+        if blake3::keyed_hash(&header_mac_key, &header[..header_len]) != header_mac {
+            return Err(crate::Error::InvalidHeader);
+        }
+
+        let sender_encryption_key = Zeroizing::new(EncryptionKey::from(blake3::derive_key(
+            common::SENDER_ENCRYPTION_KEY_CTX,
+            file_key.as_ref(),
+        )));
+        ChaCha20Blake3::new(&sender_encryption_key)
+            .decrypt_in_place_detached(&Default::default(), &[], &mut sender_id, &sender_id_tag)
+            .map_err(|_| crate::Error::InvalidHeader)?;
+        let sender = ed25519_dalek::VerifyingKey::from_bytes(&sender_id)
+            .map_err(|_| crate::Error::InvalidHeader)?;
+
+        Ok((file_key, sender))
+    }
+
+    /// Decrypts the whole bakpak file from `reader` into `writer`, and returns the key of the
+    /// sender that has signed it.
+    ///
+    /// Segments are authenticated one by one before being written, so `writer` never receives
+    /// forged data. However, it may receive the plaintext of the leading segments before tampering
+    /// or truncation is detected in a later one. On error, discard everything written to `writer`.
+    pub fn decrypt_to<R: Read, W: Write>(
+        self,
+        reader: R,
+        mut writer: W,
+    ) -> Result<ed25519_dalek::VerifyingKey, crate::Error> {
+        let mut reader = self.wrap_input(reader)?;
+        reader.copy_to(&mut writer)?;
+        Ok(*reader.sender())
+    }
+}
+
+/// Reads `N` bytes of the header, appending them to `header`.
+fn read_array<const N: usize>(
+    reader: &mut impl Read,
+    header: &mut Vec<u8>,
+) -> Result<[u8; N], crate::Error> {
+    let mut buf = [0u8; N];
+    read_exact(reader, &mut buf)?;
+    header.extend_from_slice(&buf);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+    use crate::{stream_writer::SEGMENT_SIZE, Encryptor};
+
+    fn encrypt(
+        sender: &SigningKey,
+        recipients: &[x25519_dalek::PublicKey],
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut writer = Encryptor::new(sender, recipients)
+            .unwrap()
+            .wrap_output(Vec::new())
+            .unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(identity: &StaticSecret, encrypted: &[u8]) -> Result<Vec<u8>, crate::Error> {
+        let mut reader = Decryptor::new(identity).wrap_input(encrypted)?;
+        let mut result = Vec::new();
+        reader.read_to_end(&mut result).map_err(|err| {
+            match err.into_inner().map(|it| it.downcast::<crate::Error>()) {
+                Some(Ok(err)) => *err,
+                _ => panic!("unexpected error"),
+            }
+        })?;
+        Ok(result)
+    }
+
+    fn identity() -> StaticSecret {
+        StaticSecret::random_from_rng(rand_core::OsRng)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let sender = SigningKey::generate(&mut rand_core::OsRng);
+        let alice = identity();
+        let bob = identity();
+        let recipients = [(&alice).into(), (&bob).into()];
+
+        for len in [
+            0,
+            1,
+            255,
+            256,
+            SEGMENT_SIZE - 1,
+            SEGMENT_SIZE,
+            SEGMENT_SIZE + 1,
+            3 * SEGMENT_SIZE + 12345,
+        ] {
+            let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let encrypted = encrypt(&sender, &recipients, &data);
+
+            assert_eq!(decrypt(&alice, &encrypted).unwrap(), data, "len {len}");
+            assert_eq!(decrypt(&bob, &encrypted).unwrap(), data, "len {len}");
+
+            let reader = Decryptor::new(&alice).wrap_input(&encrypted[..]).unwrap();
+            assert_eq!(reader.sender(), &sender.verifying_key());
+
+            let mut output = Vec::new();
+            let verified = Decryptor::new(&bob)
+                .decrypt_to(&encrypted[..], &mut output)
+                .unwrap();
+            assert_eq!(verified, sender.verifying_key());
+            assert_eq!(output, data, "len {len}");
+        }
+    }
+
+    #[test]
+    fn test_decrypt_to_partial_output() {
+        let sender = SigningKey::generate(&mut rand_core::OsRng);
+        let alice = identity();
+        let data = vec![42u8; 2 * SEGMENT_SIZE];
+        let mut encrypted = encrypt(&sender, &[(&alice).into()], &data);
+        // Tamper with the last segment.
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+
+        let mut output = Vec::new();
+        assert!(matches!(
+            Decryptor::new(&alice).decrypt_to(&encrypted[..], &mut output),
+            Err(crate::Error::DecryptionError)
+        ));
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_rewrap() {
+        let sender = SigningKey::generate(&mut rand_core::OsRng);
+        let alice = identity();
+        let bob = identity();
+        let data = vec![42u8; SEGMENT_SIZE + 1];
+        let encrypted = encrypt(&sender, &[(&alice).into()], &data);
+
+        let mut reader = &encrypted[..];
+        let mut rewrapped = Decryptor::new(&alice)
+            .rewrap(&mut reader, &[(&bob).into()])
+            .unwrap();
+        // The payload is reused as is.
+        rewrapped.extend_from_slice(reader);
+
+        assert_eq!(decrypt(&bob, &rewrapped).unwrap(), data);
+        let reader = Decryptor::new(&bob).wrap_input(&rewrapped[..]).unwrap();
+        assert_eq!(reader.sender(), &sender.verifying_key());
+        assert!(matches!(
+            decrypt(&alice, &rewrapped),
+            Err(crate::Error::NotARecipient)
+        ));
+        assert!(matches!(
+            Decryptor::new(&bob).rewrap(&encrypted[..], &[(&bob).into()]),
+            Err(crate::Error::NotARecipient)
+        ));
+    }
+
+    #[test]
+    fn test_not_a_recipient() {
+        let sender = SigningKey::generate(&mut rand_core::OsRng);
+        let encrypted = encrypt(&sender, &[(&identity()).into()], b"hello");
+
+        assert!(matches!(
+            decrypt(&identity(), &encrypted),
+            Err(crate::Error::NotARecipient)
+        ));
+    }
+
+    #[test]
+    fn test_tampering() {
+        let sender = SigningKey::generate(&mut rand_core::OsRng);
+        let alice = identity();
+        let data = vec![42u8; 2 * SEGMENT_SIZE];
+        let encrypted = encrypt(&sender, &[(&alice).into()], &data);
+        let segment_size = SEGMENT_SIZE + 64 + 32;
+        let header_size = encrypted.len() - 3 * segment_size;
+
+        assert!(matches!(
+            decrypt(&alice, b"nope"),
+            Err(crate::Error::InvalidMagic)
+        ));
+
+        let mut modified = encrypted.clone();
+        modified[header_size - 1] ^= 1;
+        assert!(matches!(
+            decrypt(&alice, &modified),
+            Err(crate::Error::InvalidHeader)
+        ));
+
+        let mut modified = encrypted.clone();
+        modified[header_size + 100] ^= 1;
+        assert!(matches!(
+            decrypt(&alice, &modified),
+            Err(crate::Error::DecryptionError)
+        ));
+
+        // Dropping the last segment.
+        assert!(matches!(
+            decrypt(&alice, &encrypted[..encrypted.len() - segment_size]),
+            Err(crate::Error::Truncated)
+        ));
+
+        let mut modified = encrypted.clone();
+        modified.push(0);
+        assert!(matches!(
+            decrypt(&alice, &modified),
+            Err(crate::Error::TrailingData)
+        ));
+    }
+}
@@ -42,77 +42,15 @@ impl Encryptor {
         sender: &ed25519_dalek::SigningKey,
         recipients: &[Recipient],
     ) -> Result<Encryptor, crate::Error> {
-        if recipients.len() > u32::MAX as usize {
-            return Err(crate::Error::TooManyRecipients);
-        }
-
-        let mut file_key = [0u8; 32];
+        let mut file_key = Zeroizing::new([0u8; 32]);
         csprng.fill_bytes(file_key.as_mut());
 
-        let sender_encryption_key = Zeroizing::new(EncryptionKey::from(blake3::derive_key(
-            common::SENDER_ENCRYPTION_KEY_CTX,
-            file_key.as_ref(),
-        )));
-        let header_mac_key = Zeroizing::new(blake3::derive_key(
-            common::HEADER_MAC_KEY_CTX,
-            file_key.as_ref(),
-        ));
+        let header = write_header(csprng, &file_key, &sender.verifying_key(), recipients)?;
         let payload_encryption_key = Zeroizing::new(EncryptionKey::from(blake3::derive_key(
             common::PAYLOAD_ENCRYPTION_KEY_CTX,
             file_key.as_ref(),
         )));
 
-        file_key.zeroize();
-
-        let ephemeral_key = Zeroizing::new(ReusableSecret::random_from_rng(&mut csprng));
-
-        let header_size = /* magic: */ 4 +
-            /* recipient count: */ 4 +
-            /* ephemeral share: */ 32 +
-            /* recipients section: */ recipients.len() * (32 + 32+32) +
-            /* sender_id: */ 32 + 32 +
-            /* header mac: */ 32;
-        let mut header = Vec::with_capacity(header_size);
-        header.extend_from_slice(&common::BAKPAK_MAGIC);
-
-        header.extend_from_slice(&(recipients.len() as u32).to_le_bytes());
-        header.extend_from_slice(x25519_dalek::PublicKey::from(&*ephemeral_key).as_bytes());
-
-        for r in recipients {
-            let mut shared_secret = ephemeral_key.diffie_hellman(&r);
-            let mut recipient_mac_key =
-                blake3::derive_key(common::RECIPIENT_MAC_KEY_CTX, shared_secret.as_bytes());
-            let recipient_id = blake3::keyed_hash(&recipient_mac_key, r.as_bytes());
-            recipient_mac_key.zeroize();
-
-            let mut wrap_key = blake3::derive_key(common::WRAP_KEY_CTX, shared_secret.as_bytes());
-            shared_secret.zeroize();
-            let cipher = ChaCha20Blake3::new((&wrap_key).into());
-            wrap_key.zeroize();
-
-            let mut wrapped_key = file_key.clone();
-            let tag =
-                cipher.encrypt_in_place_detached(&Default::default(), &[], &mut wrapped_key)?;
-
-            header.extend_from_slice(recipient_id.as_bytes());
-            header.extend_from_slice(&wrapped_key);
-            header.extend_from_slice(&tag);
-        }
-
-        let mut sender_id = sender.verifying_key().to_bytes();
-        let sender_id_tag = ChaCha20Blake3::new(&sender_encryption_key).encrypt_in_place_detached(
-            &Default::default(),
-            &[],
-            &mut sender_id,
-        )?;
-        header.extend_from_slice(&sender_id);
-        header.extend_from_slice(&sender_id_tag);
-
-        let header_mac = blake3::keyed_hash(&*header_mac_key, &header);
-        header.extend_from_slice(header_mac.as_bytes());
-
-        debug_assert_eq!(header.len(), header_size, "header size miscalculation");
-
         Ok(Encryptor {
             header,
             signing_key: sender.clone(),
@@ -136,6 +74,86 @@ impl Encryptor {
     }
 }
 
+/// Creates the header of a bakpak file, wrapping `file_key` for every recipient and naming
+/// `sender` as the signer of the payload.
+pub(crate) fn write_header(
+    mut csprng: impl CryptoRng + RngCore,
+    file_key: &[u8; 32],
+    sender: &ed25519_dalek::VerifyingKey,
+    recipients: &[Recipient],
+) -> Result<Vec<u8>, crate::Error> {
+    if recipients.len() > u32::MAX as usize {
+        return Err(crate::Error::TooManyRecipients);
+    }
+
+    let sender_encryption_key = Zeroizing::new(EncryptionKey::from(blake3::derive_key(
+        common::SENDER_ENCRYPTION_KEY_CTX,
+        file_key.as_ref(),
+    )));
+    let header_mac_key = Zeroizing::new(blake3::derive_key(
+        common::HEADER_MAC_KEY_CTX,
+        file_key.as_ref(),
+    ));
+
+    let ephemeral_key = Zeroizing::new(ReusableSecret::random_from_rng(&mut csprng));
+
+    let header_size = /* magic: */ 4 +
+        /* recipient count: */ 4 +
+        /* ephemeral share: */ 32 +
+        /* recipients section: */ recipients.len() * (32 + 32+32) +
+        /* sender_id: */ 32 + 32 +
+        /* header mac: */ 32;
+    let mut header = Vec::with_capacity(header_size);
+    header.extend_from_slice(&common::BAKPAK_MAGIC);
+
+    header.extend_from_slice(&(recipients.len() as u32).to_le_bytes());
+    header.extend_from_slice(x25519_dalek::PublicKey::from(&*ephemeral_key).as_bytes());
+
+    // Every buffer holding key material is zeroized when it goes out of scope, by the end of
+    // the iteration at the latest, also on errors.
+    for r in recipients {
+        let (recipient_id, cipher) = {
+            let shared_secret = Zeroizing::new(ephemeral_key.diffie_hellman(r));
+            let recipient_mac_key = Zeroizing::new(blake3::derive_key(
+                common::RECIPIENT_MAC_KEY_CTX,
+                shared_secret.as_bytes(),
+            ));
+            let wrap_key = Zeroizing::new(blake3::derive_key(
+                common::WRAP_KEY_CTX,
+                shared_secret.as_bytes(),
+            ));
+            (
+                blake3::keyed_hash(&recipient_mac_key, r.as_bytes()),
+                ChaCha20Blake3::new((&*wrap_key).into()),
+            )
+        };
+        // Holds the plaintext file key until it's encrypted in place.
+        let mut wrapped_key = Zeroizing::new(*file_key);
+        let tag =
+            cipher.encrypt_in_place_detached(&Default::default(), &[], wrapped_key.as_mut())?;
+
+        header.extend_from_slice(recipient_id.as_bytes());
+        header.extend_from_slice(wrapped_key.as_ref());
+        header.extend_from_slice(&tag);
+    }
+
+    let mut sender_id = sender.to_bytes();
+    let sender_id_tag = ChaCha20Blake3::new(&sender_encryption_key).encrypt_in_place_detached(
+        &Default::default(),
+        &[],
+        &mut sender_id,
+    )?;
+    header.extend_from_slice(&sender_id);
+    header.extend_from_slice(&sender_id_tag);
+
+    let header_mac = blake3::keyed_hash(&header_mac_key, &header);
+    header.extend_from_slice(header_mac.as_bytes());
+
+    debug_assert_eq!(header.len(), header_size, "header size miscalculation");
+
+    Ok(header)
+}
+
 #[cfg(test)]
 mod tests {
     use ed25519_dalek::SigningKey;
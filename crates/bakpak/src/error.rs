@@ -7,6 +7,22 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("encryption error")]
     EncryptionError,
+    #[error("not a bakpak file")]
+    InvalidMagic,
+    #[error("file is not encrypted for this recipient")]
+    NotARecipient,
+    #[error("invalid header")]
+    InvalidHeader,
+    #[error("decryption error")]
+    DecryptionError,
+    #[error("invalid segment signature")]
+    InvalidSignature,
+    #[error("invalid segment padding")]
+    InvalidPadding,
+    #[error("stream is truncated")]
+    Truncated,
+    #[error("unexpected data after the last segment")]
+    TrailingData,
 }
 
 impl From<Error> for std::io::Error {
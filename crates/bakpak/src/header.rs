@@ -0,0 +1,101 @@
+use crate::common;
+
+/// Size of the header fields before the recipients: magic, recipient count, and ephemeral share.
+const PREFIX_SIZE: usize = 4 + 4 + 32;
+/// Size of a recipient: ID, wrapped file key, and its tag.
+const RECIPIENT_SIZE: usize = 32 + 32 + 32;
+/// Size of the header fields after the recipients: encrypted sender ID, its tag, and header MAC.
+const SUFFIX_SIZE: usize = 32 + 32 + 32;
+
+/// Unauthenticated information from the header of a bakpak file, returned by [`peek`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderInfo {
+    /// Ephemeral public key the file key is wrapped with for every recipient.
+    pub ephemeral_share: x25519_dalek::PublicKey,
+    pub recipient_count: u32,
+    /// Size of the header, i.e., offset of the encrypted payload.
+    pub header_len: usize,
+}
+
+/// Checks that `header` starts with a complete bakpak header, without any key.
+///
+/// This is a cheap check for whether data is a bakpak file, and whether it is truncated within
+/// the header. The header MAC is keyed by the file key, so it can't be verified here: nothing in
+/// the returned info is authenticated until the file is decrypted with the secret of a recipient
+/// by [`Decryptor`](crate::Decryptor).
+///
+/// Returns [`Error::InvalidMagic`](crate::Error::InvalidMagic) if `header` is not a bakpak file,
+/// and [`Error::Truncated`](crate::Error::Truncated) if it ends before the end of the header.
+pub fn peek(header: &[u8]) -> Result<HeaderInfo, crate::Error> {
+    let magic_len = header.len().min(common::BAKPAK_MAGIC.len());
+    if header[..magic_len] != common::BAKPAK_MAGIC[..magic_len] {
+        return Err(crate::Error::InvalidMagic);
+    }
+
+    let prefix = header.get(..PREFIX_SIZE).ok_or(crate::Error::Truncated)?;
+    let recipient_count =
+        u32::from_le_bytes(prefix[4..8].try_into().expect("slice is 4 bytes long"));
+    let ephemeral_share: [u8; 32] = prefix[8..].try_into().expect("slice is 32 bytes long");
+
+    let header_len = (recipient_count as usize)
+        .checked_mul(RECIPIENT_SIZE)
+        .and_then(|it| it.checked_add(PREFIX_SIZE + SUFFIX_SIZE))
+        .ok_or(crate::Error::InvalidHeader)?;
+    if header.len() < header_len {
+        return Err(crate::Error::Truncated);
+    }
+
+    Ok(HeaderInfo {
+        ephemeral_share: ephemeral_share.into(),
+        recipient_count,
+        header_len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use ed25519_dalek::SigningKey;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::*;
+    use crate::Encryptor;
+
+    #[test]
+    fn test_peek() {
+        let sender = SigningKey::generate(&mut rand_core::OsRng);
+        let recipients =
+            [(); 3].map(|_| PublicKey::from(&StaticSecret::random_from_rng(rand_core::OsRng)));
+        let mut writer = Encryptor::new(&sender, &recipients)
+            .unwrap()
+            .wrap_output(Vec::new())
+            .unwrap();
+        writer.write_all(b"hello").unwrap();
+        let encrypted = writer.finish().unwrap();
+
+        let info = peek(&encrypted).unwrap();
+        assert_eq!(info.recipient_count, 3);
+        assert_eq!(
+            info.header_len,
+            PREFIX_SIZE + 3 * RECIPIENT_SIZE + SUFFIX_SIZE
+        );
+        assert_eq!(
+            info.ephemeral_share.as_bytes()[..],
+            encrypted[8..PREFIX_SIZE]
+        );
+        assert_eq!(peek(&encrypted[..info.header_len]).unwrap(), info);
+
+        assert!(matches!(
+            peek(&encrypted[..info.header_len - 1]),
+            Err(crate::Error::Truncated)
+        ));
+        assert!(matches!(peek(b"ba"), Err(crate::Error::Truncated)));
+        assert!(matches!(peek(b"nope"), Err(crate::Error::InvalidMagic)));
+
+        // Claims more recipients than there are.
+        let mut modified = encrypted.clone();
+        modified[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(peek(&modified).is_err());
+    }
+}
@@ -1,9 +1,15 @@
 mod chacha20_blake3;
 mod common;
+mod decryptor;
 mod encryptor;
 mod error;
+mod header;
+mod stream_reader;
 mod stream_writer;
 
+pub use decryptor::Decryptor;
 pub use encryptor::Encryptor;
 pub use error::Error;
+pub use header::{peek, HeaderInfo};
+pub use stream_reader::StreamReader;
 pub use stream_writer::StreamWriter;
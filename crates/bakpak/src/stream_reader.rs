@@ -0,0 +1,171 @@
+use std::io::{Read, Write};
+
+use aead::{AeadInPlace, KeyInit};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::{
+    chacha20_blake3::{ChaCha20Blake3, Nonce, Tag},
+    encryptor::EncryptionKey,
+    stream_writer::{segment_nonce, signature_base, Segment, ENCRYPTED_SEGMENT_SIZE, SEGMENT_SIZE},
+};
+
+const SIGNED_SEGMENT_SIZE: usize = SEGMENT_SIZE + ed25519_dalek::Signature::BYTE_SIZE;
+
+/// Reader that decrypts a bakpak stream and verifies its signatures.
+///
+/// Created by [`Decryptor::wrap_input`](crate::Decryptor::wrap_input). Every segment is
+/// authenticated before any of its bytes are returned, so a failed read never yields forged data.
+/// A stream ending before its last segment fails with [`Error::Truncated`](crate::Error::Truncated)
+/// rather than reporting a clean end of file.
+pub struct StreamReader<R> {
+    reader: R,
+    sender: ed25519_dalek::VerifyingKey,
+    encryption_key: EncryptionKey,
+    segment_count: u64,
+    /// Plaintext of the current segment.
+    segment: Segment,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R> Drop for StreamReader<R> {
+    fn drop(&mut self) {
+        self.encryption_key.zeroize();
+        self.segment.zeroize();
+    }
+}
+
+impl<R> ZeroizeOnDrop for StreamReader<R> {}
+
+impl<R> StreamReader<R> {
+    pub(crate) fn new(
+        reader: R,
+        sender: ed25519_dalek::VerifyingKey,
+        encryption_key: &EncryptionKey,
+    ) -> Self {
+        StreamReader {
+            reader,
+            sender,
+            encryption_key: *encryption_key,
+            segment_count: 0,
+            segment: Box::default(),
+            pos: 0,
+            finished: false,
+        }
+    }
+
+    /// Key of the sender that has signed the stream.
+    pub fn sender(&self) -> &ed25519_dalek::VerifyingKey {
+        &self.sender
+    }
+}
+
+impl<R: Read> StreamReader<R> {
+    fn read_segment(&mut self) -> Result<(), crate::Error> {
+        self.segment.clear();
+        self.pos = 0;
+        self.segment
+            .extend(std::iter::repeat_n(0, ENCRYPTED_SEGMENT_SIZE));
+        read_exact(&mut self.reader, &mut self.segment)?;
+
+        let (body, tag) = self.segment.split_at_mut(SIGNED_SEGMENT_SIZE);
+        let tag = Tag::clone_from_slice(tag);
+        let cipher = ChaCha20Blake3::new(&self.encryption_key);
+
+        // The last segment is encrypted with a different nonce, so try both.
+        let mut last_segment = false;
+        let mut nonce = segment_nonce(self.segment_count, false);
+        if cipher
+            .decrypt_in_place_detached(Nonce::from_slice(&nonce), &[], body, &tag)
+            .is_err()
+        {
+            last_segment = true;
+            nonce = segment_nonce(self.segment_count, true);
+            cipher
+                .decrypt_in_place_detached(Nonce::from_slice(&nonce), &[], body, &tag)
+                .map_err(|_| crate::Error::DecryptionError)?;
+        }
+
+        let (plaintext, signature) = body.split_at(SEGMENT_SIZE);
+        let signature = ed25519_dalek::Signature::from_slice(signature)
+            .map_err(|_| crate::Error::InvalidSignature)?;
+        self.sender
+            .verify_strict(
+                &signature_base(&self.encryption_key, &nonce, plaintext),
+                &signature,
+            )
+            .map_err(|_| crate::Error::InvalidSignature)?;
+
+        let len = if last_segment {
+            SEGMENT_SIZE - padding_len(plaintext)?
+        } else {
+            SEGMENT_SIZE
+        };
+        self.segment[len..].zeroize();
+        self.segment.truncate(len);
+        self.segment_count += 1;
+
+        if last_segment {
+            self.finished = true;
+            match read_exact(&mut self.reader, &mut [0u8]) {
+                Ok(()) => return Err(crate::Error::TrailingData),
+                Err(crate::Error::Truncated) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the rest of the plaintext to `writer`, a segment at a time.
+    pub(crate) fn copy_to(&mut self, writer: &mut impl Write) -> Result<(), crate::Error> {
+        loop {
+            writer.write_all(&self.segment[self.pos..])?;
+            self.pos = self.segment.len();
+            if self.finished {
+                break;
+            }
+            self.read_segment()?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.segment.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_segment()?;
+        }
+
+        let len = usize::min(buf.len(), self.segment.len() - self.pos);
+        buf[..len].copy_from_slice(&self.segment[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Like [`Read::read_exact`], but reports end of the stream as [`Error::Truncated`](crate::Error::Truncated).
+pub(crate) fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), crate::Error> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => crate::Error::Truncated,
+        _ => err.into(),
+    })
+}
+
+/// Length of the padding of the last segment. See `StreamState::pad_segment`.
+fn padding_len(segment: &[u8]) -> Result<usize, crate::Error> {
+    debug_assert_eq!(segment.len(), SEGMENT_SIZE);
+
+    match segment[SEGMENT_SIZE - 1] {
+        0 => match u16::from_le_bytes([segment[SEGMENT_SIZE - 3], segment[SEGMENT_SIZE - 2]]) {
+            0 => Ok(SEGMENT_SIZE),
+            len if len > u8::MAX as u16 => Ok(len as usize),
+            _ => Err(crate::Error::InvalidPadding),
+        },
+        len => Ok(len as usize),
+    }
+}
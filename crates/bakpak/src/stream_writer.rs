@@ -3,44 +3,53 @@ use std::io::Write;
 use aead::{AeadCore, AeadInPlace, KeyInit};
 use arrayvec::ArrayVec;
 use ed25519_dalek::ed25519::signature::Signer;
-use generic_array::{typenum::Unsigned, GenericArray};
+use generic_array::typenum::Unsigned;
 use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
-use crate::{chacha20_blake3::ChaCha20Blake3, encryptor::EncryptionKey};
+use crate::{
+    chacha20_blake3::{ChaCha20Blake3, Nonce},
+    encryptor::EncryptionKey,
+};
 
 const SIGNATURE_DOMAIN_LEN: usize = 15;
 const SIGNATURE_DOMAIN: &[u8; SIGNATURE_DOMAIN_LEN] = b"bakpak segment\0";
 
-const SEGMENT_SIZE: usize = 64 * 1024;
+pub(crate) const SEGMENT_SIZE: usize = 64 * 1024;
 
-type Segment = Box<
-    ArrayVec<
-        u8,
-        {
-            SEGMENT_SIZE
-                + ed25519_dalek::Signature::BYTE_SIZE
-                + <ChaCha20Blake3 as AeadCore>::TagSize::USIZE
-        },
-    >,
->;
+/// Size of an encrypted segment: padded plaintext, signature, and authentication tag.
+pub(crate) const ENCRYPTED_SEGMENT_SIZE: usize = SEGMENT_SIZE
+    + ed25519_dalek::Signature::BYTE_SIZE
+    + <ChaCha20Blake3 as AeadCore>::TagSize::USIZE;
+
+pub(crate) type Segment = Box<ArrayVec<u8, ENCRYPTED_SEGMENT_SIZE>>;
 
 struct StreamState {
     signing_key: ed25519_dalek::SigningKey,
     encryption_key: EncryptionKey,
     segment_count: usize,
     segment: Segment,
+    /// Buffer of a completed segment that has been written out, reused for the next segment.
+    spare: Option<Segment>,
 }
 
 impl Drop for StreamState {
     fn drop(&mut self) {
-        &self.signing_key as &dyn ZeroizeOnDrop;
         self.encryption_key.zeroize();
         self.segment.zeroize();
+        if let Some(spare) = &mut self.spare {
+            spare.zeroize();
+        }
     }
 }
 
 impl ZeroizeOnDrop for StreamState {}
 
+// The signing key isn't zeroized by `drop` above, as it zeroizes itself.
+const _: fn() = || {
+    fn assert_zeroize_on_drop<T: ZeroizeOnDrop>() {}
+    assert_zeroize_on_drop::<ed25519_dalek::SigningKey>();
+};
+
 impl StreamState {
     pub fn new(
         signing_key: &ed25519_dalek::SigningKey,
@@ -51,9 +60,17 @@ impl StreamState {
             encryption_key: *encryption_key,
             segment_count: 0,
             segment: Box::new(ArrayVec::new()),
+            spare: None,
         }
     }
 
+    /// Return the buffer of a completed segment once it's written, so that the next segment
+    /// doesn't need a new allocation.
+    pub fn recycle(&mut self, mut segment: Segment) {
+        segment.clear();
+        self.spare = Some(segment);
+    }
+
     /// Try writing `buf` into the stream.
     ///
     /// Returns number of bytes consumed and potentially a completed segment.
@@ -77,10 +94,16 @@ impl StreamState {
         self.signcrypt_segment(true)
     }
 
+    /// Pad the last segment to `SEGMENT_SIZE`.
+    ///
+    /// Padding is zeros followed by its length: a single byte if the length fits into one, or
+    /// otherwise a little-endian `u16` and a zero byte. An empty last segment is padded entirely
+    /// and its length `SEGMENT_SIZE` is written as `0`, which is not a valid padding length
+    /// otherwise.
     fn pad_segment(segment: &mut Segment) {
         let to_pad = SEGMENT_SIZE - segment.len();
         debug_assert!(to_pad > 0);
-        debug_assert!(to_pad <= u16::MAX as usize);
+        debug_assert!(to_pad <= SEGMENT_SIZE);
 
         if let Ok(byte) = u8::try_from(to_pad) {
             segment.extend(std::iter::repeat_n(0, to_pad - 1));
@@ -106,33 +129,17 @@ impl StreamState {
 
         debug_assert_eq!(self.segment.len(), SEGMENT_SIZE);
 
-        let nonce = Self::nonce(self.segment_count as u64, last_segment);
-
-        // 15 bytes signature domain, 32 bytes key, 12 bytes nonce, 32 bytes content hash
-        let mut signature_base =
-            Zeroizing::new(ArrayVec::<u8, { SIGNATURE_DOMAIN_LEN + 32 + 12 + 32 }>::new());
-        signature_base
-            .try_extend_from_slice(SIGNATURE_DOMAIN)
-            .unwrap();
-        signature_base
-            .try_extend_from_slice(&self.encryption_key)
-            .unwrap();
-        signature_base.try_extend_from_slice(&nonce).unwrap();
-        signature_base
-            .try_extend_from_slice(blake3::hash(&self.segment).as_bytes())
-            .unwrap();
-        debug_assert_eq!(signature_base.remaining_capacity(), 0);
+        let nonce = segment_nonce(self.segment_count as u64, last_segment);
 
+        let signature_base = signature_base(&self.encryption_key, &nonce, &self.segment);
         let signature = self.signing_key.sign(&signature_base);
         self.segment
             .try_extend_from_slice(&signature.to_bytes())
             .unwrap();
 
         let cipher = ChaCha20Blake3::new(&self.encryption_key);
-        &cipher as &dyn ZeroizeOnDrop;
-
         let tag = cipher.encrypt_in_place_detached(
-            GenericArray::from_slice(&nonce),
+            Nonce::from_slice(&nonce),
             &[],
             &mut self.segment,
         )?;
@@ -140,24 +147,48 @@ impl StreamState {
         self.segment.try_extend_from_slice(&tag).unwrap();
 
         self.segment_count += 1;
-        Ok(std::mem::take(&mut self.segment))
+        let next = self.spare.take().unwrap_or_default();
+        Ok(std::mem::replace(&mut self.segment, next))
     }
 
     fn segment_capacity(&self) -> usize {
         SEGMENT_SIZE - self.segment.len()
     }
+}
 
-    fn nonce(counter: u64, last_segment: bool) -> [u8; 12] {
-        debug_assert!(counter <= (u64::MAX >> 1));
+pub(crate) fn segment_nonce(counter: u64, last_segment: bool) -> [u8; 12] {
+    debug_assert!(counter <= (u64::MAX >> 1));
 
-        let nonce = counter | (last_segment as u64) << 63;
+    let nonce = counter | (last_segment as u64) << 63;
 
-        let mut result = [0u8; 12];
-        let (_, right) = result.split_at_mut(4);
-        right.copy_from_slice(&nonce.to_le_bytes());
+    let mut result = [0u8; 12];
+    let (_, right) = result.split_at_mut(4);
+    right.copy_from_slice(&nonce.to_le_bytes());
 
-        result
-    }
+    result
+}
+
+/// Message signed for every segment, binding the signature to the stream key and the segment
+/// position.
+pub(crate) fn signature_base(
+    encryption_key: &EncryptionKey,
+    nonce: &[u8; 12],
+    segment: &[u8],
+) -> Zeroizing<ArrayVec<u8, { SIGNATURE_DOMAIN_LEN + 32 + 12 + 32 }>> {
+    // 15 bytes signature domain, 32 bytes key, 12 bytes nonce, 32 bytes content hash
+    let mut signature_base = Zeroizing::new(ArrayVec::new());
+    signature_base
+        .try_extend_from_slice(SIGNATURE_DOMAIN)
+        .unwrap();
+    signature_base
+        .try_extend_from_slice(encryption_key)
+        .unwrap();
+    signature_base.try_extend_from_slice(nonce).unwrap();
+    signature_base
+        .try_extend_from_slice(blake3::hash(segment).as_bytes())
+        .unwrap();
+    debug_assert_eq!(signature_base.remaining_capacity(), 0);
+    signature_base
 }
 
 pub struct StreamWriter<W> {
@@ -206,7 +237,8 @@ impl<W: Write> StreamWriter<W> {
 
             *pos += written;
             if *pos == segment.len() {
-                self.pending_segment.take();
+                let (segment, _) = self.pending_segment.take().expect("checked above");
+                self.state.recycle(segment);
             }
         }
 
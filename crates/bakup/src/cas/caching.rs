@@ -0,0 +1,128 @@
+use bytes::Bytes;
+use tracing::warn;
+
+use super::ContentAddressableStorage;
+
+/// Content-addressable storage that layers a fast `cache` store over a slow `source` store.
+///
+/// Reads are served from the cache when possible, falling back to the source and populating the
+/// cache on success. Writes go through to both stores. Because objects are content-addressed,
+/// cached bytes can never be stale.
+///
+/// The source is authoritative: `list` and `contains` only consult the source, because the
+/// cache may keep objects that have since been removed from the source by another client.
+pub struct CachingCas<F, S> {
+    cache: F,
+    source: S,
+}
+
+impl<F, S> CachingCas<F, S> {
+    pub fn new(cache: F, source: S) -> Self {
+        CachingCas { cache, source }
+    }
+
+    pub fn cache(&self) -> &F {
+        &self.cache
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+}
+
+impl<F, S> ContentAddressableStorage for CachingCas<F, S>
+where
+    F: ContentAddressableStorage<Hash = S::Hash>,
+    S: ContentAddressableStorage,
+    S::Error: From<F::Error>,
+{
+    type Error = S::Error;
+    type Hash = S::Hash;
+
+    fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        self.source.list()
+    }
+
+    fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+        match self.cache.get(hash.clone()) {
+            Ok(Some(bytes)) => return Ok(Some(bytes)),
+            Ok(None) => {}
+            Err(err) => warn!("failed to read from cache: {err}"),
+        }
+
+        let bytes = self.source.get(hash.clone())?;
+        if let Some(bytes) = &bytes {
+            // Populating the cache is best-effort, the read itself has succeeded.
+            if let Err(err) = self.cache.put(&hash, bytes.clone()) {
+                warn!("failed to populate cache: {err}");
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        self.source.hash(bytes)
+    }
+
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        self.source.put(hash, bytes.clone())?;
+        self.cache.put(hash, bytes)?;
+        Ok(())
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.source.contains(hash)
+    }
+
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        self.source.size(hash)
+    }
+
+    fn verify(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        // Verify the authoritative copy, a healthy cached copy says nothing about the source.
+        self.source.verify(hash)
+    }
+
+    fn is_retryable(err: &Self::Error) -> bool {
+        S::is_retryable(err)
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.cache.remove(hash)?;
+        self.source.remove(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::MemoryCas;
+
+    #[test]
+    fn test_get_populates_cache() {
+        let source = MemoryCas::<blake3::Hasher>::new();
+        let hash = source.store(Bytes::from_static(b"hello")).unwrap();
+
+        let cas = CachingCas::new(MemoryCas::<blake3::Hasher>::new(), source);
+        assert!(cas.cache().is_empty());
+
+        assert_eq!(cas.get(hash).unwrap(), Some(Bytes::from_static(b"hello")));
+        assert!(cas.cache().contains(&hash).unwrap());
+
+        // Now it should be served from the cache alone.
+        cas.source().remove(&hash).unwrap();
+        assert_eq!(cas.get(hash).unwrap(), Some(Bytes::from_static(b"hello")));
+    }
+
+    #[test]
+    fn test_store_writes_through() {
+        let cas = CachingCas::new(
+            MemoryCas::<blake3::Hasher>::new(),
+            MemoryCas::<blake3::Hasher>::new(),
+        );
+
+        let hash = cas.store(Bytes::from_static(b"hello")).unwrap();
+        assert!(cas.cache().contains(&hash).unwrap());
+        assert!(cas.source().contains(&hash).unwrap());
+    }
+}
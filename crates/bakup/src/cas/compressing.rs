@@ -0,0 +1,178 @@
+use std::io;
+
+use bytes::Bytes;
+
+use super::{
+    ContentAddressableStorage,
+    envelope::{self, Codec},
+};
+
+/// How [`CompressingCas`] compresses new objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// zstd with the given level. `0` selects the zstd default.
+    Zstd {
+        level: i32,
+    },
+}
+
+/// Content-addressable storage that transparently compresses objects stored in `inner`.
+///
+/// Objects are addressed by the hash of their *uncompressed* content, so deduplication works on
+/// the original data and hashes do not depend on the compression settings. The compressed bytes
+/// are saved with [`put`](ContentAddressableStorage::put), so for the inner store the hash is just
+/// a key and it no longer matches the stored bytes.
+///
+/// Every stored object is wrapped in an envelope with its codec, so objects written with different
+/// settings can be read back regardless of the current [`Compression`]. Objects that don't shrink
+/// are stored uncompressed.
+pub struct CompressingCas<S> {
+    inner: S,
+    compression: Compression,
+}
+
+impl<S> CompressingCas<S> {
+    pub fn new(inner: S, compression: Compression) -> Self {
+        CompressingCas { inner, compression }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn encode(&self, bytes: &[u8]) -> io::Result<Bytes> {
+        if let Compression::Zstd { level } = self.compression {
+            let compressed = zstd::bulk::compress(bytes, level)?;
+            if compressed.len() < bytes.len() {
+                return Ok(envelope::seal(Codec::Zstd, &compressed));
+            }
+        }
+        Ok(envelope::seal(Codec::None, bytes))
+    }
+
+    fn decode(stored: Bytes) -> io::Result<Bytes> {
+        match envelope::open(stored)? {
+            (Codec::None, payload) => Ok(payload),
+            (Codec::Zstd, payload) => Ok(Bytes::from(zstd::decode_all(&payload[..])?)),
+            // Decrypted by `EncryptedCas` if there is one in between.
+            (Codec::Bakpak, _) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "object is encrypted, but no key is given",
+            )),
+        }
+    }
+}
+
+impl<S> ContentAddressableStorage for CompressingCas<S>
+where
+    S: ContentAddressableStorage,
+    S::Error: From<io::Error>,
+{
+    type Error = S::Error;
+    type Hash = S::Hash;
+
+    fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        self.inner.list()
+    }
+
+    fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+        let Some(stored) = self.inner.get(hash)? else {
+            return Ok(None);
+        };
+        Ok(Some(Self::decode(stored)?))
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        self.inner.hash(bytes)
+    }
+
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        if self.inner.contains(hash)? {
+            return Ok(());
+        }
+        self.inner.put(hash, self.encode(&bytes)?)
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.inner.contains(hash)
+    }
+
+    /// Size of the stored (compressed) object.
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        self.inner.size(hash)
+    }
+
+    fn is_retryable(err: &Self::Error) -> bool {
+        S::is_retryable(err)
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.inner.remove(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use digest::Digest;
+
+    use super::*;
+    use crate::cas::MemoryCas;
+
+    const ZSTD: Compression = Compression::Zstd { level: 3 };
+
+    #[test]
+    fn test_roundtrip() {
+        let cas = CompressingCas::new(MemoryCas::<blake3::Hasher>::new(), ZSTD);
+
+        let data = Bytes::from("hello world ".repeat(1000));
+        let hash = cas.store(data.clone()).unwrap();
+
+        assert_eq!(hash, blake3::Hasher::digest(&data));
+        assert_eq!(cas.get(hash).unwrap(), Some(data.clone()));
+
+        let stored = cas.inner().get(hash).unwrap().unwrap();
+        assert_eq!(stored[0], Codec::Zstd as u8);
+        assert!(stored.len() < data.len());
+    }
+
+    #[test]
+    fn test_incompressible() {
+        let cas = CompressingCas::new(MemoryCas::<blake3::Hasher>::new(), ZSTD);
+
+        let data = Bytes::from_static(b"abc");
+        let hash = cas.store(data.clone()).unwrap();
+
+        assert_eq!(cas.inner().get(hash).unwrap().unwrap(), &b"\0abc"[..]);
+        assert_eq!(cas.get(hash).unwrap(), Some(data));
+    }
+
+    #[test]
+    fn test_reads_any_codec() {
+        let cas = CompressingCas::new(MemoryCas::<blake3::Hasher>::new(), ZSTD);
+        let data = Bytes::from("hello world ".repeat(1000));
+        let hash = cas.store(data.clone()).unwrap();
+
+        let cas = CompressingCas::new(cas.inner, Compression::None);
+        assert_eq!(cas.get(hash).unwrap(), Some(data));
+
+        let unknown = cas.hash(b"unknown");
+        cas.inner()
+            .put(&unknown, Bytes::from_static(b"\x7funknown"))
+            .unwrap();
+        assert!(cas.get(unknown).is_err());
+
+        let encrypted = cas.hash(b"encrypted");
+        cas.inner()
+            .put(&encrypted, envelope::seal(Codec::Bakpak, b"bak0"))
+            .unwrap();
+        let err = cas.get(encrypted).unwrap_err();
+        assert!(err.to_string().contains("no key"));
+    }
+
+    #[test]
+    fn test_get_missing() {
+        let cas = CompressingCas::new(MemoryCas::<blake3::Hasher>::new(), ZSTD);
+        assert_eq!(cas.get(Default::default()).unwrap(), None);
+    }
+}
@@ -1,4 +1,5 @@
 use bytes::Bytes;
+use itertools::Either;
 
 pub trait ContentAddressableStorage {
     type Hash: Clone + Eq + Ord + std::hash::Hash;
@@ -11,5 +12,139 @@ pub trait ContentAddressableStorage {
     fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error>;
 
     // Store bytes and return their content hash. This may be a no-op if bytes are already stored.
-    fn store(&self, bytes: Bytes) -> Result<Self::Hash, Self::Error>;
+    fn store(&self, bytes: Bytes) -> Result<Self::Hash, Self::Error> {
+        let hash = self.hash(&bytes);
+        self.put(&hash, bytes)?;
+        Ok(hash)
+    }
+
+    // Store multiple objects and return their content hashes in the same order.
+    //
+    // The default implementation stores objects one by one. Backends may override it to store
+    // objects concurrently or pipeline requests.
+    fn store_many(
+        &self,
+        items: impl IntoIterator<Item = Bytes>,
+    ) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        items.into_iter().map(|bytes| self.store(bytes))
+    }
+
+    // Compute content hash of bytes, as used by `store`.
+    fn hash(&self, bytes: &[u8]) -> Self::Hash;
+
+    // Store bytes under the given hash without checking that the hash matches the bytes. This may
+    // be a no-op if the hash is already stored.
+    //
+    // This is the low-level counterpart of `store`, for decorators that transform stored bytes
+    // (e.g., compress them) while keeping them addressed by the hash of the original content.
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error>;
+
+    // Check whether bytes with the given hash are stored, without reading them.
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error>;
+
+    // Get size of the stored object in bytes, or `None` if it is missing.
+    //
+    // The default implementation reads the whole object. Backends should override it with a
+    // cheaper metadata lookup where possible.
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        Ok(self.get(hash.clone())?.map(|bytes| bytes.len() as u64))
+    }
+
+    // Check that the object stored under the given hash is intact, i.e., that its content still
+    // hashes to the same value. Returns `false` for corrupted or missing objects.
+    //
+    // This reads the whole object, so it is an O(data) operation.
+    fn verify(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        Ok(self
+            .get(hash.clone())?
+            .is_some_and(|bytes| self.hash(&bytes) == *hash))
+    }
+
+    // Whether the operation that failed with `err` may succeed if retried (e.g., on timeouts and
+    // other transient network failures). Used by `RetryingCas`.
+    fn is_retryable(err: &Self::Error) -> bool {
+        let _ = err;
+        false
+    }
+
+    // Remove bytes by their content hash. Returns `true` if the object existed and was removed.
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error>;
+}
+
+// Either of two stores, e.g. to pick a decorator at runtime.
+impl<L, R> ContentAddressableStorage for Either<L, R>
+where
+    L: ContentAddressableStorage,
+    R: ContentAddressableStorage<Hash = L::Hash, Error = L::Error>,
+{
+    type Hash = L::Hash;
+    type Error = L::Error;
+
+    fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        self.as_ref().map_either(L::list, R::list)
+    }
+
+    fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+        match self {
+            Either::Left(cas) => cas.get(hash),
+            Either::Right(cas) => cas.get(hash),
+        }
+    }
+
+    fn store_many(
+        &self,
+        items: impl IntoIterator<Item = Bytes>,
+    ) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        match self {
+            Either::Left(cas) => Either::Left(cas.store_many(items)),
+            Either::Right(cas) => Either::Right(cas.store_many(items)),
+        }
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        match self {
+            Either::Left(cas) => cas.hash(bytes),
+            Either::Right(cas) => cas.hash(bytes),
+        }
+    }
+
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        match self {
+            Either::Left(cas) => cas.put(hash, bytes),
+            Either::Right(cas) => cas.put(hash, bytes),
+        }
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        match self {
+            Either::Left(cas) => cas.contains(hash),
+            Either::Right(cas) => cas.contains(hash),
+        }
+    }
+
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        match self {
+            Either::Left(cas) => cas.size(hash),
+            Either::Right(cas) => cas.size(hash),
+        }
+    }
+
+    fn verify(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        match self {
+            Either::Left(cas) => cas.verify(hash),
+            Either::Right(cas) => cas.verify(hash),
+        }
+    }
+
+    // The error type is shared, so the error might have come from either store.
+    fn is_retryable(err: &Self::Error) -> bool {
+        L::is_retryable(err) || R::is_retryable(err)
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        match self {
+            Either::Left(cas) => cas.remove(hash),
+            Either::Right(cas) => cas.remove(hash),
+        }
+    }
 }
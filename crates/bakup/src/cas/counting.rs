@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+
+use super::ContentAddressableStorage;
+
+/// Content-addressable storage that counts objects newly written to `inner`.
+///
+/// Objects that are already present are not counted, so the counters show how much a backup has
+/// actually added to the store. Wrap the innermost store to count stored (e.g. compressed) bytes.
+pub struct CountingCas<S> {
+    inner: S,
+    new_objects: AtomicU64,
+    new_bytes: AtomicU64,
+}
+
+impl<S> CountingCas<S> {
+    pub fn new(inner: S) -> Self {
+        CountingCas {
+            inner,
+            new_objects: AtomicU64::new(0),
+            new_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Number of objects written that were not present before.
+    pub fn new_objects(&self) -> u64 {
+        self.new_objects.load(Ordering::Relaxed)
+    }
+
+    /// Total size of the objects counted by [`new_objects`](Self::new_objects).
+    pub fn new_bytes(&self) -> u64 {
+        self.new_bytes.load(Ordering::Relaxed)
+    }
+}
+
+impl<S: ContentAddressableStorage> ContentAddressableStorage for CountingCas<S> {
+    type Error = S::Error;
+    type Hash = S::Hash;
+
+    fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        self.inner.list()
+    }
+
+    fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+        self.inner.get(hash)
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        self.inner.hash(bytes)
+    }
+
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        // Concurrent writers of the same object may both count it, which is fine for statistics.
+        if self.inner.contains(hash)? {
+            return Ok(());
+        }
+        let len = bytes.len() as u64;
+        self.inner.put(hash, bytes)?;
+        self.new_objects.fetch_add(1, Ordering::Relaxed);
+        self.new_bytes.fetch_add(len, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.inner.contains(hash)
+    }
+
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        self.inner.size(hash)
+    }
+
+    fn verify(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.inner.verify(hash)
+    }
+
+    fn is_retryable(err: &Self::Error) -> bool {
+        S::is_retryable(err)
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.inner.remove(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::MemoryCas;
+
+    #[test]
+    fn test_counts_new_objects() {
+        let cas = CountingCas::new(MemoryCas::<blake3::Hasher>::new());
+
+        cas.store(Bytes::from_static(b"hello")).unwrap();
+        cas.store(Bytes::from_static(b"hello")).unwrap();
+        cas.store(Bytes::from_static(b"world!")).unwrap();
+
+        assert_eq!(cas.new_objects(), 2);
+        assert_eq!(cas.new_bytes(), 11);
+    }
+}
@@ -1,28 +1,241 @@
-use std::{io, marker::PhantomData};
+use std::{
+    fs::File,
+    io::{self, Write},
+    marker::PhantomData,
+};
 
 use bytes::Bytes;
-use camino::Utf8PathBuf;
+use camino::{Utf8DirEntry, Utf8Path, Utf8PathBuf};
 use digest::{Digest, Output};
-use itertools::Itertools;
+use itertools::{Either, Itertools};
+use rayon::prelude::*;
 use tracing::{debug, instrument};
 
 use super::ContentAddressableStorage;
 
+/// Prefix of temporary files created while storing objects.
+pub(super) const TEMP_PREFIX: &str = ".tmp";
+
+/// Default number of hex characters of the hash used as a shard subdirectory name.
+pub(super) const DEFAULT_PREFIX_LEN: usize = 2;
+
+/// Path of the object with `hash` under `base`, sharded by the first `prefix_len` hex characters.
+pub(super) fn sharded_path(base: &Utf8Path, prefix_len: usize, hash: &[u8]) -> Utf8PathBuf {
+    let hex = const_hex::encode(hash);
+    if prefix_len == 0 {
+        base.join(hex)
+    } else {
+        let (prefix, rest) = hex.split_at(prefix_len);
+        base.join(prefix).join(rest)
+    }
+}
+
+/// Skip an entry that is not a part of the store, or fail in `strict` mode.
+fn unexpected_entry<T>(path: &Utf8Path, strict: bool) -> Option<io::Result<T>> {
+    if strict {
+        Some(Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected entry in store: {path:?}"),
+        )))
+    } else {
+        debug!("skipping unexpected entry {path:?}");
+        None
+    }
+}
+
+/// Whether reading a directory failed because it doesn't exist. A store nothing has been written
+/// to may not have its directory created yet, and a shard directory may be removed concurrently,
+/// so both are listed as empty.
+fn is_not_found<T>(result: &io::Result<T>) -> bool {
+    matches!(result, Err(err) if err.kind() == io::ErrorKind::NotFound)
+}
+
+/// Flush directory entries (e.g., of newly renamed files) to disk.
+fn sync_dir(dir: &Utf8Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Content-addressable storage that keeps every object in a separate file.
+///
+/// Objects are sharded into subdirectories named after the first hex characters of their hash
+/// (`base/ab/cdef...`), so that no single directory grows too large.
 pub struct DirectoryCas<H> {
     base_path: Utf8PathBuf,
-    _digest: PhantomData<H>,
+    /// Number of hex characters used for shard subdirectory names. `0` means flat layout.
+    prefix_len: usize,
+    strict: bool,
+    fsync: bool,
+    _digest: PhantomData<fn() -> H>,
 }
 
 impl<H: Digest> DirectoryCas<H> {
     pub fn new(base_path: impl Into<Utf8PathBuf>) -> Self {
         DirectoryCas {
             base_path: base_path.into(),
+            prefix_len: DEFAULT_PREFIX_LEN,
+            strict: false,
+            fsync: false,
             _digest: PhantomData,
         }
     }
 
+    /// Set the number of hex characters of the hash used as a shard subdirectory name.
+    ///
+    /// `0` disables sharding and stores all objects directly in the base directory. Changing the
+    /// prefix length of an existing store makes previously stored objects invisible, so this
+    /// should be fixed for the lifetime of a repository.
+    pub fn with_prefix_len(mut self, prefix_len: usize) -> Self {
+        assert!(
+            prefix_len < 2 * <H as Digest>::output_size(),
+            "prefix_len should be shorter than the hex-encoded hash"
+        );
+        self.prefix_len = prefix_len;
+        self
+    }
+
+    /// Fail `list` on unexpected files and directories instead of skipping them.
+    ///
+    /// Temporary files left by interrupted writes are skipped in both modes. Strict mode is
+    /// useful to detect a corrupted or foreign directory layout.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Flush stored objects to disk before `store` returns.
+    ///
+    /// Without this, a successfully stored object may still only live in the page cache and be
+    /// lost on power failure. The file contents are synced before it is renamed into place, and
+    /// then the containing directory is synced as well, because the rename itself is only durable
+    /// once the directory entry reaches the disk.
+    ///
+    /// This is disabled by default as it significantly reduces write throughput.
+    pub fn with_fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
     fn path_for(&self, hash: &Output<H>) -> Utf8PathBuf {
-        self.base_path.join(const_hex::encode(hash))
+        sharded_path(&self.base_path, self.prefix_len, hash)
+    }
+
+    /// List objects in `dir`, where `prefix` is the hex prefix of all hashes in that directory.
+    fn list_dir(
+        dir: &Utf8Path,
+        prefix: String,
+        strict: bool,
+    ) -> impl Iterator<Item = io::Result<Output<H>>> + use<H> {
+        std::iter::once(dir.read_dir_utf8())
+            .filter(|it| !is_not_found(it))
+            .flatten_ok()
+            .flatten_ok()
+            .filter_map(move |entry| {
+                let entry = match entry {
+                    Ok(entry) if entry.file_name().starts_with(TEMP_PREFIX) => return None,
+                    Ok(entry) => entry,
+                    Err(err) => return Some(Err(err)),
+                };
+                let is_file = match entry.file_type() {
+                    Ok(file_type) => file_type.is_file(),
+                    Err(err) => return Some(Err(err)),
+                };
+
+                let mut hash = Output::<H>::default();
+                let hex = prefix.clone() + entry.file_name();
+                if is_file && const_hex::decode_to_slice(hex, &mut hash).is_ok() {
+                    Some(Ok(hash))
+                } else {
+                    unexpected_entry(entry.path(), strict)
+                }
+            })
+    }
+
+    /// List shard subdirectories of the base directory.
+    fn list_shards(&self) -> impl Iterator<Item = io::Result<Utf8DirEntry>> + use<H> {
+        let prefix_len = self.prefix_len;
+        let strict = self.strict;
+        std::iter::once(self.base_path.read_dir_utf8())
+            .filter(|it| !is_not_found(it))
+            .flatten_ok()
+            .flatten_ok()
+            .filter_map(move |entry| {
+                let entry = match entry {
+                    Ok(entry) if entry.file_name().starts_with(TEMP_PREFIX) => return None,
+                    Ok(entry) => entry,
+                    Err(err) => return Some(Err(err)),
+                };
+                let is_dir = match entry.file_type() {
+                    Ok(file_type) => file_type.is_dir(),
+                    Err(err) => return Some(Err(err)),
+                };
+
+                let name = entry.file_name();
+                if is_dir
+                    && name.len() == prefix_len
+                    && name.bytes().all(|it| it.is_ascii_hexdigit())
+                {
+                    Some(Ok(entry))
+                } else {
+                    unexpected_entry(entry.path(), strict)
+                }
+            })
+    }
+
+    /// Like [`list`](ContentAddressableStorage::list), but lists shard subdirectories in parallel
+    /// on the rayon thread pool, which is faster for stores with many objects when the file
+    /// system can serve concurrent reads. Objects are listed in no particular order.
+    pub fn par_list(&self) -> impl ParallelIterator<Item = io::Result<Output<H>>> + use<H> {
+        let strict = self.strict;
+        if self.prefix_len == 0 {
+            let objects = Self::list_dir(&self.base_path, String::new(), strict);
+            return Either::Left(objects.par_bridge());
+        }
+
+        // Collect the shards first, as splitting a sequence of directories to read is cheap
+        // compared to reading them.
+        let shards = self.list_shards().collect::<Vec<_>>();
+        Either::Right(
+            shards
+                .into_par_iter()
+                .flat_map_iter(move |entry| match entry {
+                    Ok(entry) => Either::Left(Self::list_dir(
+                        entry.path(),
+                        entry.file_name().to_owned(),
+                        strict,
+                    )),
+                    Err(err) => Either::Right(std::iter::once(Err(err))),
+                }),
+        )
+    }
+
+    /// Atomically create file at `path` with the contents produced by `write`.
+    ///
+    /// The data is written into a temporary file in the same directory and then moved into place,
+    /// so a partially written object is never observable under its final name. If `path` already
+    /// exists, it is left untouched.
+    fn write_atomic(
+        &self,
+        path: &Utf8Path,
+        write: impl FnOnce(&mut File) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let dir = path.parent().unwrap_or(&self.base_path);
+        let mut temp = tempfile::Builder::new()
+            .prefix(TEMP_PREFIX)
+            .tempfile_in(dir)?;
+        write(temp.as_file_mut())?;
+        if self.fsync {
+            temp.as_file().sync_all()?;
+        }
+
+        match temp.persist_noclobber(path) {
+            Ok(_) if self.fsync => sync_dir(dir),
+            Ok(_) => Ok(()),
+            Err(err) if err.error.kind() == io::ErrorKind::AlreadyExists => {
+                debug!("{path:?} has been concurrently saved");
+                Ok(())
+            }
+            Err(err) => Err(err.error),
+        }
     }
 }
 
@@ -31,14 +244,19 @@ impl<H: Digest> ContentAddressableStorage for DirectoryCas<H> {
     type Hash = Output<H>;
 
     fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
-        std::iter::once(self.base_path.read_dir_utf8())
-            .flatten_ok()
-            .flatten_ok()
-            .filter_map_ok(|entry| {
-                let mut hash = Self::Hash::default();
-                const_hex::decode_to_slice(entry.file_name(), &mut hash).ok()?;
-                Some(hash)
-            })
+        let strict = self.strict;
+        if self.prefix_len == 0 {
+            return Either::Left(Self::list_dir(&self.base_path, String::new(), strict));
+        }
+
+        Either::Right(
+            self.list_shards()
+                .map_ok(move |entry| {
+                    Self::list_dir(entry.path(), entry.file_name().to_owned(), strict)
+                })
+                .flatten_ok()
+                .map(|it| it.and_then(|it| it)),
+        )
     }
 
     fn get(&self, hash: Self::Hash) -> Result<Option<bytes::Bytes>, Self::Error> {
@@ -49,16 +267,249 @@ impl<H: Digest> ContentAddressableStorage for DirectoryCas<H> {
         }
     }
 
+    /// Store objects in parallel on the rayon thread pool.
+    fn store_many(
+        &self,
+        items: impl IntoIterator<Item = Bytes>,
+    ) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        items
+            .into_iter()
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|bytes| self.store(bytes))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        H::digest(bytes)
+    }
+
     #[instrument(skip_all)]
-    fn store(&self, bytes: bytes::Bytes) -> Result<Self::Hash, Self::Error> {
-        let hash = H::digest(&bytes);
-        let path = self.path_for(&hash);
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        let path = self.path_for(hash);
         if path.exists() {
             debug!("skipping saving {path:?}: already exists");
         } else {
             debug!("saving new content at {path:?}");
-            std::fs::write(self.path_for(&hash), &bytes)?;
+            if let Some(dir) = path.parent()
+                && !dir.exists()
+            {
+                std::fs::create_dir_all(dir)?;
+                if self.fsync {
+                    sync_dir(dir.parent().unwrap_or(dir))?;
+                }
+            }
+            self.write_atomic(&path, |file| file.write_all(&bytes))?;
+        }
+        Ok(())
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.path_for(hash).try_exists()
+    }
+
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        match std::fs::metadata(self.path_for(hash)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        match std::fs::remove_file(self.path_for(hash)) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_cas() -> (tempfile::TempDir, DirectoryCas<blake3::Hasher>) {
+        let dir = tempfile::tempdir().unwrap();
+        let cas = DirectoryCas::new(Utf8Path::from_path(dir.path()).unwrap());
+        (dir, cas)
+    }
+
+    #[test]
+    fn test_store_and_get() {
+        let (_dir, cas) = temp_cas();
+
+        let hash = cas.store(Bytes::from_static(b"hello")).unwrap();
+        assert_eq!(cas.get(hash).unwrap(), Some(Bytes::from_static(b"hello")));
+        assert_eq!(cas.list().collect::<io::Result<Vec<_>>>().unwrap(), [hash]);
+    }
+
+    #[test]
+    fn test_partial_write_is_not_observable() {
+        let (_dir, cas) = temp_cas();
+
+        let data = b"hello";
+        let hash = blake3::Hasher::digest(data);
+        let path = cas.path_for(&hash);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let result = cas.write_atomic(&path, |file| {
+            file.write_all(&data[..2])?;
+            Err(io::Error::other("simulated crash"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(cas.get(hash).unwrap(), None);
+        assert_eq!(cas.list().count(), 0);
+    }
+
+    #[test]
+    fn test_sharded_layout() {
+        let (dir, cas) = temp_cas();
+
+        let hash = cas.store(Bytes::from_static(b"hello")).unwrap();
+        let hex = const_hex::encode(hash);
+        assert!(dir.path().join(&hex[..2]).join(&hex[2..]).is_file());
+
+        let mut hashes = vec![hash];
+        for i in 0..100u32 {
+            hashes.push(cas.store(Bytes::copy_from_slice(&i.to_le_bytes())).unwrap());
+        }
+        hashes.sort();
+
+        let mut listed = cas.list().collect::<io::Result<Vec<_>>>().unwrap();
+        listed.sort();
+        assert_eq!(listed, hashes);
+    }
+
+    #[test]
+    fn test_flat_layout() {
+        let (dir, cas) = temp_cas();
+        let cas = cas.with_prefix_len(0);
+
+        let hash = cas.store(Bytes::from_static(b"hello")).unwrap();
+        assert!(dir.path().join(const_hex::encode(hash)).is_file());
+        assert_eq!(cas.get(hash).unwrap(), Some(Bytes::from_static(b"hello")));
+        assert_eq!(cas.list().collect::<io::Result<Vec<_>>>().unwrap(), [hash]);
+    }
+
+    #[test]
+    fn test_fsync() {
+        let (_dir, cas) = temp_cas();
+        let cas = cas.with_fsync(true);
+
+        let hash = cas.store(Bytes::from_static(b"hello")).unwrap();
+        assert_eq!(cas.get(hash).unwrap(), Some(Bytes::from_static(b"hello")));
+    }
+
+    #[test]
+    fn test_list_skips_stray_files() {
+        for prefix_len in [0, 2] {
+            let (dir, cas) = temp_cas();
+            let cas = cas.with_prefix_len(prefix_len);
+
+            let hash = cas.store(Bytes::from_static(b"hello")).unwrap();
+            std::fs::write(dir.path().join("README"), b"readme").unwrap();
+            std::fs::write(dir.path().join(".tmp123"), b"partial").unwrap();
+            std::fs::create_dir(dir.path().join("nested")).unwrap();
+
+            assert_eq!(cas.list().collect::<io::Result<Vec<_>>>().unwrap(), [hash]);
+
+            let cas = cas.with_strict(true);
+            let err = cas.list().collect::<io::Result<Vec<_>>>().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+            std::fs::remove_file(dir.path().join("README")).unwrap();
+            std::fs::remove_dir(dir.path().join("nested")).unwrap();
+            assert_eq!(cas.list().collect::<io::Result<Vec<_>>>().unwrap(), [hash]);
         }
-        Ok(hash)
+    }
+
+    #[test]
+    fn test_list_missing_directory() {
+        let (dir, _) = temp_cas();
+        for prefix_len in [0, 2] {
+            let cas = DirectoryCas::<blake3::Hasher>::new(
+                Utf8Path::from_path(&dir.path().join("missing")).unwrap(),
+            )
+            .with_prefix_len(prefix_len);
+            assert_eq!(cas.list().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_par_list() {
+        for prefix_len in [0, 2] {
+            let (dir, cas) = temp_cas();
+            let cas = cas.with_prefix_len(prefix_len);
+
+            let mut hashes = (0..100u32)
+                .map(|i| cas.store(Bytes::copy_from_slice(&i.to_le_bytes())).unwrap())
+                .collect::<Vec<_>>();
+            hashes.sort();
+            std::fs::write(dir.path().join("README"), b"readme").unwrap();
+
+            let mut listed = cas.par_list().collect::<io::Result<Vec<_>>>().unwrap();
+            listed.sort();
+            assert_eq!(listed, hashes);
+
+            let cas = cas.with_strict(true);
+            let err = cas.par_list().collect::<io::Result<Vec<_>>>().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let (dir, _) = temp_cas();
+        let cas = DirectoryCas::<blake3::Hasher>::new(
+            Utf8Path::from_path(&dir.path().join("missing")).unwrap(),
+        );
+        assert_eq!(cas.par_list().count(), 0);
+    }
+
+    #[test]
+    fn test_store_many() {
+        let (_dir, cas) = temp_cas();
+
+        let items = (0..100u32)
+            .map(|i| Bytes::copy_from_slice(&i.to_le_bytes()))
+            .collect::<Vec<_>>();
+        let hashes = cas
+            .store_many(items.clone())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(hashes.len(), items.len());
+        for (hash, bytes) in hashes.into_iter().zip(items) {
+            assert_eq!(cas.get(hash).unwrap(), Some(bytes));
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let (_dir, cas) = temp_cas();
+
+        let hash = cas.store(Bytes::from_static(b"hello")).unwrap();
+        assert!(cas.verify(&hash).unwrap());
+
+        std::fs::write(cas.path_for(&hash), b"hellp").unwrap();
+        assert!(!cas.verify(&hash).unwrap());
+
+        cas.remove(&hash).unwrap();
+        assert!(!cas.verify(&hash).unwrap());
+    }
+
+    #[test]
+    fn test_contains_and_remove() {
+        let (_dir, cas) = temp_cas();
+
+        let hash = cas.store(Bytes::from_static(b"hello")).unwrap();
+        assert!(cas.contains(&hash).unwrap());
+        assert_eq!(cas.size(&hash).unwrap(), Some(5));
+
+        assert!(cas.remove(&hash).unwrap());
+        assert!(!cas.contains(&hash).unwrap());
+        assert_eq!(cas.get(hash).unwrap(), None);
+        assert_eq!(cas.size(&hash).unwrap(), None);
+
+        assert!(!cas.remove(&hash).unwrap());
     }
 }
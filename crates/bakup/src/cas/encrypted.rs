@@ -0,0 +1,209 @@
+use std::io::{self, Read, Write};
+
+use bakpak::{Decryptor, Encryptor};
+use bytes::Bytes;
+use ed25519_dalek::SigningKey;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::{
+    ContentAddressableStorage,
+    envelope::{self, Codec},
+};
+
+/// Content-addressable storage that encrypts objects stored in `inner` with bakpak.
+///
+/// Like with [`CompressingCas`](super::CompressingCas), objects are addressed by the hash of
+/// their plaintext, so deduplication keeps working, and the ciphertext is saved under that hash.
+/// Every object is a separate bakpak file signed by `sender` and readable by any of
+/// `recipients`, wrapped in an envelope. Objects stored unencrypted (with another codec) are read
+/// as is, so a repository can start being encrypted at any point, unless
+/// [`require_encryption`](Self::require_encryption) is set.
+///
+/// The signature is checked to match the ciphertext, but not who the sender is: anyone with write
+/// access to the inner store can add objects. Objects are content-addressed, so the hash of the
+/// decrypted content should be verified where that matters.
+pub struct EncryptedCas<S> {
+    inner: S,
+    sender: SigningKey,
+    recipients: Vec<PublicKey>,
+    identity: Option<StaticSecret>,
+    /// Whether to reject objects that are not encrypted.
+    encryption_required: bool,
+}
+
+impl<S> EncryptedCas<S> {
+    /// Wrap `inner` store, encrypting new objects from `sender` to `recipients`.
+    pub fn new(inner: S, sender: SigningKey, recipients: Vec<PublicKey>) -> Self {
+        assert!(
+            !recipients.is_empty(),
+            "objects without recipients can't be decrypted"
+        );
+        EncryptedCas {
+            inner,
+            sender,
+            recipients,
+            identity: None,
+            encryption_required: false,
+        }
+    }
+
+    /// Fail to read objects that are stored unencrypted, e.g. in a repository that has always been
+    /// encrypted, where such objects could only have been planted by someone else.
+    pub fn require_encryption(mut self) -> Self {
+        self.encryption_required = true;
+        self
+    }
+
+    /// Set the secret key to decrypt objects with. Without it, reading objects fails.
+    pub fn with_identity(mut self, identity: StaticSecret) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    pub fn identity(&self) -> Option<&StaticSecret> {
+        self.identity.as_ref()
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn encrypt(&self, bytes: &[u8]) -> Result<Bytes, bakpak::Error> {
+        let mut writer = Encryptor::new(&self.sender, &self.recipients)?.wrap_output(Vec::new())?;
+        writer.write_all(bytes)?;
+        Ok(Bytes::from(writer.finish()?))
+    }
+
+    fn decrypt(&self, encrypted: &[u8]) -> io::Result<Bytes> {
+        let identity = self
+            .identity
+            .as_ref()
+            .ok_or_else(|| io::Error::other("no identity to decrypt objects with"))?;
+        let mut reader = Decryptor::new(identity).wrap_input(encrypted)?;
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        Ok(Bytes::from(buf))
+    }
+}
+
+impl<S> ContentAddressableStorage for EncryptedCas<S>
+where
+    S: ContentAddressableStorage,
+    S::Error: From<io::Error>,
+{
+    type Error = S::Error;
+    type Hash = S::Hash;
+
+    fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        self.inner.list()
+    }
+
+    fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+        let Some(stored) = self.inner.get(hash)? else {
+            return Ok(None);
+        };
+        match envelope::open(stored.clone())? {
+            (Codec::Bakpak, encrypted) => Ok(Some(self.decrypt(&encrypted)?)),
+            _ if self.encryption_required => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "object is not encrypted, but the repository requires encryption",
+            )
+            .into()),
+            // Left to be decoded by the outer store.
+            _ => Ok(Some(stored)),
+        }
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        self.inner.hash(bytes)
+    }
+
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        if self.inner.contains(hash)? {
+            return Ok(());
+        }
+        let encrypted = self.encrypt(&bytes).map_err(io::Error::from)?;
+        self.inner
+            .put(hash, envelope::seal(Codec::Bakpak, &encrypted))
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.inner.contains(hash)
+    }
+
+    /// Size of the encrypted object.
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        self.inner.size(hash)
+    }
+
+    fn is_retryable(err: &Self::Error) -> bool {
+        S::is_retryable(err)
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.inner.remove(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use digest::Digest;
+
+    use super::*;
+    use crate::cas::MemoryCas;
+
+    fn encrypted_cas(identity: &StaticSecret) -> EncryptedCas<MemoryCas<blake3::Hasher>> {
+        let sender = SigningKey::from_bytes(&[1; 32]);
+        EncryptedCas::new(MemoryCas::new(), sender, vec![identity.into()])
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let identity = StaticSecret::from([2; 32]);
+        let cas = encrypted_cas(&identity).with_identity(identity);
+
+        let data = Bytes::from_static(b"hello world");
+        let hash = cas.store(data.clone()).unwrap();
+
+        assert_eq!(hash, blake3::Hasher::digest(&data));
+        assert_eq!(cas.get(hash).unwrap(), Some(data.clone()));
+
+        let stored = cas.inner().get(hash).unwrap().unwrap();
+        assert!(!stored.windows(data.len()).any(|it| it == data));
+    }
+
+    #[test]
+    fn test_wrong_identity() {
+        let cas = encrypted_cas(&StaticSecret::from([2; 32]));
+        let hash = cas.store(Bytes::from_static(b"hello world")).unwrap();
+
+        assert!(cas.get(hash).is_err());
+        let cas = cas.with_identity(StaticSecret::from([3; 32]));
+        assert!(cas.get(hash).is_err());
+    }
+
+    #[test]
+    fn test_mixed_codecs() {
+        let identity = StaticSecret::from([2; 32]);
+        let cas = encrypted_cas(&identity).with_identity(identity);
+
+        // Stored before the repository was encrypted.
+        let plain = envelope::seal(Codec::None, b"plain");
+        let plain_hash = cas.hash(b"plain");
+        cas.inner().put(&plain_hash, plain.clone()).unwrap();
+        assert_eq!(cas.get(plain_hash).unwrap(), Some(plain));
+
+        // Stored before encrypted objects had an envelope.
+        let legacy_hash = cas.hash(b"legacy");
+        let legacy = cas.encrypt(b"legacy").unwrap();
+        cas.inner().put(&legacy_hash, legacy).unwrap();
+        assert_eq!(
+            cas.get(legacy_hash).unwrap(),
+            Some(Bytes::from_static(b"legacy"))
+        );
+
+        let cas = cas.require_encryption();
+        assert!(cas.get(plain_hash).is_err());
+        assert!(cas.get(legacy_hash).is_ok());
+    }
+}
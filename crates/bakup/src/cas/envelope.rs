@@ -0,0 +1,82 @@
+//! Envelope of objects stored by [`CompressingCas`](super::CompressingCas) and
+//! [`EncryptedCas`](super::EncryptedCas): a codec byte followed by the payload.
+//!
+//! The codec says how to interpret the payload, so every object can be read regardless of the
+//! settings it was written with, and a repository may mix uncompressed, compressed and encrypted
+//! objects. Codecs don't take parameters in the envelope: zstd frames and bakpak files carry their
+//! own headers. A codec that needs parameters would put them right after its byte.
+//!
+//! Envelopes nest: the plaintext of an encrypted object is itself an envelope, usually with the
+//! compressed content. The hash of an object always covers its content, not the envelope.
+
+use std::io;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Beginning of bakpak files, which are the encrypted objects written before the envelope had a
+/// codec for them.
+const BAKPAK_MAGIC: &[u8] = b"bak0";
+
+/// How the payload of a stored object is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// The content as is.
+    None = 0,
+    /// A zstd frame with the content.
+    Zstd = 1,
+    /// A bakpak file with another envelope.
+    Bakpak = 2,
+}
+
+/// Prefix `payload` with the header of `codec`.
+pub fn seal(codec: Codec, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + payload.len());
+    buf.put_u8(codec as u8);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+/// Split `stored` object into its codec and payload.
+pub fn open(stored: Bytes) -> io::Result<(Codec, Bytes)> {
+    let codec = match stored.first() {
+        Some(0) => Codec::None,
+        Some(1) => Codec::Zstd,
+        Some(2) => Codec::Bakpak,
+        _ if stored.starts_with(BAKPAK_MAGIC) => return Ok((Codec::Bakpak, stored)),
+        Some(codec) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown object codec {codec}"),
+            ));
+        }
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "object is missing codec header",
+            ));
+        }
+    };
+    Ok((codec, stored.slice(1..)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open() {
+        for codec in [Codec::None, Codec::Zstd, Codec::Bakpak] {
+            let sealed = seal(codec, b"payload");
+            assert_eq!(
+                open(sealed).unwrap(),
+                (codec, Bytes::from_static(b"payload"))
+            );
+        }
+
+        let legacy = Bytes::from_static(b"bak0\x01header");
+        assert_eq!(open(legacy.clone()).unwrap(), (Codec::Bakpak, legacy));
+
+        assert!(open(Bytes::from_static(b"\x7fpayload")).is_err());
+        assert!(open(Bytes::new()).is_err());
+    }
+}
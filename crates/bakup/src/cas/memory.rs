@@ -0,0 +1,124 @@
+use std::{collections::HashMap, io, sync::RwLock};
+
+use bytes::Bytes;
+use digest::{Digest, Output};
+
+use super::ContentAddressableStorage;
+
+/// In-memory content-addressable storage.
+///
+/// Useful for tests and as a cache layer. The error type is [`io::Error`] to be interchangeable
+/// with [`DirectoryCas`](super::DirectoryCas), although the in-memory store never fails.
+pub struct MemoryCas<H: Digest> {
+    objects: RwLock<HashMap<Output<H>, Bytes>>,
+}
+
+impl<H: Digest> MemoryCas<H> {
+    pub fn new() -> Self {
+        MemoryCas {
+            objects: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Number of stored objects.
+    pub fn len(&self) -> usize {
+        self.objects.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<H: Digest> Default for MemoryCas<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: Digest> ContentAddressableStorage for MemoryCas<H> {
+    type Error = io::Error;
+    type Hash = Output<H>;
+
+    fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        let hashes = self
+            .objects
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        hashes.into_iter().map(Ok)
+    }
+
+    fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+        Ok(self.objects.read().unwrap().get(&hash).cloned())
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        H::digest(bytes)
+    }
+
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        self.objects
+            .write()
+            .unwrap()
+            .entry(hash.clone())
+            .or_insert(bytes);
+        Ok(())
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        Ok(self.objects.read().unwrap().contains_key(hash))
+    }
+
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        Ok(self
+            .objects
+            .read()
+            .unwrap()
+            .get(hash)
+            .map(|bytes| bytes.len() as u64))
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        Ok(self.objects.write().unwrap().remove(hash).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_is_idempotent() {
+        let cas = MemoryCas::<blake3::Hasher>::new();
+
+        let hash1 = cas.store(Bytes::from_static(b"hello")).unwrap();
+        let hash2 = cas.store(Bytes::from_static(b"hello")).unwrap();
+
+        assert_eq!(hash1, hash2);
+        assert_eq!(cas.len(), 1);
+        assert_eq!(cas.get(hash1).unwrap(), Some(Bytes::from_static(b"hello")));
+    }
+
+    #[test]
+    fn test_list() {
+        let cas = MemoryCas::<blake3::Hasher>::new();
+
+        let hash1 = cas.store(Bytes::from_static(b"hello")).unwrap();
+        let hash2 = cas.store(Bytes::from_static(b"world")).unwrap();
+
+        let mut listed = cas.list().collect::<Result<Vec<_>, _>>().unwrap();
+        listed.sort();
+        let mut expected = vec![hash1, hash2];
+        expected.sort();
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn test_get_missing() {
+        let cas = MemoryCas::<blake3::Hasher>::new();
+        assert_eq!(cas.get(Output::<blake3::Hasher>::default()).unwrap(), None);
+    }
+}
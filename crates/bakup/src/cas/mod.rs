@@ -1,6 +1,31 @@
 //! Content-Addressable Storage.
+mod caching;
+mod compressing;
 mod content_addressable_store;
+mod counting;
 mod directory;
+mod encrypted;
+mod envelope;
+mod memory;
+mod packed;
+mod retrying;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "sftp")]
+mod sftp;
+mod throttling;
 
+pub use caching::CachingCas;
+pub use compressing::{CompressingCas, Compression};
 pub use content_addressable_store::ContentAddressableStorage;
+pub use counting::CountingCas;
 pub use directory::DirectoryCas;
+pub use encrypted::EncryptedCas;
+pub use memory::MemoryCas;
+pub use packed::{DEFAULT_PACK_SIZE, PackedCas, RebuildStats, RepackStats};
+pub use retrying::RetryingCas;
+#[cfg(feature = "s3")]
+pub use s3::{S3Cas, S3Config, S3Credentials, S3Error};
+#[cfg(feature = "sftp")]
+pub use sftp::{SftpAuth, SftpCas, SftpConfig};
+pub use throttling::{RateLimiter, ThrottlingCas};
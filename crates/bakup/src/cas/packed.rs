@@ -0,0 +1,830 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    io::{self, ErrorKind},
+    sync::{Mutex, RwLock},
+};
+
+use bytes::Bytes;
+use digest::{Digest, Output};
+use tracing::debug;
+
+use super::ContentAddressableStorage;
+use crate::{
+    index::{IndexEntry, IndexReader, IndexWriter},
+    pack::{self, PackReader, PackWriter},
+};
+
+/// Default target size of a pack.
+pub const DEFAULT_PACK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Number of recently read packs kept in memory.
+const RECENT_PACKS: usize = 4;
+
+/// Content-addressable storage that packs objects together instead of storing every object in a
+/// separate file.
+///
+/// New objects are appended to a pack, which is stored in `packs` once it reaches the target
+/// size. The location of every packed object is recorded in an index, stored in `indexes` by
+/// [`flush`](Self::flush). Objects are only durable (and visible to other clients) after a flush,
+/// and there is no implicit flush on drop.
+///
+/// Objects stored separately in `loose` (e.g., before the repository used packs) are still read
+/// and removed, but new objects always go into packs. Packed objects can't be removed one by one,
+/// use [`repack`](Self::repack) instead.
+///
+/// Packs and indexes are hashed with `H`, the hash of the underlying stores.
+pub struct PackedCas<S, H: Digest> {
+    loose: S,
+    packs: S,
+    indexes: S,
+    pack_size: usize,
+    /// All indexes stored in `indexes`.
+    index: RwLock<Indexes<H>>,
+    pending: Mutex<Pending<H>>,
+    /// Recently read packs, the most recent first, as objects are often read in the same order
+    /// they were written.
+    recent_packs: Mutex<VecDeque<(Output<H>, Bytes)>>,
+}
+
+/// Index loaded into memory.
+type Index<H> = IndexReader<Bytes, H>;
+
+/// Loaded indexes, along with the index of every object in them.
+struct Indexes<H: Digest> {
+    readers: Vec<Index<H>>,
+    /// Position in `readers` of the first index with every object.
+    objects: HashMap<Output<H>, usize>,
+}
+
+impl<H: Digest> Indexes<H> {
+    fn new(readers: Vec<Index<H>>) -> Self {
+        let mut indexes = Indexes {
+            readers: Vec::with_capacity(readers.len()),
+            objects: HashMap::new(),
+        };
+        for reader in readers {
+            indexes.push(reader);
+        }
+        indexes
+    }
+
+    fn push(&mut self, reader: Index<H>) {
+        let position = self.readers.len();
+        for entry in reader.iter() {
+            self.objects.entry(entry.hash().clone()).or_insert(position);
+        }
+        self.readers.push(reader);
+    }
+
+    fn lookup(&self, hash: &Output<H>) -> Option<IndexEntry<H>> {
+        self.readers[*self.objects.get(hash)?].lookup(hash)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Index<H>> {
+        self.readers.iter()
+    }
+}
+
+/// Objects written since the last flush.
+struct Pending<H: Digest> {
+    /// Pack that is being filled.
+    pack: PackWriter<Vec<u8>, H>,
+    /// Objects in `pack`.
+    objects: HashMap<Output<H>, Bytes>,
+    /// Index of the stored packs.
+    index: IndexWriter<H>,
+    /// Locations (pack ID and offset) of the objects in `index`.
+    locations: HashMap<Output<H>, (Output<H>, u32)>,
+}
+
+impl<H: Digest> Default for Pending<H> {
+    fn default() -> Self {
+        Pending {
+            pack: PackWriter::new(Vec::new()),
+            objects: HashMap::new(),
+            index: IndexWriter::new(),
+            locations: HashMap::new(),
+        }
+    }
+}
+
+/// Where to find a packed object.
+enum Location<H: Digest> {
+    /// In a pack that is not stored yet.
+    Pending(Bytes),
+    Packed {
+        pack_id: Output<H>,
+        offset: u32,
+    },
+}
+
+/// Outcome of [`PackedCas::repack`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RepackStats {
+    pub removed_objects: u64,
+    /// Size of the removed packs, minus size of the packs written in their place.
+    pub removed_bytes: u64,
+}
+
+/// Outcome of [`PackedCas::rebuild`].
+#[derive(Debug, Default)]
+pub struct RebuildStats<Hash> {
+    /// Number of indexed packs.
+    pub packs: u64,
+    /// Number of indexed objects.
+    pub objects: u64,
+    /// Packs that couldn't be parsed, and why. Their objects are not indexed.
+    pub malformed_packs: Vec<(Hash, io::Error)>,
+}
+
+impl<S, H> PackedCas<S, H>
+where
+    S: ContentAddressableStorage<Hash = Output<H>>,
+    S::Error: From<io::Error>,
+    H: Digest,
+{
+    /// Open the store, loading all indexes from `indexes`.
+    pub fn open(loose: S, packs: S, indexes: S) -> Result<Self, S::Error> {
+        let index = Self::load_indexes(&indexes)?;
+        Ok(PackedCas {
+            loose,
+            packs,
+            indexes,
+            pack_size: DEFAULT_PACK_SIZE,
+            index: RwLock::new(Indexes::new(index)),
+            pending: Mutex::new(Pending::default()),
+            recent_packs: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// Open the store, replacing all indexes in `indexes` with a single one built from the
+    /// packs in `packs`, e.g. if indexes are lost or corrupted.
+    ///
+    /// Packs that can't be parsed are reported in the stats and skipped. Like
+    /// [`repack`](Self::repack), this must not run concurrently with other writers.
+    pub fn rebuild(
+        loose: S,
+        packs: S,
+        indexes: S,
+    ) -> Result<(Self, RebuildStats<S::Hash>), S::Error> {
+        let mut stats = RebuildStats::default();
+        let mut index = IndexWriter::new();
+        for pack_id in packs.list() {
+            let pack_id = pack_id?;
+            // Removed since listing.
+            let Some(data) = packs.get(pack_id.clone())? else {
+                continue;
+            };
+            let entries = PackReader::<_, H>::new(&data)
+                .and_then(|reader| reader.iter().collect::<io::Result<Vec<_>>>());
+            match entries {
+                Ok(entries) => {
+                    stats.packs += 1;
+                    stats.objects += entries.len() as u64;
+                    index.extend_from_pack(pack_id, entries);
+                }
+                Err(err) => stats.malformed_packs.push((pack_id, err)),
+            }
+        }
+
+        let old_indexes = indexes.list().collect::<Result<Vec<_>, _>>()?;
+        let cas = PackedCas {
+            loose,
+            packs,
+            indexes,
+            pack_size: DEFAULT_PACK_SIZE,
+            index: RwLock::new(Indexes::new(Vec::new())),
+            pending: Mutex::new(Pending::default()),
+            recent_packs: Mutex::new(VecDeque::new()),
+        };
+        let new_index = if stats.objects > 0 {
+            Some(cas.store_index(index)?)
+        } else {
+            None
+        };
+        for hash in old_indexes {
+            if new_index
+                .as_ref()
+                .is_none_or(|(new_hash, _)| *new_hash != hash)
+            {
+                cas.indexes.remove(&hash)?;
+            }
+        }
+        *cas.index.write().unwrap() =
+            Indexes::new(new_index.into_iter().map(|(_, reader)| reader).collect());
+        Ok((cas, stats))
+    }
+
+    /// Set the target size of new packs. Packs are stored once they exceed it, so they may be
+    /// larger by up to one object.
+    pub fn with_pack_size(mut self, pack_size: usize) -> Self {
+        self.pack_size = pack_size;
+        self
+    }
+
+    /// Load the indexes from `indexes` again, e.g. after taking a lock, as a `repack` by another
+    /// process may have replaced the ones loaded before. Objects that are not flushed yet are kept.
+    pub fn reload(&self) -> Result<(), S::Error> {
+        let index = Self::load_indexes(&self.indexes)?;
+        *self.index.write().unwrap() = Indexes::new(index);
+        // The pack may have been removed by the `repack`.
+        self.recent_packs.lock().unwrap().clear();
+        Ok(())
+    }
+
+    pub fn loose(&self) -> &S {
+        &self.loose
+    }
+
+    /// Store the pack that is being filled, and the index of all packs stored since the last
+    /// flush.
+    pub fn flush(&self) -> Result<(), S::Error> {
+        let mut pending = self.pending.lock().unwrap();
+        self.store_pending_pack(&mut pending)?;
+        if pending.locations.is_empty() {
+            return Ok(());
+        }
+
+        let (_, index) = self.store_index(std::mem::take(&mut pending.index))?;
+        self.index.write().unwrap().push(index);
+        pending.locations.clear();
+        Ok(())
+    }
+
+    /// Remove packed objects for which `keep` returns `false`, rewriting packs that contain both
+    /// kept and removed objects, and replace all indexes with a single one.
+    ///
+    /// Packs that are not referenced by any index are removed as well, so this must not run
+    /// concurrently with other writers.
+    pub fn repack(&self, keep: impl Fn(&S::Hash) -> bool) -> Result<RepackStats, S::Error> {
+        self.flush()?;
+        let mut index = self.index.write().unwrap();
+        let mut stats = RepackStats::default();
+
+        // Objects by pack, and whether to keep them. An object may have been stored in several
+        // packs by concurrent writers, only the first copy is kept.
+        let mut packs = BTreeMap::<Output<H>, Vec<(pack::IndexEntry<H>, bool)>>::new();
+        let mut seen = HashSet::new();
+        for entry in index.iter().flat_map(|it| it.iter()) {
+            let first = seen.insert(entry.hash().clone());
+            let live = first && keep(entry.hash());
+            if first && !live {
+                stats.removed_objects += 1;
+            }
+            let pack_entry = pack::IndexEntry {
+                hash: entry.hash().clone(),
+                offset: entry.offset(),
+            };
+            packs
+                .entry(entry.pack_id().clone())
+                .or_default()
+                .push((pack_entry, live));
+        }
+
+        // Kept packs go to the new index as is, and live objects of the other packs are copied
+        // into new packs.
+        let mut new = Pending::default();
+        let mut new_packs = HashSet::new();
+        let mut obsolete = Vec::new();
+        let mut indexed = 0;
+        for (pack_id, entries) in &packs {
+            let live = entries.iter().filter(|(_, live)| *live).count();
+            indexed += live;
+            if live == entries.len() {
+                let entries = entries.iter().map(|(it, _)| it.clone()).collect();
+                new.index.extend_from_pack(pack_id.clone(), entries);
+                continue;
+            }
+
+            obsolete.push(pack_id.clone());
+            if live == 0 {
+                continue;
+            }
+            let data = self.packs.get(pack_id.clone())?.ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("pack {} is missing", const_hex::encode(pack_id)),
+                )
+            })?;
+            let reader = PackReader::<_, H>::new(&data)?;
+            for (entry, _) in entries.iter().filter(|(_, live)| *live) {
+                let (_, blob) = reader.blob(entry.offset)?;
+                new.pack.write(entry.hash.clone(), blob)?;
+                new.objects
+                    .insert(entry.hash.clone(), Bytes::copy_from_slice(blob));
+                if new.pack.size() >= self.pack_size {
+                    new_packs.extend(self.store_pending_pack(&mut new)?);
+                }
+            }
+        }
+        new_packs.extend(self.store_pending_pack(&mut new)?);
+
+        // Packs left by interrupted writers.
+        for pack_id in self.packs.list() {
+            let pack_id = pack_id?;
+            if !packs.contains_key(&pack_id) && !new_packs.contains(&pack_id) {
+                obsolete.push(pack_id);
+            }
+        }
+
+        // Replace the indexes before removing packs, so that an interruption leaves extra
+        // packs, rather than objects pointing to removed ones.
+        let old_indexes = self.indexes.list().collect::<Result<Vec<_>, _>>()?;
+        let new_index = if indexed > 0 {
+            Some(self.store_index(new.index)?)
+        } else {
+            None
+        };
+        for hash in old_indexes {
+            if new_index
+                .as_ref()
+                .is_none_or(|(new_hash, _)| *new_hash != hash)
+            {
+                self.indexes.remove(&hash)?;
+            }
+        }
+        *index = Indexes::new(new_index.into_iter().map(|(_, reader)| reader).collect());
+        self.recent_packs.lock().unwrap().clear();
+
+        let mut removed_bytes = 0;
+        for pack_id in obsolete {
+            removed_bytes += self.packs.size(&pack_id)?.unwrap_or(0);
+            self.packs.remove(&pack_id)?;
+        }
+        for pack_id in new_packs {
+            let size = self.packs.size(&pack_id)?.unwrap_or(0);
+            removed_bytes = removed_bytes.saturating_sub(size);
+        }
+        stats.removed_bytes = removed_bytes;
+        Ok(stats)
+    }
+
+    /// Move loose objects into packs, returning the number of moved objects.
+    ///
+    /// Loose objects are only removed once the index of their pack is stored, and the ones that
+    /// are already packed are just removed, so an interrupted move can be resumed by running it
+    /// again. Like [`repack`](Self::repack), this must not run concurrently with other writers.
+    pub fn pack_loose(&self) -> Result<u64, S::Error> {
+        let mut moved = Vec::new();
+        let mut count = 0;
+        for hash in self.loose.list() {
+            let hash = hash?;
+            if self.locate(&hash).is_none() {
+                // Removed concurrently.
+                let Some(bytes) = self.loose.get(hash.clone())? else {
+                    continue;
+                };
+                self.put_packed(&hash, bytes)?;
+            }
+            moved.push(hash);
+            // Stored packs are indexed and their objects removed as they go, so that little work
+            // is lost on interruption.
+            if !self.pending.lock().unwrap().locations.is_empty() {
+                count += self.remove_moved(&mut moved)?;
+            }
+        }
+        count += self.remove_moved(&mut moved)?;
+        Ok(count)
+    }
+
+    /// Flush the pending pack and index, and remove the loose copies of `moved` objects.
+    fn remove_moved(&self, moved: &mut Vec<Output<H>>) -> Result<u64, S::Error> {
+        self.flush()?;
+        let count = moved.len() as u64;
+        for hash in moved.drain(..) {
+            self.loose.remove(&hash)?;
+        }
+        Ok(count)
+    }
+
+    /// Write the object into the pack that is being filled, unless it's already there.
+    fn put_packed(&self, hash: &Output<H>, bytes: Bytes) -> Result<(), S::Error> {
+        let mut pending = self.pending.lock().unwrap();
+        // Might have been stored concurrently since the caller checked.
+        if pending.objects.contains_key(hash) || pending.locations.contains_key(hash) {
+            return Ok(());
+        }
+        pending.pack.write(hash.clone(), &bytes)?;
+        pending.objects.insert(hash.clone(), bytes);
+        if pending.pack.size() >= self.pack_size {
+            self.store_pending_pack(&mut pending)?;
+        }
+        Ok(())
+    }
+
+    /// Store the pack of `pending`, if it's not empty, and add it to the pending index. Returns
+    /// the pack ID.
+    fn store_pending_pack(&self, pending: &mut Pending<H>) -> Result<Option<Output<H>>, S::Error> {
+        let pack = std::mem::replace(&mut pending.pack, PackWriter::new(Vec::new()));
+        // On failure, the objects are forgotten, so that they are not reported as present.
+        let objects = std::mem::take(&mut pending.objects);
+        if objects.is_empty() {
+            return Ok(None);
+        }
+
+        let pack = pack.finalize()?;
+        let pack_id = self.packs.store(Bytes::from(pack.writer))?;
+        debug!(
+            "stored pack {} with {} objects",
+            const_hex::encode(&pack_id),
+            objects.len()
+        );
+
+        pending.locations.extend(
+            pack.index
+                .iter()
+                .map(|entry| (entry.hash.clone(), (pack_id.clone(), entry.offset))),
+        );
+        pending.index.extend_from_pack(pack_id.clone(), pack.index);
+        Ok(Some(pack_id))
+    }
+
+    /// Store `index`, returning its hash and a reader of the stored index.
+    fn store_index(&self, mut index: IndexWriter<H>) -> Result<(S::Hash, Index<H>), S::Error> {
+        let mut buf = Vec::with_capacity(index.size());
+        index.write(&mut buf)?;
+        let bytes = Bytes::from(buf);
+        let hash = self.indexes.store(bytes.clone())?;
+        Ok((hash, IndexReader::new(bytes)?))
+    }
+
+    fn load_indexes(indexes: &S) -> Result<Vec<Index<H>>, S::Error> {
+        indexes
+            .list()
+            .map(|hash| IndexReader::load(indexes, hash?))
+            .collect()
+    }
+
+    fn locate(&self, hash: &Output<H>) -> Option<Location<H>> {
+        let pending = self.pending.lock().unwrap();
+        if let Some(bytes) = pending.objects.get(hash) {
+            return Some(Location::Pending(bytes.clone()));
+        }
+
+        let (pack_id, offset) = match pending.locations.get(hash) {
+            Some(location) => location.clone(),
+            None => {
+                let entry = self.index.read().unwrap().lookup(hash)?;
+                (entry.pack_id().clone(), entry.offset())
+            }
+        };
+        Some(Location::Packed { pack_id, offset })
+    }
+
+    /// Read the object `hash` from the pack `pack_id`. Returns `None` if the pack is missing.
+    fn read_packed(
+        &self,
+        hash: &Output<H>,
+        pack_id: Output<H>,
+        offset: u32,
+    ) -> Result<Option<Bytes>, S::Error> {
+        let cached = {
+            let mut recent = self.recent_packs.lock().unwrap();
+            let position = recent.iter().position(|(id, _)| *id == pack_id);
+            position.and_then(|it| recent.remove(it)).inspect(|it| {
+                recent.push_front(it.clone());
+            })
+        };
+        let pack = match cached {
+            Some((_, pack)) => pack,
+            None => {
+                let Some(pack) = self.packs.get(pack_id.clone())? else {
+                    return Ok(None);
+                };
+                let mut recent = self.recent_packs.lock().unwrap();
+                recent.retain(|(id, _)| *id != pack_id);
+                recent.truncate(RECENT_PACKS - 1);
+                recent.push_front((pack_id.clone(), pack.clone()));
+                pack
+            }
+        };
+
+        let reader = PackReader::<_, H>::new(&pack)?;
+        let (stored_hash, data) = reader.blob(offset)?;
+        if stored_hash != hash {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "pack {} doesn't have object {} at offset {offset}",
+                    const_hex::encode(&pack_id),
+                    const_hex::encode(hash)
+                ),
+            )
+            .into());
+        }
+        Ok(Some(pack.slice_ref(data)))
+    }
+}
+
+impl<S, H> ContentAddressableStorage for PackedCas<S, H>
+where
+    S: ContentAddressableStorage<Hash = Output<H>>,
+    S::Error: From<io::Error>,
+    H: Digest,
+{
+    type Error = S::Error;
+    type Hash = S::Hash;
+
+    fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        let mut packed = BTreeSet::new();
+        {
+            let pending = self.pending.lock().unwrap();
+            packed.extend(pending.objects.keys().cloned());
+            packed.extend(pending.locations.keys().cloned());
+        }
+        for index in self.index.read().unwrap().iter() {
+            packed.extend(index.iter().map(|it| it.hash().clone()));
+        }
+
+        packed.into_iter().map(Ok).chain(self.loose.list())
+    }
+
+    fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+        match self.locate(&hash) {
+            Some(Location::Pending(bytes)) => Ok(Some(bytes)),
+            Some(Location::Packed { pack_id, offset }) => self.read_packed(&hash, pack_id, offset),
+            None => self.loose.get(hash),
+        }
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        self.packs.hash(bytes)
+    }
+
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        if self.contains(hash)? {
+            return Ok(());
+        }
+        self.put_packed(hash, bytes)
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        if self.locate(hash).is_some() {
+            return Ok(true);
+        }
+        self.loose.contains(hash)
+    }
+
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        match self.locate(hash) {
+            Some(Location::Pending(bytes)) => Ok(Some(bytes.len() as u64)),
+            Some(Location::Packed { pack_id, offset }) => Ok(self
+                .read_packed(hash, pack_id, offset)?
+                .map(|it| it.len() as u64)),
+            None => self.loose.size(hash),
+        }
+    }
+
+    fn is_retryable(err: &Self::Error) -> bool {
+        S::is_retryable(err)
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        if self.locate(hash).is_some() {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "packed objects can only be removed by repacking",
+            )
+            .into());
+        }
+        self.loose.remove(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use super::*;
+    use crate::cas::DirectoryCas;
+
+    type Cas = PackedCas<DirectoryCas<blake3::Hasher>, blake3::Hasher>;
+
+    fn open(dir: &Utf8Path) -> Cas {
+        PackedCas::open(
+            DirectoryCas::new(dir),
+            DirectoryCas::new(dir.join("packs")),
+            DirectoryCas::new(dir.join("index")),
+        )
+        .unwrap()
+        .with_pack_size(64)
+    }
+
+    fn objects(n: u32) -> Vec<Bytes> {
+        (0..n).map(|i| Bytes::from(format!("object {i}"))).collect()
+    }
+
+    fn list(cas: &Cas) -> BTreeSet<<Cas as ContentAddressableStorage>::Hash> {
+        cas.list().collect::<io::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn test_store_flush_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+
+        let cas = open(dir);
+        let hashes = objects(10)
+            .into_iter()
+            .map(|it| cas.store(it).unwrap())
+            .collect::<Vec<_>>();
+        // Readable before the flush, from both stored and pending packs.
+        for (hash, bytes) in hashes.iter().zip(objects(10)) {
+            assert!(cas.contains(hash).unwrap());
+            assert_eq!(cas.get(*hash).unwrap(), Some(bytes));
+        }
+        assert_eq!(list(&cas), hashes.iter().copied().collect());
+
+        cas.flush().unwrap();
+        let cas = open(dir);
+        for (hash, bytes) in hashes.iter().zip(objects(10)) {
+            assert_eq!(cas.get(*hash).unwrap(), Some(bytes.clone()));
+            assert_eq!(cas.size(hash).unwrap(), Some(bytes.len() as u64));
+        }
+        assert_eq!(list(&cas), hashes.iter().copied().collect());
+        assert!(cas.packs.list().count() > 1);
+        assert!(cas.loose().list().next().is_none());
+    }
+
+    #[test]
+    fn test_unflushed_objects_are_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+
+        let hash = open(dir).store(Bytes::from_static(b"hello")).unwrap();
+        assert!(!open(dir).contains(&hash).unwrap());
+    }
+
+    #[test]
+    fn test_reads_loose_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+
+        let hash = DirectoryCas::<blake3::Hasher>::new(dir)
+            .store(Bytes::from_static(b"hello"))
+            .unwrap();
+        let cas = open(dir);
+        assert_eq!(cas.get(hash).unwrap(), Some(Bytes::from_static(b"hello")));
+        assert_eq!(list(&cas), BTreeSet::from([hash]));
+
+        // Not stored again into a pack.
+        cas.store(Bytes::from_static(b"hello")).unwrap();
+        cas.flush().unwrap();
+        assert_eq!(cas.packs.list().count(), 0);
+
+        assert!(cas.remove(&hash).unwrap());
+        assert!(!cas.contains(&hash).unwrap());
+    }
+
+    #[test]
+    fn test_pack_loose() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+
+        let loose = DirectoryCas::<blake3::Hasher>::new(dir);
+        let hashes = objects(10)
+            .into_iter()
+            .map(|it| loose.store(it).unwrap())
+            .collect::<Vec<_>>();
+        let cas = open(dir);
+        // Already packed by an interrupted move.
+        cas.put_packed(&hashes[0], objects(1)[0].clone()).unwrap();
+        cas.flush().unwrap();
+
+        assert_eq!(cas.pack_loose().unwrap(), 10);
+        for cas in [cas, open(dir)] {
+            assert!(cas.loose().list().next().is_none());
+            assert_eq!(list(&cas), hashes.iter().copied().collect());
+            for (hash, bytes) in hashes.iter().zip(objects(10)) {
+                assert_eq!(cas.get(*hash).unwrap(), Some(bytes));
+            }
+            assert_eq!(cas.pack_loose().unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_recent_packs_are_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+
+        let cas = open(dir);
+        let hashes = objects(40)
+            .into_iter()
+            .map(|it| cas.store(it).unwrap())
+            .collect::<Vec<_>>();
+        cas.flush().unwrap();
+
+        let cas = open(dir);
+        let pack_of = |hash| *cas.index.read().unwrap().lookup(hash).unwrap().pack_id();
+        let packs = hashes.iter().map(pack_of).collect::<BTreeSet<_>>();
+        assert!(packs.len() > RECENT_PACKS);
+        for hash in &hashes {
+            assert!(cas.get(*hash).unwrap().is_some());
+        }
+
+        let mut recent = Vec::new();
+        for pack_id in hashes.iter().rev().map(pack_of) {
+            if recent.len() < RECENT_PACKS && !recent.contains(&pack_id) {
+                recent.push(pack_id);
+            }
+        }
+        for pack_id in &packs {
+            cas.packs.remove(pack_id).unwrap();
+        }
+        // Objects of the packs read last are still readable from memory.
+        for (hash, bytes) in hashes.iter().zip(objects(40)) {
+            let expected = recent.contains(&pack_of(hash)).then_some(bytes);
+            assert_eq!(cas.get(*hash).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_repack() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+
+        let cas = open(dir);
+        let hashes = objects(10)
+            .into_iter()
+            .map(|it| cas.store(it).unwrap())
+            .collect::<Vec<_>>();
+        cas.flush().unwrap();
+        // Left by an interrupted writer.
+        let orphan = cas.packs.store(Bytes::from_static(b"orphan pack")).unwrap();
+
+        let kept = hashes.iter().step_by(3).copied().collect::<BTreeSet<_>>();
+        assert!(cas.remove(&hashes[0]).is_err());
+        let stats = cas.repack(|hash| kept.contains(hash)).unwrap();
+        assert_eq!(stats.removed_objects, 6);
+        assert!(stats.removed_bytes > 0);
+        assert!(!cas.packs.contains(&orphan).unwrap());
+
+        for cas in [cas, open(dir)] {
+            assert_eq!(list(&cas), kept);
+            for (hash, bytes) in hashes.iter().zip(objects(10)) {
+                let expected = kept.contains(hash).then_some(bytes);
+                assert_eq!(cas.get(*hash).unwrap(), expected);
+            }
+            assert_eq!(cas.indexes.list().count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+
+        let cas = open(dir);
+        let hashes = objects(10)
+            .into_iter()
+            .map(|it| cas.store(it).unwrap())
+            .collect::<Vec<_>>();
+        cas.flush().unwrap();
+        let packs = cas.packs.list().count() as u64;
+        // A lost index, and a corrupted one.
+        let index = cas.indexes.list().next().unwrap().unwrap();
+        cas.indexes.remove(&index).unwrap();
+        cas.indexes
+            .store(Bytes::from_static(b"corrupted index"))
+            .unwrap();
+        let malformed = cas
+            .packs
+            .store(Bytes::from_static(b"malformed pack"))
+            .unwrap();
+        drop(cas);
+
+        let (cas, stats) = Cas::rebuild(
+            DirectoryCas::new(dir),
+            DirectoryCas::new(dir.join("packs")),
+            DirectoryCas::new(dir.join("index")),
+        )
+        .unwrap();
+        assert_eq!(stats.packs, packs);
+        assert_eq!(stats.objects, 10);
+        assert_eq!(stats.malformed_packs.len(), 1);
+        assert_eq!(stats.malformed_packs[0].0, malformed);
+
+        for cas in [cas, open(dir)] {
+            assert_eq!(cas.indexes.list().count(), 1);
+            for (hash, bytes) in hashes.iter().zip(objects(10)) {
+                assert_eq!(cas.get(*hash).unwrap(), Some(bytes));
+            }
+        }
+    }
+
+    #[test]
+    fn test_repack_everything() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+
+        let cas = open(dir);
+        for object in objects(10) {
+            cas.store(object).unwrap();
+        }
+        let stats = cas.repack(|_| false).unwrap();
+        assert_eq!(stats.removed_objects, 10);
+        assert_eq!(cas.packs.list().count(), 0);
+        assert_eq!(cas.indexes.list().count(), 0);
+        assert!(list(&cas).is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use itertools::Either;
+use tracing::warn;
+
+use super::ContentAddressableStorage;
+
+/// Content-addressable storage that retries failed operations of the `inner` store.
+///
+/// Only errors classified as transient by [`ContentAddressableStorage::is_retryable`] are retried,
+/// with exponential backoff and full jitter between attempts. `remove` is not retried, because
+/// its result is not meaningful after a partially failed attempt.
+pub struct RetryingCas<S> {
+    inner: S,
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl<S> RetryingCas<S> {
+    pub fn new(inner: S) -> Self {
+        RetryingCas {
+            inner,
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+
+    /// Set the maximum number of attempts of each operation (including the first one).
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        assert!(max_attempts > 0, "max_attempts should be positive");
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set the delay range for the first retry. It is doubled on every subsequent retry, up to
+    /// `max_delay`.
+    pub fn with_backoff(mut self, base_delay: Duration, max_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Delay before the retry following the failed `attempt` (0-based).
+    fn backoff(&self, attempt: u32) -> Duration {
+        let cap = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(31))
+            .min(self.max_delay);
+        // Full jitter: a random delay in `[0, cap]`.
+        let random = RandomState::new().hash_one(attempt);
+        cap.mul_f64(random as f64 / u64::MAX as f64)
+    }
+}
+
+impl<S: ContentAddressableStorage> RetryingCas<S> {
+    fn retry<T>(&self, mut op: impl FnMut() -> Result<T, S::Error>) -> Result<T, S::Error> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(err) if attempt + 1 < self.max_attempts && S::is_retryable(&err) => {
+                    let delay = self.backoff(attempt);
+                    warn!("retrying in {delay:?} after error: {err}");
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<S: ContentAddressableStorage> ContentAddressableStorage for RetryingCas<S> {
+    type Error = S::Error;
+    type Hash = S::Hash;
+
+    /// Listing is restarted from scratch on failure, so the whole list is collected before
+    /// returning.
+    fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        match self.retry(|| self.inner.list().collect::<Result<Vec<_>, _>>()) {
+            Ok(hashes) => Either::Left(hashes.into_iter().map(Ok)),
+            Err(err) => Either::Right(std::iter::once(Err(err))),
+        }
+    }
+
+    fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+        self.retry(|| self.inner.get(hash.clone()))
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        self.inner.hash(bytes)
+    }
+
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        self.retry(|| self.inner.put(hash, bytes.clone()))
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.retry(|| self.inner.contains(hash))
+    }
+
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        self.retry(|| self.inner.size(hash))
+    }
+
+    fn is_retryable(err: &Self::Error) -> bool {
+        S::is_retryable(err)
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.inner.remove(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use digest::Output;
+
+    use super::*;
+    use crate::cas::MemoryCas;
+
+    /// Store that fails the first `failures` operations with the given error kind.
+    struct FlakyCas {
+        inner: MemoryCas<blake3::Hasher>,
+        failures: AtomicU32,
+        kind: io::ErrorKind,
+        calls: AtomicU32,
+    }
+
+    impl FlakyCas {
+        fn new(failures: u32, kind: io::ErrorKind) -> Self {
+            FlakyCas {
+                inner: MemoryCas::new(),
+                failures: AtomicU32::new(failures),
+                kind,
+                calls: AtomicU32::new(0),
+            }
+        }
+
+        fn fail(&self) -> io::Result<()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            match self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |it| it.checked_sub(1))
+            {
+                Ok(_) => Err(io::Error::new(self.kind, "flaky")),
+                Err(_) => Ok(()),
+            }
+        }
+    }
+
+    impl ContentAddressableStorage for FlakyCas {
+        type Error = io::Error;
+        type Hash = Output<blake3::Hasher>;
+
+        fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+            match self.fail() {
+                Ok(()) => Either::Left(self.inner.list()),
+                Err(err) => Either::Right(std::iter::once(Err(err))),
+            }
+        }
+
+        fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+            self.fail()?;
+            self.inner.get(hash)
+        }
+
+        fn hash(&self, bytes: &[u8]) -> Self::Hash {
+            self.inner.hash(bytes)
+        }
+
+        fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+            self.fail()?;
+            self.inner.put(hash, bytes)
+        }
+
+        fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+            self.fail()?;
+            self.inner.contains(hash)
+        }
+
+        fn is_retryable(err: &Self::Error) -> bool {
+            err.kind() == io::ErrorKind::TimedOut
+        }
+
+        fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+            self.fail()?;
+            self.inner.remove(hash)
+        }
+    }
+
+    fn retrying(inner: FlakyCas) -> RetryingCas<FlakyCas> {
+        RetryingCas::new(inner).with_backoff(Duration::from_millis(1), Duration::from_millis(1))
+    }
+
+    #[test]
+    fn test_succeeds_on_third_attempt() {
+        let cas = retrying(FlakyCas::new(2, io::ErrorKind::TimedOut));
+
+        let hash = cas.store(Bytes::from_static(b"hello")).unwrap();
+        assert_eq!(cas.inner().calls.load(Ordering::Relaxed), 3);
+
+        cas.inner().failures.store(2, Ordering::Relaxed);
+        assert_eq!(cas.get(hash).unwrap(), Some(Bytes::from_static(b"hello")));
+
+        cas.inner().failures.store(2, Ordering::Relaxed);
+        assert_eq!(cas.list().collect::<io::Result<Vec<_>>>().unwrap(), [hash]);
+    }
+
+    #[test]
+    fn test_gives_up_after_max_attempts() {
+        let cas = retrying(FlakyCas::new(10, io::ErrorKind::TimedOut)).with_max_attempts(3);
+
+        let err = cas.store(Bytes::from_static(b"hello")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(cas.inner().calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_does_not_retry_permanent_errors() {
+        let cas = retrying(FlakyCas::new(1, io::ErrorKind::PermissionDenied));
+
+        let err = cas.store(Bytes::from_static(b"hello")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(cas.inner().calls.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,324 @@
+use std::{collections::VecDeque, fmt, io, marker::PhantomData, sync::Arc};
+
+use aws_sdk_s3::{config::http::HttpResponse, error::SdkError, primitives::ByteStream};
+use bytes::Bytes;
+use digest::{Digest, Output};
+use tokio::runtime::Runtime;
+use tracing::{debug, instrument};
+
+use super::ContentAddressableStorage;
+
+/// Configuration of an [`S3Cas`].
+#[derive(Debug, Clone, Default)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Key prefix under which objects are stored (e.g. `"repo/"`). May be empty.
+    pub prefix: String,
+    /// Region name. If `None`, the region is taken from the environment (`AWS_REGION`, profile).
+    pub region: Option<String>,
+    /// Custom endpoint for S3-compatible services (MinIO, Backblaze B2, etc.).
+    ///
+    /// Setting an endpoint also enables path-style addressing, which most S3-compatible services
+    /// expect.
+    pub endpoint: Option<String>,
+    /// Credentials to sign requests with. If `None`, they are taken from the environment
+    /// (`AWS_ACCESS_KEY_ID`, profile, instance metadata, etc.).
+    pub credentials: Option<S3Credentials>,
+}
+
+/// Static credentials of an [`S3Cas`], e.g. from a configuration file.
+#[derive(Clone)]
+pub struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Session token of temporary credentials.
+    pub session_token: Option<String>,
+}
+
+// Implemented by hand, so that secrets don't end up in logs.
+impl fmt::Debug for S3Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum S3Error {
+    #[error("S3 request failed")]
+    Request {
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+        /// Whether the failure is transient: a timeout, a network failure, throttling, or a
+        /// server error.
+        retryable: bool,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+fn request_error<E>(err: SdkError<E, HttpResponse>) -> S3Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let retryable = match &err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(err) => {
+            let status = err.raw().status();
+            status.is_server_error() || status.as_u16() == 429
+        }
+        _ => false,
+    };
+    S3Error::Request {
+        source: Box::new(err),
+        retryable,
+    }
+}
+
+/// Content-addressable storage backed by an S3-compatible object storage.
+///
+/// Every object is stored under `{prefix}{hex hash}` key. The AWS SDK is async, so the store owns
+/// a tokio runtime and blocks on every request, which makes it usable from the synchronous
+/// [`ContentAddressableStorage`] interface (including from multiple threads at once). It must not
+/// be called from within an async context.
+pub struct S3Cas<H> {
+    client: aws_sdk_s3::Client,
+    runtime: Arc<Runtime>,
+    bucket: String,
+    prefix: String,
+    _digest: PhantomData<H>,
+}
+
+impl<H: Digest> S3Cas<H> {
+    /// Create a new store, using the credentials of `config` or loading them from the environment
+    /// (env variables, AWS profile, instance metadata, etc.).
+    pub fn new(config: S3Config) -> Result<Self, S3Error> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+
+        let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+        if let Some(region) = config.region {
+            loader = loader.region(aws_config::Region::new(region));
+        }
+        if let Some(credentials) = config.credentials {
+            loader = loader.credentials_provider(aws_sdk_s3::config::Credentials::new(
+                credentials.access_key_id,
+                credentials.secret_access_key,
+                credentials.session_token,
+                None,
+                "bakup",
+            ));
+        }
+        let sdk_config = runtime.block_on(loader.load());
+
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&sdk_config);
+        if let Some(endpoint) = config.endpoint {
+            s3_config = s3_config.endpoint_url(endpoint).force_path_style(true);
+        }
+        let client = aws_sdk_s3::Client::from_conf(s3_config.build());
+
+        Ok(Self::with_client(
+            client,
+            Arc::new(runtime),
+            config.bucket,
+            config.prefix,
+        ))
+    }
+
+    /// Create a new store from a preconfigured client.
+    ///
+    /// `runtime` is used to drive the client requests.
+    pub fn with_client(
+        client: aws_sdk_s3::Client,
+        runtime: Arc<Runtime>,
+        bucket: String,
+        mut prefix: String,
+    ) -> Self {
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        S3Cas {
+            client,
+            runtime,
+            bucket,
+            prefix,
+            _digest: PhantomData,
+        }
+    }
+
+    fn key_for(&self, hash: &Output<H>) -> String {
+        format!("{}{}", self.prefix, const_hex::encode(hash))
+    }
+
+    fn parse_key(prefix: &str, key: &str) -> Option<Output<H>> {
+        let mut hash = Output::<H>::default();
+        const_hex::decode_to_slice(key.strip_prefix(prefix)?, &mut hash).ok()?;
+        Some(hash)
+    }
+}
+
+impl<H: Digest> ContentAddressableStorage for S3Cas<H> {
+    type Error = S3Error;
+    type Hash = Output<H>;
+
+    fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        let mut keys = VecDeque::<String>::new();
+        let mut continuation_token = None;
+        let mut done = false;
+
+        std::iter::from_fn(move || {
+            loop {
+                if let Some(key) = keys.pop_front() {
+                    match Self::parse_key(&self.prefix, &key) {
+                        Some(hash) => return Some(Ok(hash)),
+                        None => {
+                            debug!("skipping unknown key {key:?}");
+                            continue;
+                        }
+                    }
+                }
+
+                if done {
+                    return None;
+                }
+
+                let request = self
+                    .client
+                    .list_objects_v2()
+                    .bucket(&self.bucket)
+                    .prefix(&self.prefix)
+                    .set_continuation_token(continuation_token.take())
+                    .send();
+                match self.runtime.block_on(request) {
+                    Ok(output) => {
+                        keys.extend(
+                            output
+                                .contents()
+                                .iter()
+                                .filter_map(|it| it.key().map(str::to_owned)),
+                        );
+                        continuation_token = output.next_continuation_token;
+                        done = continuation_token.is_none();
+                    }
+                    Err(err) => {
+                        done = true;
+                        return Some(Err(request_error(err)));
+                    }
+                }
+            }
+        })
+    }
+
+    fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.key_for(&hash))
+            .send();
+
+        self.runtime.block_on(async {
+            match request.await {
+                Ok(output) => {
+                    let data = output
+                        .body
+                        .collect()
+                        .await
+                        .map_err(|err| S3Error::Request {
+                            source: Box::new(err),
+                            // The connection has failed while streaming the body.
+                            retryable: true,
+                        })?;
+                    Ok(Some(data.into_bytes()))
+                }
+                Err(err) if err.as_service_error().is_some_and(|it| it.is_no_such_key()) => {
+                    Ok(None)
+                }
+                Err(err) => Err(request_error(err)),
+            }
+        })
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        H::digest(bytes)
+    }
+
+    #[instrument(skip_all)]
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        let key = self.key_for(hash);
+        if self.contains(hash)? {
+            debug!("skipping saving {key:?}: already exists");
+            return Ok(());
+        }
+
+        debug!("saving new content at {key:?}");
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(bytes))
+            .send();
+        self.runtime.block_on(request).map_err(request_error)?;
+        Ok(())
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        let request = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.key_for(hash))
+            .send();
+        match self.runtime.block_on(request) {
+            Ok(_) => Ok(true),
+            Err(err) if err.as_service_error().is_some_and(|it| it.is_not_found()) => Ok(false),
+            Err(err) => Err(request_error(err)),
+        }
+    }
+
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        let request = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.key_for(hash))
+            .send();
+        match self.runtime.block_on(request) {
+            Ok(output) => Ok(output.content_length().map(|it| it as u64)),
+            Err(err) if err.as_service_error().is_some_and(|it| it.is_not_found()) => Ok(None),
+            Err(err) => Err(request_error(err)),
+        }
+    }
+
+    fn is_retryable(err: &Self::Error) -> bool {
+        matches!(
+            err,
+            S3Error::Request {
+                retryable: true,
+                ..
+            }
+        )
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        // DeleteObject succeeds for missing keys too, so check for existence first to report
+        // whether anything was removed.
+        if !self.contains(hash)? {
+            return Ok(false);
+        }
+
+        let request = self
+            .client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.key_for(hash))
+            .send();
+        self.runtime.block_on(request).map_err(request_error)?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,422 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    net::TcpStream,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use camino::{Utf8Path, Utf8PathBuf};
+use digest::{Digest, Output};
+use itertools::Either;
+use ssh2::{CheckResult, FileStat, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp};
+use tracing::{debug, instrument};
+
+use super::{
+    ContentAddressableStorage,
+    directory::{DEFAULT_PREFIX_LEN, TEMP_PREFIX, sharded_path},
+};
+
+/// Objects at least this large are uploaded into a temporary file named after their hash, so that
+/// an interrupted upload is continued by the next attempt instead of starting over.
+const RESUMABLE_UPLOAD_SIZE: usize = 4 << 20;
+
+/// How to authenticate to the SSH server.
+#[derive(Debug, Clone)]
+pub enum SftpAuth {
+    /// Use keys from the running ssh-agent.
+    Agent,
+    /// Use a private key file.
+    KeyFile {
+        private_key: PathBuf,
+        passphrase: Option<String>,
+    },
+}
+
+/// Configuration of an [`SftpCas`].
+#[derive(Debug, Clone)]
+pub struct SftpConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub auth: SftpAuth,
+    /// Remote directory to store objects in.
+    pub base_path: Utf8PathBuf,
+    /// Known hosts file used to verify the server key. Defaults to `~/.ssh/known_hosts`.
+    pub known_hosts: Option<PathBuf>,
+    /// Number of SSH connections to open. Requests over a single connection are serialized, so
+    /// more connections let multiple threads transfer objects concurrently.
+    pub connections: NonZeroUsize,
+}
+
+/// Content-addressable storage that keeps objects as files on a remote host accessed over SFTP.
+///
+/// The layout is the same as the one of [`DirectoryCas`](super::DirectoryCas), so a repository
+/// can be moved between local and remote storage by copying files.
+pub struct SftpCas<H> {
+    /// SFTP sessions over separate connections, used in turns.
+    connections: Vec<Sftp>,
+    next_connection: AtomicUsize,
+    base_path: Utf8PathBuf,
+    prefix_len: usize,
+    _digest: PhantomData<H>,
+}
+
+impl<H: Digest> SftpCas<H> {
+    /// Connect to the SSH server and open an SFTP session over each of `config.connections`
+    /// connections.
+    ///
+    /// The server host key must be present in the known hosts file.
+    pub fn connect(config: SftpConfig) -> io::Result<Self> {
+        let connections = (0..config.connections.get())
+            .map(|_| Self::open_session(&config))
+            .collect::<io::Result<_>>()?;
+        Ok(SftpCas {
+            connections,
+            next_connection: AtomicUsize::new(0),
+            base_path: config.base_path,
+            prefix_len: DEFAULT_PREFIX_LEN,
+            _digest: PhantomData,
+        })
+    }
+
+    fn open_session(config: &SftpConfig) -> io::Result<Sftp> {
+        let tcp = TcpStream::connect((config.host.as_str(), config.port))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+
+        Self::verify_host_key(&session, config)?;
+
+        match &config.auth {
+            SftpAuth::Agent => session.userauth_agent(&config.user)?,
+            SftpAuth::KeyFile {
+                private_key,
+                passphrase,
+            } => session.userauth_pubkey_file(
+                &config.user,
+                None,
+                private_key,
+                passphrase.as_deref(),
+            )?,
+        }
+
+        Ok(session.sftp()?)
+    }
+
+    /// Set the number of hex characters of the hash used as a shard subdirectory name. See
+    /// [`DirectoryCas::with_prefix_len`](super::DirectoryCas::with_prefix_len).
+    pub fn with_prefix_len(mut self, prefix_len: usize) -> Self {
+        assert!(
+            prefix_len < 2 * <H as Digest>::output_size(),
+            "prefix_len should be shorter than the hex-encoded hash"
+        );
+        self.prefix_len = prefix_len;
+        self
+    }
+
+    fn verify_host_key(session: &Session, config: &SftpConfig) -> io::Result<()> {
+        let known_hosts_path = match &config.known_hosts {
+            Some(path) => path.clone(),
+            None => std::env::home_dir()
+                .ok_or_else(|| io::Error::other("cannot determine home directory"))?
+                .join(".ssh/known_hosts"),
+        };
+
+        let mut known_hosts = session.known_hosts()?;
+        known_hosts.read_file(&known_hosts_path, KnownHostFileKind::OpenSSH)?;
+
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| io::Error::other("server did not provide host key"))?;
+        match known_hosts.check_port(&config.host, config.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("host key for {} does not match known hosts", config.host),
+            )),
+            CheckResult::NotFound => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("host {} is not present in known hosts", config.host),
+            )),
+            CheckResult::Failure => Err(io::Error::other("failed to check host key")),
+        }
+    }
+
+    /// Session to send the next request over.
+    fn sftp(&self) -> &Sftp {
+        let next = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        &self.connections[next % self.connections.len()]
+    }
+
+    fn path_for(&self, hash: &Output<H>) -> Utf8PathBuf {
+        sharded_path(&self.base_path, self.prefix_len, hash)
+    }
+
+    /// Unique name for a temporary file.
+    fn temp_name() -> String {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        format!(
+            "{TEMP_PREFIX}{}.{}.{nanos}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    fn exists(&self, path: &Utf8Path) -> io::Result<bool> {
+        Ok(self.stat(path)?.is_some())
+    }
+
+    /// Attributes of the file at `path`, or `None` if it doesn't exist.
+    fn stat(&self, path: &Utf8Path) -> io::Result<Option<FileStat>> {
+        match self.sftp().stat(path.as_std_path()) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(err) => match io::Error::from(err) {
+                err if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                err => Err(err),
+            },
+        }
+    }
+
+    /// Upload `bytes` into the temporary file at `temp_path`, continuing after the bytes already
+    /// uploaded by an interrupted attempt if `resume` is set.
+    ///
+    /// Objects with the same hash have the same content, so concurrent uploads of the same object
+    /// write the same bytes at the same offsets. The file is never truncated, as that would cut
+    /// the bytes written by another upload from under it.
+    fn upload(&self, temp_path: &Path, bytes: &[u8], resume: bool) -> io::Result<()> {
+        let sftp = self.sftp();
+        if !resume {
+            let mut file = sftp.create(temp_path)?;
+            file.write_all(bytes)?;
+            return file.close().map_err(io::Error::from);
+        }
+
+        let flags = OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE;
+        let mut file = sftp.open_mode(temp_path, flags, 0o644, OpenType::File)?;
+        let kept = resume_upload(&mut file, bytes)?;
+        if kept > 0 {
+            debug!("resumed upload of {temp_path:?} after {kept} bytes");
+        }
+        file.close().map_err(io::Error::from)
+    }
+
+    /// List objects in `dir`, where `prefix` is the hex prefix of all hashes in that directory.
+    fn list_dir(&self, dir: &Utf8Path, prefix: &str) -> io::Result<Vec<Output<H>>> {
+        let entries = self.sftp().readdir(dir.as_std_path())?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|(path, _)| {
+                let mut hash = Output::<H>::default();
+                let hex = format!("{prefix}{}", path.file_name()?.to_str()?);
+                const_hex::decode_to_slice(hex, &mut hash).ok()?;
+                Some(hash)
+            })
+            .collect())
+    }
+
+    /// Names of shard subdirectories (or a single empty prefix for flat layout).
+    fn list_shards(&self) -> io::Result<Vec<String>> {
+        if self.prefix_len == 0 {
+            return Ok(vec![String::new()]);
+        }
+
+        let entries = self.sftp().readdir(self.base_path.as_std_path())?;
+        Ok(entries
+            .into_iter()
+            .filter(|(_, stat)| stat.is_dir())
+            .filter_map(|(path, _)| {
+                let name = path.file_name()?.to_str()?.to_owned();
+                (name.len() == self.prefix_len).then_some(name)
+            })
+            .collect())
+    }
+}
+
+/// Write `bytes` into `file`, keeping the bytes an interrupted upload already wrote if they are a
+/// prefix of `bytes`, and check that `file` holds exactly `bytes` afterwards. Returns the number of
+/// kept bytes.
+///
+/// Fails with [`io::ErrorKind::InvalidData`] if `file` is longer than `bytes`, as it then holds
+/// something else and has to be removed.
+fn resume_upload(file: &mut (impl Read + Write + Seek), bytes: &[u8]) -> io::Result<usize> {
+    let size = file.seek(SeekFrom::End(0))?;
+    let mut kept = 0;
+    if let Some(expected) = bytes.get(..size as usize) {
+        let mut prefix = vec![0; expected.len()];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut prefix)?;
+        if prefix == expected {
+            kept = prefix.len();
+        }
+    }
+
+    file.seek(SeekFrom::Start(kept as u64))?;
+    file.write_all(&bytes[kept..])?;
+    file.flush()?;
+    let size = file.seek(SeekFrom::End(0))?;
+    if size != bytes.len() as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "partial upload has {size} bytes, but the object has {}",
+                bytes.len()
+            ),
+        ));
+    }
+    Ok(kept)
+}
+
+impl<H: Digest> ContentAddressableStorage for SftpCas<H> {
+    type Error = io::Error;
+    type Hash = Output<H>;
+
+    fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        let shards = match self.list_shards() {
+            Ok(shards) => Either::Left(shards.into_iter().map(Ok)),
+            Err(err) => Either::Right(std::iter::once(Err(err))),
+        };
+        shards.flat_map(move |shard| {
+            let hashes =
+                shard.and_then(|shard| self.list_dir(&self.base_path.join(&shard), &shard));
+            match hashes {
+                Ok(hashes) => Either::Left(hashes.into_iter().map(Ok)),
+                Err(err) => Either::Right(std::iter::once(Err(err))),
+            }
+        })
+    }
+
+    fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+        let mut file = match self.sftp().open(self.path_for(&hash).as_std_path()) {
+            Ok(file) => file,
+            Err(err) => {
+                let err = io::Error::from(err);
+                return match err.kind() {
+                    io::ErrorKind::NotFound => Ok(None),
+                    _ => Err(err),
+                };
+            }
+        };
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        Ok(Some(Bytes::from(buf)))
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        H::digest(bytes)
+    }
+
+    #[instrument(skip_all)]
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        let path = self.path_for(hash);
+        if self.exists(&path)? {
+            debug!("skipping saving {path:?}: already exists");
+            return Ok(());
+        }
+
+        debug!("saving new content at {path:?}");
+        let dir = path.parent().unwrap_or(&self.base_path);
+        if !self.exists(dir)? {
+            // Another writer might create the directory concurrently, so only fail if it still
+            // doesn't exist.
+            if let Err(err) = self.sftp().mkdir(dir.as_std_path(), 0o755)
+                && !self.exists(dir)?
+            {
+                return Err(err.into());
+            }
+        }
+
+        // Upload into a temporary file first, so a partially uploaded object is never observable
+        // under its final name. Partial uploads of large objects are kept to be resumed.
+        let resume = bytes.len() >= RESUMABLE_UPLOAD_SIZE;
+        let temp_path = if resume {
+            dir.join(format!(
+                "{TEMP_PREFIX}{}",
+                path.file_name().unwrap_or_default()
+            ))
+        } else {
+            dir.join(Self::temp_name())
+        };
+        if let Err(err) = self.upload(temp_path.as_std_path(), &bytes, resume) {
+            // Partial uploads that can't be resumed are removed, so the next attempt starts over.
+            if !resume || err.kind() == io::ErrorKind::InvalidData {
+                let _ = self.sftp().unlink(temp_path.as_std_path());
+            }
+            return Err(err);
+        }
+
+        if let Err(err) = self
+            .sftp()
+            .rename(temp_path.as_std_path(), path.as_std_path(), None)
+        {
+            let _ = self.sftp().unlink(temp_path.as_std_path());
+            // The rename fails if the object has been concurrently saved (SFTP doesn't allow
+            // overwriting files on rename), which is fine.
+            if !self.exists(&path)? {
+                return Err(err.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.exists(&self.path_for(hash))
+    }
+
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        Ok(self.stat(&self.path_for(hash))?.and_then(|stat| stat.size))
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        match self.sftp().unlink(self.path_for(hash).as_std_path()) {
+            Ok(()) => Ok(true),
+            Err(err) => match io::Error::from(err) {
+                err if err.kind() == io::ErrorKind::NotFound => Ok(false),
+                err => Err(err),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_resume_upload() {
+        let bytes = b"hello world";
+
+        let mut file = Cursor::new(Vec::new());
+        assert_eq!(resume_upload(&mut file, bytes).unwrap(), 0);
+        assert_eq!(file.get_ref(), bytes);
+
+        // Left by an interrupted upload.
+        let mut file = Cursor::new(b"hello".to_vec());
+        assert_eq!(resume_upload(&mut file, bytes).unwrap(), 5);
+        assert_eq!(file.get_ref(), bytes);
+
+        // Doesn't match the object, e.g. with a hole of zeroes.
+        let mut file = Cursor::new(b"hel\0\0".to_vec());
+        assert_eq!(resume_upload(&mut file, bytes).unwrap(), 0);
+        assert_eq!(file.get_ref(), bytes);
+
+        let mut file = Cursor::new(bytes.to_vec());
+        assert_eq!(resume_upload(&mut file, bytes).unwrap(), bytes.len());
+        assert_eq!(file.get_ref(), bytes);
+
+        let mut file = Cursor::new(b"hello world!".to_vec());
+        let err = resume_upload(&mut file, bytes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
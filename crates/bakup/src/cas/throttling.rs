@@ -0,0 +1,178 @@
+use std::{
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use super::ContentAddressableStorage;
+
+/// Token bucket limiting throughput to a number of bytes per second.
+///
+/// The limiter is thread-safe, and can be shared between stores (and threads) that should be
+/// limited together. Up to one second worth of bytes may be transferred in a burst after a period
+/// of inactivity, but the bucket starts empty.
+pub struct RateLimiter {
+    bytes_per_second: NonZeroU64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Bytes that may be transferred without waiting. Negative if transfers already in progress
+    /// have to wait for the bucket to refill.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: NonZeroU64) -> Self {
+        RateLimiter {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Block until `bytes` may be transferred.
+    ///
+    /// Transfers larger than the burst size are allowed, and make the following ones wait for
+    /// longer.
+    pub fn acquire(&self, bytes: u64) {
+        let rate = self.bytes_per_second.get() as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.refilled_at = now;
+            // Reserve the bytes before waiting, so that concurrent transfers queue up behind.
+            bucket.tokens -= bytes as f64;
+            (-bucket.tokens).max(0.0) / rate
+        };
+        if wait > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+/// Content-addressable storage that limits the rate of writes to `inner`.
+///
+/// Every written object counts against the limit, even if `inner` already has it. Reads are not
+/// limited.
+pub struct ThrottlingCas<S> {
+    inner: S,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl<S> ThrottlingCas<S> {
+    /// Create a store without a limit.
+    pub fn new(inner: S) -> Self {
+        ThrottlingCas {
+            inner,
+            limiter: None,
+        }
+    }
+
+    /// Limit writes with `limiter`, which may be shared with other stores.
+    pub fn with_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn throttle(&self, bytes: &Bytes) {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(bytes.len() as u64);
+        }
+    }
+}
+
+impl<S: ContentAddressableStorage> ContentAddressableStorage for ThrottlingCas<S> {
+    type Error = S::Error;
+    type Hash = S::Hash;
+
+    fn list(&self) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        self.inner.list()
+    }
+
+    fn get(&self, hash: Self::Hash) -> Result<Option<Bytes>, Self::Error> {
+        self.inner.get(hash)
+    }
+
+    fn store_many(
+        &self,
+        items: impl IntoIterator<Item = Bytes>,
+    ) -> impl Iterator<Item = Result<Self::Hash, Self::Error>> {
+        self.inner
+            .store_many(items.into_iter().inspect(|bytes| self.throttle(bytes)))
+    }
+
+    fn hash(&self, bytes: &[u8]) -> Self::Hash {
+        self.inner.hash(bytes)
+    }
+
+    fn put(&self, hash: &Self::Hash, bytes: Bytes) -> Result<(), Self::Error> {
+        self.throttle(&bytes);
+        self.inner.put(hash, bytes)
+    }
+
+    fn contains(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.inner.contains(hash)
+    }
+
+    fn size(&self, hash: &Self::Hash) -> Result<Option<u64>, Self::Error> {
+        self.inner.size(hash)
+    }
+
+    fn verify(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.inner.verify(hash)
+    }
+
+    fn is_retryable(err: &Self::Error) -> bool {
+        S::is_retryable(err)
+    }
+
+    fn remove(&self, hash: &Self::Hash) -> Result<bool, Self::Error> {
+        self.inner.remove(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rayon::prelude::*;
+
+    use super::*;
+    use crate::cas::MemoryCas;
+
+    #[test]
+    fn test_limits_write_rate() {
+        let limiter = Arc::new(RateLimiter::new(NonZeroU64::new(100_000).unwrap()));
+        let cas = ThrottlingCas::new(MemoryCas::<blake3::Hasher>::new()).with_limiter(limiter);
+
+        let start = Instant::now();
+        (0..20u8).into_par_iter().for_each(|i| {
+            cas.store(Bytes::from(vec![i; 1000])).unwrap();
+        });
+        cas.store_many((0..10u8).map(|i| Bytes::from(vec![i; 1000])))
+            .for_each(|it| {
+                it.unwrap();
+            });
+
+        // 30 kB at 100 kB/s.
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(cas.list().count(), 20);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let cas = ThrottlingCas::new(MemoryCas::<blake3::Hasher>::new());
+        let hash = cas.store(Bytes::from_static(b"hello")).unwrap();
+        assert_eq!(cas.get(hash).unwrap().as_deref(), Some(&b"hello"[..]));
+    }
+}
@@ -0,0 +1,183 @@
+use std::io::{self, Write};
+
+use anyhow::{Context, anyhow, bail};
+use camino::Utf8Path;
+use const_hex::ToHexExt;
+use digest::Output;
+
+use crate::{
+    cas::ContentAddressableStorage,
+    cli,
+    lock::RepoLock,
+    manifest::{EntryType, SnapshotManifest, covering_chunks},
+    repo, snapshots,
+    status::{self, IntegrityError},
+};
+
+/// Write a single file of a snapshot (or a byte range of it) to stdout, fetching its chunks one by
+/// one.
+pub fn cat(cmd: cli::Cat) -> anyhow::Result<()> {
+    let _lock = RepoLock::shared(&cmd.remote, &cmd.lock)?;
+    let cas = repo::open(&cmd.remote, cmd.identity.as_deref())?;
+    let (_, manifest) = snapshots::resolve(&cmd.remote, &cas, &cmd.snapshot)?;
+    let path =
+        camino::absolute_utf8(&cmd.path).with_context(|| format!("invalid path {}", cmd.path))?;
+    let FileContent {
+        content,
+        chunk_sizes,
+        unstable,
+    } = file_content(&manifest, &path)?;
+    if unstable {
+        status::note(format_args!(
+            "{path} changed while being backed up, its content may be inconsistent"
+        ));
+    }
+
+    let range = cmd.range.unwrap_or(0..u64::MAX);
+    // Without chunk sizes (in older snapshots), chunks are fetched from the start of the file.
+    let (chunks, mut offset) = if chunk_sizes.len() == content.len() {
+        covering_chunks(chunk_sizes, range.clone())
+    } else {
+        (0..content.len(), 0)
+    };
+
+    let mut stdout = io::stdout().lock();
+    for hash in &content[chunks] {
+        if offset >= range.end {
+            break;
+        }
+        let chunk = cas.get(*hash)?.ok_or_else(|| {
+            anyhow!(IntegrityError(format!(
+                "chunk {} is missing from the repository",
+                hash.encode_hex()
+            )))
+        })?;
+        if !cmd.no_verify && cas.hash(&chunk) != *hash {
+            bail!(IntegrityError(format!(
+                "chunk {} is corrupt",
+                hash.encode_hex()
+            )));
+        }
+        let len = chunk.len() as u64;
+        let start = range.start.saturating_sub(offset).min(len) as usize;
+        let end = (range.end - offset).min(len) as usize;
+        stdout.write_all(&chunk[start..end])?;
+        offset += len;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+struct FileContent<'a> {
+    content: &'a [Output<blake3::Hasher>],
+    /// Sizes of the chunks, empty if not recorded in the snapshot.
+    chunk_sizes: &'a [u64],
+    /// The file changed while being backed up.
+    unstable: bool,
+}
+
+/// Chunks of the regular file at `path` in the snapshot (following hard links).
+fn file_content<'a>(
+    manifest: &'a SnapshotManifest,
+    path: &Utf8Path,
+) -> anyhow::Result<FileContent<'a>> {
+    let find = |path: &Utf8Path| {
+        manifest
+            .entries
+            .iter()
+            .find(|entry| entry.path == path)
+            .ok_or_else(|| anyhow!("{path} is not in the snapshot"))
+    };
+
+    let mut entry = find(path)?;
+    if let EntryType::Hardlink { target } = &entry.ty {
+        entry = find(target)?;
+    }
+    match &entry.ty {
+        EntryType::File {
+            content,
+            chunk_sizes,
+            unstable,
+            ..
+        } => Ok(FileContent {
+            content,
+            chunk_sizes,
+            unstable: *unstable,
+        }),
+        EntryType::Directory => bail!("{path} is a directory, not a file"),
+        EntryType::Symlink { target } => bail!("{path} is a symlink to {target}, not a file"),
+        EntryType::Hardlink { target } => bail!("{path} is a hard link to another link {target}"),
+        EntryType::Fifo | EntryType::Socket | EntryType::Device { .. } => {
+            bail!("{path} is a special file, not a regular file")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use digest::Digest;
+
+    use super::*;
+    use crate::manifest::{EntryManifest, MANIFEST_VERSION};
+
+    fn entry(path: &str, ty: EntryType) -> EntryManifest {
+        EntryManifest {
+            path: path.into(),
+            ty,
+            mtime: None,
+            uid: None,
+            gid: None,
+            mode: None,
+            xattrs: None,
+        }
+    }
+
+    #[test]
+    fn test_file_content() {
+        let hash = blake3::Hasher::digest(b"hello");
+        let manifest = SnapshotManifest {
+            version: MANIFEST_VERSION,
+            name: None,
+            hostname: None,
+            paths: Vec::new(),
+            tags: Vec::new(),
+            description: None,
+            time: SystemTime::now(),
+            chunker: None,
+            entries: vec![
+                entry("/a", EntryType::Directory),
+                entry(
+                    "/a/file",
+                    EntryType::File {
+                        content: vec![hash],
+                        size: 5,
+                        chunk_sizes: vec![5],
+                        unstable: false,
+                    },
+                ),
+                entry(
+                    "/a/link",
+                    EntryType::Hardlink {
+                        target: "/a/file".into(),
+                    },
+                ),
+                entry(
+                    "/a/symlink",
+                    EntryType::Symlink {
+                        target: "file".into(),
+                    },
+                ),
+            ],
+        };
+
+        let content =
+            |path: &str| file_content(&manifest, path.into()).map(|it| it.content.to_vec());
+        assert_eq!(content("/a/file").unwrap(), [hash]);
+        assert_eq!(content("/a/link").unwrap(), [hash]);
+        assert!(content("/a").is_err());
+        assert!(content("/a/symlink").is_err());
+        assert!(content("/a/missing").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use const_hex::ToHexExt;
+use digest::Output;
+use rayon::prelude::*;
+
+use crate::{
+    cas::ContentAddressableStorage,
+    cli,
+    lock::RepoLock,
+    manifest::EntryType,
+    output::say,
+    progress,
+    repo::{self, Repository},
+    snapshots,
+    status::IntegrityError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ObjectStatus {
+    Ok,
+    Missing,
+    /// The object exists, but its content doesn't match its hash.
+    Corrupt,
+}
+
+pub fn check(cmd: cli::Check) -> anyhow::Result<()> {
+    let _lock = RepoLock::shared(&cmd.remote, &cmd.lock)?;
+    let cas = repo::open(&cmd.remote, cmd.identity.as_deref())?;
+
+    let ids = snapshots::list(&cmd.remote)?;
+    let mut broken_snapshots = 0;
+    let mut inconsistent_files = 0;
+    // All referenced objects, with a description of the first place referencing each one.
+    let mut objects = BTreeMap::<Output<blake3::Hasher>, String>::new();
+    for &id in &ids {
+        let manifest = match snapshots::load(&cas, id) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                println!("error: {err:#}");
+                broken_snapshots += 1;
+                continue;
+            }
+        };
+
+        let snapshot = id.encode_hex();
+        objects.insert(id, format!("manifest of snapshot {snapshot}"));
+        for entry in manifest.entries {
+            if let Err(problem) = entry.ty.check_chunk_sizes() {
+                println!(
+                    "inconsistent file {} in snapshot {snapshot}: {problem}",
+                    entry.path
+                );
+                inconsistent_files += 1;
+            }
+            if let EntryType::File { content, .. } = entry.ty {
+                for hash in content {
+                    objects
+                        .entry(hash)
+                        .or_insert_with(|| format!("{} in snapshot {snapshot}", entry.path));
+                }
+            }
+        }
+    }
+
+    let progress = progress::bar(objects.len() as u64, "{wide_bar} {pos}/{len} objects");
+    let statuses = objects
+        .par_iter()
+        .map(|(hash, referrer)| {
+            let status = check_object(&cas, hash, cmd.read_data)?;
+            let problem = match status {
+                ObjectStatus::Ok => None,
+                ObjectStatus::Missing => Some("missing"),
+                ObjectStatus::Corrupt => Some("corrupt"),
+            };
+            if let Some(problem) = problem {
+                progress.suspend(|| {
+                    println!(
+                        "{problem} object {} (referenced by {referrer})",
+                        hash.encode_hex()
+                    )
+                });
+            }
+            progress.inc(1);
+            Ok(status)
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    progress.finish_and_clear();
+
+    let count = |status| statuses.iter().filter(|&&it| it == status).count();
+    let missing = count(ObjectStatus::Missing);
+    let corrupt = count(ObjectStatus::Corrupt);
+    say!(
+        "checked {} snapshots and {} objects{}: {broken_snapshots} broken snapshots, \
+         {inconsistent_files} inconsistent files, {missing} missing objects, {corrupt} corrupt \
+         objects",
+        ids.len(),
+        objects.len(),
+        if cmd.read_data { " with data" } else { "" },
+    );
+
+    let problems = broken_snapshots + inconsistent_files + missing + corrupt;
+    if problems > 0 {
+        bail!(IntegrityError(format!(
+            "repository check found {problems} problems"
+        )));
+    }
+    Ok(())
+}
+
+/// Check that object `hash` exists and, if `read_data` is set, that its content matches the hash.
+fn check_object(
+    cas: &Repository,
+    hash: &Output<blake3::Hasher>,
+    read_data: bool,
+) -> std::io::Result<ObjectStatus> {
+    if !cas.contains(hash)? {
+        return Ok(ObjectStatus::Missing);
+    }
+    if read_data && !cas.verify(hash)? {
+        // `verify` doesn't distinguish missing objects, which may have been removed concurrently.
+        return Ok(if cas.contains(hash)? {
+            ObjectStatus::Corrupt
+        } else {
+            ObjectStatus::Missing
+        });
+    }
+    Ok(ObjectStatus::Ok)
+}
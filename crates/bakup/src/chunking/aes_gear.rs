@@ -12,10 +12,15 @@ pub struct AesGearConfig<'a> {
 
 impl AesGearConfig<'static> {
     pub fn new(aes: Aes128Enc) -> Self {
-        AesGearConfig {
-            table: &DEFAULT_TABLE,
-            aes,
-        }
+        Self::with_table(&DEFAULT_TABLE, aes)
+    }
+}
+
+impl<'a> AesGearConfig<'a> {
+    /// Create config with a custom gear table, e.g., one derived with
+    /// [`gear_table_from_seed`](super::gear_table_from_seed).
+    pub fn with_table(table: &'a [u64; 256], aes: Aes128Enc) -> Self {
+        AesGearConfig { table, aes }
     }
 }
 
@@ -1,3 +1,26 @@
+/// Context string for deriving gear tables from a seed.
+const GEAR_TABLE_CONTEXT: &str = "bakup gear table v1";
+
+/// Deterministically derive a gear table from a secret `seed`.
+///
+/// Using a per-repository secret table makes chunk boundaries (and therefore chunk sizes)
+/// unpredictable without the seed, which resists fingerprinting of known files by their chunk
+/// sizes. Changing the seed changes all chunk boundaries, so new snapshots won't deduplicate
+/// against data stored with the old seed.
+pub fn gear_table_from_seed(seed: &[u8; 32]) -> [u64; 256] {
+    let mut bytes = [0u8; 256 * 8];
+    blake3::Hasher::new_derive_key(GEAR_TABLE_CONTEXT)
+        .update(seed)
+        .finalize_xof()
+        .fill(&mut bytes);
+
+    let mut table = [0u64; 256];
+    for (entry, bytes) in table.iter_mut().zip(bytes.chunks_exact(8)) {
+        *entry = u64::from_le_bytes(bytes.try_into().expect("chunk should be 8 bytes"));
+    }
+    table
+}
+
 pub static DEFAULT_TABLE: [u64; 256] = [
     0x2ce6506a7c701b3b,
     0xac03147754978b24,
@@ -256,3 +279,16 @@ pub static DEFAULT_TABLE: [u64; 256] = [
     0xc47489d235bafaed,
     0xd2eca8484a9778f4,
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gear_table_from_seed() {
+        let table = gear_table_from_seed(&[1; 32]);
+        assert_eq!(table, gear_table_from_seed(&[1; 32]));
+        assert_ne!(table, gear_table_from_seed(&[2; 32]));
+        assert_ne!(table, DEFAULT_TABLE);
+    }
+}
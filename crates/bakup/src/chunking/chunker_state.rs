@@ -2,6 +2,24 @@ use crate::chunking::aes_gear::AesGearConfig;
 
 use super::aes_gear::AesGearHash;
 
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ChunkerConfigError {
+    #[error("avg_size ({avg_size}) should be a power of 2")]
+    AvgSizeNotPowerOfTwo { avg_size: usize },
+    #[error("normalization_bits ({normalization_bits}) is too large for avg_size ({avg_size})")]
+    NormalizationTooLarge {
+        avg_size: usize,
+        normalization_bits: u32,
+    },
+    #[error("min_size ({min_size}) should not exceed max_size ({max_size})")]
+    MinExceedsMax { min_size: usize, max_size: usize },
+    #[error(
+        "min_size ({min_size}) should be at least {}",
+        ChunkerConfig::MIN_MIN_SIZE
+    )]
+    MinSizeTooSmall { min_size: usize },
+}
+
 pub struct ChunkerConfig<'a> {
     gear_config: AesGearConfig<'a>,
     min_size: usize,
@@ -12,6 +30,33 @@ pub struct ChunkerConfig<'a> {
 }
 
 impl<'a> ChunkerConfig<'a> {
+    /// The smallest supported `min_size`.
+    ///
+    /// Normally, the gear hash is primed with 64 bytes before the first possible chunk boundary.
+    /// Smaller chunks are supported but the boundaries then depend on fewer bytes of input.
+    pub const MIN_MIN_SIZE: usize = 1;
+
+    pub const DEFAULT_MIN_SIZE: usize = 1024 * 1024;
+    pub const DEFAULT_AVG_SIZE: usize = 4 * 1024 * 1024;
+    pub const DEFAULT_MAX_SIZE: usize = 16 * 1024 * 1024;
+    pub const DEFAULT_NORMALIZATION_BITS: u32 = 3;
+
+    /// Start building a config with named parameters, which default to the `DEFAULT_*` ones.
+    pub fn builder(gear_config: AesGearConfig<'a>) -> ChunkerConfigBuilder<'a> {
+        ChunkerConfigBuilder {
+            gear_config,
+            min_size: Self::DEFAULT_MIN_SIZE,
+            avg_size: Self::DEFAULT_AVG_SIZE,
+            max_size: Self::DEFAULT_MAX_SIZE,
+            normalization_bits: Self::DEFAULT_NORMALIZATION_BITS,
+        }
+    }
+
+    /// Create a new config.
+    ///
+    /// # Panics
+    ///
+    /// Panics if parameters are invalid. Use [`try_new`](Self::try_new) to handle this.
     pub fn new(
         gear_config: AesGearConfig<'a>,
         min_size: usize,
@@ -19,22 +64,109 @@ impl<'a> ChunkerConfig<'a> {
         max_size: usize,
         normalization_bits: u32,
     ) -> Self {
-        assert!(
-            avg_size & (avg_size - 1) == 0,
-            "avg_size should be a power of 2"
-        );
+        Self::try_new(
+            gear_config,
+            min_size,
+            avg_size,
+            max_size,
+            normalization_bits,
+        )
+        .unwrap()
+    }
+
+    pub fn try_new(
+        gear_config: AesGearConfig<'a>,
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+        normalization_bits: u32,
+    ) -> Result<Self, ChunkerConfigError> {
+        if !avg_size.is_power_of_two() {
+            return Err(ChunkerConfigError::AvgSizeNotPowerOfTwo { avg_size });
+        }
         let avg_base = avg_size.ilog2();
-        assert!(avg_base > normalization_bits);
+        // Masks are built by shifting a u64, so the stricter one should still fit.
+        if avg_base <= normalization_bits || avg_base + normalization_bits >= u64::BITS - 1 {
+            return Err(ChunkerConfigError::NormalizationTooLarge {
+                avg_size,
+                normalization_bits,
+            });
+        }
+        if min_size < Self::MIN_MIN_SIZE {
+            return Err(ChunkerConfigError::MinSizeTooSmall { min_size });
+        }
+        if min_size > max_size {
+            return Err(ChunkerConfigError::MinExceedsMax { min_size, max_size });
+        }
+
         let before_avg_size_mask = (2 << (avg_base + normalization_bits) as u64) - 1;
         let after_avg_size_mask = (2 << (avg_base - normalization_bits) as u64) - 1;
-        ChunkerConfig {
+        Ok(ChunkerConfig {
             gear_config,
             min_size,
             avg_size,
             max_size,
             before_avg_size_mask,
             after_avg_size_mask,
-        }
+        })
+    }
+
+    pub fn min_size(&self) -> usize {
+        self.min_size
+    }
+
+    pub fn avg_size(&self) -> usize {
+        self.avg_size
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+/// Builder of a [`ChunkerConfig`], see [`ChunkerConfig::builder`].
+pub struct ChunkerConfigBuilder<'a> {
+    gear_config: AesGearConfig<'a>,
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    normalization_bits: u32,
+}
+
+impl<'a> ChunkerConfigBuilder<'a> {
+    /// Chunks are never smaller than this, except for the last chunk of a stream.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Target average chunk size. Should be a power of 2.
+    pub fn avg_size(mut self, avg_size: usize) -> Self {
+        self.avg_size = avg_size;
+        self
+    }
+
+    /// Chunks are cut at this size if no boundary is found before.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// How much harder it is to find a boundary before `avg_size` and easier after it, which
+    /// narrows the distribution of chunk sizes.
+    pub fn normalization_bits(mut self, normalization_bits: u32) -> Self {
+        self.normalization_bits = normalization_bits;
+        self
+    }
+
+    pub fn build(self) -> Result<ChunkerConfig<'a>, ChunkerConfigError> {
+        ChunkerConfig::try_new(
+            self.gear_config,
+            self.min_size,
+            self.avg_size,
+            self.max_size,
+            self.normalization_bits,
+        )
     }
 }
 
@@ -54,17 +186,32 @@ impl<'a> ChunkerState<'a> {
         }
     }
 
+    /// Reset the state to start chunking a new stream, as if it was freshly created.
+    pub fn reset(&mut self) {
+        self.gear = AesGearHash::new(&self.config.gear_config);
+        self.size = 0;
+    }
+
+    pub fn config(&self) -> &'a ChunkerConfig<'a> {
+        self.config
+    }
+
     /// Process `buf` and return `Some(consumed)` if chunk boundary is found (where `consumed` is
     /// offset into `buf`). If no chunk boundary is found, returns `None`, which means that the
     /// whole `buf` was consumed.
+    ///
+    /// All state is carried between calls, so splitting input into buffers arbitrarily (down to
+    /// single bytes) produces the same chunk boundaries. The bytes that can't influence the next
+    /// boundary are skipped without per-byte processing.
     pub fn update(&mut self, buf: &[u8]) -> Option<usize> {
         // Offset into `buf` where we're reading now.
         let mut i = 0;
 
         // Skip hashing the first min_size-64 bytes as their hash does not influence chunking
         // decision.
-        if self.size < self.config.min_size - 64 {
-            let to_skip = self.config.min_size - 64 - self.size;
+        let hash_from = self.config.min_size.saturating_sub(64);
+        if self.size < hash_from {
+            let to_skip = hash_from - self.size;
             if to_skip >= buf.len() {
                 // consume whole buf
                 self.size += buf.len();
@@ -76,7 +223,7 @@ impl<'a> ChunkerState<'a> {
         }
 
         // Hash 63 bytes before min_size without checking for boundary.
-        while self.size < self.config.min_size - 1 {
+        while self.size + 1 < self.config.min_size {
             // Consume without checking boundary.
             if i >= buf.len() {
                 return None;
@@ -125,3 +272,94 @@ impl<'a> ChunkerState<'a> {
         Some(i)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::KeyInit;
+
+    use super::*;
+
+    fn try_config(
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+        normalization_bits: u32,
+    ) -> Result<(), ChunkerConfigError> {
+        let aes = aes::Aes128Enc::new_from_slice(&[0u8; 16]).unwrap();
+        let gear_config = AesGearConfig::new(aes);
+        ChunkerConfig::try_new(
+            gear_config,
+            min_size,
+            avg_size,
+            max_size,
+            normalization_bits,
+        )
+        .map(|_| ())
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert_eq!(try_config(128, 256, 1024, 3), Ok(()));
+        assert_eq!(
+            try_config(128, 300, 1024, 3),
+            Err(ChunkerConfigError::AvgSizeNotPowerOfTwo { avg_size: 300 })
+        );
+        assert_eq!(
+            try_config(128, 0, 1024, 3),
+            Err(ChunkerConfigError::AvgSizeNotPowerOfTwo { avg_size: 0 })
+        );
+        assert_eq!(
+            try_config(128, 256, 1024, 8),
+            Err(ChunkerConfigError::NormalizationTooLarge {
+                avg_size: 256,
+                normalization_bits: 8
+            })
+        );
+        assert_eq!(
+            try_config(2048, 256, 1024, 3),
+            Err(ChunkerConfigError::MinExceedsMax {
+                min_size: 2048,
+                max_size: 1024
+            })
+        );
+        assert_eq!(
+            try_config(0, 256, 1024, 3),
+            Err(ChunkerConfigError::MinSizeTooSmall { min_size: 0 })
+        );
+    }
+
+    #[test]
+    fn test_builder() {
+        let gear_config =
+            || AesGearConfig::new(aes::Aes128Enc::new_from_slice(&[0u8; 16]).unwrap());
+
+        let config = ChunkerConfig::builder(gear_config()).build().unwrap();
+        assert_eq!(config.min_size(), ChunkerConfig::DEFAULT_MIN_SIZE);
+        assert_eq!(config.avg_size(), ChunkerConfig::DEFAULT_AVG_SIZE);
+        assert_eq!(config.max_size(), ChunkerConfig::DEFAULT_MAX_SIZE);
+
+        let config = ChunkerConfig::builder(gear_config())
+            .min_size(128)
+            .avg_size(256)
+            .max_size(1024)
+            .normalization_bits(2)
+            .build()
+            .unwrap();
+        assert_eq!(
+            (config.min_size(), config.avg_size(), config.max_size()),
+            (128, 256, 1024)
+        );
+
+        let err = ChunkerConfig::builder(gear_config())
+            .max_size(1024)
+            .build()
+            .err();
+        assert_eq!(
+            err,
+            Some(ChunkerConfigError::MinExceedsMax {
+                min_size: ChunkerConfig::DEFAULT_MIN_SIZE,
+                max_size: 1024
+            })
+        );
+    }
+}
@@ -0,0 +1,81 @@
+/// Statistics of produced chunks, useful to tune chunking parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkerStats {
+    count: u64,
+    total_bytes: u64,
+    min_size: u64,
+    max_size: u64,
+    /// Number of chunks with size in `2^i..2^(i+1)` range for every `i`.
+    histogram: [u64; 64],
+}
+
+impl ChunkerStats {
+    pub fn new() -> Self {
+        ChunkerStats {
+            count: 0,
+            total_bytes: 0,
+            min_size: u64::MAX,
+            max_size: 0,
+            histogram: [0; 64],
+        }
+    }
+
+    /// Account a produced chunk of `size` bytes.
+    pub fn record(&mut self, size: u64) {
+        self.count += 1;
+        self.total_bytes += size;
+        self.min_size = self.min_size.min(size);
+        self.max_size = self.max_size.max(size);
+        if size > 0 {
+            self.histogram[size.ilog2() as usize] += 1;
+        }
+    }
+
+    /// Add up statistics collected separately (e.g., by different threads).
+    pub fn merge(&mut self, other: &ChunkerStats) {
+        self.count += other.count;
+        self.total_bytes += other.total_bytes;
+        self.min_size = self.min_size.min(other.min_size);
+        self.max_size = self.max_size.max(other.max_size);
+        for (bucket, other) in self.histogram.iter_mut().zip(other.histogram) {
+            *bucket += other;
+        }
+    }
+
+    /// Number of chunks.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Total size of all chunks.
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Size of the smallest chunk, or `None` if there were no chunks.
+    pub fn min_size(&self) -> Option<u64> {
+        (self.count > 0).then_some(self.min_size)
+    }
+
+    /// Size of the largest chunk, or `None` if there were no chunks.
+    pub fn max_size(&self) -> Option<u64> {
+        (self.count > 0).then_some(self.max_size)
+    }
+
+    /// Mean chunk size, or `None` if there were no chunks.
+    pub fn mean_size(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.total_bytes as f64 / self.count as f64)
+    }
+
+    /// Number of chunks by power-of-two size buckets: `histogram()[i]` is the number of chunks
+    /// with size in `2^i..2^(i+1)` range.
+    pub fn histogram(&self) -> &[u64; 64] {
+        &self.histogram
+    }
+}
+
+impl Default for ChunkerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::{Condvar, Mutex};
+
+/// Budget of bytes of chunks kept in memory at once, shared by threads chunking files
+/// concurrently.
+///
+/// A reservation larger than the remaining budget waits until other reservations are released.
+/// A reservation larger than the whole budget is granted once nothing else is reserved, so that
+/// it's still processed, just alone.
+pub struct MemoryBudget {
+    limit: usize,
+    used: Mutex<usize>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Reserve `size` bytes, waiting until they are available.
+    pub fn reserve(&self, size: usize) -> Reservation<'_> {
+        let mut used = self.used.lock().unwrap();
+        while *used > 0 && *used + size > self.limit {
+            used = self.released.wait(used).unwrap();
+        }
+        *used += size;
+        Reservation { budget: self, size }
+    }
+
+    /// Number of bytes currently reserved.
+    pub fn used(&self) -> usize {
+        *self.used.lock().unwrap()
+    }
+
+    fn release(&self, size: usize) {
+        if size == 0 {
+            return;
+        }
+        *self.used.lock().unwrap() -= size;
+        self.released.notify_all();
+    }
+}
+
+/// Bytes reserved from a [`MemoryBudget`], released on drop.
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    size: usize,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.budget.release(self.size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let budget = MemoryBudget::new(100);
+        let first = budget.reserve(30);
+        assert_eq!(budget.used(), 30);
+
+        let second = budget.reserve(70);
+        assert_eq!(budget.used(), 100);
+
+        let granted = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _third = budget.reserve(50);
+                granted.store(true, Ordering::SeqCst);
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!granted.load(Ordering::SeqCst));
+            drop(second);
+        });
+        assert!(granted.load(Ordering::SeqCst));
+        drop(first);
+        assert_eq!(budget.used(), 0);
+
+        // Larger than the whole budget, but nothing else is reserved.
+        let large = budget.reserve(1000);
+        assert_eq!(budget.used(), 1000);
+        drop(large);
+    }
+}
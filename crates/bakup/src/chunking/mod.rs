@@ -1,8 +1,25 @@
 mod aes_gear;
 mod aes_gear_table;
 mod chunker_state;
+mod chunker_stats;
+#[cfg(feature = "std")]
+mod memory_budget;
+#[cfg(feature = "std")]
+mod pipeline;
+#[cfg(feature = "std")]
+mod push_chunker;
+#[cfg(feature = "std")]
 mod stream_chunker;
 
 pub use aes_gear::AesGearConfig;
-pub use chunker_state::ChunkerConfig;
-pub use stream_chunker::StreamChunker;
+pub use aes_gear_table::gear_table_from_seed;
+pub use chunker_state::{ChunkerConfig, ChunkerConfigBuilder, ChunkerConfigError, ChunkerState};
+pub use chunker_stats::ChunkerStats;
+#[cfg(feature = "std")]
+pub use memory_budget::{MemoryBudget, Reservation};
+#[cfg(feature = "std")]
+pub use pipeline::chunk_and_store;
+#[cfg(feature = "std")]
+pub use push_chunker::Chunker;
+#[cfg(feature = "std")]
+pub use stream_chunker::{Chunk, ChunksWithOffsets, StreamChunker};
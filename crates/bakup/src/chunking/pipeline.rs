@@ -0,0 +1,196 @@
+use std::{
+    io::{self, BufRead},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, SyncSender},
+    },
+};
+
+use bytes::Bytes;
+use rayon::ThreadPool;
+
+use super::{ChunkerConfig, MemoryBudget, Reservation, StreamChunker};
+use crate::cas::ContentAddressableStorage;
+
+/// Chunk `reader` and store chunks in `cas`, returning hashes and sizes of the chunks in stream
+/// order.
+///
+/// Chunking runs on the current thread, while chunks are stored concurrently by up to `workers`
+/// tasks on `pool`, which can be shared by many streams chunked at once. Chunks are passed through
+/// a bounded queue, so only a few chunks are kept in memory at a time regardless of the stream
+/// size. A stream of a single chunk is stored on the current thread. `on_stored` is called with the
+/// size of every stored chunk (e.g., to report progress).
+///
+/// If `budget` is given, every chunk reserves its bytes from it once it's cut and until it's
+/// stored, so that files chunked concurrently keep at most that many bytes of chunks in memory
+/// besides the chunks being read.
+///
+/// On failure, chunking stops as soon as possible and the first encountered error is returned.
+pub fn chunk_and_store<R, C>(
+    config: &ChunkerConfig,
+    reader: R,
+    cas: &C,
+    pool: &ThreadPool,
+    workers: usize,
+    budget: Option<&MemoryBudget>,
+    on_stored: impl Fn(u64) + Sync,
+) -> Result<Vec<(C::Hash, u64)>, C::Error>
+where
+    R: BufRead,
+    C: ContentAddressableStorage + Sync,
+    C::Hash: Send,
+    C::Error: From<io::Error> + Send,
+{
+    assert!(workers > 0, "workers should be positive");
+
+    let mut read = read_chunks(config, reader, budget);
+    let Some(first) = read.next().transpose()? else {
+        return Ok(Vec::new());
+    };
+    let Some(second) = read.next().transpose()? else {
+        let (data, reservation) = first;
+        let len = data.len() as u64;
+        let hash = cas.store(data)?;
+        on_stored(len);
+        drop(reservation);
+        return Ok(vec![(hash, len)]);
+    };
+
+    let (chunk_tx, chunk_rx) =
+        mpsc::sync_channel::<(usize, Bytes, Option<Reservation>)>(2 * workers);
+    let chunk_rx = Mutex::new(chunk_rx);
+    let (hash_tx, hash_rx) = mpsc::channel();
+    let failed = AtomicBool::new(false);
+
+    let chunked = pool.in_place_scope(|scope| {
+        for _ in 0..workers {
+            let hash_tx = hash_tx.clone();
+            let (chunk_rx, failed, on_stored) = (&chunk_rx, &failed, &on_stored);
+            scope.spawn(move |_| {
+                loop {
+                    let Ok((index, data, reservation)) = chunk_rx.lock().unwrap().recv() else {
+                        break;
+                    };
+                    // Drain the queue without storing anything after a failure.
+                    if failed.load(Ordering::Relaxed) {
+                        continue;
+                    }
+
+                    let len = data.len() as u64;
+                    let result = cas.store(data).map(|hash| (hash, len));
+                    match result {
+                        Ok(_) => on_stored(len),
+                        Err(_) => failed.store(true, Ordering::Relaxed),
+                    }
+                    drop(reservation);
+                    let _ = hash_tx.send((index, result));
+                }
+            });
+        }
+
+        let chunks = [first, second].into_iter().map(Ok).chain(read);
+        feed(chunks, chunk_tx, &failed)
+    });
+    drop(hash_tx);
+
+    let mut chunks = Vec::new();
+    for (index, result) in hash_rx {
+        if chunks.len() <= index {
+            chunks.resize(index + 1, None);
+        }
+        chunks[index] = Some(result?);
+    }
+    let chunked = chunked?;
+
+    debug_assert_eq!(chunks.len(), chunked);
+    Ok(chunks
+        .into_iter()
+        .map(|it| it.expect("all chunks should have been stored"))
+        .collect())
+}
+
+/// Read chunks of `reader` along with their reservations from `budget`.
+fn read_chunks<'a, R: BufRead>(
+    config: &'a ChunkerConfig,
+    reader: R,
+    budget: Option<&'a MemoryBudget>,
+) -> impl Iterator<Item = io::Result<(Bytes, Option<Reservation<'a>>)>> {
+    let mut chunker = StreamChunker::new(config, reader);
+    std::iter::from_fn(move || {
+        let data = match chunker.next()? {
+            Ok(data) => data,
+            Err(err) => return Some(Err(err)),
+        };
+        let reservation = budget.map(|it| it.reserve(data.len()));
+        Some(Ok((Bytes::from(data), reservation)))
+    })
+}
+
+/// Send `chunks` into `chunk_tx` until the end of stream or failure of a worker. Returns the
+/// number of chunks sent.
+fn feed<'a>(
+    chunks: impl Iterator<Item = io::Result<(Bytes, Option<Reservation<'a>>)>>,
+    chunk_tx: SyncSender<(usize, Bytes, Option<Reservation<'a>>)>,
+    failed: &AtomicBool,
+) -> io::Result<usize> {
+    let mut count = 0;
+    for chunk in chunks {
+        let (data, reservation) = chunk?;
+        if failed.load(Ordering::Relaxed) {
+            break;
+        }
+        if chunk_tx.send((count, data, reservation)).is_err() {
+            break;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::KeyInit;
+    use digest::Digest;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{cas::MemoryCas, chunking::AesGearConfig};
+
+    proptest! {
+        #[test]
+        fn test_chunk_and_store(bytes in prop::collection::vec(any::<u8>(), 0..=16384)) {
+            let aes = aes::Aes128Enc::new_from_slice(&[0u8; 16]).unwrap();
+            let gear_config = AesGearConfig::new(aes);
+            let chunker_config = ChunkerConfig::new(gear_config, 128, 256, 1024, 3);
+            let cas = MemoryCas::<blake3::Hasher>::new();
+            let budget = MemoryBudget::new(2048);
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+
+            let chunks = chunk_and_store(
+                &chunker_config,
+                bytes.as_ref(),
+                &cas,
+                &pool,
+                4,
+                Some(&budget),
+                |_| {},
+            )
+            .unwrap();
+            prop_assert_eq!(budget.used(), 0);
+
+            let expected = StreamChunker::new(&chunker_config, bytes.as_ref())
+                .map(|it| it.unwrap())
+                .map(|it| (blake3::Hasher::digest(&it), it.len() as u64))
+                .collect::<Vec<_>>();
+            prop_assert_eq!(&chunks, &expected);
+
+            let restored = chunks
+                .into_iter()
+                .map(|(hash, _)| cas.get(hash).unwrap().unwrap())
+                .collect::<Vec<_>>()
+                .concat();
+            prop_assert_eq!(restored, bytes);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::{marker::PhantomData, mem};
+
+use digest::Digest;
+
+use super::{
+    chunker_state::{ChunkerConfig, ChunkerState},
+    stream_chunker::Chunk,
+};
+
+/// Push-based counterpart of [`StreamChunker`](super::StreamChunker): the caller feeds bytes as
+/// they arrive and gets back complete chunks with their hashes.
+///
+/// Bytes after the last boundary are buffered until the next [`push`](Self::push) or
+/// [`finish`](Self::finish). For the same input, the chunks are the same as `StreamChunker`
+/// produces, regardless of how the input is split into `push` calls.
+pub struct Chunker<'a, H> {
+    state: ChunkerState<'a>,
+    /// Data of the chunk in progress.
+    pending: Vec<u8>,
+    /// Offset of the chunk in progress in the stream.
+    offset: u64,
+    _digest: PhantomData<H>,
+}
+
+impl<'a, H: Digest> Chunker<'a, H> {
+    pub fn new(config: &'a ChunkerConfig<'a>) -> Self {
+        Chunker {
+            state: ChunkerState::new(config),
+            pending: Vec::new(),
+            offset: 0,
+            _digest: PhantomData,
+        }
+    }
+
+    /// Feed the next `data` of the stream and return the chunks completed by it.
+    pub fn push(&mut self, mut data: &[u8]) -> Vec<Chunk<H>> {
+        let mut chunks = Vec::new();
+        while let Some(consumed) = self.state.update(data) {
+            self.pending.extend_from_slice(&data[..consumed]);
+            chunks.push(self.take());
+            data = &data[consumed..];
+        }
+        self.pending.extend_from_slice(data);
+        chunks
+    }
+
+    /// End the stream and return the last, possibly short, chunk. Returns `None` if the stream
+    /// ended at a chunk boundary.
+    ///
+    /// The chunker can then be reused for a new stream.
+    pub fn finish(&mut self) -> Option<Chunk<H>> {
+        let chunk = (!self.pending.is_empty()).then(|| self.take());
+        self.state.reset();
+        self.offset = 0;
+        chunk
+    }
+
+    fn take(&mut self) -> Chunk<H> {
+        // Every chunk except the last one is at least min_size, so reserve that upfront instead of
+        // growing the buffer with every small push.
+        let data = mem::replace(
+            &mut self.pending,
+            Vec::with_capacity(self.state.config().min_size()),
+        );
+        let offset = self.offset;
+        self.offset += data.len() as u64;
+        Chunk {
+            offset,
+            hash: H::digest(&data),
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::KeyInit;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::chunking::{AesGearConfig, StreamChunker};
+
+    proptest! {
+        #[test]
+        fn test_matches_stream_chunker(
+            a in prop::collection::vec(any::<u8>(), 0..=4096),
+            b in prop::collection::vec(any::<u8>(), 0..=4096),
+            splits in prop::collection::vec(any::<prop::sample::Index>(), 0..16),
+        ) {
+            let aes = aes::Aes128Enc::new_from_slice(&[0u8; 16]).unwrap();
+            let gear_config = AesGearConfig::new(aes);
+            let chunker_config = ChunkerConfig::new(gear_config, 128, 256, 1024, 3);
+
+            // Chunk two streams with the same chunker to check that `finish` resets it.
+            let mut chunker = Chunker::<blake3::Hasher>::new(&chunker_config);
+            for bytes in [&a, &b] {
+                let mut splits = splits
+                    .iter()
+                    .map(|it| it.index(bytes.len() + 1))
+                    .collect::<Vec<_>>();
+                splits.sort();
+                splits.push(bytes.len());
+
+                let mut chunks = Vec::new();
+                let mut start = 0;
+                for end in splits {
+                    chunks.extend(chunker.push(&bytes[start..end]));
+                    start = end;
+                }
+                chunks.extend(chunker.finish());
+
+                let expected = StreamChunker::new(&chunker_config, bytes.as_slice())
+                    .with_offsets::<blake3::Hasher>()
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap();
+                prop_assert_eq!(chunks.len(), expected.len());
+                for (chunk, expected) in chunks.iter().zip(&expected) {
+                    prop_assert_eq!(chunk.offset, expected.offset);
+                    prop_assert_eq!(&chunk.data, &expected.data);
+                    prop_assert_eq!(chunk.hash, expected.hash);
+                }
+            }
+        }
+    }
+}
@@ -1,12 +1,18 @@
 use std::io::{self, BufRead};
 
-use super::chunker_state::{ChunkerConfig, ChunkerState};
+use digest::{Digest, Output};
+
+use super::{
+    chunker_state::{ChunkerConfig, ChunkerState},
+    chunker_stats::ChunkerStats,
+};
 
 pub struct StreamChunker<'a, R> {
     reader: R,
     /// `true` if we reached end of stream or an error.
     ended: bool,
     state: ChunkerState<'a>,
+    stats: ChunkerStats,
 }
 
 impl<'a, R: BufRead> StreamChunker<'a, R> {
@@ -15,8 +21,31 @@ impl<'a, R: BufRead> StreamChunker<'a, R> {
             reader,
             ended: false,
             state: ChunkerState::new(config),
+            stats: ChunkerStats::new(),
+        }
+    }
+
+    /// Yield chunks together with their offsets in the stream and content hashes.
+    pub fn with_offsets<H: Digest>(self) -> ChunksWithOffsets<'a, R, H> {
+        ChunksWithOffsets {
+            chunker: self,
+            offset: 0,
+            _digest: std::marker::PhantomData,
         }
     }
+
+    /// Statistics of the chunks produced so far. These are accumulated across
+    /// [`reset`](Self::reset)s.
+    pub fn stats(&self) -> &ChunkerStats {
+        &self.stats
+    }
+
+    /// Start chunking a new stream from `reader`, reusing the chunker.
+    pub fn reset(&mut self, reader: R) {
+        self.reader = reader;
+        self.ended = false;
+        self.state.reset();
+    }
 }
 
 impl<'a, R: BufRead> Iterator for StreamChunker<'a, R> {
@@ -27,7 +56,9 @@ impl<'a, R: BufRead> Iterator for StreamChunker<'a, R> {
             return None;
         }
 
-        let mut data = Vec::new();
+        // Every chunk except the last one is at least min_size, so reserve that upfront instead of
+        // growing the buffer with every small read.
+        let mut data = Vec::with_capacity(self.state.config().min_size());
 
         loop {
             let buf = match self.reader.fill_buf() {
@@ -37,6 +68,7 @@ impl<'a, R: BufRead> Iterator for StreamChunker<'a, R> {
                     return if data.is_empty() {
                         None
                     } else {
+                        self.stats.record(data.len() as u64);
                         Some(Ok(data))
                     };
                 }
@@ -53,6 +85,7 @@ impl<'a, R: BufRead> Iterator for StreamChunker<'a, R> {
             self.reader.consume(consumed);
 
             if maybe_chunk_boundary.is_some() {
+                self.stats.record(data.len() as u64);
                 return Some(Ok(data));
             }
             // else loop read next chunk
@@ -60,6 +93,41 @@ impl<'a, R: BufRead> Iterator for StreamChunker<'a, R> {
     }
 }
 
+/// A chunk of the stream.
+pub struct Chunk<H: Digest> {
+    /// Offset of the chunk in the stream.
+    pub offset: u64,
+    pub data: Vec<u8>,
+    /// Hash of `data`.
+    pub hash: Output<H>,
+}
+
+/// Iterator over [`Chunk`]s, created by [`StreamChunker::with_offsets`].
+pub struct ChunksWithOffsets<'a, R, H> {
+    chunker: StreamChunker<'a, R>,
+    offset: u64,
+    _digest: std::marker::PhantomData<H>,
+}
+
+impl<'a, R: BufRead, H: Digest> Iterator for ChunksWithOffsets<'a, R, H> {
+    type Item = io::Result<Chunk<H>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = match self.chunker.next()? {
+            Ok(data) => data,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let offset = self.offset;
+        self.offset += data.len() as u64;
+        Some(Ok(Chunk {
+            offset,
+            hash: H::digest(&data),
+            data,
+        }))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::chunking::aes_gear::AesGearConfig;
@@ -82,7 +150,7 @@ mod tests {
 
             let chunks = stream_chunker.collect::<Result<Vec<_>, _>>().unwrap();
 
-            if chunks.len() >= 1 {
+            if !chunks.is_empty() {
                 for chunk in &chunks[..chunks.len() - 1] {
                     // All but last chunk should satisfy the min_size..=max_size condiition.
                     prop_assert!((MIN_SIZE..=MAX_SIZE).contains(&chunk.len()));
@@ -92,5 +160,104 @@ mod tests {
 
             prop_assert_eq!(chunks.concat(), bytes, "Chunks should reconstruct input bytes");
         }
+
+        #[test]
+        fn test_small_min_size(
+            bytes in prop::collection::vec(any::<u8>(), 0..=4096),
+            min_size in 1usize..64,
+        ) {
+            const AVG_SIZE: usize = 64;
+            const MAX_SIZE: usize = 256;
+
+            let aes = aes::Aes128Enc::new_from_slice(&[0u8; 16]).unwrap();
+            let gear_config = AesGearConfig::new(aes);
+            let chunker_config = ChunkerConfig::new(gear_config, min_size, AVG_SIZE, MAX_SIZE, 2);
+            let stream_chunker = StreamChunker::new(&chunker_config, bytes.as_ref());
+
+            let chunks = stream_chunker.collect::<Result<Vec<_>, _>>().unwrap();
+
+            if !chunks.is_empty() {
+                for chunk in &chunks[..chunks.len() - 1] {
+                    prop_assert!((min_size..=MAX_SIZE).contains(&chunk.len()));
+                }
+            }
+            prop_assert_eq!(chunks.concat(), bytes, "Chunks should reconstruct input bytes");
+        }
+
+        #[test]
+        fn test_offsets(bytes in prop::collection::vec(any::<u8>(), 0..=4096)) {
+            let aes = aes::Aes128Enc::new_from_slice(&[0u8; 16]).unwrap();
+            let gear_config = AesGearConfig::new(aes);
+            let chunker_config = ChunkerConfig::new(gear_config, 128, 256, 1024, 3);
+
+            let chunks = StreamChunker::new(&chunker_config, bytes.as_ref())
+                .with_offsets::<blake3::Hasher>()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+
+            for chunk in chunks {
+                let offset = chunk.offset as usize;
+                prop_assert_eq!(&chunk.data[..], &bytes[offset..offset + chunk.data.len()]);
+                prop_assert_eq!(chunk.hash, blake3::Hasher::digest(&chunk.data));
+            }
+        }
+
+        #[test]
+        fn test_small_reads(bytes in prop::collection::vec(any::<u8>(), 0..=4096)) {
+            let aes = aes::Aes128Enc::new_from_slice(&[0u8; 16]).unwrap();
+            let gear_config = AesGearConfig::new(aes);
+            let chunker_config = ChunkerConfig::new(gear_config, 128, 256, 1024, 3);
+
+            // Every boundary straddles many `fill_buf` calls.
+            let reader = std::io::BufReader::with_capacity(1, bytes.as_slice());
+            let chunks = StreamChunker::new(&chunker_config, reader)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+
+            let expected = StreamChunker::new(&chunker_config, bytes.as_ref())
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            prop_assert_eq!(chunks, expected);
+        }
+
+        #[test]
+        fn test_stats(bytes in prop::collection::vec(any::<u8>(), 0..=4096)) {
+            let aes = aes::Aes128Enc::new_from_slice(&[0u8; 16]).unwrap();
+            let gear_config = AesGearConfig::new(aes);
+            let chunker_config = ChunkerConfig::new(gear_config, 128, 256, 1024, 3);
+
+            let mut chunker = StreamChunker::new(&chunker_config, bytes.as_ref());
+            let chunks = chunker.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+            let stats = chunker.stats();
+
+            prop_assert_eq!(stats.count(), chunks.len() as u64);
+            prop_assert_eq!(stats.total_bytes(), bytes.len() as u64);
+            prop_assert_eq!(stats.histogram().iter().sum::<u64>(), chunks.len() as u64);
+            prop_assert_eq!(
+                stats.max_size(),
+                chunks.iter().map(|it| it.len() as u64).max()
+            );
+        }
+
+        #[test]
+        fn test_reset(
+            a in prop::collection::vec(any::<u8>(), 0..=4096),
+            b in prop::collection::vec(any::<u8>(), 0..=4096),
+        ) {
+            let aes = aes::Aes128Enc::new_from_slice(&[0u8; 16]).unwrap();
+            let gear_config = AesGearConfig::new(aes);
+            let chunker_config = ChunkerConfig::new(gear_config, 128, 256, 1024, 3);
+
+            // Stop in the middle of a chunk to make sure no state leaks into the next stream.
+            let mut chunker = StreamChunker::new(&chunker_config, a.as_ref());
+            chunker.next();
+            chunker.reset(b.as_ref());
+            let reused = chunker.collect::<Result<Vec<_>, _>>().unwrap();
+
+            let fresh = StreamChunker::new(&chunker_config, b.as_ref())
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            prop_assert_eq!(reused, fresh);
+        }
     }
 }
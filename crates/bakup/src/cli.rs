@@ -1,16 +1,137 @@
+use std::{
+    num::{NonZeroU64, NonZeroUsize},
+    ops::Range,
+};
+
 use camino::Utf8PathBuf;
 
+use crate::{
+    cas::Compression,
+    cat, check, config, copy, keys, list,
+    manifest::{ChunkSizes, ManifestFormat},
+    migrate, output, progress, prune, rebuild_index, restore, snapshot, stats,
+};
+
 #[derive(clap::Parser)]
-#[command(version)]
+#[command(version, after_long_help = IDENTITY_HELP)]
 pub struct Cli {
+    /// Log debug messages and timings of operations to stderr (repeat for more detail).
+    /// `RUST_LOG` overrides it.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Don't print messages about what the command did, and hide progress bars unless
+    /// `--progress always` is given. Requested output, warnings and errors are still printed.
+    ///
+    /// Exit status is 0 on success, 1 on failure, 2 on usage errors (including missing
+    /// repositories, keys and snapshots), 3 if something was skipped or a file kept changing while
+    /// being backed up (with a warning), and 4 if objects in the repository are missing or corrupt.
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+    /// When to show progress bars on stderr. `auto` shows them if stderr is a terminal.
+    #[arg(long, global = true, value_enum, default_value_t = ProgressMode::Auto)]
+    pub progress: ProgressMode,
     #[command(subcommand)]
     pub command: Command,
 }
 
+const IDENTITY_HELP: &str = "\
+Secret keys are read from the file given by `--identity`. Without it, the key is taken from the \
+environment: `BAKUP_IDENTITY_FILE` holds the path of the key file, `BAKUP_IDENTITY` the key \
+itself, and `BAKUP_IDENTITY_COMMAND` a shell command printing the key (e.g. to read it from the \
+OS keyring). If none of them is set and bakup is built with the `keyring` feature, the key is read \
+from the OS keyring entry of service `bakup` and user `identity`. Keys are never accepted as \
+arguments, as those are visible to other users.";
+
 #[derive(clap::Subcommand)]
 pub enum Command {
+    /// Create a new repository.
+    Init(Init),
     /// Backup one or more paths.
-    Snapshot(Snapshot),
+    Snapshot(Box<Snapshot>),
+    /// Restore files from a snapshot.
+    Restore(Restore),
+    /// Write a file from a snapshot to stdout.
+    Cat(Cat),
+    /// Copy a snapshot and the chunks it references to another repository.
+    Copy(Copy),
+    /// List snapshots in the repository.
+    #[command(visible_alias = "snapshots")]
+    List(List),
+    /// Check that all objects referenced by snapshots are present in the repository.
+    Check(Check),
+    /// Show how much space snapshots take and how well they are deduplicated.
+    Stats(Stats),
+    /// Manage encryption keys.
+    #[command(subcommand)]
+    Key(KeyCommand),
+    /// Remove snapshots from the repository. Their data is only deleted by `prune`.
+    Forget(Forget),
+    /// Delete objects that are not referenced by any snapshot.
+    ///
+    /// Pruning locks the repository and fails if a snapshot is being written.
+    Prune(Prune),
+    /// Rebuild the index of the repository from its packs, e.g. if index files are lost or
+    /// corrupted.
+    ///
+    /// Rebuilding locks the repository and fails if it is in use.
+    RebuildIndex(RebuildIndex),
+    /// Upgrade the repository to the latest format version.
+    ///
+    /// Migrating locks the repository and fails if it is in use. An interrupted migration can be
+    /// resumed by running it again.
+    Migrate(Migrate),
+}
+
+/// Run the command given on the command line.
+pub fn run(cli: Cli) -> anyhow::Result<()> {
+    output::init(cli.quiet);
+    progress::init(match cli.progress {
+        ProgressMode::Auto if cli.quiet => ProgressMode::Never,
+        mode => mode,
+    });
+    match cli.command {
+        Command::Init(cmd) => config::init(cmd)?,
+        Command::Snapshot(cmd) => snapshot::snapshot(*cmd)?,
+        Command::Restore(cmd) => restore::restore(cmd)?,
+        Command::Cat(cmd) => cat::cat(cmd)?,
+        Command::Copy(cmd) => copy::copy(cmd)?,
+        Command::List(cmd) => list::list(cmd)?,
+        Command::Check(cmd) => check::check(cmd)?,
+        Command::Stats(cmd) => stats::stats(cmd)?,
+        Command::Key(cmd) => keys::key(cmd)?,
+        Command::Forget(cmd) => prune::forget(cmd)?,
+        Command::Prune(cmd) => prune::prune(cmd)?,
+        Command::RebuildIndex(cmd) => rebuild_index::rebuild_index(cmd)?,
+        Command::Migrate(cmd) => migrate::migrate(cmd)?,
+    }
+
+    Ok(())
+}
+
+#[derive(clap::Args)]
+pub struct Init {
+    /// Path to the backup repository.
+    #[arg(short, long)]
+    pub remote: Utf8PathBuf,
+    /// Require objects to be encrypted. Snapshots then need `--identity` or `--recipient`.
+    #[arg(long)]
+    pub encrypted: bool,
+    /// Secret key file to seal the chunking key of an encrypted repository to.
+    #[arg(long, value_name = "FILE", requires = "encrypted")]
+    pub identity: Option<Utf8PathBuf>,
+    /// Also seal the chunking key to the public key (may be repeated).
+    #[arg(
+        long,
+        value_name = "PUBLIC_KEY",
+        value_parser = keys::parse_recipient,
+        requires = "encrypted"
+    )]
+    pub recipient: Vec<x25519_dalek::PublicKey>,
+    #[command(flatten)]
+    pub chunking: ChunkingArgs,
+    /// Target size of packs of objects (e.g. `16M`).
+    #[arg(long, value_name = "SIZE", value_parser = parse_size::<usize>)]
+    pub pack_size: Option<usize>,
 }
 
 #[derive(clap::Args)]
@@ -18,10 +139,578 @@ pub struct Snapshot {
     /// Snapshot name.
     #[arg(short, long)]
     pub name: Option<String>,
+    /// Tag the snapshot (may be repeated).
+    #[arg(long = "tag", value_name = "TAG", value_parser = parse_tag)]
+    pub tags: Vec<String>,
+    /// Free-form description of the snapshot.
+    #[arg(long, value_name = "TEXT")]
+    pub description: Option<String>,
     /// Path to save backup snapshot to.
     #[arg(short, long)]
     pub remote: Utf8PathBuf,
+    /// Secret key file to encrypt and sign new objects with, and to decrypt the parent snapshot.
+    #[arg(long, value_name = "FILE")]
+    pub identity: Option<Utf8PathBuf>,
+    /// Also encrypt new objects for the public key (may be repeated). Without `--identity`,
+    /// objects are signed with a one-time key.
+    #[arg(long, value_name = "PUBLIC_KEY", value_parser = keys::parse_recipient)]
+    pub recipient: Vec<x25519_dalek::PublicKey>,
+    /// Skip files and directories matching the glob pattern (may be repeated).
+    ///
+    /// Patterns without `/` match file names at any depth, patterns with `/` match absolute paths.
+    /// Excluding a directory skips its whole subtree.
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+    /// Read exclude patterns from a file, one per line.
+    #[arg(long, value_name = "FILE")]
+    pub exclude_from: Vec<Utf8PathBuf>,
+    /// Back up paths matching the glob pattern even if they match an exclude pattern (may be
+    /// repeated).
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+    /// Skip files larger than this size (e.g. `100M`).
+    #[arg(long, value_name = "SIZE", value_parser = parse_size::<u64>)]
+    pub exclude_larger_than: Option<u64>,
+    /// Skip directories marked as caches by a `CACHEDIR.TAG` file (see
+    /// <https://bford.info/cachedir/>), including the directory itself.
+    #[arg(long)]
+    pub exclude_caches: bool,
+    /// Don't descend into directories on a different file system than the backed up path (like
+    /// `tar --one-file-system`).
+    ///
+    /// Mount points themselves are still backed up as empty directories. Without
+    /// `--follow-symlinks`, symlinks are stored as is wherever they point to. Bind mounts of the
+    /// same file system share the device and are not detected as boundaries.
+    #[arg(short = 'x', long)]
+    pub one_file_system: bool,
+    /// Back up the files and directories that symlinks point to, instead of the symlinks.
+    ///
+    /// Symlinks are stored as is (with a warning) if they are dangling, point to a directory that
+    /// is already backed up (which breaks cycles), or are nested in more than 16 followed
+    /// symlinks. With `--one-file-system`, symlinks to directories on other file systems are not
+    /// followed either, while symlinks to files are.
+    #[arg(short = 'L', long)]
+    pub follow_symlinks: bool,
+    /// Back up the targets of the given paths that are symlinks, under the given paths, while
+    /// symlinks inside them are still stored as is.
+    ///
+    /// Without it (and without `--follow-symlinks`), a symlink given as a path to back up is
+    /// stored as a symlink, and its target is not backed up.
+    #[arg(long)]
+    pub dereference_root: bool,
+    /// Back up extended attributes (SELinux labels, file capabilities, etc.).
+    #[arg(long)]
+    pub xattrs: bool,
+    /// Snapshot ID (or unique ID prefix) or name to reuse unchanged files from. Defaults to the
+    /// latest snapshot with the same name.
+    ///
+    /// Files with the same path, size and modification time as in the parent are assumed to be
+    /// unchanged and are not read again.
+    #[arg(long, value_name = "SNAPSHOT")]
+    pub parent: Option<String>,
+    /// Read all files, even if they look unchanged since the parent snapshot.
+    #[arg(long, conflicts_with = "parent")]
+    pub force_rehash: bool,
+    /// Continue an interrupted snapshot of the same paths with the same name, skipping files
+    /// stored before the interruption unless they have changed since.
+    #[arg(long)]
+    pub resume: bool,
+    #[command(flatten)]
+    pub chunking: ChunkingArgs,
+    /// Use chunking parameters different from the previous snapshots. Unchanged files are then
+    /// chunked differently and are not deduplicated.
+    ///
+    /// Chunking parameters default to the ones of the parent snapshot, or of the newest snapshot
+    /// in the repository, or to the ones in the repository config.
+    #[arg(long)]
+    pub force_chunking: bool,
+    #[command(flatten)]
+    pub compression: CompressionArgs,
+    /// Number of files to read concurrently. Defaults to the number of CPUs.
+    ///
+    /// Every file is also stored by up to N (at most 4) threads, so lower values reduce the load on
+    /// the disk and on the repository backend.
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<NonZeroUsize>,
+    /// Keep at most SIZE bytes of chunks read from files in memory (e.g. `1G`), so that many jobs
+    /// don't run out of memory. Reading waits for chunks to be stored when the limit is reached.
+    ///
+    /// Chunks count towards the limit once they are cut, so every file being read may hold up to
+    /// the maximum chunk size on top of it. A chunk larger than the limit is still stored, but only
+    /// while nothing else is in memory.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size::<usize>)]
+    pub memory_limit: Option<usize>,
+    /// Limit writes to the repository to this many bytes per second (e.g. `5M`).
+    #[arg(long, value_name = "RATE", value_parser = parse_size::<NonZeroU64>)]
+    pub limit_upload: Option<NonZeroU64>,
+    /// After writing the snapshot, read back a random FRACTION (0 to 1, all chunks if omitted) of
+    /// the chunks it references and check that they match their hashes.
+    ///
+    /// Catches corruption by the repository backend at write time rather than at restore time.
+    /// The snapshot is kept, but the command fails if any chunk doesn't match.
+    #[arg(
+        long,
+        value_name = "FRACTION",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "1",
+        value_parser = parse_fraction,
+    )]
+    pub verify_after_write: Option<f64>,
+    /// Format to store the snapshot manifest in. Snapshots in any format can be read.
+    #[arg(long, value_enum, default_value_t = ManifestFormat::Json)]
+    pub manifest_format: ManifestFormat,
+    /// Output format. Progress is always reported on stderr.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+    /// Read paths to backup from a file (`-` for stdin), one per line. Directories are backed up
+    /// recursively, and paths inside other listed directories are only backed up once.
+    #[arg(long, value_name = "FILE")]
+    pub files_from: Option<Utf8PathBuf>,
+    /// Paths in `--files-from` are separated by NUL characters instead of newlines (like
+    /// `find -print0`).
+    #[arg(long, requires = "files_from")]
+    pub null: bool,
+    /// Fail if any of the paths to backup doesn't exist, or if any file or directory under them
+    /// can't be read, instead of reporting and skipping it. Skipped paths are counted in the
+    /// summary.
+    #[arg(long)]
+    pub strict: bool,
+    /// Skip files with names that are not valid UTF-8 (and symlinks pointing to such names),
+    /// instead of failing. Skipped paths are reported and counted in the summary.
+    #[arg(long)]
+    pub skip_invalid_paths: bool,
+    /// Walk the paths to compute their total size before backing them up, so that progress is
+    /// shown as a percentage with an ETA.
+    #[arg(long)]
+    pub scan: bool,
     /// Paths to backup.
-    #[arg(required = true)]
+    #[arg(required_unless_present = "files_from")]
     pub paths: Vec<Utf8PathBuf>,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(clap::Args, Default)]
+pub struct ChunkingArgs {
+    /// Minimum chunk size (e.g. `512K`).
+    #[arg(long, value_name = "SIZE", value_parser = parse_size::<usize>)]
+    pub chunk_min: Option<usize>,
+    /// Average chunk size, a power of 2 (e.g. `4M`).
+    #[arg(long, value_name = "SIZE", value_parser = parse_size::<usize>)]
+    pub chunk_avg: Option<usize>,
+    /// Maximum chunk size (e.g. `16M`).
+    #[arg(long, value_name = "SIZE", value_parser = parse_size::<usize>)]
+    pub chunk_max: Option<usize>,
+    /// Number of bits to normalize chunk sizes by, making them closer to the average.
+    #[arg(long, value_name = "BITS")]
+    pub chunk_normalization: Option<u32>,
+}
+
+impl ChunkingArgs {
+    /// `base` with the sizes given on the command line replaced.
+    pub fn apply(&self, base: ChunkSizes) -> ChunkSizes {
+        ChunkSizes {
+            min_size: self.chunk_min.unwrap_or(base.min_size),
+            avg_size: self.chunk_avg.unwrap_or(base.avg_size),
+            max_size: self.chunk_max.unwrap_or(base.max_size),
+            normalization_bits: self.chunk_normalization.unwrap_or(base.normalization_bits),
+        }
+    }
+}
+
+/// Tags are listed comma-separated, so they can't contain commas.
+fn parse_tag(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains(',') {
+        return Err(format!(
+            "invalid tag {s:?}: tags must be non-empty and contain no commas"
+        ));
+    }
+    Ok(s.to_owned())
+}
+
+/// Parse a size in bytes with an optional binary suffix, e.g. `4096`, `512K`, `4M` or `1GiB`.
+pub fn parse_size<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let lower = s.trim().to_ascii_lowercase();
+    let number = lower
+        .strip_suffix("ib")
+        .or_else(|| lower.strip_suffix('b'))
+        .unwrap_or(&lower);
+    let (number, shift) = match number.char_indices().last() {
+        Some((i, 'k')) => (&number[..i], 10),
+        Some((i, 'm')) => (&number[..i], 20),
+        Some((i, 'g')) => (&number[..i], 30),
+        Some((i, 't')) => (&number[..i], 40),
+        _ => (number, 0),
+    };
+    let invalid = || format!("invalid size {s:?}");
+    let value = number.parse::<u64>().map_err(|_| invalid())?;
+    let value = value.checked_mul(1 << shift).ok_or_else(invalid)?;
+    T::try_from(value).map_err(|_| invalid())
+}
+
+/// Parse a fraction in `0..=1`, e.g. `0.1`.
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!(
+            "invalid fraction {s:?}: expected a number from 0 to 1"
+        )),
+    }
+}
+
+/// Parse a byte range `START..END`, where either bound may be omitted and both are sizes accepted
+/// by [`parse_size`].
+fn parse_range(s: &str) -> Result<Range<u64>, String> {
+    let Some((start, end)) = s.split_once("..") else {
+        return Err(format!("invalid range {s:?}: expected START..END"));
+    };
+    let start = match start {
+        "" => 0,
+        start => parse_size(start)?,
+    };
+    let end = match end {
+        "" => u64::MAX,
+        end => parse_size(end)?,
+    };
+    if start > end {
+        return Err(format!("invalid range {s:?}: start is after end"));
+    }
+    Ok(start..end)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressMode {
+    Auto,
+    /// Show progress bars even if stderr is not a terminal.
+    Always,
+    /// Never show progress bars, e.g. when running from cron. Warnings, errors and summaries are
+    /// still printed.
+    Never,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    /// A single JSON object with the snapshot ID and statistics.
+    Json,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum CompressionType {
+    None,
+    Zstd,
+}
+
+#[derive(clap::Args)]
+pub struct Restore {
+    /// Path to the backup repository.
+    #[arg(short, long)]
+    pub remote: Utf8PathBuf,
+    /// Secret key file to decrypt repository objects with.
+    #[arg(long, value_name = "FILE")]
+    pub identity: Option<Utf8PathBuf>,
+    /// Snapshot ID, unique ID prefix (at least 4 hex digits) or name. If multiple snapshots have
+    /// the same name, the latest one is restored. Names take precedence over ID prefixes.
+    pub snapshot: String,
+    /// Directory to restore files into.
+    pub target: Utf8PathBuf,
+    /// Restore into a non-empty directory, overwriting existing files.
+    #[arg(long)]
+    pub force: bool,
+    /// Restore extended attributes stored in the snapshot.
+    #[arg(long)]
+    pub xattrs: bool,
+    /// Number of chunks of a file to fetch from the repository concurrently. Chunks are still
+    /// written in order, so at most N fetched chunks are kept in memory.
+    #[arg(long, value_name = "N", default_value = "4")]
+    pub read_concurrency: NonZeroUsize,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(clap::Args)]
+pub struct Cat {
+    /// Path to the backup repository.
+    #[arg(short, long)]
+    pub remote: Utf8PathBuf,
+    /// Secret key file to decrypt repository objects with.
+    #[arg(long, value_name = "FILE")]
+    pub identity: Option<Utf8PathBuf>,
+    /// Snapshot ID, unique ID prefix (at least 4 hex digits) or name. If multiple snapshots have
+    /// the same name, the latest one is used. Names take precedence over ID prefixes.
+    pub snapshot: String,
+    /// Path of the file in the snapshot. Relative paths are resolved against the current
+    /// directory, like paths given to `snapshot`.
+    pub path: Utf8PathBuf,
+    /// Don't check that every chunk matches its hash before writing it, e.g. to get whatever data
+    /// is left in a damaged repository.
+    #[arg(long)]
+    pub no_verify: bool,
+    /// Write only the given byte range of the file, e.g. `4096..8192`, `1M..` or `..512K`.
+    /// Only the chunks covering the range are fetched.
+    #[arg(long, value_name = "START..END", value_parser = parse_range)]
+    pub range: Option<Range<u64>>,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(clap::Args)]
+pub struct Copy {
+    /// Path to the repository to copy the snapshot from.
+    #[arg(long)]
+    pub from: Utf8PathBuf,
+    /// Secret key file to decrypt objects of the source repository with.
+    #[arg(long, value_name = "FILE")]
+    pub from_identity: Option<Utf8PathBuf>,
+    /// Path to the repository to copy the snapshot to.
+    #[arg(long)]
+    pub to: Utf8PathBuf,
+    /// Secret key file to encrypt and sign copied objects with.
+    #[arg(long, value_name = "FILE")]
+    pub to_identity: Option<Utf8PathBuf>,
+    /// Also encrypt copied objects for the public key (may be repeated). Without
+    /// `--to-identity`, objects are signed with a one-time key.
+    #[arg(long, value_name = "PUBLIC_KEY", value_parser = keys::parse_recipient)]
+    pub recipient: Vec<x25519_dalek::PublicKey>,
+    #[command(flatten)]
+    pub compression: CompressionArgs,
+    /// Snapshot ID, unique ID prefix (at least 4 hex digits) or name. If multiple snapshots have
+    /// the same name, the latest one is copied. Names take precedence over ID prefixes.
+    pub snapshot: String,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(clap::Args)]
+pub struct List {
+    /// Path to the backup repository.
+    #[arg(short, long)]
+    pub remote: Utf8PathBuf,
+    /// Secret key file to decrypt repository objects with.
+    #[arg(long, value_name = "FILE")]
+    pub identity: Option<Utf8PathBuf>,
+    /// Only list snapshots with the tag (may be repeated to require multiple tags).
+    #[arg(long = "tag", value_name = "TAG", value_parser = parse_tag)]
+    pub tags: Vec<String>,
+    /// Print snapshots as JSON.
+    #[arg(long)]
+    pub json: bool,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(clap::Args)]
+pub struct Check {
+    /// Path to the backup repository.
+    #[arg(short, long)]
+    pub remote: Utf8PathBuf,
+    /// Secret key file to decrypt repository objects with.
+    #[arg(long, value_name = "FILE")]
+    pub identity: Option<Utf8PathBuf>,
+    /// Also read all objects and verify that their content matches their hash.
+    #[arg(long)]
+    pub read_data: bool,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(clap::Args)]
+pub struct Stats {
+    /// Path to the backup repository.
+    #[arg(short, long)]
+    pub remote: Utf8PathBuf,
+    /// Secret key file to decrypt repository objects with.
+    #[arg(long, value_name = "FILE")]
+    pub identity: Option<Utf8PathBuf>,
+    /// Number of the largest files and the most referenced chunks to show.
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub top: usize,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(clap::Args)]
+pub struct Forget {
+    /// Path to the backup repository.
+    #[arg(short, long)]
+    pub remote: Utf8PathBuf,
+    /// Secret key file to decrypt repository objects with.
+    #[arg(long, value_name = "FILE")]
+    pub identity: Option<Utf8PathBuf>,
+    /// Snapshot IDs, unique ID prefixes or names. If multiple snapshots have the same name, the
+    /// latest one is forgotten.
+    #[arg(required_unless_present = "policy")]
+    pub snapshots: Vec<String>,
+    #[command(flatten)]
+    pub keep: KeepArgs,
+    /// Only print which snapshots would be forgotten.
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Prune the repository after forgetting snapshots.
+    #[arg(long)]
+    pub prune: bool,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+/// Retention policy, forgetting all snapshots it doesn't keep. Snapshots are grouped by name, and
+/// the policy applies to every group separately.
+///
+/// Days, weeks (ISO weeks starting on Monday), months and years are in the local time zone.
+#[derive(clap::Args)]
+#[group(id = "policy", multiple = true, conflicts_with = "snapshots")]
+pub struct KeepArgs {
+    /// Keep the N newest snapshots.
+    #[arg(long, value_name = "N")]
+    pub keep_last: Option<usize>,
+    /// Keep the newest snapshot of each of the N latest hours with snapshots.
+    #[arg(long, value_name = "N")]
+    pub keep_hourly: Option<usize>,
+    /// Keep the newest snapshot of each of the N latest days with snapshots.
+    #[arg(long, value_name = "N")]
+    pub keep_daily: Option<usize>,
+    /// Keep the newest snapshot of each of the N latest weeks with snapshots.
+    #[arg(long, value_name = "N")]
+    pub keep_weekly: Option<usize>,
+    /// Keep the newest snapshot of each of the N latest months with snapshots.
+    #[arg(long, value_name = "N")]
+    pub keep_monthly: Option<usize>,
+    /// Keep the newest snapshot of each of the N latest years with snapshots.
+    #[arg(long, value_name = "N")]
+    pub keep_yearly: Option<usize>,
+    /// Keep all snapshots with the tag (may be repeated).
+    #[arg(long, value_name = "TAG")]
+    pub keep_tag: Vec<String>,
+}
+
+#[derive(clap::Args)]
+pub struct CompressionArgs {
+    /// Compression of stored chunks.
+    #[arg(long, value_enum, default_value_t = CompressionType::Zstd)]
+    pub compression: CompressionType,
+    /// zstd compression level (1-22, or negative for faster compression).
+    #[arg(long, default_value_t = 3, allow_negative_numbers = true)]
+    pub compression_level: i32,
+}
+
+impl CompressionArgs {
+    pub fn compression(&self) -> Compression {
+        match self.compression {
+            CompressionType::None => Compression::None,
+            CompressionType::Zstd => Compression::Zstd {
+                level: self.compression_level,
+            },
+        }
+    }
+}
+
+#[derive(clap::Args)]
+pub struct LockArgs {
+    /// Remove locks of other processes that conflict with this command instead of failing, e.g.
+    /// locks left behind by crashed processes or by processes on other hosts.
+    ///
+    /// Make sure the processes are not running anymore: pruning while a snapshot is written may
+    /// delete its data.
+    #[arg(long)]
+    pub break_lock: bool,
+}
+
+#[derive(clap::Args)]
+pub struct Prune {
+    /// Path to the backup repository.
+    #[arg(short, long)]
+    pub remote: Utf8PathBuf,
+    /// Secret key file to decrypt repository objects with.
+    #[arg(long, value_name = "FILE")]
+    pub identity: Option<Utf8PathBuf>,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(clap::Args)]
+pub struct RebuildIndex {
+    /// Path to the backup repository.
+    #[arg(short, long)]
+    pub remote: Utf8PathBuf,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(clap::Args)]
+pub struct Migrate {
+    /// Path to the backup repository.
+    #[arg(short, long)]
+    pub remote: Utf8PathBuf,
+    /// Only show the migration steps that would be applied.
+    #[arg(long)]
+    pub dry_run: bool,
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(clap::Subcommand)]
+pub enum KeyCommand {
+    /// Generate a new secret key and print its public key.
+    Generate(KeyGenerate),
+    /// Print the public key of a secret key.
+    Pubkey(KeyPubkey),
+}
+
+#[derive(clap::Args)]
+pub struct KeyGenerate {
+    /// File to write the new secret key to. It must not exist.
+    pub output: Utf8PathBuf,
+}
+
+#[derive(clap::Args)]
+pub struct KeyPubkey {
+    /// Secret key file.
+    pub secret_file: Utf8PathBuf,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size::<u64>("4096"), Ok(4096));
+        assert_eq!(parse_size::<u64>("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size::<u64>("4m"), Ok(4 * 1024 * 1024));
+        assert_eq!(parse_size::<u64>("1GiB"), Ok(1024 * 1024 * 1024));
+        assert_eq!(parse_size::<u64>("10B"), Ok(10));
+
+        assert!(parse_size::<u64>("").is_err());
+        assert!(parse_size::<u64>("M").is_err());
+        assert!(parse_size::<u64>("1.5M").is_err());
+        assert!(parse_size::<u64>("-1").is_err());
+        assert!(parse_size::<u64>("16777216T").is_err());
+        assert!(parse_size::<u32>("4G").is_err());
+    }
+
+    #[test]
+    fn test_parse_fraction() {
+        assert_eq!(parse_fraction("0.1"), Ok(0.1));
+        assert_eq!(parse_fraction("1"), Ok(1.0));
+        assert_eq!(parse_fraction("0"), Ok(0.0));
+
+        assert!(parse_fraction("1.5").is_err());
+        assert!(parse_fraction("-0.1").is_err());
+        assert!(parse_fraction("NaN").is_err());
+        assert!(parse_fraction("all").is_err());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("4096..8192"), Ok(4096..8192));
+        assert_eq!(parse_range("1M.."), Ok(1024 * 1024..u64::MAX));
+        assert_eq!(parse_range("..512K"), Ok(0..512 * 1024));
+        assert_eq!(parse_range(".."), Ok(0..u64::MAX));
+        assert_eq!(parse_range("5..5"), Ok(5..5));
+
+        assert!(parse_range("4096").is_err());
+        assert!(parse_range("8..4").is_err());
+        assert!(parse_range("a..b").is_err());
+    }
 }
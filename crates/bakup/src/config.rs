@@ -0,0 +1,315 @@
+//! Repository config, describing the format of the repository and how new snapshots are written.
+//!
+//! The config is a JSON file `config` at the root of the repository, written by `bakup init`.
+
+use std::io::{Read, Write};
+
+use anyhow::{Context, bail};
+use bakpak::{Decryptor, Encryptor};
+use camino::Utf8Path;
+use ed25519_dalek::SigningKey;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_with::{base64::Base64, hex::Hex, serde_as};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    cas::DEFAULT_PACK_SIZE,
+    cli,
+    keys::Key,
+    manifest::{ChunkSizes, ChunkerParams},
+    output::say,
+    repo::{INDEX_DIR, PACKS_DIR},
+    snapshots::SNAPSHOTS_DIR,
+    status::UsageError,
+};
+
+pub const CONFIG_FILE: &str = "config";
+
+/// Latest version of the repository format, written to new repositories.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Hash function objects are addressed by.
+pub const HASH: &str = "blake3";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    pub version: u32,
+    pub hash: String,
+    /// Chunk sizes of the first snapshot. Later snapshots inherit chunking parameters from the
+    /// previous ones.
+    pub chunker: ChunkSizes,
+    /// Whether objects must be encrypted, so that they are never written in plaintext.
+    pub encrypted: bool,
+    #[serde(default = "default_pack_size")]
+    pub pack_size: usize,
+    /// Gear key of the first snapshot, which later ones inherit. Repositories initialized before
+    /// `init` generated it don't have one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunker_key: Option<ChunkerKey>,
+}
+
+/// Gear key of the chunker, in plaintext, or sealed with bakpak if the repository is encrypted.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkerKey {
+    Plain(#[serde_as(as = "Hex")] [u8; 16]),
+    Sealed(#[serde_as(as = "Base64")] Vec<u8>),
+}
+
+impl ChunkerKey {
+    /// Seal `key` from `sender` to `recipients`.
+    fn seal(key: &[u8; 16], sender: &SigningKey, recipients: &[PublicKey]) -> anyhow::Result<Self> {
+        let mut writer = Encryptor::new(sender, recipients)?.wrap_output(Vec::new())?;
+        writer.write_all(key)?;
+        Ok(ChunkerKey::Sealed(writer.finish()?))
+    }
+
+    /// The key, unsealed with `identity` if needed. Returns `None` if the key is sealed and there
+    /// is no identity.
+    pub fn open(&self, identity: Option<&StaticSecret>) -> anyhow::Result<Option<[u8; 16]>> {
+        let sealed = match self {
+            ChunkerKey::Plain(key) => return Ok(Some(*key)),
+            ChunkerKey::Sealed(sealed) => sealed,
+        };
+        let Some(identity) = identity else {
+            return Ok(None);
+        };
+        let mut key = Vec::new();
+        Decryptor::new(identity)
+            .wrap_input(&sealed[..])?
+            .read_to_end(&mut key)?;
+        let key = key
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("malformed chunking key"))?;
+        Ok(Some(key))
+    }
+}
+
+fn default_pack_size() -> usize {
+    DEFAULT_PACK_SIZE
+}
+
+impl Config {
+    /// Config of repositories created implicitly by the first snapshot, before `init` existed.
+    /// They are version 0, as their objects are not packed.
+    fn legacy() -> Self {
+        Config {
+            version: 0,
+            hash: HASH.to_owned(),
+            chunker: ChunkSizes::DEFAULT,
+            encrypted: false,
+            pack_size: DEFAULT_PACK_SIZE,
+            chunker_key: None,
+        }
+    }
+
+    /// Load config of the repository at `remote`.
+    pub fn load(remote: &Utf8Path) -> anyhow::Result<Self> {
+        let path = remote.join(CONFIG_FILE);
+        let config: Config = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .with_context(|| format!("malformed repository config {path}"))?,
+            Err(err)
+                if err.kind() == std::io::ErrorKind::NotFound
+                    && remote.join(SNAPSHOTS_DIR).is_dir() =>
+            {
+                Self::legacy()
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                bail!(UsageError(format!(
+                    "{remote} is not a repository (create one with `bakup init`)"
+                )))
+            }
+            Err(err) => return Err(err).with_context(|| format!("failed to read {path}")),
+        };
+
+        if config.version > FORMAT_VERSION {
+            bail!(UsageError(format!(
+                "repository {remote} has format version {}, but only versions up to \
+                 {FORMAT_VERSION} are supported",
+                config.version
+            )));
+        }
+        if config.hash != HASH {
+            bail!(UsageError(format!(
+                "repository {remote} uses unsupported hash {:?}",
+                config.hash
+            )));
+        }
+        Ok(config)
+    }
+
+    /// Replace the config of the repository at `remote` with this one. The config is replaced
+    /// atomically, so readers see either the old or the new one.
+    pub fn save(&self, remote: &Utf8Path) -> anyhow::Result<()> {
+        let path = remote.join(CONFIG_FILE);
+        let mut temp = tempfile::Builder::new()
+            .prefix(".tmp")
+            .tempfile_in(remote)
+            .with_context(|| format!("failed to write {path}"))?;
+        serde_json::to_writer_pretty(&mut temp, self)?;
+        writeln!(temp)?;
+        temp.as_file().sync_all()?;
+        temp.persist(&path)
+            .with_context(|| format!("failed to write {path}"))?;
+        Ok(())
+    }
+}
+
+pub fn init(cmd: cli::Init) -> anyhow::Result<()> {
+    let remote = &cmd.remote;
+    let mut key = [0u8; 16];
+    OsRng.fill_bytes(&mut key);
+    let chunker_key = if cmd.encrypted {
+        let owner = Key::resolve(cmd.identity.as_deref())?;
+        let mut recipients = cmd.recipient.clone();
+        recipients.extend(owner.as_ref().map(Key::recipient));
+        if recipients.is_empty() {
+            bail!(UsageError(
+                "an encrypted repository needs `--identity` or `--recipient` to seal its chunking \
+                 key to"
+                    .to_owned()
+            ));
+        }
+        let sender = match &owner {
+            Some(owner) => owner.signing_key.clone(),
+            None => SigningKey::generate(&mut OsRng),
+        };
+        ChunkerKey::seal(&key, &sender, &recipients)?
+    } else {
+        ChunkerKey::Plain(key)
+    };
+    let config = Config {
+        version: FORMAT_VERSION,
+        hash: HASH.to_owned(),
+        chunker: cmd.chunking.apply(ChunkSizes::DEFAULT),
+        encrypted: cmd.encrypted,
+        pack_size: cmd.pack_size.unwrap_or(DEFAULT_PACK_SIZE),
+        chunker_key: Some(chunker_key),
+    };
+    // Reject invalid sizes now rather than on the first snapshot.
+    ChunkerParams {
+        sizes: config.chunker,
+        key,
+    }
+    .config()
+    .context("invalid chunking parameters")?;
+
+    std::fs::create_dir_all(remote).with_context(|| format!("failed to create {remote}"))?;
+    if remote.read_dir_utf8()?.next().is_some() {
+        bail!("refusing to initialize repository in non-empty directory {remote}");
+    }
+
+    let path = remote.join(CONFIG_FILE);
+    let mut file =
+        std::fs::File::create_new(&path).with_context(|| format!("failed to create {path}"))?;
+    serde_json::to_writer_pretty(&mut file, &config)?;
+    writeln!(file)?;
+    file.sync_all()?;
+    for dir in [SNAPSHOTS_DIR, PACKS_DIR, INDEX_DIR] {
+        std::fs::create_dir(remote.join(dir))?;
+    }
+
+    say!("initialized repository {remote}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+
+    use super::*;
+
+    fn init_args(remote: &Utf8Path, key: &Key) -> cli::Init {
+        cli::Init {
+            remote: remote.to_owned(),
+            encrypted: true,
+            identity: None,
+            recipient: vec![key.recipient()],
+            chunking: cli::ChunkingArgs {
+                chunk_min: None,
+                chunk_avg: Some(1024 * 1024),
+                chunk_max: None,
+                chunk_normalization: None,
+            },
+            pack_size: None,
+        }
+    }
+
+    #[test]
+    fn test_init_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = Utf8PathBuf::try_from(dir.path().join("repo")).unwrap();
+
+        let key = Key::generate();
+        init(init_args(&remote, &key)).unwrap();
+        let config = Config::load(&remote).unwrap();
+        assert_eq!(config.version, FORMAT_VERSION);
+        assert!(config.encrypted);
+        assert_eq!(config.chunker.avg_size, 1024 * 1024);
+        assert_eq!(config.chunker.max_size, ChunkSizes::DEFAULT.max_size);
+        assert_eq!(config.pack_size, DEFAULT_PACK_SIZE);
+        assert!(remote.join(SNAPSHOTS_DIR).is_dir());
+
+        // The chunking key is only readable with the key it is sealed to.
+        let chunker_key = config.chunker_key.unwrap();
+        assert!(matches!(chunker_key, ChunkerKey::Sealed(_)));
+        assert!(chunker_key.open(Some(&key.identity)).unwrap().is_some());
+        assert_eq!(chunker_key.open(None).unwrap(), None);
+        assert!(chunker_key.open(Some(&Key::generate().identity)).is_err());
+
+        // The repository is not empty anymore.
+        assert!(init(init_args(&remote, &key)).is_err());
+    }
+
+    #[test]
+    fn test_init_chunker_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+
+        // Nothing to seal the key to.
+        let args = cli::Init {
+            recipient: Vec::new(),
+            ..init_args(&dir.join("encrypted"), &Key::generate())
+        };
+        assert!(init(args).is_err());
+
+        let args = |remote: &str| cli::Init {
+            encrypted: false,
+            recipient: Vec::new(),
+            ..init_args(&dir.join(remote), &Key::generate())
+        };
+        init(args("a")).unwrap();
+        init(args("b")).unwrap();
+        let key = |remote: &str| match Config::load(&dir.join(remote)).unwrap().chunker_key {
+            Some(ChunkerKey::Plain(key)) => key,
+            other => panic!("unexpected chunker key {other:?}"),
+        };
+        // Random for every repository.
+        assert_ne!(key("a"), key("b"));
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = Utf8Path::from_path(dir.path()).unwrap();
+
+        assert!(Config::load(remote).is_err());
+
+        std::fs::create_dir(remote.join(SNAPSHOTS_DIR)).unwrap();
+        assert_eq!(Config::load(remote).unwrap(), Config::legacy());
+
+        let future = Config {
+            version: FORMAT_VERSION + 1,
+            ..Config::legacy()
+        };
+        std::fs::write(
+            remote.join(CONFIG_FILE),
+            serde_json::to_vec(&future).unwrap(),
+        )
+        .unwrap();
+        assert!(Config::load(remote).is_err());
+    }
+}
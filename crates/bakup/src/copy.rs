@@ -0,0 +1,153 @@
+use std::collections::BTreeSet;
+
+use anyhow::{anyhow, bail};
+use const_hex::ToHexExt;
+use digest::Output;
+use indicatif::HumanBytes;
+use rayon::prelude::*;
+
+use crate::{
+    cas::ContentAddressableStorage,
+    cli,
+    config::Config,
+    lock::RepoLock,
+    manifest::EntryType,
+    output::say,
+    progress,
+    repo::{self, Repository},
+    snapshots,
+    status::IntegrityError,
+};
+
+/// Copy a snapshot from one repository to another, transferring only the chunks the destination
+/// doesn't have yet.
+///
+/// Objects are decrypted with the key of the source and encrypted again for the destination, so
+/// the repositories may use different keys. Object hashes don't depend on encryption, so the
+/// snapshot keeps its ID.
+pub fn copy(cmd: cli::Copy) -> anyhow::Result<()> {
+    // Keep `prune` from removing the chunks from the source while they are copied, and from the
+    // destination before the snapshot references them. The locks are taken before opening, so the
+    // loaded pack indexes can't be changed by a `prune` in between.
+    let _source_lock = RepoLock::shared(&cmd.from, &cmd.lock)?;
+    let _lock = RepoLock::shared(&cmd.to, &cmd.lock)?;
+
+    let source = repo::open(&cmd.from, cmd.from_identity.as_deref())?;
+    let config = Config::load(&cmd.to)?;
+    let destination = repo::open_for_writing(
+        &cmd.to,
+        &config,
+        cmd.to_identity.as_deref(),
+        &cmd.recipient,
+        cmd.compression.compression(),
+        None,
+    )?;
+
+    let (id, manifest) = snapshots::resolve(&cmd.from, &source, &cmd.snapshot)?;
+    if snapshots::list(&cmd.to)?.contains(&id) {
+        say!("snapshot {} is already in {}", id.encode_hex(), cmd.to);
+        return Ok(());
+    }
+
+    let chunks = manifest
+        .entries
+        .into_iter()
+        .filter_map(|entry| match entry.ty {
+            EntryType::File { content, .. } => Some(content),
+            _ => None,
+        })
+        .flatten()
+        .collect::<BTreeSet<_>>();
+
+    let progress = progress::bar(chunks.len() as u64, "{wide_bar} {pos}/{len} chunks");
+    let copied = chunks
+        .par_iter()
+        .map(|hash| {
+            let copied = copy_object(&source, &destination, hash)?;
+            progress.inc(1);
+            Ok(copied)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    progress.finish_and_clear();
+
+    // The manifest is copied as is, so it keeps its format.
+    copy_object(&source, &destination, &id)?;
+    repo::packed(&destination).flush()?;
+    snapshots::write_ref(&cmd.to, id)?;
+
+    let counter = repo::counter(&destination);
+    say!("snapshot: {}", id.encode_hex());
+    say!(
+        "{} of {} chunks copied, {} stored",
+        copied.iter().filter(|&&it| it).count(),
+        chunks.len(),
+        HumanBytes(counter.new_bytes()),
+    );
+    Ok(())
+}
+
+/// Copy the object `hash` from `source` to `destination` unless it's already there, returning
+/// whether it was copied.
+fn copy_object(
+    source: &Repository,
+    destination: &Repository,
+    hash: &Output<blake3::Hasher>,
+) -> anyhow::Result<bool> {
+    if destination.contains(hash)? {
+        return Ok(false);
+    }
+    let bytes = source.get(*hash)?.ok_or_else(|| {
+        anyhow!(IntegrityError(format!(
+            "object {} is missing from the source",
+            hash.encode_hex()
+        )))
+    })?;
+    if destination.hash(&bytes) != *hash {
+        bail!(IntegrityError(format!(
+            "object {} is corrupt in the source",
+            hash.encode_hex()
+        )));
+    }
+    destination.store(bytes)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use camino::Utf8Path;
+
+    use super::*;
+    use crate::cas::Compression;
+
+    fn open(dir: &tempfile::TempDir) -> Repository {
+        let remote = Utf8Path::from_path(dir.path()).unwrap();
+        std::fs::create_dir(remote.join(snapshots::SNAPSHOTS_DIR)).unwrap();
+        let config = Config::load(remote).unwrap();
+        repo::open_for_writing(remote, &config, None, &[], Compression::None, None).unwrap()
+    }
+
+    #[test]
+    fn test_copy_object() {
+        let (source_dir, destination_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (source, destination) = (open(&source_dir), open(&destination_dir));
+        let hash = source.store(Bytes::from_static(b"chunk")).unwrap();
+
+        assert!(copy_object(&source, &destination, &hash).unwrap());
+        assert_eq!(
+            destination.get(hash).unwrap().as_deref(),
+            Some(&b"chunk"[..])
+        );
+        assert!(!copy_object(&source, &destination, &hash).unwrap());
+
+        let missing = source.hash(b"missing");
+        assert!(copy_object(&source, &destination, &missing).is_err());
+
+        // Nothing is stored for an object that doesn't match its hash.
+        let corrupt = source.hash(b"corrupt");
+        source.put(&corrupt, Bytes::from_static(b"other")).unwrap();
+        assert!(copy_object(&source, &destination, &corrupt).is_err());
+        assert!(!destination.contains(&source.hash(b"other")).unwrap());
+    }
+}
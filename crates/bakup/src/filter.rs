@@ -0,0 +1,98 @@
+use std::path::Path;
+
+use anyhow::Context;
+use camino::Utf8Path;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Decides which paths are included in a snapshot.
+///
+/// A path is skipped if it matches any exclude pattern and no include pattern, so includes take
+/// precedence and can re-include paths that an exclude pattern would otherwise skip (e.g.,
+/// `--exclude '*.log' --include 'important.log'`). Excluding a directory prunes its whole
+/// subtree, so files inside an excluded directory can't be re-included.
+///
+/// Patterns without a `/` match the file name at any depth (`target` matches every `target`
+/// directory). Patterns with a `/` match the whole absolute path (`/home/*/.cache`).
+pub struct PathFilter {
+    exclude: GlobSet,
+    include: GlobSet,
+}
+
+impl PathFilter {
+    pub fn new(exclude: &[String], include: &[String]) -> anyhow::Result<Self> {
+        Ok(PathFilter {
+            exclude: build_glob_set(exclude)?,
+            include: build_glob_set(include)?,
+        })
+    }
+
+    pub fn is_included(&self, path: &Path) -> bool {
+        !self.exclude.is_match(path) || self.include.is_match(path)
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.trim_end_matches('/');
+        let full_pattern = if pattern.contains('/') {
+            pattern.to_owned()
+        } else {
+            format!("**/{pattern}")
+        };
+        let glob = GlobBuilder::new(&full_pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("invalid glob pattern {pattern:?}"))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+/// Read patterns from a file with one pattern per line. Empty lines and lines starting with `#`
+/// are ignored.
+pub fn read_patterns(path: &Utf8Path) -> anyhow::Result<Vec<String>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(exclude: &[&str], include: &[&str]) -> PathFilter {
+        let exclude = exclude.iter().map(|it| it.to_string()).collect::<Vec<_>>();
+        let include = include.iter().map(|it| it.to_string()).collect::<Vec<_>>();
+        PathFilter::new(&exclude, &include).unwrap()
+    }
+
+    #[test]
+    fn test_name_patterns() {
+        let filter = filter(&["target/", ".git", "*.log"], &[]);
+        assert!(!filter.is_included(Path::new("/src/project/target")));
+        assert!(!filter.is_included(Path::new("/src/project/.git")));
+        assert!(!filter.is_included(Path::new("/var/app.log")));
+        assert!(filter.is_included(Path::new("/src/project/src/target.rs")));
+        assert!(filter.is_included(Path::new("/src/project")));
+    }
+
+    #[test]
+    fn test_path_patterns() {
+        let filter = filter(&["/home/*/.cache"], &[]);
+        assert!(!filter.is_included(Path::new("/home/user/.cache")));
+        assert!(filter.is_included(Path::new("/home/user/project/.cache")));
+    }
+
+    #[test]
+    fn test_include_overrides_exclude() {
+        let filter = filter(&["*.log"], &["important.log"]);
+        assert!(!filter.is_included(Path::new("/var/app.log")));
+        assert!(filter.is_included(Path::new("/var/important.log")));
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    io::{self, ErrorKind, Read},
+    marker::PhantomData,
+};
+
+use bytes::Bytes;
+use digest::{Digest, Output, typenum::Unsigned};
+
+use super::index_writer::{FANOUT_SIZE, IndexEntry};
+use crate::cas::ContentAddressableStorage;
+
+/// Reader for the index format produced by [`IndexWriter`](super::IndexWriter).
+///
+/// The reader operates directly on the serialized bytes, so lookups don't require parsing the
+/// whole index upfront. `B` can be an owned buffer (see [`IndexReader::read`]) or a memory-mapped
+/// file (see `IndexReader::map`, available with the `mmap` feature). Blobs and packs are hashed
+/// with `H`.
+pub struct IndexReader<B, H> {
+    data: B,
+    hasher: PhantomData<H>,
+}
+
+impl<H: Digest> IndexReader<Vec<u8>, H> {
+    /// Read the whole index from `reader` into memory.
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        IndexReader::new(data)
+    }
+}
+
+impl<H: Digest> IndexReader<Bytes, H> {
+    /// Load the index stored in `cas` under `hash` (see
+    /// [`IndexWriter::store`](super::IndexWriter::store)).
+    ///
+    /// Returns [`ErrorKind::NotFound`] error if there is no such object in `cas`.
+    pub fn load<C>(cas: &C, hash: C::Hash) -> Result<Self, C::Error>
+    where
+        C: ContentAddressableStorage,
+        C::Error: From<io::Error>,
+    {
+        let data = cas
+            .get(hash)?
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "index not found"))?;
+        Ok(IndexReader::new(data)?)
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl<H: Digest> IndexReader<memmap2::Mmap, H> {
+    /// Memory-map index `file`.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while the returned reader is alive. Doing so
+    /// is undefined behavior (and in practice results in `SIGBUS` when accessing truncated
+    /// pages). Index files are never modified after being written, so this holds as long as no
+    /// other process tampers with the repository.
+    pub unsafe fn map(file: &std::fs::File) -> io::Result<Self> {
+        // SAFETY: upheld by the caller.
+        let data = unsafe { memmap2::Mmap::map(file)? };
+        IndexReader::new(data)
+    }
+}
+
+impl<B: AsRef<[u8]>, H: Digest> IndexReader<B, H> {
+    /// Wrap serialized index `data`.
+    ///
+    /// Returns error if `data` is not a well-formed index. Only the fanout table and overall size
+    /// are validated here, the entries themselves are validated lazily during lookup.
+    pub fn new(data: B) -> io::Result<Self> {
+        let bytes = data.as_ref();
+        if bytes.len() < FANOUT_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "index is too short"));
+        }
+
+        let entries_size = bytes.len() - FANOUT_SIZE;
+        if !entries_size.is_multiple_of(IndexEntry::<H>::size()) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "index size is not a multiple of entry size",
+            ));
+        }
+
+        let reader = IndexReader {
+            data,
+            hasher: PhantomData,
+        };
+        let count = entries_size / IndexEntry::<H>::size();
+        if reader.fanout(255) != count {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "index fanout table does not match entry count",
+            ));
+        }
+
+        Ok(reader)
+    }
+
+    /// Number of entries in the index.
+    pub fn len(&self) -> usize {
+        self.fanout(255)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over all entries, sorted by hash.
+    pub fn iter(&self) -> impl Iterator<Item = IndexEntry<H>> + '_ {
+        (0..self.len()).map(|i| {
+            Self::parse_entry(
+                self.entry_bytes(i)
+                    .expect("entry count is validated in new"),
+            )
+        })
+    }
+
+    /// Find the index entry for the given `hash`.
+    pub fn lookup(&self, hash: &Output<H>) -> Option<IndexEntry<H>> {
+        let first_byte = *hash.first()?;
+        let mut lo = match first_byte {
+            0 => 0,
+            b => self.fanout(b - 1),
+        };
+        let mut hi = self.fanout(first_byte);
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let entry = self.entry_bytes(mid)?;
+            match entry[..H::OutputSize::USIZE].cmp(hash) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(Self::parse_entry(entry)),
+            }
+        }
+
+        None
+    }
+
+    /// Read the `i`-th fanout table entry.
+    fn fanout(&self, i: u8) -> usize {
+        let start = i as usize * size_of::<u32>();
+        let bytes = &self.data.as_ref()[start..start + size_of::<u32>()];
+        u32::from_le_bytes(bytes.try_into().expect("slice is 4 bytes long")) as usize
+    }
+
+    /// Get bytes of the `i`-th entry, or `None` if `i` is out of bounds.
+    fn entry_bytes(&self, i: usize) -> Option<&[u8]> {
+        let size = IndexEntry::<H>::size();
+        let start = i.checked_mul(size)?.checked_add(FANOUT_SIZE)?;
+        self.data.as_ref().get(start..start.checked_add(size)?)
+    }
+
+    fn parse_entry(bytes: &[u8]) -> IndexEntry<H> {
+        let (hash, rest) = bytes.split_at(H::OutputSize::USIZE);
+        let (pack_id, offset) = rest.split_at(H::OutputSize::USIZE);
+        IndexEntry {
+            hash: Output::<H>::clone_from_slice(hash),
+            pack_id: Output::<H>::clone_from_slice(pack_id),
+            offset: u32::from_le_bytes(offset.try_into().expect("entry has offset")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use camino::Utf8Path;
+
+    use super::*;
+    use crate::{cas::DirectoryCas, index::IndexWriter, pack};
+
+    use proptest::prelude::*;
+
+    type Reader<B> = IndexReader<B, blake3::Hasher>;
+
+    fn write_index(entries: &HashMap<[u8; 32], ([u8; 32], u32)>) -> Vec<u8> {
+        let mut writer = IndexWriter::<blake3::Hasher>::new();
+        for (hash, (pack_id, offset)) in entries {
+            writer.extend_from_pack(
+                (*pack_id).into(),
+                vec![pack::IndexEntry {
+                    hash: (*hash).into(),
+                    offset: *offset,
+                }],
+            );
+        }
+
+        let mut output = Vec::new();
+        writer.write(&mut output).unwrap();
+        assert_eq!(writer.size(), output.len());
+        output
+    }
+
+    proptest! {
+        #[test]
+        fn test_lookup(entries: HashMap<[u8; 32], ([u8; 32], u32)>, missing: [u8; 32]) {
+            let reader = Reader::new(write_index(&entries)).unwrap();
+
+            prop_assert_eq!(reader.len(), entries.len());
+            prop_assert_eq!(reader.iter().count(), entries.len());
+            prop_assert!(reader.iter().is_sorted_by(|a, b| a.hash < b.hash));
+            for (hash, (pack_id, offset)) in &entries {
+                let entry = reader.lookup(&(*hash).into()).unwrap();
+                prop_assert_eq!(entry.hash().as_slice(), hash);
+                prop_assert_eq!(entry.pack_id().as_slice(), pack_id);
+                prop_assert_eq!(entry.offset(), *offset);
+            }
+
+            if !entries.contains_key(&missing) {
+                prop_assert!(reader.lookup(&missing.into()).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_truncated_index() {
+        let entries = HashMap::from([([1u8; 32], ([2u8; 32], 3))]);
+        let mut bytes = write_index(&entries);
+        bytes.pop();
+
+        assert!(Reader::new(bytes).is_err());
+    }
+
+    #[test]
+    fn test_rejects_inconsistent_fanout() {
+        let entries = HashMap::from([([1u8; 32], ([2u8; 32], 3))]);
+        let mut bytes = write_index(&entries);
+        // Bump the last fanout entry to claim more entries than there are.
+        bytes[FANOUT_SIZE - 4] += 1;
+
+        assert!(Reader::new(bytes).is_err());
+    }
+
+    #[test]
+    fn test_store_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let cas = DirectoryCas::<blake3::Hasher>::new(Utf8Path::from_path(dir.path()).unwrap());
+
+        let mut writer = IndexWriter::<blake3::Hasher>::new();
+        writer.extend_from_pack(
+            [2u8; 32].into(),
+            vec![pack::IndexEntry {
+                hash: [1u8; 32].into(),
+                offset: 3,
+            }],
+        );
+        let hash = writer.store(&cas).unwrap();
+
+        let reader = Reader::load(&cas, hash).unwrap();
+        let entry = reader.lookup(&[1u8; 32].into()).unwrap();
+        assert_eq!(entry.pack_id, [2u8; 32].into());
+        assert_eq!(entry.offset, 3);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap_lookup() {
+        use std::io::Write;
+
+        let entries = HashMap::from([([1u8; 32], ([2u8; 32], 3)), ([7u8; 32], ([8u8; 32], 9))]);
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&write_index(&entries)).unwrap();
+
+        // SAFETY: the file is private to this test.
+        let reader = unsafe { Reader::map(&file) }.unwrap();
+        let entry = reader.lookup(&[7u8; 32].into()).unwrap();
+        assert_eq!(entry.pack_id, [8u8; 32].into());
+        assert_eq!(entry.offset, 9);
+    }
+}
@@ -1,42 +1,106 @@
-use std::io::{self, Write};
+use std::{
+    fmt,
+    io::{self, Write},
+};
 
+use bytes::Bytes;
+use digest::{Digest, Output, typenum::Unsigned};
 use rayon::slice::ParallelSliceMut;
 
-use crate::pack;
+use crate::{cas::ContentAddressableStorage, pack};
 
-pub struct IndexEntry<const HASH_SIZE: usize> {
-    hash: [u8; HASH_SIZE],
-    pack_id: [u8; HASH_SIZE],
-    offset: u32,
+/// Size of the fanout table at the start of the index.
+///
+/// Fanout table has 256 entries (one per possible first byte of the hash), each entry being u32
+/// number of index entries whose hash starts with a byte less than or equal to entry's byte.
+pub(super) const FANOUT_SIZE: usize = 256 * size_of::<u32>();
+
+/// Location of a blob: the pack that contains it and its offset in the pack.
+///
+/// Fields are read with [`hash`](Self::hash), [`pack_id`](Self::pack_id) and
+/// [`offset`](Self::offset), so that entries can only be built by the index itself.
+pub struct IndexEntry<H: Digest> {
+    pub(super) hash: Output<H>,
+    pub(super) pack_id: Output<H>,
+    pub(super) offset: u32,
+}
+
+// Implemented by hand, as derives would require `H` itself to implement the traits.
+impl<H: Digest> Clone for IndexEntry<H> {
+    fn clone(&self) -> Self {
+        IndexEntry {
+            hash: self.hash.clone(),
+            pack_id: self.pack_id.clone(),
+            offset: self.offset,
+        }
+    }
+}
+
+impl<H: Digest> Copy for IndexEntry<H> where Output<H>: Copy {}
+
+impl<H: Digest> fmt::Debug for IndexEntry<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndexEntry")
+            .field("hash", &self.hash)
+            .field("pack_id", &self.pack_id)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+impl<H: Digest> PartialEq for IndexEntry<H> {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.pack_id == other.pack_id && self.offset == other.offset
+    }
+}
+
+impl<H: Digest> Eq for IndexEntry<H> {}
+
+/// Writer of the index of packs of blobs hashed with `H`.
+pub struct IndexWriter<H: Digest> {
+    index: Vec<IndexEntry<H>>,
 }
 
-pub struct IndexWriter<const HASH_SIZE: usize> {
-    index: Vec<IndexEntry<HASH_SIZE>>,
+impl<H: Digest> Default for IndexWriter<H> {
+    fn default() -> Self {
+        IndexWriter::new()
+    }
 }
 
-impl<const HASH_SIZE: usize> IndexEntry<HASH_SIZE> {
-    const fn size() -> usize {
-        HASH_SIZE + HASH_SIZE + size_of::<u32>()
+impl<H: Digest> IndexEntry<H> {
+    pub(super) const fn size() -> usize {
+        H::OutputSize::USIZE + H::OutputSize::USIZE + size_of::<u32>()
+    }
+
+    /// Hash of the blob.
+    pub fn hash(&self) -> &Output<H> {
+        &self.hash
+    }
+
+    /// ID of the pack containing the blob.
+    pub fn pack_id(&self) -> &Output<H> {
+        &self.pack_id
+    }
+
+    /// Offset of the blob in the pack.
+    pub fn offset(&self) -> u32 {
+        self.offset
     }
 }
 
-impl<const HASH_SIZE: usize> IndexWriter<HASH_SIZE> {
+impl<H: Digest> IndexWriter<H> {
     pub fn new() -> Self {
         IndexWriter { index: Vec::new() }
     }
 
     pub fn size(&self) -> usize {
-        self.index.len() * IndexEntry::<HASH_SIZE>::size()
+        FANOUT_SIZE + self.index.len() * IndexEntry::<H>::size()
     }
 
-    pub fn extend_from_pack(
-        &mut self,
-        pack_id: [u8; HASH_SIZE],
-        indices: Vec<pack::IndexEntry<HASH_SIZE>>,
-    ) {
+    pub fn extend_from_pack(&mut self, pack_id: Output<H>, indices: Vec<pack::IndexEntry<H>>) {
         self.index.extend(indices.into_iter().map(|it| IndexEntry {
             hash: it.hash,
-            pack_id,
+            pack_id: pack_id.clone(),
             offset: it.offset,
         }));
     }
@@ -44,6 +108,22 @@ impl<const HASH_SIZE: usize> IndexWriter<HASH_SIZE> {
     pub fn write<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
         self.index.par_sort_by(|a, b| a.hash.cmp(&b.hash));
 
+        let mut fanout = [0u32; 256];
+        for entry in &self.index {
+            fanout[entry.hash[0] as usize] += 1;
+        }
+        let mut total = 0u32;
+        for count in &mut fanout {
+            total = total
+                .checked_add(*count)
+                .ok_or(io::ErrorKind::FileTooLarge)?;
+            *count = total;
+        }
+
+        for count in fanout {
+            w.write_all(&count.to_le_bytes())?;
+        }
+
         for entry in &self.index {
             w.write_all(&entry.hash)?;
             w.write_all(&entry.pack_id)?;
@@ -52,4 +132,18 @@ impl<const HASH_SIZE: usize> IndexWriter<HASH_SIZE> {
 
         w.flush()
     }
+
+    /// Serialize the index and store it in `cas`.
+    ///
+    /// Returns the content hash of the index, which can later be passed to
+    /// [`IndexReader::load`](super::IndexReader::load).
+    pub fn store<C>(mut self, cas: &C) -> Result<C::Hash, C::Error>
+    where
+        C: ContentAddressableStorage,
+        C::Error: From<io::Error>,
+    {
+        let mut buf = Vec::with_capacity(self.size());
+        self.write(&mut buf)?;
+        cas.store(Bytes::from(buf))
+    }
 }
@@ -1 +1,5 @@
+mod index_reader;
 mod index_writer;
+
+pub use index_reader::IndexReader;
+pub use index_writer::{IndexEntry, IndexWriter};
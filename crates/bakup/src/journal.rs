@@ -0,0 +1,248 @@
+//! Checkpoint journal of a running snapshot, which lets `snapshot --resume` skip files that were
+//! already stored before an interruption.
+//!
+//! Entries of stored files are periodically written to the CAS in batches, like manifests, so
+//! they are encrypted in encrypted repositories. The journal file `journals/<ID>` lists the
+//! hex-encoded hashes of the batches, one per line. The ID is derived from the snapshot name and
+//! the backed up paths, so only a snapshot of the same paths resumes from the journal.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use camino::{Utf8Path, Utf8PathBuf};
+use const_hex::ToHexExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cas::ContentAddressableStorage,
+    manifest::{ChunkerParams, EntryManifest},
+    repo::{self, Repository},
+    snapshots::SnapshotId,
+    status,
+};
+
+pub const JOURNALS_DIR: &str = "journals";
+
+/// Minimum time between checkpoints. Every checkpoint flushes the pack being filled.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize)]
+struct Batch {
+    chunker: ChunkerParams,
+    entries: Vec<EntryManifest>,
+}
+
+/// Progress of an interrupted snapshot.
+#[derive(Default)]
+pub struct Resumed {
+    /// Chunking parameters of the interrupted snapshot.
+    pub chunker: Option<ChunkerParams>,
+    /// Stored files by path.
+    pub entries: HashMap<Utf8PathBuf, EntryManifest>,
+}
+
+/// Path of the journal of a snapshot of `roots` named `name` in the repository at `remote`.
+pub fn path(remote: &Utf8Path, name: Option<&str>, roots: &[Utf8PathBuf]) -> Utf8PathBuf {
+    let key = serde_json::to_vec(&(name, roots)).expect("key should be JSON-serializable");
+    let id = blake3::hash(&key);
+    remote.join(JOURNALS_DIR).join(id.as_bytes().encode_hex())
+}
+
+/// Load progress recorded in the journal at `path`, if any.
+///
+/// Batches that are missing or unreadable (e.g. pruned since) are reported and skipped, as their
+/// files can be stored again.
+pub fn load(repo: &Repository, path: &Utf8Path) -> anyhow::Result<Resumed> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Resumed::default()),
+        Err(err) => return Err(err).with_context(|| format!("failed to read journal {path}")),
+    };
+
+    let mut resumed = Resumed::default();
+    for line in content.lines() {
+        let mut id = SnapshotId::default();
+        // The last line may be truncated if the snapshot was interrupted while writing it.
+        if const_hex::decode_to_slice(line.trim(), &mut id).is_err() {
+            continue;
+        }
+        let batch = match load_batch(repo, id) {
+            Ok(batch) => batch,
+            Err(err) => {
+                status::note(format_args!("{err:#}"));
+                continue;
+            }
+        };
+        resumed.chunker = Some(batch.chunker);
+        resumed.entries.extend(
+            batch
+                .entries
+                .into_iter()
+                .map(|entry| (entry.path.clone(), entry)),
+        );
+    }
+    Ok(resumed)
+}
+
+fn load_batch(repo: &Repository, id: SnapshotId) -> anyhow::Result<Batch> {
+    let hex = id.encode_hex();
+    let bytes = repo
+        .get(id)
+        .with_context(|| format!("failed to read journal batch {hex}"))?
+        .with_context(|| format!("journal batch {hex} is missing"))?;
+    serde_json::from_slice(&bytes).with_context(|| format!("malformed journal batch {hex}"))
+}
+
+/// Journal being written by a running snapshot.
+pub struct Journal {
+    path: Utf8PathBuf,
+    chunker: ChunkerParams,
+    /// Entries stored since the last checkpoint.
+    pending: Mutex<Vec<EntryManifest>>,
+    /// Time of the last checkpoint. Held while writing a checkpoint.
+    checkpoint: Mutex<Instant>,
+    interval: Duration,
+}
+
+impl Journal {
+    /// Start the journal at `path`, continuing the existing one if `resume` is set.
+    pub fn create(path: Utf8PathBuf, chunker: ChunkerParams, resume: bool) -> anyhow::Result<Self> {
+        if !resume {
+            match std::fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(err).with_context(|| format!("failed to remove journal {path}"));
+                }
+                _ => {}
+            }
+        }
+        Ok(Journal {
+            path,
+            chunker,
+            pending: Mutex::new(Vec::new()),
+            checkpoint: Mutex::new(Instant::now()),
+            interval: CHECKPOINT_INTERVAL,
+        })
+    }
+
+    /// Record `entry`, whose content has been stored to `repo`, and write a checkpoint if it's
+    /// due.
+    ///
+    /// Only one thread writes a checkpoint at a time, the other ones just add their entries.
+    pub fn record(&self, entry: EntryManifest, repo: &Repository) -> anyhow::Result<()> {
+        self.pending.lock().unwrap().push(entry);
+
+        let Ok(mut checkpoint) = self.checkpoint.try_lock() else {
+            return Ok(());
+        };
+        if checkpoint.elapsed() < self.interval {
+            return Ok(());
+        }
+        let entries = std::mem::take(&mut *self.pending.lock().unwrap());
+        let batch = Batch {
+            chunker: self.chunker.clone(),
+            entries,
+        };
+        let batch = serde_json::to_vec(&batch).expect("journal batch should be JSON-serializable");
+        let id = repo.store(Bytes::from(batch))?;
+        // Chunks of the entries (and the batch itself) must be persisted before the journal
+        // refers to them.
+        repo::packed(repo).flush()?;
+        self.append(id)
+            .with_context(|| format!("failed to write journal {}", self.path))?;
+        *checkpoint = Instant::now();
+        Ok(())
+    }
+
+    fn append(&self, id: SnapshotId) -> io::Result<()> {
+        std::fs::create_dir_all(
+            self.path
+                .parent()
+                .expect("journal should be in a directory"),
+        )?;
+        let mut file = File::options().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", id.encode_hex())?;
+        file.sync_data()
+    }
+
+    /// Remove the journal of the completed snapshot.
+    pub fn remove(self) -> anyhow::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("failed to remove journal {}", self.path))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cas::Compression;
+
+    use super::*;
+    use crate::{config::Config, manifest::EntryType, snapshots};
+
+    fn entry(path: &str, repo: &Repository) -> EntryManifest {
+        let hash = repo.store(Bytes::from(path.to_owned())).unwrap();
+        EntryManifest {
+            path: path.into(),
+            ty: EntryType::File {
+                content: vec![hash],
+                size: path.len() as u64,
+                chunk_sizes: vec![path.len() as u64],
+                unstable: false,
+            },
+            mtime: None,
+            uid: None,
+            gid: None,
+            mode: None,
+            xattrs: None,
+        }
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = Utf8Path::from_path(dir.path()).unwrap();
+        std::fs::create_dir(remote.join(snapshots::SNAPSHOTS_DIR)).unwrap();
+        let open = || {
+            let config = Config::load(remote).unwrap();
+            repo::open_for_writing(remote, &config, None, &[], Compression::None, None).unwrap()
+        };
+        let path = path(remote, Some("home"), &["/home".into()]);
+        assert_ne!(path, self::path(remote, None, &["/home".into()]));
+
+        let repo = open();
+        let mut journal = Journal::create(path.clone(), ChunkerParams::LEGACY, false).unwrap();
+        journal.interval = Duration::ZERO;
+        journal.record(entry("/home/a", &repo), &repo).unwrap();
+        journal.interval = Duration::MAX;
+        journal.record(entry("/home/b", &repo), &repo).unwrap();
+        // The snapshot is interrupted, losing the pending entry and unflushed chunks.
+        drop(repo);
+
+        let repo = open();
+        let resumed = load(&repo, &path).unwrap();
+        assert_eq!(resumed.chunker, Some(ChunkerParams::LEGACY));
+        assert_eq!(
+            resumed.entries.keys().collect::<Vec<_>>(),
+            [&Utf8PathBuf::from("/home/a")]
+        );
+        let EntryType::File { content, .. } = &resumed.entries[Utf8Path::new("/home/a")].ty else {
+            panic!("expected a file");
+        };
+        assert!(repo.contains(&content[0]).unwrap());
+
+        // Starting over discards the journal.
+        let journal = Journal::create(path.clone(), ChunkerParams::LEGACY, false).unwrap();
+        assert!(load(&repo, &path).unwrap().entries.is_empty());
+        journal.remove().unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+//! Repository encryption keys.
+//!
+//! A secret key file holds a random seed, from which both the X25519 identity decrypting objects
+//! and the Ed25519 key signing them are derived. The public part of the identity is the recipient
+//! other keys can encrypt to.
+//!
+//! Commands read the secret key from the file given by `--identity`. Without it, the key is taken
+//! from the environment, so that unattended backups don't need it on the command line:
+//!
+//! - `BAKUP_IDENTITY_FILE` holds the path of the key file;
+//! - `BAKUP_IDENTITY` holds the armored key itself;
+//! - `BAKUP_IDENTITY_COMMAND` is a shell command printing the armored key, e.g. to read it from the
+//!   OS keyring with `secret-tool lookup service bakup`.
+//!
+//! At most one of them may be set. With the `keyring` feature, if none of them is set either, the
+//! armored key is read from the OS keyring entry of service `bakup` and user `identity`, so the
+//! lookup order is `--identity`, then the variables above, then the keyring. Key material is never
+//! accepted as a command line argument, as arguments are visible to other users in `ps`.
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, anyhow, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use const_hex::ToHexExt;
+use ed25519_dalek::SigningKey;
+use rand_core::{OsRng, RngCore};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{ZeroizeOnDrop, Zeroizing};
+
+use crate::{
+    cli::{self, KeyCommand},
+    status::UsageError,
+};
+
+const IDENTITY_CTX: &str = "bakup key file 2026-10-16 x25519 identity";
+const SIGNING_KEY_CTX: &str = "bakup key file 2026-10-16 ed25519 signing key";
+
+const ARMOR_BEGIN: &str = "-----BEGIN BAKUP SECRET KEY-----";
+const ARMOR_END: &str = "-----END BAKUP SECRET KEY-----";
+
+pub const IDENTITY_FILE_ENV: &str = "BAKUP_IDENTITY_FILE";
+pub const IDENTITY_ENV: &str = "BAKUP_IDENTITY";
+pub const IDENTITY_COMMAND_ENV: &str = "BAKUP_IDENTITY_COMMAND";
+
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "bakup";
+#[cfg(feature = "keyring")]
+const KEYRING_USER: &str = "identity";
+
+pub fn key(cmd: KeyCommand) -> anyhow::Result<()> {
+    match cmd {
+        KeyCommand::Generate(cmd) => generate(cmd),
+        KeyCommand::Pubkey(cmd) => pubkey(cmd),
+    }
+}
+
+fn generate(cmd: cli::KeyGenerate) -> anyhow::Result<()> {
+    let key = Key::generate();
+    key.save(&cmd.output)?;
+    println!("{}", key.recipient().as_bytes().encode_hex());
+    Ok(())
+}
+
+fn pubkey(cmd: cli::KeyPubkey) -> anyhow::Result<()> {
+    let key = Key::load(&cmd.secret_file)?;
+    println!("{}", key.recipient().as_bytes().encode_hex());
+    Ok(())
+}
+
+/// Secret key. All key material is zeroized on drop.
+pub struct Key {
+    seed: Zeroizing<[u8; 32]>,
+    pub identity: StaticSecret,
+    pub signing_key: SigningKey,
+}
+
+impl ZeroizeOnDrop for Key {}
+
+impl Key {
+    pub fn generate() -> Self {
+        let mut seed = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(seed.as_mut());
+        Self::from_seed(seed)
+    }
+
+    fn from_seed(seed: Zeroizing<[u8; 32]>) -> Self {
+        let identity = StaticSecret::from(blake3::derive_key(IDENTITY_CTX, seed.as_ref()));
+        let signing_key = SigningKey::from_bytes(&Zeroizing::new(blake3::derive_key(
+            SIGNING_KEY_CTX,
+            seed.as_ref(),
+        )));
+        Key {
+            seed,
+            identity,
+            signing_key,
+        }
+    }
+
+    pub fn load(path: &Utf8Path) -> anyhow::Result<Self> {
+        let content = Zeroizing::new(
+            std::fs::read_to_string(path).with_context(|| format!("failed to read key {path}"))?,
+        );
+        let seed = dearmor(&content).with_context(|| format!("invalid key file {path}"))?;
+        Ok(Self::from_seed(seed))
+    }
+
+    /// Load the key at `path`, or the one given by the environment (or the OS keyring) if `path`
+    /// is `None`. Returns `None` if neither is given.
+    pub fn resolve(path: Option<&Utf8Path>) -> anyhow::Result<Option<Self>> {
+        let Some(path) = path else {
+            let key = Self::from_env(|name| std::env::var_os(name))?;
+            #[cfg(feature = "keyring")]
+            let key = match key {
+                Some(key) => Some(key),
+                None => Self::from_keyring()?,
+            };
+            return Ok(key);
+        };
+        Self::load(path).map(Some)
+    }
+
+    /// Read the key from the OS keyring. Returns `None` if it has no key, or if the keyring can't
+    /// be used (e.g. on a server without a Secret Service), which is reported with a warning.
+    #[cfg(feature = "keyring")]
+    fn from_keyring() -> anyhow::Result<Option<Self>> {
+        let armored = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER)
+            .and_then(|entry| entry.get_password());
+        let armored = match armored {
+            Ok(armored) => Zeroizing::new(armored),
+            Err(keyring::Error::NoEntry) => return Ok(None),
+            Err(err) => {
+                crate::status::note(format_args!(
+                    "failed to read the key from the OS keyring: {err}"
+                ));
+                return Ok(None);
+            }
+        };
+        let seed = dearmor(&armored).context("invalid key in the OS keyring")?;
+        Ok(Some(Self::from_seed(seed)))
+    }
+
+    fn from_env(var: impl Fn(&str) -> Option<OsString>) -> anyhow::Result<Option<Self>> {
+        let set = [IDENTITY_FILE_ENV, IDENTITY_ENV, IDENTITY_COMMAND_ENV]
+            .into_iter()
+            .filter_map(|name| Some((name, var(name)?)))
+            .collect::<Vec<_>>();
+        let (name, value) = match <[_; 1]>::try_from(set) {
+            Ok([it]) => it,
+            Err(set) if set.is_empty() => return Ok(None),
+            Err(set) => bail!(UsageError(format!(
+                "only one of {} may be set",
+                set.iter().map(|it| it.0).collect::<Vec<_>>().join(", ")
+            ))),
+        };
+        let value = Zeroizing::new(
+            value
+                .into_string()
+                .map_err(|_| anyhow!("{name} is not valid UTF-8"))?,
+        );
+        let seed = match name {
+            IDENTITY_FILE_ENV => return Self::load(&Utf8PathBuf::from(value.as_str())).map(Some),
+            IDENTITY_ENV => dearmor(&value).with_context(|| format!("invalid key in {name}"))?,
+            _ => {
+                let content = run_command(&value)?;
+                dearmor(&content).with_context(|| format!("invalid key printed by {name}"))?
+            }
+        };
+        Ok(Some(Self::from_seed(seed)))
+    }
+
+    /// Write the key to a new file at `path`, readable only by the owner.
+    pub fn save(&self, path: &Utf8Path) -> anyhow::Result<()> {
+        let mut file = File::options()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("failed to create key file {path}"))?;
+        file.write_all(armor(&self.seed).as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Public key to encrypt objects for this key.
+    pub fn recipient(&self) -> PublicKey {
+        PublicKey::from(&self.identity)
+    }
+}
+
+/// Run the shell command `command` and return its output. Its stderr is passed through, e.g. for
+/// prompts of the keyring.
+fn run_command(command: &str) -> anyhow::Result<Zeroizing<String>> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("failed to run {IDENTITY_COMMAND_ENV}"))?;
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        bail!("{IDENTITY_COMMAND_ENV} failed with {}", output.status);
+    }
+    let stdout = std::str::from_utf8(&stdout)
+        .with_context(|| format!("{IDENTITY_COMMAND_ENV} printed invalid UTF-8"))?;
+    Ok(Zeroizing::new(stdout.to_owned()))
+}
+
+fn armor(seed: &[u8; 32]) -> Zeroizing<String> {
+    let hex = Zeroizing::new(seed.encode_hex());
+    Zeroizing::new(format!("{ARMOR_BEGIN}\n{}\n{ARMOR_END}\n", hex.as_str()))
+}
+
+fn dearmor(content: &str) -> anyhow::Result<Zeroizing<[u8; 32]>> {
+    let body = content
+        .trim()
+        .strip_prefix(ARMOR_BEGIN)
+        .and_then(|it| it.strip_suffix(ARMOR_END))
+        .ok_or_else(|| anyhow!("missing armor lines"))?;
+    let mut seed = Zeroizing::new([0u8; 32]);
+    const_hex::decode_to_slice(body.trim(), seed.as_mut())?;
+    Ok(seed)
+}
+
+/// Parse a hex-encoded recipient public key.
+pub fn parse_recipient(s: &str) -> Result<PublicKey, const_hex::FromHexError> {
+    let mut bytes = [0u8; 32];
+    const_hex::decode_to_slice(s, &mut bytes)?;
+    Ok(PublicKey::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_armor() {
+        let key = Key::generate();
+        let armored = armor(&key.seed);
+        assert!(armored.starts_with(ARMOR_BEGIN));
+
+        let loaded = Key::from_seed(dearmor(&armored).unwrap());
+        assert_eq!(loaded.recipient(), key.recipient());
+        assert_eq!(loaded.signing_key, key.signing_key);
+
+        assert!(dearmor(&key.seed.encode_hex()).is_err());
+    }
+
+    #[test]
+    fn test_from_env() {
+        let key = Key::generate();
+        let armored = armor(&key.seed);
+        let dir = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(dir.path()).unwrap().join("key");
+        std::fs::write(&path, armored.as_bytes()).unwrap();
+
+        let from_env = |vars: &[(&str, &str)]| {
+            Key::from_env(|name| {
+                vars.iter()
+                    .find(|it| it.0 == name)
+                    .map(|it| OsString::from(it.1))
+            })
+        };
+        assert!(from_env(&[]).unwrap().is_none());
+        for vars in [
+            [(IDENTITY_FILE_ENV, path.as_str())],
+            [(IDENTITY_ENV, armored.as_str())],
+            [(IDENTITY_COMMAND_ENV, &format!("cat '{path}'"))],
+        ] {
+            let loaded = from_env(&vars).unwrap().unwrap();
+            assert_eq!(loaded.recipient(), key.recipient());
+        }
+
+        assert!(from_env(&[(IDENTITY_COMMAND_ENV, "false")]).is_err());
+        assert!(from_env(&[(IDENTITY_ENV, "garbage")]).is_err());
+        let ambiguous = from_env(&[(IDENTITY_FILE_ENV, path.as_str()), (IDENTITY_ENV, &armored)]);
+        assert!(ambiguous.is_err());
+    }
+}
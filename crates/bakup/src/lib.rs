@@ -1,4 +1,56 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
 pub mod cas;
 pub mod chunking;
+#[cfg(feature = "std")]
 pub mod index;
+#[cfg(feature = "std")]
 pub mod pack;
+
+#[cfg(feature = "std")]
+mod cat;
+#[cfg(feature = "std")]
+mod check;
+#[cfg(feature = "std")]
+pub mod cli;
+#[cfg(feature = "std")]
+mod config;
+#[cfg(feature = "std")]
+mod copy;
+#[cfg(feature = "std")]
+mod filter;
+#[cfg(feature = "std")]
+mod journal;
+#[cfg(feature = "std")]
+mod keys;
+#[cfg(feature = "std")]
+mod list;
+#[cfg(feature = "std")]
+mod lock;
+#[cfg(feature = "std")]
+pub mod manifest;
+#[cfg(feature = "std")]
+mod migrate;
+#[cfg(feature = "std")]
+mod output;
+#[cfg(feature = "std")]
+mod progress;
+#[cfg(feature = "std")]
+mod prune;
+#[cfg(feature = "std")]
+mod rebuild_index;
+#[cfg(feature = "std")]
+mod repo;
+#[cfg(feature = "std")]
+mod restore;
+#[cfg(feature = "std")]
+mod retention;
+#[cfg(feature = "std")]
+pub mod snapshot;
+#[cfg(feature = "std")]
+mod snapshots;
+#[cfg(feature = "std")]
+mod stats;
+#[cfg(feature = "std")]
+pub mod status;
@@ -0,0 +1,212 @@
+use std::{
+    io::{self, Write},
+    time::SystemTime,
+};
+
+use camino::Utf8PathBuf;
+use const_hex::ToHexExt;
+use indicatif::HumanBytes;
+use itertools::Itertools;
+use serde::Serialize;
+use serde_with::{TimestampSecondsWithFrac, serde_as};
+
+use crate::{
+    cli,
+    lock::RepoLock,
+    manifest::{EntryType, SnapshotManifest},
+    repo, snapshots, status,
+};
+
+#[serde_as]
+#[derive(Serialize)]
+struct SnapshotSummary {
+    id: String,
+    name: Option<String>,
+    hostname: Option<String>,
+    /// Paths that were backed up.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    paths: Vec<Utf8PathBuf>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde_as(as = "TimestampSecondsWithFrac<String>")]
+    time: SystemTime,
+    entries: usize,
+    /// Total size of files in bytes.
+    size: u64,
+}
+
+impl SnapshotSummary {
+    fn new(id: String, manifest: &SnapshotManifest) -> Self {
+        SnapshotSummary {
+            id,
+            name: manifest.name.clone(),
+            hostname: manifest.hostname.clone(),
+            paths: manifest.paths.clone(),
+            tags: manifest.tags.clone(),
+            description: manifest.description.clone(),
+            time: manifest.time,
+            entries: manifest.entries.len(),
+            size: manifest
+                .entries
+                .iter()
+                .map(|entry| match entry.ty {
+                    EntryType::File { size, .. } => size,
+                    _ => 0,
+                })
+                .sum(),
+        }
+    }
+}
+
+pub fn list(cmd: cli::List) -> anyhow::Result<()> {
+    let _lock = RepoLock::shared(&cmd.remote, &cmd.lock)?;
+    let cas = repo::open(&cmd.remote, cmd.identity.as_deref())?;
+
+    let mut summaries = Vec::new();
+    for id in snapshots::list(&cmd.remote)? {
+        // A broken snapshot shouldn't hide the others.
+        match snapshots::load(&cas, id) {
+            Ok(manifest) if !cmd.tags.iter().all(|tag| manifest.tags.contains(tag)) => {}
+            Ok(manifest) => summaries.push(SnapshotSummary::new(id.encode_hex(), &manifest)),
+            Err(err) => status::warn(format_args!("{err:#}")),
+        }
+    }
+    summaries.sort_by_key(|it| it.time);
+
+    if cmd.json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(());
+    }
+
+    write_table(&summaries, &mut std::io::stdout().lock())?;
+    Ok(())
+}
+
+/// Write `summaries` as a table, one snapshot per line (followed by its description, if any).
+fn write_table(summaries: &[SnapshotSummary], out: &mut impl Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:<16}  {:<20}  {:<16}  {:<20}  {:>8}  {:>10}  {:<16}  PATHS",
+        "ID", "NAME", "HOST", "TIME", "ENTRIES", "SIZE", "TAGS"
+    )?;
+    for summary in summaries {
+        writeln!(
+            out,
+            "{:<16}  {:<20}  {:<16}  {:<20}  {:>8}  {:>10}  {:<16}  {}",
+            &summary.id[..16],
+            summary.name.as_deref().unwrap_or("-"),
+            summary.hostname.as_deref().unwrap_or("-"),
+            humantime::format_rfc3339_seconds(summary.time).to_string(),
+            summary.entries,
+            HumanBytes(summary.size).to_string(),
+            if summary.tags.is_empty() {
+                "-".to_owned()
+            } else {
+                summary.tags.join(",")
+            },
+            if summary.paths.is_empty() {
+                "-".to_owned()
+            } else {
+                summary.paths.iter().map(|it| it.as_str()).join(" ")
+            },
+        )?;
+        if let Some(description) = &summary.description {
+            writeln!(out, "  {description}")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::manifest::{EntryManifest, MANIFEST_VERSION};
+
+    fn manifest(hostname: Option<&str>, paths: &[&str]) -> SnapshotManifest {
+        SnapshotManifest {
+            version: MANIFEST_VERSION,
+            name: Some("home".to_owned()),
+            hostname: hostname.map(str::to_owned),
+            paths: paths.iter().map(Utf8PathBuf::from).collect(),
+            tags: Vec::new(),
+            description: None,
+            time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            chunker: None,
+            entries: vec![EntryManifest {
+                path: Utf8PathBuf::from("/a"),
+                ty: EntryType::Directory,
+                mtime: None,
+                uid: None,
+                gid: None,
+                mode: None,
+                xattrs: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_write_table() {
+        let id = "ab".repeat(32);
+        let summaries = [
+            SnapshotSummary::new(id.clone(), &manifest(Some("host"), &["/a", "/b"])),
+            // Older snapshots have neither.
+            SnapshotSummary::new(id, &manifest(None, &[])),
+        ];
+        let mut out = Vec::new();
+        write_table(&summaries, &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let rows = out
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                vec![
+                    "ID", "NAME", "HOST", "TIME", "ENTRIES", "SIZE", "TAGS", "PATHS"
+                ],
+                vec![
+                    "abababababababab",
+                    "home",
+                    "host",
+                    "2023-11-14T22:13:20Z",
+                    "1",
+                    "0",
+                    "B",
+                    "-",
+                    "/a",
+                    "/b"
+                ],
+                vec![
+                    "abababababababab",
+                    "home",
+                    "-",
+                    "2023-11-14T22:13:20Z",
+                    "1",
+                    "0",
+                    "B",
+                    "-",
+                    "-"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let summary = SnapshotSummary::new("ab".repeat(32), &manifest(Some("host"), &["/a"]));
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["hostname"], "host");
+        assert_eq!(json["paths"], serde_json::json!(["/a"]));
+
+        let summary = SnapshotSummary::new("ab".repeat(32), &manifest(None, &[]));
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["hostname"], serde_json::Value::Null);
+        assert!(json.get("paths").is_none());
+    }
+}
@@ -0,0 +1,308 @@
+//! Advisory lock on the repository, which keeps `prune` from deleting objects of a snapshot that
+//! is being written or read.
+//!
+//! Commands that write new objects or read existing ones take a shared lock and may run
+//! concurrently, while commands that remove objects (`prune`, `rebuild-index`) take an exclusive
+//! one. A shared lock waits for an exclusive one to be released, while an exclusive lock fails
+//! right away if the repository is in use.
+//!
+//! There are two layers:
+//! - An OS file lock on `<repository>/lock`, which is released even if the process is killed, but
+//!   only protects against processes on the same host (or on file systems that support locking
+//!   over the network).
+//! - A record of every holder (hostname, pid, time and kind of the lock) in
+//!   `<repository>/locks/`, which covers processes on other hosts. A holder writes its record
+//!   before checking the records of others, so of two conflicting processes at least one sees
+//!   the other and fails. Records are removed when the lock is released, but stay behind if the
+//!   process crashes. Such stale records keep conflicting until they are removed with
+//!   `--break-lock`, since a record of another host can't be told apart from a live one.
+//!   Breaking the lock of a process that is still running may let `prune` delete objects it
+//!   writes.
+
+use std::{
+    fs::{File, TryLockError},
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::LockArgs,
+    status::{self, UsageError},
+};
+
+const LOCK_FILE: &str = "lock";
+
+/// Subdirectory of the repository with records of the lock holders.
+const LOCKS_DIR: &str = "locks";
+
+/// Prefix of records that are being written.
+const TEMP_PREFIX: &str = ".tmp";
+
+/// Held lock, released on drop.
+pub struct RepoLock {
+    record: Utf8PathBuf,
+    _file: File,
+}
+
+impl RepoLock {
+    /// Lock the repository at `remote` for writing new objects or reading existing ones, waiting
+    /// for a running prune to finish.
+    pub fn shared(remote: &Utf8Path, args: &LockArgs) -> anyhow::Result<Self> {
+        let file = open(remote)?;
+        file.lock_shared()
+            .with_context(|| format!("failed to lock repository {remote}"))?;
+        register(remote, file, false, args.break_lock)
+    }
+
+    /// Lock the repository at `remote` for removing objects. Fails if the repository is in use.
+    pub fn exclusive(remote: &Utf8Path, args: &LockArgs) -> anyhow::Result<Self> {
+        let file = open(remote)?;
+        match file.try_lock() {
+            Ok(()) => register(remote, file, true, args.break_lock),
+            Err(TryLockError::WouldBlock) => {
+                bail!("repository {remote} is in use by another process")
+            }
+            Err(TryLockError::Error(err)) => {
+                Err(err).with_context(|| format!("failed to lock repository {remote}"))
+            }
+        }
+    }
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        // Removed before the file lock is released, so that processes waiting for it don't see
+        // the record.
+        let _ = std::fs::remove_file(&self.record);
+    }
+}
+
+fn open(remote: &Utf8Path) -> anyhow::Result<File> {
+    if !remote.is_dir() {
+        bail!(UsageError(format!(
+            "{remote} is not a repository (create one with `bakup init`)"
+        )));
+    }
+    let path = remote.join(LOCK_FILE);
+    File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("failed to open {path}"))
+}
+
+/// Record of a lock holder.
+#[derive(Debug, Serialize, Deserialize)]
+struct Holder {
+    hostname: String,
+    pid: u32,
+    /// Seconds since the Unix epoch when the lock was taken.
+    time: u64,
+    exclusive: bool,
+}
+
+impl Holder {
+    fn current(exclusive: bool) -> Self {
+        Holder {
+            hostname: holder_hostname(),
+            pid: std::process::id(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |it| it.as_secs()),
+            exclusive,
+        }
+    }
+
+    /// Whether the holder ran on this host and is not running anymore.
+    fn is_stale(&self) -> bool {
+        if self.hostname != holder_hostname() {
+            return false;
+        }
+        let Ok(pid) = libc::pid_t::try_from(self.pid) else {
+            return true;
+        };
+        // Signal 0 only checks whether the process exists.
+        let result = unsafe { libc::kill(pid, 0) };
+        result != 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+    }
+
+    fn describe(&self) -> String {
+        let kind = if self.exclusive {
+            "exclusive"
+        } else {
+            "shared"
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(self.time);
+        let stale = if self.is_stale() {
+            " (stale, the process is not running)"
+        } else {
+            ""
+        };
+        format!(
+            "{kind} lock of process {} on {} since {}{stale}",
+            self.pid,
+            self.hostname,
+            humantime::format_rfc3339_seconds(time),
+        )
+    }
+}
+
+fn holder_hostname() -> String {
+    hostname().unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Name of this host, or `None` if it can't be determined.
+pub(crate) fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&it| it == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/// Record the current process as a holder of the lock in `file`, and check that no holder of
+/// a conflicting lock is recorded. Conflicting records are removed instead if `break_lock` is
+/// set.
+fn register(
+    remote: &Utf8Path,
+    file: File,
+    exclusive: bool,
+    break_lock: bool,
+) -> anyhow::Result<RepoLock> {
+    let dir = remote.join(LOCKS_DIR);
+    std::fs::create_dir_all(&dir).with_context(|| format!("failed to create {dir}"))?;
+
+    let holder = Holder::current(exclusive);
+    let name = format!(
+        "{}-{}-{:016x}",
+        holder.hostname,
+        holder.pid,
+        OsRng.next_u64()
+    );
+    let temp = dir.join(format!("{TEMP_PREFIX}{name}"));
+    let record = dir.join(name);
+    std::fs::write(&temp, serde_json::to_vec(&holder)?)
+        .and_then(|()| std::fs::rename(&temp, &record))
+        .with_context(|| format!("failed to write {record}"))?;
+    // Removes the record if any of the checks below fail.
+    let lock = RepoLock {
+        record,
+        _file: file,
+    };
+
+    let mut conflicts = Vec::new();
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.path() == lock.record || entry.file_name().starts_with(TEMP_PREFIX) {
+            continue;
+        }
+        let description = match std::fs::read(entry.path()) {
+            Ok(bytes) => match serde_json::from_slice::<Holder>(&bytes) {
+                Ok(other) if !exclusive && !other.exclusive => continue,
+                Ok(other) => other.describe(),
+                Err(_) => format!("unreadable lock record {}", entry.path()),
+            },
+            // Released meanwhile.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", entry.path()));
+            }
+        };
+
+        if break_lock {
+            status::note(format_args!("breaking {description}"));
+            match std::fs::remove_file(entry.path()) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(err).with_context(|| format!("failed to remove {}", entry.path()));
+                }
+                _ => {}
+            }
+        } else {
+            conflicts.push(description);
+        }
+    }
+
+    if !conflicts.is_empty() {
+        for description in &conflicts {
+            status::error(format_args!(
+                "repository {remote} is locked by {description}"
+            ));
+        }
+        bail!(
+            "repository {remote} is in use (if the processes holding the lock are not running \
+             anymore, use --break-lock)"
+        );
+    }
+    Ok(lock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEEP: LockArgs = LockArgs { break_lock: false };
+    const BREAK: LockArgs = LockArgs { break_lock: true };
+
+    #[test]
+    fn test_exclusive_conflicts_with_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = Utf8Path::from_path(dir.path()).unwrap();
+
+        let shared1 = RepoLock::shared(remote, &KEEP).unwrap();
+        let shared2 = RepoLock::shared(remote, &KEEP).unwrap();
+        assert!(RepoLock::exclusive(remote, &KEEP).is_err());
+
+        drop(shared1);
+        drop(shared2);
+        let _exclusive = RepoLock::exclusive(remote, &KEEP).unwrap();
+        assert!(RepoLock::exclusive(remote, &KEEP).is_err());
+    }
+
+    #[test]
+    fn test_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = Utf8Path::from_path(dir.path()).unwrap();
+        let records = || remote.join(LOCKS_DIR).read_dir_utf8().unwrap().count();
+
+        let lock = RepoLock::shared(remote, &KEEP).unwrap();
+        assert_eq!(records(), 1);
+        drop(lock);
+        assert_eq!(records(), 0);
+
+        // A shared lock of a process on another host.
+        let other = Holder {
+            hostname: "other-host".to_owned(),
+            pid: 1,
+            time: 1_700_000_000,
+            exclusive: false,
+        };
+        let path = remote.join(LOCKS_DIR).join("other");
+        std::fs::write(&path, serde_json::to_vec(&other).unwrap()).unwrap();
+        assert!(!other.is_stale());
+
+        drop(RepoLock::shared(remote, &KEEP).unwrap());
+        let err = RepoLock::exclusive(remote, &KEEP).err().unwrap();
+        assert!(err.to_string().contains("--break-lock"));
+        assert_eq!(records(), 1);
+
+        drop(RepoLock::exclusive(remote, &BREAK).unwrap());
+        assert_eq!(records(), 0);
+    }
+
+    #[test]
+    fn test_stale() {
+        let mut holder = Holder::current(true);
+        assert!(!holder.is_stale());
+        holder.pid = i32::MAX as u32;
+        assert!(holder.is_stale());
+        assert!(holder.describe().contains("stale"));
+    }
+}
@@ -0,0 +1,600 @@
+//! Snapshot manifests, stored in the repository as JSON objects (the default, which is easy to
+//! inspect) or in the more compact MessagePack format, see [`ManifestFormat`].
+//!
+//! Compatibility policy: within a [`MANIFEST_VERSION`], fields may only be added, and new fields
+//! must be optional (`#[serde(default)]`), so that manifests written by older versions of bakup
+//! stay readable. Unknown fields are ignored, so older versions of bakup can read manifests with
+//! fields added later, at the cost of not restoring what they describe. New entry types may be
+//! added too, and older versions of bakup fail to read only the manifests that contain them. Any
+//! other change (removing or changing the meaning of a field) requires a new version, which older
+//! versions of bakup refuse to read.
+
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::chunking::{AesGearConfig, ChunkerConfig, ChunkerConfigError};
+use aes::cipher::KeyInit;
+use anyhow::bail;
+use camino::Utf8PathBuf;
+use const_hex::ToHexExt;
+use digest::Output;
+use serde::{Deserialize, Serialize};
+use serde_with::{base64::Base64, hex::Hex, serde_as, serde_conv};
+
+/// Version of the manifest format written by this version of bakup.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Leading byte of MessagePack manifests. JSON manifests start with `{`, so they need no tag.
+const MESSAGE_PACK_TAG: u8 = 0;
+
+/// Serialization format of manifests, detected automatically when reading them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ManifestFormat {
+    /// Pretty-printed JSON.
+    #[default]
+    Json,
+    /// MessagePack, smaller and faster to parse for snapshots with many files.
+    Msgpack,
+}
+
+impl ManifestFormat {
+    fn deserialize<'de, T: Deserialize<'de>>(self, bytes: &'de [u8]) -> anyhow::Result<T> {
+        Ok(match self {
+            ManifestFormat::Json => serde_json::from_slice(bytes)?,
+            ManifestFormat::Msgpack => rmp_serde::from_slice(bytes)?,
+        })
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    /// Manifest format version. Missing in manifests written before versioning, which are
+    /// version 1.
+    #[serde(default = "initial_version")]
+    pub version: u32,
+    // TODO: username
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Host the snapshot was taken on. Missing in older snapshots.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Paths that were backed up. Missing in older snapshots.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<Utf8PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Free-form note about the snapshot.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde_as(as = "ExactTimestamp")]
+    pub time: SystemTime,
+    /// Parameters the file content was chunked with. Missing in older snapshots, which were all
+    /// chunked with [`ChunkerParams::LEGACY`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunker: Option<ChunkerParams>,
+    pub entries: Vec<EntryManifest>,
+}
+
+fn initial_version() -> u32 {
+    1
+}
+
+/// Just the version of a manifest, which can be read from manifests of any version.
+#[derive(Deserialize)]
+struct ManifestVersion {
+    #[serde(default = "initial_version")]
+    version: u32,
+}
+
+impl SnapshotManifest {
+    /// Serialize the manifest in `format`.
+    pub fn to_bytes(&self, format: ManifestFormat) -> Vec<u8> {
+        match format {
+            ManifestFormat::Json => {
+                serde_json::to_vec_pretty(self).expect("manifest should be JSON-serializable")
+            }
+            ManifestFormat::Msgpack => {
+                let mut bytes = vec![MESSAGE_PACK_TAG];
+                // Structs are serialized as maps, so that fields can be added and skipped.
+                rmp_serde::encode::write_named(&mut bytes, self)
+                    .expect("manifest should be MessagePack-serializable");
+                bytes
+            }
+        }
+    }
+
+    /// Parse a manifest in any [`ManifestFormat`], rejecting manifests of versions newer than
+    /// [`MANIFEST_VERSION`].
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let (format, bytes) = match bytes.split_first() {
+            Some((&MESSAGE_PACK_TAG, rest)) => (ManifestFormat::Msgpack, rest),
+            _ => (ManifestFormat::Json, bytes),
+        };
+        // A manifest of a newer version may not parse at all, so its version is read separately.
+        let manifest = format.deserialize::<SnapshotManifest>(bytes);
+        let version = match &manifest {
+            Ok(manifest) => Some(manifest.version),
+            Err(_) => format
+                .deserialize::<ManifestVersion>(bytes)
+                .ok()
+                .map(|it| it.version),
+        };
+        if let Some(version) = version
+            && version > MANIFEST_VERSION
+        {
+            bail!(
+                "manifest version {version} is not supported (the latest supported version is \
+                 {MANIFEST_VERSION}), upgrade bakup to read it"
+            );
+        }
+        manifest
+    }
+
+    pub fn chunker_params(&self) -> ChunkerParams {
+        self.chunker.clone().unwrap_or(ChunkerParams::LEGACY)
+    }
+}
+
+/// Content-defined chunking parameters. Files are only deduplicated against snapshots chunked
+/// with the same parameters.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkerParams {
+    #[serde(flatten)]
+    pub sizes: ChunkSizes,
+    /// AES key of the gear hash, which makes chunk boundaries unpredictable without it.
+    #[serde_as(as = "Hex")]
+    pub key: [u8; 16],
+}
+
+/// Chunk size limits, see [`ChunkerConfig::try_new`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSizes {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+    pub normalization_bits: u32,
+}
+
+impl ChunkSizes {
+    pub const DEFAULT: ChunkSizes = ChunkSizes {
+        min_size: ChunkerConfig::DEFAULT_MIN_SIZE,
+        avg_size: ChunkerConfig::DEFAULT_AVG_SIZE,
+        max_size: ChunkerConfig::DEFAULT_MAX_SIZE,
+        normalization_bits: ChunkerConfig::DEFAULT_NORMALIZATION_BITS,
+    };
+}
+
+impl ChunkerParams {
+    /// Parameters used before they were recorded in manifests.
+    pub const LEGACY: ChunkerParams = ChunkerParams {
+        sizes: ChunkSizes::DEFAULT,
+        key: [0; 16],
+    };
+
+    pub fn config(&self) -> Result<ChunkerConfig<'static>, ChunkerConfigError> {
+        let aes = aes::Aes128Enc::new_from_slice(&self.key).expect("key should be 16 bytes");
+        ChunkerConfig::builder(AesGearConfig::new(aes))
+            .min_size(self.sizes.min_size)
+            .avg_size(self.sizes.avg_size)
+            .max_size(self.sizes.max_size)
+            .normalization_bits(self.sizes.normalization_bits)
+            .build()
+    }
+}
+
+#[serde_as]
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntryManifest {
+    pub path: Utf8PathBuf,
+    #[serde(flatten)]
+    pub ty: EntryType,
+    #[serde_as(as = "Option<ExactTimestamp>")]
+    #[serde(default)]
+    pub mtime: Option<SystemTime>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Extended attributes, if they were backed up and the entry has any.
+    #[serde_as(as = "Option<BTreeMap<_, Base64>>")]
+    #[serde(default)]
+    pub xattrs: Option<BTreeMap<String, Vec<u8>>>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum EntryType {
+    Directory,
+    File {
+        #[serde_as(as = "Vec<HexHash>")]
+        content: Vec<Output<blake3::Hasher>>,
+        /// File size in bytes.
+        #[serde(default)]
+        size: u64,
+        /// Sizes of the `content` chunks in bytes, to find the chunks covering a byte range without
+        /// fetching them, see [`covering_chunks`]. Empty in snapshots taken before chunk sizes
+        /// were recorded.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        chunk_sizes: Vec<u64>,
+        /// The file kept changing while being read, so `content` may mix old and new data.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        unstable: bool,
+    },
+    Symlink {
+        target: Utf8PathBuf,
+    },
+    /// Another name of a file with multiple hard links. `target` is the path of the entry that
+    /// holds the file content.
+    Hardlink {
+        target: Utf8PathBuf,
+    },
+    /// Named pipe.
+    Fifo,
+    /// Unix domain socket. Restoring it only creates the file, which no process listens on.
+    Socket,
+    /// Block or character device node.
+    Device {
+        kind: DeviceKind,
+        /// Device number (`st_rdev`).
+        rdev: u64,
+    },
+}
+
+impl EntryType {
+    /// Recorded size of a file. `None` for other entries, and for non-empty files of snapshots
+    /// taken before file sizes were recorded, whose size reads as 0.
+    pub fn file_size(&self) -> Option<u64> {
+        match self {
+            EntryType::File { content, size, .. } if *size > 0 || content.is_empty() => Some(*size),
+            _ => None,
+        }
+    }
+
+    /// Check that the recorded chunk sizes of a file add up to its size, describing the mismatch
+    /// otherwise. Files without recorded chunk sizes and other entries pass.
+    pub fn check_chunk_sizes(&self) -> Result<(), String> {
+        let EntryType::File {
+            content,
+            size,
+            chunk_sizes,
+            ..
+        } = self
+        else {
+            return Ok(());
+        };
+        if chunk_sizes.is_empty() {
+            return Ok(());
+        }
+        if chunk_sizes.len() != content.len() {
+            return Err(format!(
+                "{} chunk sizes are recorded for {} chunks",
+                chunk_sizes.len(),
+                content.len()
+            ));
+        }
+        let total = chunk_sizes.iter().sum::<u64>();
+        if total != *size {
+            return Err(format!(
+                "chunks add up to {total} bytes, but the file size is {size}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    Block,
+    Char,
+}
+
+/// Indices of the chunks of a file with `chunk_sizes` that cover bytes `range` of it, and the
+/// offset of the first of them in the file.
+pub fn covering_chunks(chunk_sizes: &[u64], range: Range<u64>) -> (Range<usize>, u64) {
+    // Offsets of the ends of the chunks.
+    let ends = chunk_sizes
+        .iter()
+        .scan(0, |end, size| {
+            *end += size;
+            Some(*end)
+        })
+        .collect::<Vec<_>>();
+    let first = ends.partition_point(|&end| end <= range.start);
+    let last = ends.partition_point(|&end| end < range.end);
+    let chunks = if range.is_empty() || first == ends.len() {
+        first..first
+    } else {
+        first..last.min(ends.len() - 1) + 1
+    };
+    let offset = first.checked_sub(1).map_or(0, |i| ends[i]);
+    (chunks, offset)
+}
+
+serde_conv!(
+    pub HexHash,
+    Output<blake3::Hasher>,
+    |hash: &Output<blake3::Hasher>| hash.encode_hex(),
+    |s: &str| -> Result<_, const_hex::FromHexError> {
+        let mut hash = Output::<blake3::Hasher>::default();
+        const_hex::decode_to_slice(s, &mut hash)?;
+        Ok(hash)
+    }
+);
+
+// Seconds since the Unix epoch as a decimal string with up to nanosecond precision, e.g.
+// "1700000000.123456789". Unlike `TimestampSecondsWithFrac`, it doesn't round through `f64`, so
+// modification times survive the round trip exactly.
+serde_conv!(
+    pub ExactTimestamp,
+    SystemTime,
+    |time: &SystemTime| format_timestamp(*time),
+    parse_timestamp
+);
+
+fn format_timestamp(time: SystemTime) -> String {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => format!("{}.{:09}", since.as_secs(), since.subsec_nanos()),
+        Err(err) => {
+            let before = err.duration();
+            format!("-{}.{:09}", before.as_secs(), before.subsec_nanos())
+        }
+    }
+}
+
+fn parse_timestamp(s: &str) -> Result<SystemTime, &'static str> {
+    const INVALID: &str = "invalid timestamp";
+
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let (secs, frac) = s.split_once('.').unwrap_or((s, ""));
+    let secs = secs.parse::<u64>().map_err(|_| INVALID)?;
+    if !frac.bytes().all(|it| it.is_ascii_digit()) {
+        return Err(INVALID);
+    }
+    // Anything below nanoseconds is truncated.
+    let nanos = frac
+        .bytes()
+        .chain(std::iter::repeat(b'0'))
+        .take(9)
+        .fold(0, |acc, digit| acc * 10 + u32::from(digit - b'0'));
+
+    let duration = Duration::new(secs, nanos);
+    let time = if negative {
+        UNIX_EPOCH.checked_sub(duration)
+    } else {
+        UNIX_EPOCH.checked_add(duration)
+    };
+    time.ok_or(INVALID)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        assert_eq!(format_timestamp(time), "1700000000.123456789");
+        assert_eq!(parse_timestamp("1700000000.123456789"), Ok(time));
+
+        let before = UNIX_EPOCH - Duration::new(1, 500_000_000);
+        assert_eq!(parse_timestamp(&format_timestamp(before)), Ok(before));
+
+        // Produced by `TimestampSecondsWithFrac` in older manifests.
+        assert_eq!(
+            parse_timestamp("1700000000.5"),
+            Ok(UNIX_EPOCH + Duration::new(1_700_000_000, 500_000_000))
+        );
+        assert_eq!(
+            parse_timestamp("1700000000"),
+            Ok(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+
+        assert!(parse_timestamp("").is_err());
+        assert!(parse_timestamp("1.2e3").is_err());
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let json = r#"{"path":"/a","type":"Directory","xattrs":{"user.test":"AP8="}}"#;
+        let entry: EntryManifest = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            entry.xattrs,
+            Some(BTreeMap::from([("user.test".to_owned(), vec![0, 255])]))
+        );
+        assert_eq!(serde_json::to_string(&entry).unwrap(), json);
+    }
+
+    #[test]
+    fn test_unstable() {
+        let json = r#"{"path":"/a","type":"File","content":[],"size":0}"#;
+        let entry: EntryManifest = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            entry.ty,
+            EntryType::File {
+                unstable: false,
+                ..
+            }
+        ));
+        assert_eq!(serde_json::to_string(&entry).unwrap(), json);
+
+        let json = r#"{"path":"/a","type":"File","content":[],"size":0,"unstable":true}"#;
+        let entry: EntryManifest = serde_json::from_str(json).unwrap();
+        assert!(matches!(entry.ty, EntryType::File { unstable: true, .. }));
+        assert_eq!(serde_json::to_string(&entry).unwrap(), json);
+    }
+
+    #[test]
+    fn test_chunk_sizes() {
+        let json = r#"{"path":"/a","type":"File","content":[],"size":0}"#;
+        let entry: EntryManifest = serde_json::from_str(json).unwrap();
+        assert!(matches!(entry.ty, EntryType::File { chunk_sizes, .. } if chunk_sizes.is_empty()));
+
+        let json = r#"{"path":"/a","type":"File","content":[],"size":3,"chunk_sizes":[1,2]}"#;
+        let entry: EntryManifest = serde_json::from_str(json).unwrap();
+        assert!(matches!(&entry.ty, EntryType::File { chunk_sizes, .. } if chunk_sizes == &[1, 2]));
+        assert_eq!(serde_json::to_string(&entry).unwrap(), json);
+    }
+
+    #[test]
+    fn test_check_chunk_sizes() {
+        let file = |content: usize, size, chunk_sizes: &[u64]| EntryType::File {
+            content: vec![Output::<blake3::Hasher>::default(); content],
+            size,
+            chunk_sizes: chunk_sizes.to_vec(),
+            unstable: false,
+        };
+        assert_eq!(file(2, 3, &[1, 2]).check_chunk_sizes(), Ok(()));
+        assert_eq!(file(0, 0, &[]).file_size(), Some(0));
+        // Snapshots without chunk sizes, or even file sizes.
+        assert_eq!(file(2, 3, &[]).check_chunk_sizes(), Ok(()));
+        assert_eq!(file(2, 0, &[]).file_size(), None);
+        assert_eq!(EntryType::Directory.file_size(), None);
+
+        assert!(file(2, 4, &[1, 2]).check_chunk_sizes().is_err());
+        assert!(file(3, 3, &[1, 2]).check_chunk_sizes().is_err());
+    }
+
+    #[test]
+    fn test_covering_chunks() {
+        // Chunks at 0..10, 10..15 and 15..30.
+        let sizes = [10, 5, 15];
+        assert_eq!(covering_chunks(&sizes, 0..30), (0..3, 0));
+        assert_eq!(covering_chunks(&sizes, 0..u64::MAX), (0..3, 0));
+        assert_eq!(covering_chunks(&sizes, 0..1), (0..1, 0));
+        assert_eq!(covering_chunks(&sizes, 9..11), (0..2, 0));
+        assert_eq!(covering_chunks(&sizes, 10..15), (1..2, 10));
+        assert_eq!(covering_chunks(&sizes, 12..16), (1..3, 10));
+        assert_eq!(covering_chunks(&sizes, 29..40), (2..3, 15));
+
+        // Nothing to read.
+        assert_eq!(covering_chunks(&sizes, 30..40).0, 3..3);
+        assert_eq!(covering_chunks(&sizes, 12..12).0.len(), 0);
+        assert_eq!(covering_chunks(&[], 0..10).0, 0..0);
+    }
+
+    #[test]
+    fn test_tags() {
+        let json = r#"{"version":1,"time":"1700000000.000000000","entries":[]}"#;
+        let manifest: SnapshotManifest = serde_json::from_str(json).unwrap();
+        assert!(manifest.tags.is_empty());
+        assert_eq!(manifest.description, None);
+        assert_eq!(serde_json::to_string(&manifest).unwrap(), json);
+
+        let json = r#"{"version":1,"tags":["release"],"description":"v1.0","time":"1700000000.000000000","entries":[]}"#;
+        let manifest: SnapshotManifest = serde_json::from_str(json).unwrap();
+        assert_eq!(manifest.tags, ["release"]);
+        assert_eq!(manifest.description.as_deref(), Some("v1.0"));
+        assert_eq!(serde_json::to_string(&manifest).unwrap(), json);
+    }
+
+    #[test]
+    fn test_version() {
+        let json = r#"{"time":"1700000000.000000000","entries":[]}"#;
+        let manifest = SnapshotManifest::from_bytes(json.as_bytes()).unwrap();
+        assert_eq!(manifest.version, 1);
+        assert_eq!(
+            serde_json::to_string(&manifest).unwrap(),
+            r#"{"version":1,"time":"1700000000.000000000","entries":[]}"#
+        );
+
+        // Unknown fields of the same version are ignored.
+        let json = r#"{"version":1,"time":"1700000000.000000000","entries":[],"new":true}"#;
+        assert!(SnapshotManifest::from_bytes(json.as_bytes()).is_ok());
+
+        let json = r#"{"version":2,"time":"1700000000.000000000","entries":[]}"#;
+        let err = SnapshotManifest::from_bytes(json.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("version 2 is not supported"));
+        // Even if the rest of the manifest has changed incompatibly.
+        let json = r#"{"version":2,"entries":{}}"#;
+        let err = SnapshotManifest::from_bytes(json.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("version 2 is not supported"));
+    }
+
+    #[test]
+    fn test_special_files() {
+        let json = r#"{"path":"/dev/null","type":"Device","kind":"char","rdev":259}"#;
+        let entry: EntryManifest = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            entry.ty,
+            EntryType::Device {
+                kind: DeviceKind::Char,
+                rdev: 259
+            }
+        ));
+        assert_eq!(serde_json::to_string(&entry).unwrap(), json);
+
+        let json = r#"{"path":"/run/pipe","type":"Fifo"}"#;
+        let entry: EntryManifest = serde_json::from_str(json).unwrap();
+        assert!(matches!(entry.ty, EntryType::Fifo));
+    }
+
+    #[test]
+    fn test_msgpack() {
+        let entry = |path: &str, ty| EntryManifest {
+            path: Utf8PathBuf::from(path),
+            ty,
+            mtime: Some(UNIX_EPOCH + Duration::new(1_700_000_000, 1)),
+            uid: Some(1000),
+            gid: None,
+            mode: Some(0o644),
+            xattrs: Some(BTreeMap::from([("user.test".to_owned(), vec![0, 255])])),
+        };
+        let manifest = SnapshotManifest {
+            version: MANIFEST_VERSION,
+            name: Some("home".to_owned()),
+            hostname: None,
+            paths: Vec::new(),
+            tags: vec!["release".to_owned()],
+            description: None,
+            time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            chunker: Some(ChunkerParams::LEGACY),
+            entries: vec![
+                entry("/a", EntryType::Directory),
+                entry(
+                    "/a/file",
+                    EntryType::File {
+                        content: vec![Output::<blake3::Hasher>::default()],
+                        size: 5,
+                        chunk_sizes: vec![5],
+                        unstable: true,
+                    },
+                ),
+                entry(
+                    "/a/symlink",
+                    EntryType::Symlink {
+                        target: "file".into(),
+                    },
+                ),
+            ],
+        };
+
+        let json = manifest.to_bytes(ManifestFormat::Json);
+        let msgpack = manifest.to_bytes(ManifestFormat::Msgpack);
+        assert_eq!(msgpack[0], MESSAGE_PACK_TAG);
+        assert!(msgpack.len() < json.len());
+        // Both formats are detected and parse to the same manifest.
+        for bytes in [&json, &msgpack] {
+            let parsed = SnapshotManifest::from_bytes(bytes).unwrap();
+            assert_eq!(parsed.to_bytes(ManifestFormat::Json), json);
+        }
+
+        let mut future = manifest;
+        future.version = MANIFEST_VERSION + 1;
+        let err =
+            SnapshotManifest::from_bytes(&future.to_bytes(ManifestFormat::Msgpack)).unwrap_err();
+        assert!(err.to_string().contains("is not supported"));
+    }
+}
@@ -0,0 +1,138 @@
+//! Upgrades of repositories to the latest format version, [`FORMAT_VERSION`].
+//!
+//! Every migration step upgrades a repository from one version to the next, and the version in
+//! the config is only bumped once the step is complete. Steps must be idempotent, so that an
+//! interrupted migration is resumed by running it again.
+//!
+//! Manifests are not converted between formats (e.g. to MessagePack): snapshot IDs are hashes of
+//! the manifests, so converting them would change the IDs.
+
+use anyhow::Context;
+use camino::Utf8Path;
+
+use crate::{
+    cli,
+    config::{Config, FORMAT_VERSION},
+    lock::RepoLock,
+    output::say,
+    repo::{self, INDEX_DIR, PACKS_DIR},
+};
+
+struct Migration {
+    /// Version the step upgrades from, to the next one.
+    from: u32,
+    description: &'static str,
+    run: fn(&Utf8Path, &Config) -> anyhow::Result<()>,
+}
+
+/// Registered migration steps, ordered by version.
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 0,
+    description: "move loose objects into packs and write the repository config",
+    run: pack_loose_objects,
+}];
+
+pub fn migrate(cmd: cli::Migrate) -> anyhow::Result<()> {
+    let remote = &cmd.remote;
+    // Rejects directories that are not repositories, and repositories of newer versions.
+    let mut config = Config::load(remote)?;
+    let _lock = RepoLock::exclusive(remote, &cmd.lock)?;
+    let mut version = config.version;
+
+    let steps = MIGRATIONS
+        .iter()
+        .skip_while(|it| it.from < version)
+        .collect::<Vec<_>>();
+    if steps.is_empty() {
+        say!("repository {remote} is already at format version {version}");
+        return Ok(());
+    }
+
+    for step in steps {
+        debug_assert_eq!(step.from, version, "migrations should cover every version");
+        if cmd.dry_run {
+            println!("{} -> {}: {}", step.from, step.from + 1, step.description);
+            continue;
+        }
+        say!(
+            "migrating from version {} to {}: {}",
+            step.from,
+            step.from + 1,
+            step.description
+        );
+        (step.run)(remote, &config)
+            .with_context(|| format!("failed to migrate {remote} to version {}", step.from + 1))?;
+        version = step.from + 1;
+        config.version = version;
+        config.save(remote)?;
+    }
+    if !cmd.dry_run {
+        say!("repository {remote} is at format version {FORMAT_VERSION}");
+    }
+    Ok(())
+}
+
+/// Version 0 to 1: objects of repositories from before packs were stored one per file, in
+/// subdirectories by hash prefix or, before that, directly in the repository root.
+fn pack_loose_objects(remote: &Utf8Path, config: &Config) -> anyhow::Result<()> {
+    for dir in [PACKS_DIR, INDEX_DIR] {
+        std::fs::create_dir_all(remote.join(dir))?;
+    }
+    let moved = repo::pack_loose(remote, config.pack_size)?;
+    say!("moved {moved} loose objects into packs");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use const_hex::ToHexExt;
+
+    use super::*;
+    use crate::{
+        cas::{ContentAddressableStorage, DirectoryCas},
+        cli::LockArgs,
+        snapshots::SNAPSHOTS_DIR,
+    };
+
+    #[test]
+    fn test_migrate() {
+        assert_eq!(MIGRATIONS.last().unwrap().from + 1, FORMAT_VERSION);
+
+        let dir = tempfile::tempdir().unwrap();
+        let remote = Utf8Path::from_path(dir.path()).unwrap();
+        std::fs::create_dir(remote.join(SNAPSHOTS_DIR)).unwrap();
+        let hash = DirectoryCas::<blake3::Hasher>::new(remote)
+            .store(Bytes::from_static(b"object"))
+            .unwrap();
+        let flat_hash = DirectoryCas::<blake3::Hasher>::new(remote)
+            .with_prefix_len(0)
+            .store(Bytes::from_static(b"flat object"))
+            .unwrap();
+        let migrate = || {
+            super::migrate(cli::Migrate {
+                remote: remote.to_owned(),
+                dry_run: false,
+                lock: LockArgs { break_lock: false },
+            })
+        };
+
+        assert_eq!(Config::load(remote).unwrap().version, 0);
+        migrate().unwrap();
+        let config = Config::load(remote).unwrap();
+        assert_eq!(config.version, FORMAT_VERSION);
+        let repo = repo::open(remote, None).unwrap();
+        assert_eq!(
+            repo::packed(&repo).loose().list().count(),
+            0,
+            "loose objects should be packed"
+        );
+        assert!(repo.contains(&hash).unwrap());
+        assert!(repo.contains(&flat_hash).unwrap());
+        assert!(!remote.join(flat_hash.encode_hex()).exists());
+
+        // Nothing left to do.
+        migrate().unwrap();
+        assert_eq!(Config::load(remote).unwrap(), config);
+    }
+}
@@ -0,0 +1,26 @@
+//! Messages about what a command did, printed to stdout unless `--quiet` is given. Output that a
+//! command is asked for (listings, reports, JSON, file content) is printed regardless.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Suppress messages printed with [`say`] afterwards.
+pub fn init(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Like `println!`, unless `--quiet` is given.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !$crate::output::is_quiet() {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use say;
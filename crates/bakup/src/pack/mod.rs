@@ -1,3 +1,5 @@
+mod pack_reader;
 mod pack_writer;
 
-pub use pack_writer::{IndexEntry, PackWriter};
+pub use pack_reader::PackReader;
+pub use pack_writer::{FinalizedPack, IndexEntry, PackWriter};
@@ -0,0 +1,150 @@
+use std::{
+    io::{self, ErrorKind},
+    marker::PhantomData,
+};
+
+use digest::{Digest, Output, typenum::Unsigned};
+
+use super::IndexEntry;
+
+/// Reader for the pack format produced by [`PackWriter`](super::PackWriter).
+///
+/// Blobs are located by their offset, as recorded in the pack index. Blobs are hashed with `H`.
+pub struct PackReader<B, H> {
+    data: B,
+    /// Size of the blob section, i.e., offset of the index at the end of the pack.
+    blobs_size: usize,
+    /// Size of the index.
+    index_size: usize,
+    hasher: PhantomData<H>,
+}
+
+impl<B: AsRef<[u8]>, H: Digest> PackReader<B, H> {
+    /// Wrap serialized pack `data`.
+    ///
+    /// Returns error if the index size recorded at the end of the pack doesn't fit in `data`, or
+    /// isn't a whole number of index entries.
+    pub fn new(data: B) -> io::Result<Self> {
+        let bytes = data.as_ref();
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "malformed pack");
+
+        let size_start = bytes
+            .len()
+            .checked_sub(size_of::<u32>())
+            .ok_or_else(invalid)?;
+        let index_size = u32::from_le_bytes(
+            bytes[size_start..]
+                .try_into()
+                .expect("slice is 4 bytes long"),
+        ) as usize;
+        let blobs_size = size_start.checked_sub(index_size).ok_or_else(invalid)?;
+        if !index_size.is_multiple_of(H::OutputSize::USIZE + size_of::<u32>()) {
+            return Err(invalid());
+        }
+
+        Ok(PackReader {
+            data,
+            blobs_size,
+            index_size,
+            hasher: PhantomData,
+        })
+    }
+
+    /// Iterate over the index at the end of the pack, checking that every entry points to a blob
+    /// with the same hash.
+    pub fn iter(&self) -> impl Iterator<Item = io::Result<IndexEntry<H>>> + '_ {
+        let index = &self.data.as_ref()[self.blobs_size..][..self.index_size];
+        index
+            .chunks_exact(H::OutputSize::USIZE + size_of::<u32>())
+            .map(|entry| {
+                let (hash, offset) = entry.split_at(H::OutputSize::USIZE);
+                let entry = IndexEntry::<H> {
+                    hash: Output::<H>::clone_from_slice(hash),
+                    offset: u32::from_le_bytes(offset.try_into().expect("slice is 4 bytes long")),
+                };
+                let (hash, _) = self.blob(entry.offset)?;
+                if *hash != entry.hash {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "pack index doesn't match the blobs",
+                    ));
+                }
+                Ok(entry)
+            })
+    }
+
+    /// Read the hash and data of the blob at `offset`.
+    pub fn blob(&self, offset: u32) -> io::Result<(&Output<H>, &[u8])> {
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "blob offset is out of bounds");
+
+        let blobs = &self.data.as_ref()[..self.blobs_size];
+        let header = blobs.get(offset as usize..).ok_or_else(invalid)?;
+        let (hash, rest) = header
+            .split_at_checked(H::OutputSize::USIZE)
+            .ok_or_else(invalid)?;
+        let (size, rest) = rest
+            .split_at_checked(size_of::<u32>())
+            .ok_or_else(invalid)?;
+        let size = u32::from_le_bytes(size.try_into().expect("slice is 4 bytes long")) as usize;
+        let data = rest.get(..size).ok_or_else(invalid)?;
+
+        Ok((Output::<H>::from_slice(hash), data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::PackWriter;
+
+    type Reader<B> = PackReader<B, blake3::Hasher>;
+
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_read_blobs(blobs: Vec<Vec<u8>>) {
+            let mut output = Vec::new();
+            let mut pack_writer = PackWriter::new(&mut output);
+            for blob in &blobs {
+                let hash = blake3::Hasher::digest(blob);
+                pack_writer.write(hash, blob).unwrap();
+            }
+            let index = pack_writer.finalize().unwrap().index;
+
+            let reader = Reader::new(&output).unwrap();
+            prop_assert_eq!(reader.iter().collect::<io::Result<Vec<_>>>().unwrap(), index.clone());
+            for entry in index {
+                let (hash, data) = reader.blob(entry.offset).unwrap();
+                prop_assert_eq!(hash, &entry.hash);
+                prop_assert_eq!(&blake3::Hasher::digest(data), hash);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_malformed_pack() {
+        assert!(Reader::new(b"\x01\x00").is_err());
+        assert!(Reader::new(b"\xff\x00\x00\x00").is_err());
+        // Index size is not a multiple of the entry size.
+        assert!(Reader::new(b"\x00\x01\x00\x00\x00").is_err());
+
+        let reader = Reader::new(b"\x00\x00\x00\x00").unwrap();
+        assert!(reader.blob(0).is_err());
+        assert!(reader.blob(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn test_rejects_truncated_blob() {
+        let mut output = Vec::new();
+        let mut pack_writer = PackWriter::<_, blake3::Hasher>::new(&mut output);
+        pack_writer.write([1u8; 32].into(), b"hello").unwrap();
+        pack_writer.finalize().unwrap();
+
+        // Claim the blob is longer than the blob section.
+        output[32] = 100;
+        let reader = Reader::new(&output).unwrap();
+        assert!(reader.blob(0).is_err());
+        assert!(reader.iter().next().unwrap().is_err());
+    }
+}
@@ -1,27 +1,89 @@
-use std::io::{self, ErrorKind, Write};
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct IndexEntry<const HASH_SIZE: usize> {
-    pub hash: [u8; HASH_SIZE],
+use digest::{Digest, Output, typenum::Unsigned};
+
+use super::PackReader;
+
+/// Blob of a pack, hashed with `H`, and its offset in the pack.
+pub struct IndexEntry<H: Digest> {
+    pub hash: Output<H>,
     pub offset: u32,
 }
 
+// Implemented by hand, as derives would require `H` itself to implement the traits.
+impl<H: Digest> Clone for IndexEntry<H> {
+    fn clone(&self) -> Self {
+        IndexEntry {
+            hash: self.hash.clone(),
+            offset: self.offset,
+        }
+    }
+}
+
+impl<H: Digest> Copy for IndexEntry<H> where Output<H>: Copy {}
+
+impl<H: Digest> fmt::Debug for IndexEntry<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndexEntry")
+            .field("hash", &self.hash)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+impl<H: Digest> PartialEq for IndexEntry<H> {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.offset == other.offset
+    }
+}
+
+impl<H: Digest> Eq for IndexEntry<H> {}
+
 #[derive(Debug)]
-pub struct FinalizedPack<W, const HASH_SIZE: usize> {
+pub struct FinalizedPack<W, H: Digest> {
     pub writer: W,
-    pub index: Vec<IndexEntry<HASH_SIZE>>,
+    pub index: Vec<IndexEntry<H>>,
 }
 
-pub struct PackWriter<W, const HASH_SIZE: usize> {
+impl<W: AsRef<[u8]>, H: Digest> FinalizedPack<W, H> {
+    /// Check that the pack written to `writer` reads back with [`PackReader`]: its index must equal
+    /// `index`, and every blob must match its hash.
+    pub fn verify(&self) -> io::Result<()> {
+        let reader = PackReader::<_, H>::new(self.writer.as_ref())?;
+        let mismatch = |msg| io::Error::new(ErrorKind::InvalidData, msg);
+
+        let mut expected = self.index.iter();
+        for entry in reader.iter() {
+            let entry = entry?;
+            if expected.next() != Some(&entry) {
+                return Err(mismatch("pack index doesn't match the written index"));
+            }
+            let (_, data) = reader.blob(entry.offset)?;
+            if H::digest(data) != entry.hash {
+                return Err(mismatch("pack blob doesn't match its hash"));
+            }
+        }
+        if expected.next().is_some() {
+            return Err(mismatch("pack index is missing entries"));
+        }
+        Ok(())
+    }
+}
+
+/// Writer of packs of blobs hashed with `H`.
+pub struct PackWriter<W, H: Digest> {
     writer: W,
     written_size: usize,
-    index: Vec<IndexEntry<HASH_SIZE>>,
+    index: Vec<IndexEntry<H>>,
 }
 
 // TODO: pack files need some kind of header
 
-impl<W: Write, const HASH_SIZE: usize> PackWriter<W, HASH_SIZE> {
-    pub fn new(writer: W) -> PackWriter<W, HASH_SIZE> {
+impl<W: Write, H: Digest> PackWriter<W, H> {
+    pub fn new(writer: W) -> PackWriter<W, H> {
         PackWriter {
             writer,
             written_size: 0,
@@ -29,17 +91,32 @@ impl<W: Write, const HASH_SIZE: usize> PackWriter<W, HASH_SIZE> {
         }
     }
 
-    pub fn write(&mut self, hash: [u8; HASH_SIZE], data: &[u8]) -> io::Result<()> {
+    pub fn write(&mut self, hash: Output<H>, data: &[u8]) -> io::Result<()> {
         let data_size = u32::try_from(data.len()).map_err(|_| ErrorKind::InvalidInput)?;
+        self.write_from(hash, data, data_size)
+    }
+
+    /// Write a blob of `len` bytes, streaming them from `reader` rather than holding the whole blob
+    /// in memory.
+    ///
+    /// Returns error if `reader` ends before `len` bytes. The pack is unusable after any error, as
+    /// the header and part of the data may already have been written.
+    pub fn write_from(&mut self, hash: Output<H>, reader: impl Read, len: u32) -> io::Result<()> {
         let offset = u32::try_from(self.written_size).map_err(|_| ErrorKind::FileTooLarge)?;
 
         // header
         self.writer.write_all(&hash)?;
-        self.writer.write_all(&data_size.to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?;
         // data
-        self.writer.write_all(data)?;
+        let copied = io::copy(&mut reader.take(len.into()), &mut self.writer)?;
+        if copied != u64::from(len) {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("blob ended after {copied} of {len} bytes"),
+            ));
+        }
 
-        self.written_size += HASH_SIZE + size_of::<u32>() + data.len();
+        self.written_size += H::OutputSize::USIZE + size_of::<u32>() + len as usize;
 
         self.index.push(IndexEntry { hash, offset });
 
@@ -52,20 +129,20 @@ impl<W: Write, const HASH_SIZE: usize> PackWriter<W, HASH_SIZE> {
 
     /// How much adding the item would contribute to pack size.
     pub const fn item_size(data_size: usize) -> usize {
-        let header = /* hash: */ HASH_SIZE + /* size: */ size_of::<u32>();
-        let index_overhead = /* hash: */ HASH_SIZE + /* offset: */ size_of::<u32>();
+        let header = /* hash: */ H::OutputSize::USIZE + /* size: */ size_of::<u32>();
+        let index_overhead = /* hash: */ H::OutputSize::USIZE + /* offset: */ size_of::<u32>();
         header + data_size + index_overhead
     }
 
     fn index_size(&self) -> usize {
         // Index format is: (N-bit hash, u32 offset)
-        self.index.len() * (HASH_SIZE + size_of::<u32>())
+        self.index.len() * (H::OutputSize::USIZE + size_of::<u32>())
     }
 
     /// Finalize the pack file by writing its index at the end of the file.
     ///
     /// Returns the writer and the index.
-    pub fn finalize(mut self) -> io::Result<FinalizedPack<W, HASH_SIZE>> {
+    pub fn finalize(mut self) -> io::Result<FinalizedPack<W, H>> {
         self.finalize_inner()?;
         Ok(FinalizedPack {
             writer: self.writer,
@@ -103,9 +180,9 @@ mod tests {
         fn test_final_size(blobs: Vec<Vec<u8>>) {
             let mut output = Vec::new();
 
-            let mut pack_writer = PackWriter::new(&mut output);
+            let mut pack_writer = PackWriter::<_, blake3::Hasher>::new(&mut output);
             for blob in &blobs {
-                let hash: [u8; 32] = blake3::hash(blob).into();
+                let hash = blake3::Hasher::digest(blob);
                 pack_writer.write(hash, blob).unwrap();
             }
 
@@ -121,11 +198,11 @@ mod tests {
         fn test_index(blobs: Vec<Vec<u8>>) {
             let mut output = Vec::new();
 
-            let mut pack_writer = PackWriter::new(&mut output);
+            let mut pack_writer = PackWriter::<_, blake3::Hasher>::new(&mut output);
 
             let mut input_hashes = HashSet::new();
             for blob in &blobs {
-                let hash: [u8; 32] = blake3::hash(blob).into();
+                let hash = blake3::Hasher::digest(blob);
                 input_hashes.insert(hash);
                 pack_writer.write(hash, blob).unwrap();
             }
@@ -141,10 +218,10 @@ mod tests {
         fn test_index_is_sorted(blobs: Vec<Vec<u8>>) {
             let mut output = Vec::new();
 
-            let mut pack_writer = PackWriter::new(&mut output);
+            let mut pack_writer = PackWriter::<_, blake3::Hasher>::new(&mut output);
 
             for blob in &blobs {
-                let hash: [u8; 32] = blake3::hash(blob).into();
+                let hash = blake3::Hasher::digest(blob);
                 pack_writer.write(hash, blob).unwrap();
             }
 
@@ -153,4 +230,80 @@ mod tests {
             prop_assert!(pack.index.is_sorted_by(|a,b| a.hash <= b.hash));
         }
     }
+
+    proptest! {
+        #[test]
+        fn test_verify(blobs: Vec<Vec<u8>>) {
+            let mut pack_writer = PackWriter::<_, blake3::Hasher>::new(Vec::new());
+            for blob in &blobs {
+                let hash = blake3::Hasher::digest(blob);
+                pack_writer.write(hash, blob).unwrap();
+            }
+
+            let pack = pack_writer.finalize().unwrap();
+            prop_assert!(pack.verify().is_ok());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_write_from(blobs: Vec<Vec<u8>>) {
+            let write = |streamed: bool| {
+                let mut pack_writer = PackWriter::<_, blake3::Hasher>::new(Vec::new());
+                for blob in &blobs {
+                    let hash = blake3::Hasher::digest(blob);
+                    if streamed {
+                        let len = blob.len() as u32;
+                        pack_writer.write_from(hash, io::Cursor::new(blob), len).unwrap();
+                    } else {
+                        pack_writer.write(hash, blob).unwrap();
+                    }
+                }
+                let size = pack_writer.size();
+                let pack = pack_writer.finalize().unwrap();
+                (size, pack.writer, pack.index)
+            };
+
+            prop_assert_eq!(write(true), write(false));
+        }
+    }
+
+    #[test]
+    fn test_write_from_short_reader() {
+        let mut pack_writer = PackWriter::<_, blake3::Hasher>::new(Vec::new());
+        let err = pack_writer
+            .write_from([1u8; 32].into(), &b"hello"[..], 10)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_verify_detects_drift() {
+        let mut pack_writer = PackWriter::<_, blake3::Hasher>::new(Vec::new());
+        for blob in [&b"hello"[..], b"world"] {
+            pack_writer
+                .write(blake3::Hasher::digest(blob), blob)
+                .unwrap();
+        }
+        let pack = pack_writer.finalize().unwrap();
+
+        let mut stale = FinalizedPack {
+            writer: pack.writer.clone(),
+            index: pack.index.clone(),
+        };
+        stale.index[1].offset += 1;
+        assert!(stale.verify().is_err());
+
+        let mut missing = FinalizedPack {
+            writer: pack.writer.clone(),
+            index: pack.index.clone(),
+        };
+        missing.index.pop();
+        assert!(missing.verify().is_err());
+
+        // Corrupt the data of the first blob.
+        let mut corrupt = pack;
+        corrupt.writer[36] ^= 1;
+        assert!(corrupt.verify().is_err());
+    }
 }
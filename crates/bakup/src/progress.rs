@@ -0,0 +1,33 @@
+//! Progress bars on stderr, shown according to `--progress`.
+
+use std::sync::OnceLock;
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::cli::ProgressMode;
+
+static MODE: OnceLock<ProgressMode> = OnceLock::new();
+
+/// Set the mode of all progress bars drawn afterwards.
+pub fn init(mode: ProgressMode) {
+    MODE.set(mode).expect("progress mode should be set once");
+}
+
+/// Where to draw progress bars: to stderr if it's a terminal, unless `--progress` says
+/// otherwise.
+pub fn draw_target() -> ProgressDrawTarget {
+    match MODE.get().copied().unwrap_or(ProgressMode::Auto) {
+        ProgressMode::Auto => ProgressDrawTarget::stderr(),
+        // `ProgressDrawTarget::stderr` skips drawing if stderr is not a terminal.
+        ProgressMode::Always => {
+            ProgressDrawTarget::term_like_with_hz(Box::new(console::Term::buffered_stderr()), 20)
+        }
+        ProgressMode::Never => ProgressDrawTarget::hidden(),
+    }
+}
+
+/// Progress bar of `len` steps, drawn with `template`.
+pub fn bar(len: u64, template: &str) -> ProgressBar {
+    ProgressBar::with_draw_target(Some(len), draw_target())
+        .with_style(ProgressStyle::with_template(template).unwrap())
+}
@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::{Context, bail};
+use const_hex::ToHexExt;
+use indicatif::HumanBytes;
+use rayon::prelude::*;
+
+use crate::{
+    cas::ContentAddressableStorage,
+    cli,
+    lock::RepoLock,
+    manifest::EntryType,
+    output::say,
+    progress,
+    repo::{self, Repository},
+    retention::{self, Policy},
+    snapshots::{self, SnapshotId},
+    status::UsageError,
+};
+
+/// Remove references to the selected snapshots, or to the ones not kept by the retention policy.
+/// Their data is only deleted by `prune`.
+pub fn forget(cmd: cli::Forget) -> anyhow::Result<()> {
+    let cas = repo::open(&cmd.remote, cmd.identity.as_deref())?;
+    let mut forgotten = Vec::new();
+    for selector in &cmd.snapshots {
+        let (id, _) = snapshots::resolve(&cmd.remote, &cas, selector)?;
+        forgotten.push(id);
+    }
+    if cmd.snapshots.is_empty() {
+        forgotten = apply_policy(&cmd, &cas)?;
+    }
+
+    for id in forgotten {
+        if cmd.dry_run {
+            say!("would forget snapshot {}", id.encode_hex());
+        } else {
+            snapshots::remove_ref(&cmd.remote, id)?;
+            say!("forgot snapshot {}", id.encode_hex());
+        }
+    }
+
+    if cmd.prune && !cmd.dry_run {
+        prune(cli::Prune {
+            remote: cmd.remote,
+            identity: cmd.identity,
+            lock: cmd.lock,
+        })?;
+    }
+    Ok(())
+}
+
+/// Snapshots that the retention policy of `cmd` doesn't keep.
+fn apply_policy(cmd: &cli::Forget, cas: &Repository) -> anyhow::Result<Vec<SnapshotId>> {
+    let keep = &cmd.keep;
+    let policy = Policy {
+        last: keep.keep_last.unwrap_or(0),
+        hourly: keep.keep_hourly.unwrap_or(0),
+        daily: keep.keep_daily.unwrap_or(0),
+        weekly: keep.keep_weekly.unwrap_or(0),
+        monthly: keep.keep_monthly.unwrap_or(0),
+        yearly: keep.keep_yearly.unwrap_or(0),
+        tags: keep.keep_tag.clone(),
+    };
+    if policy.is_empty() {
+        bail!(UsageError(
+            "refusing to forget all snapshots: the retention policy keeps none".to_owned()
+        ));
+    }
+
+    // Snapshots of every name, with their times and tags.
+    let mut groups = BTreeMap::<_, Vec<_>>::new();
+    for id in snapshots::list(&cmd.remote)? {
+        // Forgetting is decided by the other snapshots too, so an unreadable one aborts it.
+        let manifest = snapshots::load(cas, id).context("refusing to apply retention policy")?;
+        groups
+            .entry(manifest.name)
+            .or_default()
+            .push((id, manifest.time, manifest.tags));
+    }
+
+    let mut forgotten = Vec::new();
+    for (name, snapshots) in groups {
+        let decision = retention::apply(&policy, &snapshots, retention::local_time);
+        say!(
+            "{}: keeping {} snapshots, forgetting {}",
+            name.as_deref().unwrap_or("unnamed snapshots"),
+            decision.keep.len(),
+            decision.forget.len()
+        );
+        forgotten.extend(decision.forget);
+    }
+    Ok(forgotten)
+}
+
+/// Remove all objects that are not reachable from any snapshot.
+pub fn prune(cmd: cli::Prune) -> anyhow::Result<()> {
+    let _lock = RepoLock::exclusive(&cmd.remote, &cmd.lock)?;
+    let cas = repo::open(&cmd.remote, cmd.identity.as_deref())?;
+
+    // Mark. Any unreadable manifest aborts pruning, as its chunks can't be told apart from
+    // garbage.
+    let mut reachable = HashSet::new();
+    let ids = snapshots::list(&cmd.remote)?;
+    let progress = progress::bar(ids.len() as u64, "{wide_bar} {pos}/{len} snapshots");
+    for id in ids {
+        progress.inc(1);
+        let manifest = snapshots::load(&cas, id).context("refusing to prune")?;
+        reachable.insert(id);
+        for entry in manifest.entries {
+            if let EntryType::File { content, .. } = entry.ty {
+                reachable.extend(content);
+            }
+        }
+    }
+
+    progress.finish_and_clear();
+
+    // Sweep. Packs are rewritten without unreachable objects, and objects stored before the
+    // repository used packs are removed one by one.
+    let packed = repo::packed(&cas);
+    let stats = packed.repack(|hash| reachable.contains(hash))?;
+    let mut removed = stats.removed_objects;
+    let mut removed_bytes = stats.removed_bytes;
+    let loose = packed.loose();
+    let unreachable = loose
+        .inner()
+        .par_list()
+        .filter(|hash| !hash.as_ref().is_ok_and(|it| reachable.contains(it)))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let progress = progress::bar(unreachable.len() as u64, "{wide_bar} {pos}/{len} objects");
+    for hash in progress.wrap_iter(unreachable.into_iter()) {
+        let size = loose.size(&hash)?.unwrap_or(0);
+        if loose.remove(&hash)? {
+            removed += 1;
+            removed_bytes += size;
+        }
+    }
+
+    progress.finish_and_clear();
+
+    say!(
+        "removed {removed} objects ({}), kept {} objects",
+        HumanBytes(removed_bytes),
+        reachable.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use anyhow::bail;
+use const_hex::ToHexExt;
+
+use crate::{cli, config::Config, lock::RepoLock, output::say, repo, status};
+
+/// Recover the index of the repository from its packs. Malformed packs are reported, and their
+/// objects are left out of the index.
+pub fn rebuild_index(cmd: cli::RebuildIndex) -> anyhow::Result<()> {
+    // Make sure it's a repository before replacing anything in it.
+    Config::load(&cmd.remote)?;
+    let _lock = RepoLock::exclusive(&cmd.remote, &cmd.lock)?;
+    let stats = repo::rebuild_index(&cmd.remote)?;
+
+    for (pack, err) in &stats.malformed_packs {
+        status::error(format_args!(
+            "pack {} is malformed: {err}",
+            pack.encode_hex()
+        ));
+    }
+    say!("indexed {} objects in {} packs", stats.objects, stats.packs);
+    if !stats.malformed_packs.is_empty() {
+        bail!(
+            "{} malformed packs were not indexed",
+            stats.malformed_packs.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,167 @@
+//! Object storage of a repository.
+
+use std::{num::NonZeroU64, sync::Arc};
+
+use anyhow::{Context, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use digest::Output;
+use ed25519_dalek::SigningKey;
+use itertools::Either;
+use rand_core::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    cas::{
+        CompressingCas, Compression, CountingCas, DirectoryCas, EncryptedCas, PackedCas,
+        RateLimiter, RebuildStats, ThrottlingCas,
+    },
+    config::Config,
+    keys::Key,
+    status::UsageError,
+};
+
+/// Subdirectory of the repository with packs of objects.
+pub const PACKS_DIR: &str = "packs";
+/// Subdirectory of the repository with indexes of the packs.
+pub const INDEX_DIR: &str = "index";
+
+type Backend = ThrottlingCas<DirectoryCas<blake3::Hasher>>;
+type Packed = PackedCas<Backend, blake3::Hasher>;
+type Store = CountingCas<Packed>;
+
+/// Objects (file chunks and snapshot manifests) of the repository, keyed by blake3 hash of their
+/// uncompressed content. Objects are compressed before being encrypted, if the repository is
+/// encrypted, and then packed. Writes are counted for the snapshot summary.
+///
+/// New objects are only persisted by [`packed`]`(repo).flush()`.
+pub type Repository = CompressingCas<Either<Store, EncryptedCas<Store>>>;
+
+/// Open objects of the repository at `remote` for reading, decrypting them with the key at
+/// `key_path` if given, or with the one given by the environment (see [`Key::resolve`]).
+pub fn open(remote: &Utf8Path, key_path: Option<&Utf8Path>) -> anyhow::Result<Repository> {
+    let config = Config::load(remote)?;
+    open_for_writing(remote, &config, key_path, &[], Compression::None, None)
+}
+
+/// Open objects of the repository at `remote`, compressing new objects with `compression`.
+///
+/// New objects are encrypted to `recipients` and to the key at `key_path` (or given by the
+/// environment), and signed with that key (or with a random one if there is no key). Without any
+/// of them, objects are stored unencrypted.
+///
+/// Writes to the repository are limited to `upload_limit` bytes per second, if given.
+///
+/// Objects are read back regardless of how they were compressed, and of whether they were
+/// encrypted if the key is given.
+pub fn open_for_writing(
+    remote: &Utf8Path,
+    config: &Config,
+    key_path: Option<&Utf8Path>,
+    recipients: &[PublicKey],
+    compression: Compression,
+    upload_limit: Option<NonZeroU64>,
+) -> anyhow::Result<Repository> {
+    let key = Key::resolve(key_path)?;
+    // Loose objects, packs and indexes share the limit.
+    let limiter = upload_limit.map(|rate| Arc::new(RateLimiter::new(rate)));
+    let backend = |path: Utf8PathBuf| {
+        let backend = ThrottlingCas::new(DirectoryCas::new(path));
+        match &limiter {
+            Some(limiter) => backend.with_limiter(limiter.clone()),
+            None => backend,
+        }
+    };
+    let packed = PackedCas::open(
+        backend(remote.to_owned()),
+        backend(remote.join(PACKS_DIR)),
+        backend(remote.join(INDEX_DIR)),
+    )
+    .with_context(|| format!("failed to read index of repository {remote}"))?
+    .with_pack_size(config.pack_size);
+    let store = CountingCas::new(packed);
+    if key.is_none() && recipients.is_empty() {
+        if config.encrypted {
+            bail!(UsageError(format!(
+                "repository {remote} is encrypted, but no key is given"
+            )));
+        }
+        return Ok(CompressingCas::new(Either::Left(store), compression));
+    }
+
+    let mut recipients = recipients.to_vec();
+    let sender = match &key {
+        Some(key) => {
+            recipients.push(key.recipient());
+            key.signing_key.clone()
+        }
+        None => SigningKey::generate(&mut OsRng),
+    };
+    let mut store = EncryptedCas::new(store, sender, recipients);
+    if config.encrypted {
+        store = store.require_encryption();
+    }
+    if let Some(key) = key {
+        store = store.with_identity(key.identity.clone());
+    }
+    Ok(CompressingCas::new(Either::Right(store), compression))
+}
+
+/// Replace the indexes of the repository at `remote` with one built from its packs. Packs hold
+/// objects as stored, so no key is needed.
+pub fn rebuild_index(remote: &Utf8Path) -> anyhow::Result<RebuildStats<Output<blake3::Hasher>>> {
+    let backend = |path: Utf8PathBuf| ThrottlingCas::new(DirectoryCas::new(path));
+    let (_, stats) = Packed::rebuild(
+        backend(remote.to_owned()),
+        backend(remote.join(PACKS_DIR)),
+        backend(remote.join(INDEX_DIR)),
+    )
+    .with_context(|| format!("failed to rebuild index of repository {remote}"))?;
+    Ok(stats)
+}
+
+/// Move loose objects of the repository at `remote` into packs of `pack_size`, returning the
+/// number of moved objects. Like indexes, packs hold objects as stored, so no key is needed.
+///
+/// Both layouts of loose objects are packed: sharded into subdirectories by hash prefix, and flat
+/// in the repository root, as they were stored before sharding.
+pub fn pack_loose(remote: &Utf8Path, pack_size: usize) -> anyhow::Result<u64> {
+    let backend = |path: Utf8PathBuf| ThrottlingCas::new(DirectoryCas::new(path));
+    let mut moved = 0;
+    for loose in [
+        DirectoryCas::new(remote),
+        DirectoryCas::new(remote).with_prefix_len(0),
+    ] {
+        let packed = Packed::open(
+            ThrottlingCas::new(loose),
+            backend(remote.join(PACKS_DIR)),
+            backend(remote.join(INDEX_DIR)),
+        )
+        .with_context(|| format!("failed to read index of repository {remote}"))?
+        .with_pack_size(pack_size);
+        moved += packed
+            .pack_loose()
+            .with_context(|| format!("failed to pack loose objects of repository {remote}"))?;
+    }
+    Ok(moved)
+}
+
+/// Counter of objects written to `repo`.
+pub fn counter(repo: &Repository) -> &Store {
+    match repo.inner() {
+        Either::Left(store) => store,
+        Either::Right(store) => store.inner(),
+    }
+}
+
+/// Secret key objects of `repo` are decrypted with, if any.
+pub fn identity(repo: &Repository) -> Option<&StaticSecret> {
+    match repo.inner() {
+        Either::Left(_) => None,
+        Either::Right(store) => store.identity(),
+    }
+}
+
+/// Packed storage of `repo`.
+pub fn packed(repo: &Repository) -> &Packed {
+    counter(repo).inner()
+}
@@ -0,0 +1,483 @@
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fs::{File, Permissions},
+    io::{self, Write},
+    num::NonZeroUsize,
+    os::unix::fs::PermissionsExt,
+    sync::{Mutex, mpsc},
+};
+
+use anyhow::{Context, anyhow, bail};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use const_hex::ToHexExt;
+use digest::Output;
+use filetime::FileTime;
+use indicatif::ProgressBar;
+
+use crate::{
+    cas::ContentAddressableStorage,
+    cli::{self, LockArgs},
+    lock::RepoLock,
+    manifest::{DeviceKind, EntryManifest, EntryType, SnapshotManifest},
+    progress, repo,
+    snapshot::{Repository, RestoreOptions, SnapshotId},
+    snapshots,
+    status::{self, IntegrityError, UsageError},
+};
+
+impl Repository {
+    /// Restore the snapshot `id` into the directory `target`, which is created if it doesn't
+    /// exist.
+    ///
+    /// Entries that can't be created by the current user (like device nodes) are skipped with a
+    /// warning on stderr, and progress is shown there if it is a terminal.
+    pub fn restore(
+        &self,
+        id: &SnapshotId,
+        target: &Utf8Path,
+        opts: &RestoreOptions,
+    ) -> anyhow::Result<()> {
+        let _lock = RepoLock::shared(
+            &self.remote,
+            &LockArgs {
+                break_lock: opts.break_lock,
+            },
+        )?;
+        // A `prune` since opening may have moved the chunks to other packs.
+        repo::packed(&self.objects).reload()?;
+        let manifest = snapshots::load(&self.objects, *id)?;
+        self.restore_manifest(&manifest, target, opts)
+    }
+
+    /// Restore the entries of `manifest` into `target`, with the repository already locked.
+    fn restore_manifest(
+        &self,
+        manifest: &SnapshotManifest,
+        target: &Utf8Path,
+        opts: &RestoreOptions,
+    ) -> anyhow::Result<()> {
+        std::fs::create_dir_all(target)?;
+        if !opts.force && target.read_dir()?.next().is_some() {
+            bail!(UsageError(format!(
+                "target directory {target} is not empty (use --force to restore into it anyway)"
+            )));
+        }
+        let target = target.canonicalize_utf8()?;
+
+        // Map all paths before touching the filesystem, so a malicious manifest is rejected as a
+        // whole.
+        let entries = manifest
+            .entries
+            .iter()
+            .map(|entry| Ok((target_path(&target, &entry.path)?, entry)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let total = entries
+            .iter()
+            .map(|(_, entry)| match entry.ty {
+                EntryType::File { size, .. } => size,
+                _ => 0,
+            })
+            .sum();
+        let progress = progress::bar(
+            total,
+            "{wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, {eta} left)",
+        );
+
+        // Entries are sorted by path, so parent directories come before their contents.
+        let mut skipped = Vec::new();
+        for (path, entry) in &entries {
+            if !restore_entry(
+                &self.objects,
+                &target,
+                path,
+                entry,
+                opts.read_concurrency,
+                &progress,
+            )
+            .with_context(|| format!("failed to restore {}", entry.path))?
+            {
+                skipped.push(path);
+            }
+        }
+        progress.finish_and_clear();
+
+        // Hard link targets may come after the link itself, so create links once all files exist.
+        for (path, entry) in &entries {
+            if let EntryType::Hardlink {
+                target: link_target,
+            } = &entry.ty
+            {
+                restore_hardlink(&target, path, link_target)
+                    .with_context(|| format!("failed to restore {}", entry.path))?;
+            }
+        }
+
+        // Restore metadata in reverse order, so that creating files doesn't change modification
+        // time of already restored directories.
+        for (path, entry) in entries.iter().rev() {
+            if skipped.contains(&path) {
+                continue;
+            }
+            restore_metadata(path, entry, opts.xattrs)
+                .with_context(|| format!("failed to restore metadata of {}", entry.path))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn restore(cmd: cli::Restore) -> anyhow::Result<()> {
+    // Locked before opening, so the loaded pack indexes stay valid.
+    let _lock = RepoLock::shared(&cmd.remote, &cmd.lock)?;
+    let repo = Repository::open(&cmd.remote, cmd.identity.as_deref())?;
+    let opts = RestoreOptions {
+        force: cmd.force,
+        xattrs: cmd.xattrs,
+        read_concurrency: cmd.read_concurrency,
+        break_lock: cmd.lock.break_lock,
+    };
+    let (_, manifest) = snapshots::resolve(&cmd.remote, &repo.objects, &cmd.snapshot)?;
+    repo.restore_manifest(&manifest, &cmd.target, &opts)
+}
+
+/// Map absolute snapshot `path` to a path inside `target`.
+///
+/// Paths with `.` or `..` components are rejected, so a manifest can't write outside of `target`.
+fn target_path(target: &Utf8Path, path: &Utf8Path) -> anyhow::Result<Utf8PathBuf> {
+    let mut result = target.to_owned();
+    for component in path.components() {
+        match component {
+            Utf8Component::Prefix(_) | Utf8Component::RootDir => {}
+            Utf8Component::Normal(name) => result.push(name),
+            Utf8Component::CurDir | Utf8Component::ParentDir => {
+                bail!("refusing to restore {path}: path escapes the target directory")
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Restore `entry` to `path`, returning `false` if it's skipped because it can't be created by the
+/// current user.
+fn restore_entry(
+    cas: &repo::Repository,
+    target: &Utf8Path,
+    path: &Utf8Path,
+    entry: &EntryManifest,
+    read_concurrency: NonZeroUsize,
+    progress: &ProgressBar,
+) -> anyhow::Result<bool> {
+    if let Some(parent) = path.parent()
+        && path != target
+    {
+        std::fs::create_dir_all(parent)?;
+        // Already restored (or pre-existing) symlinks could otherwise redirect writes outside.
+        if !parent.canonicalize_utf8()?.starts_with(target) {
+            bail!(
+                "refusing to restore {}: parent is outside of target directory",
+                entry.path
+            );
+        }
+    }
+
+    match &entry.ty {
+        EntryType::Directory => {
+            if path.symlink_metadata().is_ok_and(|it| !it.is_dir()) {
+                std::fs::remove_file(path)?;
+            }
+            std::fs::create_dir_all(path)?;
+        }
+        EntryType::File {
+            content, unstable, ..
+        } => {
+            if *unstable {
+                progress.suspend(|| {
+                    status::note(format_args!(
+                        "{} changed while being backed up, its content may be inconsistent",
+                        entry.path
+                    ))
+                });
+            }
+            remove_non_dir(path)?;
+            let file = File::create_new(path)?;
+            let written = write_chunks(cas, content, read_concurrency, progress.wrap_write(file))?;
+            // Chunks are checked against their hashes, so a mismatch means that the manifest lists
+            // wrong ones.
+            if let Some(size) = entry.ty.file_size()
+                && written != size
+            {
+                bail!(IntegrityError(format!(
+                    "restored {written} bytes, but the snapshot recorded {size}"
+                )));
+            }
+        }
+        EntryType::Symlink { target } => {
+            remove_non_dir(path)?;
+            std::os::unix::fs::symlink(target, path)?;
+        }
+        // Restored separately, after the files they point to.
+        EntryType::Hardlink { .. } => {}
+        EntryType::Fifo => {
+            remove_non_dir(path)?;
+            mknod(path, libc::S_IFIFO, 0)?;
+        }
+        EntryType::Socket => {
+            remove_non_dir(path)?;
+            mknod(path, libc::S_IFSOCK, 0)?;
+        }
+        EntryType::Device { kind, rdev } => {
+            remove_non_dir(path)?;
+            let kind = match kind {
+                DeviceKind::Block => libc::S_IFBLK,
+                DeviceKind::Char => libc::S_IFCHR,
+            };
+            match mknod(path, kind, *rdev) {
+                // Only root can create device nodes.
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                    progress.suspend(|| {
+                        status::warn(format_args!("skipping device {}: {err}", entry.path))
+                    });
+                    return Ok(false);
+                }
+                result => result?,
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Fetch chunks with `hashes` from `cas` and write them to `writer` in order, returning the number
+/// of bytes written. Fails with [`IntegrityError`] if a chunk is missing or doesn't match its hash.
+///
+/// Up to `concurrency` chunks are fetched at once by worker threads. Fetched chunks wait in a
+/// reorder buffer until all preceding ones are written, and a new fetch only starts once a chunk
+/// is written, so at most `concurrency` chunks are held in memory.
+fn write_chunks<C>(
+    cas: &C,
+    hashes: &[Output<blake3::Hasher>],
+    concurrency: NonZeroUsize,
+    mut writer: impl Write,
+) -> anyhow::Result<u64>
+where
+    C: ContentAddressableStorage<Hash = Output<blake3::Hasher>> + Sync,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    let workers = concurrency.get().min(hashes.len());
+    let (task_tx, task_rx) = mpsc::channel::<usize>();
+    let task_rx = Mutex::new(task_rx);
+    let (chunk_tx, chunk_rx) = mpsc::channel();
+
+    let bytes = std::thread::scope(|scope| {
+        for _ in 0..workers {
+            let chunk_tx = chunk_tx.clone();
+            let task_rx = &task_rx;
+            scope.spawn(move || {
+                loop {
+                    let Ok(index) = task_rx.lock().unwrap().recv() else {
+                        break;
+                    };
+                    // Hashed by the workers, so that chunks are verified concurrently too.
+                    let chunk = cas.get(hashes[index]).map(|chunk| {
+                        chunk.map(|chunk| {
+                            let intact = cas.hash(&chunk) == hashes[index];
+                            (chunk, intact)
+                        })
+                    });
+                    if chunk_tx.send((index, chunk)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(chunk_tx);
+
+        // Closing the task channel on return (including failures) stops the workers.
+        let task_tx = task_tx;
+        let mut next_task = 0;
+        while next_task < workers {
+            task_tx.send(next_task)?;
+            next_task += 1;
+        }
+
+        let mut fetched = BTreeMap::new();
+        let mut written = 0;
+        let mut bytes = 0;
+        while written < hashes.len() {
+            let (index, chunk) = chunk_rx
+                .recv()
+                .expect("workers should only exit once all chunks are fetched");
+            let (chunk, intact) = chunk?.ok_or_else(|| {
+                anyhow!(IntegrityError(format!(
+                    "chunk {} is missing from the repository",
+                    hashes[index].encode_hex()
+                )))
+            })?;
+            if !intact {
+                bail!(IntegrityError(format!(
+                    "chunk {} is corrupt",
+                    hashes[index].encode_hex()
+                )));
+            }
+            fetched.insert(index, chunk);
+
+            while let Some(chunk) = fetched.remove(&written) {
+                writer.write_all(&chunk)?;
+                written += 1;
+                bytes += chunk.len() as u64;
+                if next_task < hashes.len() {
+                    task_tx.send(next_task)?;
+                    next_task += 1;
+                }
+            }
+        }
+        anyhow::Ok(bytes)
+    })?;
+    writer.flush()?;
+    Ok(bytes)
+}
+
+/// Create a special file of `kind` (`S_IFIFO`, `S_IFSOCK`, `S_IFBLK` or `S_IFCHR`) at `path`.
+/// Permissions are restored later with the rest of the metadata.
+fn mknod(path: &Utf8Path, kind: libc::mode_t, rdev: u64) -> io::Result<()> {
+    let path = CString::new(path.as_str())?;
+    // SAFETY: `path` is a valid NUL-terminated string.
+    if unsafe { libc::mknod(path.as_ptr(), kind | 0o600, rdev as libc::dev_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Create `path` as a hard link to the already restored `link_target` (an absolute snapshot
+/// path).
+fn restore_hardlink(
+    target: &Utf8Path,
+    path: &Utf8Path,
+    link_target: &Utf8Path,
+) -> anyhow::Result<()> {
+    let link_target = target_path(target, link_target)?;
+    if let Some(parent) = link_target.parent()
+        && !parent.canonicalize_utf8()?.starts_with(target)
+    {
+        bail!("refusing to link to {link_target}: it is outside of target directory");
+    }
+
+    remove_non_dir(path)?;
+    std::fs::hard_link(&link_target, path)?;
+    Ok(())
+}
+
+/// Remove existing file or symlink at `path`, so it can be replaced.
+fn remove_non_dir(path: &Utf8Path) -> anyhow::Result<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => bail!("{path} already exists and is a directory"),
+        Ok(_) => Ok(std::fs::remove_file(path)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn restore_metadata(path: &Utf8Path, entry: &EntryManifest, xattrs: bool) -> anyhow::Result<()> {
+    if entry.uid.is_some() || entry.gid.is_some() {
+        match std::os::unix::fs::lchown(path, entry.uid, entry.gid) {
+            // Only root can give files away, so this is expected when restoring as a regular user.
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {}
+            result => result?,
+        }
+    }
+
+    // After ownership, as changing the owner clears file capabilities, but before permissions,
+    // which may make the file read-only.
+    if xattrs && let Some(xattrs) = &entry.xattrs {
+        for (name, value) in xattrs {
+            match xattr::set(path, name, value) {
+                // Like with ownership, some namespaces (`trusted.`, `security.`) are reserved for
+                // root, and the target file system may not support xattrs at all.
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+                    ) =>
+                {
+                    status::warn(format_args!(
+                        "failed to restore xattr {name} of {path}: {err}"
+                    ));
+                }
+                result => result?,
+            }
+        }
+    }
+
+    // Symlink permissions are not meaningful on Linux.
+    if let Some(mode) = entry.mode
+        && !matches!(entry.ty, EntryType::Symlink { .. })
+    {
+        std::fs::set_permissions(path, Permissions::from_mode(mode & 0o7777))?;
+    }
+
+    if let Some(mtime) = entry.mtime {
+        let mtime = FileTime::from_system_time(mtime);
+        filetime::set_symlink_file_times(path, mtime, mtime)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cas::MemoryCas;
+    use bytes::Bytes;
+    use digest::Digest;
+
+    use super::*;
+
+    #[test]
+    fn test_target_path() {
+        let target = Utf8Path::new("/restore");
+        assert_eq!(
+            target_path(target, Utf8Path::new("/home/user/file")).unwrap(),
+            "/restore/home/user/file"
+        );
+        assert_eq!(target_path(target, Utf8Path::new("/")).unwrap(), "/restore");
+        assert!(target_path(target, Utf8Path::new("/home/../etc/passwd")).is_err());
+        assert!(target_path(target, Utf8Path::new("../etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn test_write_chunks() {
+        let cas = MemoryCas::<blake3::Hasher>::new();
+        let chunks = (0..100u32)
+            .map(|i| i.to_le_bytes().repeat(i as usize))
+            .collect::<Vec<_>>();
+        let hashes = chunks
+            .iter()
+            .map(|it| cas.store(Bytes::copy_from_slice(it)).unwrap())
+            .collect::<Vec<_>>();
+
+        for concurrency in [1, 3, 200] {
+            let mut out = Vec::new();
+            let concurrency = NonZeroUsize::new(concurrency).unwrap();
+            let written = write_chunks(&cas, &hashes, concurrency, &mut out).unwrap();
+            assert_eq!(out, chunks.concat());
+            assert_eq!(written, out.len() as u64);
+        }
+
+        let mut out = Vec::new();
+        write_chunks(&cas, &[], NonZeroUsize::MIN, &mut out).unwrap();
+        assert!(out.is_empty());
+
+        let mut missing = hashes.clone();
+        missing[50] = blake3::Hasher::digest(b"missing");
+        let err = write_chunks(&cas, &missing, NonZeroUsize::MIN, io::sink()).unwrap_err();
+        assert!(err.to_string().contains("missing from the repository"));
+
+        let mut corrupt = hashes;
+        corrupt[60] = blake3::Hasher::digest(b"corrupt");
+        cas.put(&corrupt[60], Bytes::from_static(b"tampered"))
+            .unwrap();
+        let err = write_chunks(&cas, &corrupt, NonZeroUsize::MIN, io::sink()).unwrap_err();
+        assert!(err.is::<IntegrityError>());
+        assert!(err.to_string().contains("is corrupt"));
+    }
+}
@@ -0,0 +1,273 @@
+//! Retention policy, selecting snapshots to keep by their time and tags.
+//!
+//! Snapshots are bucketed by calendar periods (hours, days, ISO weeks, months, years) of their
+//! local time, so a day is still a single bucket when a DST transition makes it 23 or 25 hours
+//! long. Going from the newest snapshot, the newest snapshot of each of the `N` most recent
+//! buckets is kept. A snapshot is kept if any rule of the policy keeps it.
+
+use std::{collections::BTreeSet, time::SystemTime};
+
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, Timelike, Utc};
+
+#[derive(Debug, Default)]
+pub struct Policy {
+    /// Number of the newest snapshots to keep.
+    pub last: usize,
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+    pub yearly: usize,
+    /// Snapshots with any of these tags are always kept.
+    pub tags: Vec<String>,
+}
+
+impl Policy {
+    /// Whether the policy keeps no snapshots at all.
+    pub fn is_empty(&self) -> bool {
+        self.last == 0
+            && self.hourly == 0
+            && self.daily == 0
+            && self.weekly == 0
+            && self.monthly == 0
+            && self.yearly == 0
+            && self.tags.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Period {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Period {
+    fn bucket(self, time: NaiveDateTime) -> (i32, u32) {
+        match self {
+            Period::Hour => (time.num_days_from_ce(), time.hour()),
+            Period::Day => (time.num_days_from_ce(), 0),
+            Period::Week => (time.iso_week().year(), time.iso_week().week()),
+            Period::Month => (time.year(), time.month()),
+            Period::Year => (time.year(), 0),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Decision<Id> {
+    pub keep: BTreeSet<Id>,
+    pub forget: BTreeSet<Id>,
+}
+
+/// Local time of `time` in the system time zone.
+pub fn local_time(time: SystemTime) -> NaiveDateTime {
+    DateTime::<Utc>::from(time)
+        .with_timezone(&Local)
+        .naive_local()
+}
+
+/// Select which of `snapshots` (ID, time and tags) to keep according to `policy`, bucketing them
+/// by their time converted with `to_local`.
+pub fn apply<Id: Copy + Ord>(
+    policy: &Policy,
+    snapshots: &[(Id, SystemTime, Vec<String>)],
+    to_local: impl Fn(SystemTime) -> NaiveDateTime,
+) -> Decision<Id> {
+    let mut sorted = snapshots.iter().collect::<Vec<_>>();
+    // Newest first, with ties broken by ID for a stable result.
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then(b.0.cmp(&a.0)));
+
+    // Remaining count and the last bucket of every rule.
+    let mut rules = [
+        (Period::Hour, policy.hourly),
+        (Period::Day, policy.daily),
+        (Period::Week, policy.weekly),
+        (Period::Month, policy.monthly),
+        (Period::Year, policy.yearly),
+    ]
+    .map(|(period, count)| (period, count, None));
+
+    let mut decision = Decision {
+        keep: BTreeSet::new(),
+        forget: BTreeSet::new(),
+    };
+    for (i, (id, time, tags)) in sorted.into_iter().enumerate() {
+        let local = to_local(*time);
+        let mut keep = i < policy.last || tags.iter().any(|tag| policy.tags.contains(tag));
+        for (period, remaining, last) in &mut rules {
+            let bucket = period.bucket(local);
+            if *remaining > 0 && *last != Some(bucket) {
+                *last = Some(bucket);
+                *remaining -= 1;
+                keep = true;
+            }
+        }
+
+        if keep {
+            decision.keep.insert(*id);
+        } else {
+            decision.forget.insert(*id);
+        }
+    }
+    decision
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use chrono::{FixedOffset, NaiveDate, TimeZone};
+
+    use super::*;
+
+    fn utc(date: &str) -> SystemTime {
+        let time = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap();
+        UNIX_EPOCH + Duration::from_secs(time.and_utc().timestamp() as u64)
+    }
+
+    fn in_utc(time: SystemTime) -> NaiveDateTime {
+        DateTime::<Utc>::from(time).naive_utc()
+    }
+
+    fn at_times(times: &[&str]) -> Vec<(usize, SystemTime, Vec<String>)> {
+        times
+            .iter()
+            .enumerate()
+            .map(|(i, time)| (i, utc(time), Vec::new()))
+            .collect()
+    }
+
+    fn kept(decision: Decision<usize>) -> Vec<usize> {
+        decision.keep.into_iter().collect()
+    }
+
+    #[test]
+    fn test_daily() {
+        let snapshots = at_times(&[
+            "2025-01-01 10:00",
+            "2025-01-01 20:00",
+            "2025-01-02 10:00",
+            "2025-01-04 00:00",
+            "2025-01-04 23:59",
+        ]);
+        let policy = Policy {
+            daily: 2,
+            ..Policy::default()
+        };
+        let decision = apply(&policy, &snapshots, in_utc);
+        assert_eq!(decision.keep, BTreeSet::from([2, 4]));
+        assert_eq!(decision.forget, BTreeSet::from([0, 1, 3]));
+
+        let policy = Policy {
+            daily: 10,
+            ..Policy::default()
+        };
+        assert_eq!(kept(apply(&policy, &snapshots, in_utc)), [1, 2, 4]);
+    }
+
+    #[test]
+    fn test_rules_combine() {
+        let snapshots = at_times(&[
+            "2024-12-30 12:00",
+            "2025-01-05 12:00",
+            "2025-01-06 12:00",
+            "2025-01-31 12:00",
+            "2025-02-01 12:00",
+            "2025-02-01 13:00",
+        ]);
+        let policy = Policy {
+            last: 2,
+            // 2024-12-30 and 2025-01-05 are in the same ISO week 2025-W01.
+            weekly: 3,
+            ..Policy::default()
+        };
+        assert_eq!(kept(apply(&policy, &snapshots, in_utc)), [1, 2, 4, 5]);
+
+        let policy = Policy {
+            monthly: 2,
+            yearly: 2,
+            ..Policy::default()
+        };
+        assert_eq!(kept(apply(&policy, &snapshots, in_utc)), [0, 3, 5]);
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut snapshots = at_times(&["2025-01-01 10:00", "2025-01-02 10:00", "2025-01-03 10:00"]);
+        snapshots[0].2 = vec!["release".to_owned()];
+        let policy = Policy {
+            last: 1,
+            tags: vec!["release".to_owned()],
+            ..Policy::default()
+        };
+        assert_eq!(kept(apply(&policy, &snapshots, in_utc)), [0, 2]);
+
+        assert!(Policy::default().is_empty());
+        assert!(
+            apply(&Policy::default(), &snapshots, in_utc)
+                .keep
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_time_zone() {
+        // 23:30 UTC is already the next day at UTC+2.
+        let snapshots = at_times(&["2025-01-01 12:00", "2025-01-01 23:30"]);
+        let policy = Policy {
+            daily: 2,
+            ..Policy::default()
+        };
+        let zone = FixedOffset::east_opt(2 * 3600).unwrap();
+        let to_local = |time| {
+            DateTime::<Utc>::from(time)
+                .with_timezone(&zone)
+                .naive_local()
+        };
+        assert_eq!(kept(apply(&policy, &snapshots, to_local)), [0, 1]);
+
+        let policy = Policy {
+            daily: 1,
+            ..Policy::default()
+        };
+        assert_eq!(kept(apply(&policy, &snapshots, in_utc)), [1]);
+    }
+
+    #[test]
+    fn test_dst() {
+        // Like Europe/Berlin: UTC+1, and UTC+2 from 2025-03-30 01:00 UTC to 2025-10-26 01:00 UTC.
+        let to_local = |time: SystemTime| {
+            let summer = utc("2025-03-30 01:00") <= time && time < utc("2025-10-26 01:00");
+            let zone = FixedOffset::east_opt(if summer { 2 } else { 1 } * 3600).unwrap();
+            zone.from_utc_datetime(&in_utc(time)).naive_local()
+        };
+
+        // The 23-hour day of 2025-03-30 spans from 03-29 23:00 to 03-30 22:00 UTC.
+        let snapshots = at_times(&[
+            "2025-03-29 22:30",
+            "2025-03-29 23:30",
+            "2025-03-30 21:30",
+            "2025-03-30 22:30",
+        ]);
+        let policy = Policy {
+            daily: 3,
+            ..Policy::default()
+        };
+        assert_eq!(kept(apply(&policy, &snapshots, to_local)), [0, 2, 3]);
+
+        // The local hour 02:00-03:00 of 2025-10-26 happens twice, and is a single bucket.
+        let snapshots = at_times(&["2025-10-26 00:15", "2025-10-26 01:15", "2025-10-26 02:15"]);
+        assert_eq!(
+            to_local(snapshots[0].1).date(),
+            NaiveDate::from_ymd_opt(2025, 10, 26).unwrap()
+        );
+        let policy = Policy {
+            hourly: 3,
+            ..Policy::default()
+        };
+        assert_eq!(kept(apply(&policy, &snapshots, to_local)), [1, 2]);
+    }
+}
@@ -0,0 +1,1564 @@
+//! Backing up paths into snapshots of a [`Repository`], and restoring them.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, hash_map::Entry},
+    fs::File,
+    io::{self, BufReader, Read},
+    num::{NonZeroU64, NonZeroUsize},
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, bail};
+use bytes::Bytes;
+use camino::{Utf8Path, Utf8PathBuf};
+use const_hex::ToHexExt;
+use digest::Output;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use rand_core::{OsRng, RngCore};
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_with::{DurationSecondsWithFrac, serde_as};
+use tracing::{Span, field, info_span, instrument};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub use crate::snapshots::SnapshotId;
+use crate::{
+    cas::{Compression, ContentAddressableStorage},
+    chunking::{ChunkerConfig, MemoryBudget, chunk_and_store},
+    cli::{self, LockArgs},
+    config::Config,
+    filter::{self, PathFilter},
+    journal::{self, Journal},
+    lock::{self, RepoLock},
+    manifest::{
+        ChunkerParams, DeviceKind, EntryManifest, EntryType, MANIFEST_VERSION, ManifestFormat,
+        SnapshotManifest,
+    },
+    output::say,
+    progress, repo, snapshots,
+    status::{self, IntegrityError, UsageError},
+};
+
+/// Maximum number of tasks storing chunks of a single file.
+const STORE_WORKERS: usize = 4;
+
+/// Number of times to read a file again if it changes while being read.
+const CHANGED_FILE_RETRIES: usize = 1;
+
+/// Beginning of a `CACHEDIR.TAG` file marking a cache directory.
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Maximum number of nested symlinks to directories followed with `--follow-symlinks`.
+const MAX_FOLLOWED_SYMLINKS: usize = 16;
+
+/// Walked entries, with whether they are symlinks to follow.
+type Walk<'a> = Box<dyn Iterator<Item = walkdir::Result<(walkdir::DirEntry, bool)>> + Send + 'a>;
+
+/// Failure to read a path being backed up, as opposed to a failure to store it in the repository.
+/// Such paths are skipped with a warning, unless `--strict` is set.
+#[derive(Debug, thiserror::Error)]
+#[error("failed to read {}", path.display())]
+struct UnreadablePath {
+    path: PathBuf,
+    #[source]
+    source: io::Error,
+}
+
+impl UnreadablePath {
+    fn new(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        UnreadablePath {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+/// Reader of a file being backed up, which records whether reading it failed.
+struct SourceReader<R> {
+    inner: R,
+    failed: bool,
+}
+
+impl<R: Read> Read for SourceReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).inspect_err(|err| {
+            if err.kind() != io::ErrorKind::Interrupted {
+                self.failed = true;
+            }
+        })
+    }
+}
+
+struct SnapshotContext<'a> {
+    out_dir: &'a repo::Repository,
+    chunker_config: ChunkerConfig<'a>,
+    /// The first seen path of every file with multiple hard links, by (device, inode).
+    hardlinks: Mutex<HashMap<(u64, u64), Utf8PathBuf>>,
+    /// Threads storing chunks of all files being read.
+    store_pool: rayon::ThreadPool,
+    /// Number of tasks storing chunks of a single file.
+    store_workers: usize,
+    /// Bytes of chunks kept in memory by all files being read.
+    memory_budget: Option<MemoryBudget>,
+    /// Whether to stay on the file system of every backed up path.
+    one_file_system: bool,
+    /// Whether to follow symlinks.
+    follow_symlinks: bool,
+    /// Whether to follow symlinks given as paths to back up.
+    dereference_root: bool,
+    /// Directories walked so far by (device, inode), only tracked when following symlinks.
+    visited: Mutex<HashSet<(u64, u64)>>,
+    /// Whether to back up extended attributes.
+    xattrs: bool,
+    /// Whether to skip paths that are not valid UTF-8 instead of failing.
+    skip_invalid_paths: bool,
+    /// Number of paths skipped because they are not valid UTF-8.
+    skipped_paths: AtomicU64,
+    /// Files larger than this are skipped.
+    exclude_larger_than: Option<u64>,
+    /// Whether to skip cache directories.
+    exclude_caches: bool,
+    /// Number of files and directories skipped by `exclude_larger_than` and `exclude_caches`.
+    excluded_paths: AtomicU64,
+    /// Whether to fail on unreadable paths instead of skipping them.
+    strict: bool,
+    /// Number of paths skipped because they couldn't be read.
+    unreadable_paths: AtomicU64,
+    /// Entries of the parent snapshot by path.
+    parent: HashMap<Utf8PathBuf, EntryManifest>,
+    /// Entries stored by the interrupted snapshot being resumed, by path.
+    resumed: HashMap<Utf8PathBuf, EntryManifest>,
+    journal: Journal,
+    /// Total size of files read from disk, which excludes files reused from the parent.
+    bytes_read: AtomicU64,
+    progress: MultiProgress,
+    global_progress: ProgressBar,
+}
+
+/// Outcome of a snapshot, printed with `--output json`.
+#[serde_as]
+#[derive(Serialize)]
+struct SnapshotResult {
+    id: String,
+    parent: Option<String>,
+    files: usize,
+    bytes_read: u64,
+    /// Size of new chunks as stored in the repository (after compression).
+    bytes_stored: u64,
+    new_chunks: u64,
+    /// Paths skipped because they are not valid UTF-8.
+    skipped_paths: u64,
+    /// Large files and cache directories skipped with `--exclude-larger-than` and
+    /// `--exclude-caches`.
+    excluded_paths: u64,
+    /// Paths skipped because they couldn't be read.
+    unreadable_paths: u64,
+    /// Chunks read back with `--verify-after-write`.
+    verified_chunks: usize,
+    /// Verified chunks that didn't match their hashes.
+    corrupt_chunks: Vec<String>,
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    duration: Duration,
+}
+
+/// Repository to back up paths into and restore snapshots from.
+pub struct Repository {
+    pub(crate) remote: Utf8PathBuf,
+    config: Config,
+    pub(crate) objects: repo::Repository,
+}
+
+/// How [`Repository::snapshot`] backs up paths. The defaults match the ones of the `snapshot`
+/// command.
+#[derive(Default)]
+pub struct SnapshotOptions {
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    pub description: Option<String>,
+    /// Snapshot ID or name to reuse unchanged files from, instead of the latest snapshot with the
+    /// same name.
+    pub parent: Option<String>,
+    /// Read all files, even if they look unchanged since the parent snapshot.
+    pub force_rehash: bool,
+    /// Continue an interrupted snapshot of the same paths with the same name.
+    pub resume: bool,
+    /// Chunk sizes overriding the ones of the previous snapshots.
+    pub chunking: cli::ChunkingArgs,
+    /// Allow chunking parameters that differ from the previous snapshots.
+    pub force_chunking: bool,
+    /// Glob patterns of paths to skip, see `snapshot --exclude`.
+    pub exclude: Vec<String>,
+    /// Glob patterns of paths to back up even if they are excluded.
+    pub include: Vec<String>,
+    /// Skip files larger than this size.
+    pub exclude_larger_than: Option<u64>,
+    /// Skip directories marked as caches by a `CACHEDIR.TAG` file.
+    pub exclude_caches: bool,
+    /// Don't descend into directories on other file systems.
+    pub one_file_system: bool,
+    /// Back up the targets of symlinks instead of the symlinks.
+    pub follow_symlinks: bool,
+    /// Back up the targets of paths that are symlinks, but not of symlinks inside them.
+    pub dereference_root: bool,
+    /// Back up extended attributes.
+    pub xattrs: bool,
+    /// Number of files to read concurrently. Defaults to the number of CPUs.
+    pub jobs: Option<NonZeroUsize>,
+    /// Keep at most this many bytes of chunks in memory, reading fewer files at once if needed.
+    pub memory_limit: Option<usize>,
+    /// Fail if any of the paths doesn't exist, or if anything under them can't be read, instead of
+    /// skipping it with a warning.
+    pub strict: bool,
+    /// Skip paths that are not valid UTF-8 instead of failing.
+    pub skip_invalid_paths: bool,
+    /// Compute the total size of the paths first, so that progress has an ETA.
+    pub scan: bool,
+    /// Read back this fraction of the chunks of the snapshot, and fail if any doesn't match its
+    /// hash.
+    pub verify_after_write: Option<f64>,
+    pub manifest_format: ManifestFormat,
+    /// Remove conflicting locks instead of failing, see `--break-lock`.
+    pub break_lock: bool,
+}
+
+/// How [`Repository::restore`] restores a snapshot. The defaults match the ones of the `restore`
+/// command.
+pub struct RestoreOptions {
+    /// Restore into a non-empty directory, overwriting existing files.
+    pub force: bool,
+    /// Restore extended attributes stored in the snapshot.
+    pub xattrs: bool,
+    /// Number of chunks of a file to fetch from the repository concurrently.
+    pub read_concurrency: NonZeroUsize,
+    /// Remove conflicting locks instead of failing, see `--break-lock`.
+    pub break_lock: bool,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        RestoreOptions {
+            force: false,
+            xattrs: false,
+            read_concurrency: NonZeroUsize::new(4).unwrap(),
+            break_lock: false,
+        }
+    }
+}
+
+impl Repository {
+    /// Open the repository at `remote`, decrypting objects with the secret key at `identity` if
+    /// given, or with the one given by `BAKUP_IDENTITY*` environment variables otherwise. New
+    /// objects are stored uncompressed.
+    pub fn open(remote: &Utf8Path, identity: Option<&Utf8Path>) -> anyhow::Result<Self> {
+        Self::open_for_writing(remote, identity, &[], Compression::None, None)
+    }
+
+    /// Open the repository at `remote`, compressing new objects with `compression` and writing
+    /// them at most `upload_limit` bytes per second.
+    ///
+    /// New objects are encrypted to `recipients` and to the secret key at `identity`, which also
+    /// decrypts existing ones. Without any of them, objects are stored unencrypted.
+    pub fn open_for_writing(
+        remote: &Utf8Path,
+        identity: Option<&Utf8Path>,
+        recipients: &[PublicKey],
+        compression: Compression,
+        upload_limit: Option<NonZeroU64>,
+    ) -> anyhow::Result<Self> {
+        let config = Config::load(remote)?;
+        let objects = repo::open_for_writing(
+            remote,
+            &config,
+            identity,
+            recipients,
+            compression,
+            upload_limit,
+        )?;
+        Ok(Repository {
+            remote: remote.to_owned(),
+            config,
+            objects,
+        })
+    }
+
+    /// Back up `paths` into a new snapshot. Directories are backed up recursively.
+    ///
+    /// Warnings about skipped paths are printed to stderr, and progress is shown there if it is a
+    /// terminal.
+    pub fn snapshot(
+        &self,
+        paths: &[Utf8PathBuf],
+        opts: &SnapshotOptions,
+    ) -> anyhow::Result<SnapshotId> {
+        let (id, result) = self.snapshot_with_result(paths, opts)?;
+        result.check_verified()?;
+        Ok(id)
+    }
+
+    #[instrument(skip_all, fields(name = opts.name.as_deref(), roots = field::Empty, files = field::Empty))]
+    fn snapshot_with_result(
+        &self,
+        paths: &[Utf8PathBuf],
+        opts: &SnapshotOptions,
+    ) -> anyhow::Result<(SnapshotId, SnapshotResult)> {
+        let start = Instant::now();
+
+        let progress = MultiProgress::with_draw_target(progress::draw_target());
+        let global_progress =
+            progress
+                .add(ProgressBar::no_length().with_style(
+                    ProgressStyle::with_template("{bytes} ({bytes_per_sec})").unwrap(),
+                ));
+
+        // Keep `prune` from removing new chunks before the snapshot references them.
+        let _lock = RepoLock::shared(
+            &self.remote,
+            &LockArgs {
+                break_lock: opts.break_lock,
+            },
+        )?;
+        // Chunks that a `prune` removed since opening must not be taken as stored.
+        repo::packed(&self.objects).reload()?;
+        let counter = repo::counter(&self.objects);
+        let (objects_before, bytes_before) = (counter.new_objects(), counter.new_bytes());
+
+        let roots = backup_roots(paths.to_vec())?;
+        Span::current().record("roots", roots.len());
+        let journal_path = journal::path(&self.remote, opts.name.as_deref(), &roots);
+        let resumed = if opts.resume {
+            journal::load(&self.objects, &journal_path)?
+        } else {
+            journal::Resumed::default()
+        };
+
+        let parent = if opts.force_rehash {
+            None
+        } else if let Some(selector) = &opts.parent {
+            Some(snapshots::resolve(&self.remote, &self.objects, selector)?)
+        } else if let Some(name) = &opts.name {
+            snapshots::latest(&self.remote, &self.objects, name)?
+        } else {
+            None
+        };
+
+        // Files are only deduplicated if they're chunked the same way, so keep the parameters of
+        // the parent, or of the newest snapshot if there is none.
+        let reference = match &parent {
+            Some((id, manifest)) => Some((*id, manifest.chunker_params())),
+            None => snapshots::newest(&self.remote, &self.objects)?
+                .map(|(id, manifest)| (id, manifest.chunker_params())),
+        };
+        let identity = repo::identity(&self.objects);
+        let chunker_params = progress
+            .suspend(|| chunker_params(opts, &self.config, identity, reference, resumed.chunker))?;
+        let chunker_config = chunker_params
+            .config()
+            .context("invalid chunking parameters")?;
+
+        let parent_id = parent.as_ref().map(|(id, _)| id.encode_hex());
+        let parent = match parent {
+            Some((_, manifest)) => manifest
+                .entries
+                .into_iter()
+                .map(|entry| (entry.path.clone(), entry))
+                .collect(),
+            None => HashMap::new(),
+        };
+
+        let store_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(opts.jobs.map_or(0, NonZeroUsize::get))
+            .build()
+            .context("failed to start worker threads")?;
+        let ctx = SnapshotContext {
+            out_dir: &self.objects,
+            chunker_config,
+            hardlinks: Mutex::new(HashMap::new()),
+            store_pool,
+            store_workers: opts
+                .jobs
+                .map_or(STORE_WORKERS, |jobs| jobs.get().min(STORE_WORKERS)),
+            memory_budget: opts.memory_limit.map(MemoryBudget::new),
+            one_file_system: opts.one_file_system,
+            follow_symlinks: opts.follow_symlinks,
+            dereference_root: opts.dereference_root,
+            visited: Mutex::new(HashSet::new()),
+            xattrs: opts.xattrs,
+            skip_invalid_paths: opts.skip_invalid_paths,
+            skipped_paths: AtomicU64::new(0),
+            exclude_larger_than: opts.exclude_larger_than,
+            exclude_caches: opts.exclude_caches,
+            excluded_paths: AtomicU64::new(0),
+            strict: opts.strict,
+            unreadable_paths: AtomicU64::new(0),
+            parent,
+            resumed: resumed.entries,
+            journal: Journal::create(journal_path, chunker_params.clone(), opts.resume)?,
+            bytes_read: AtomicU64::new(0),
+            progress,
+            global_progress,
+        };
+
+        let filter = PathFilter::new(&opts.exclude, &opts.include)?;
+
+        let mut existing_roots = Vec::new();
+        for path in roots {
+            match path.symlink_metadata() {
+                Ok(_) => existing_roots.push(path),
+                Err(err) if err.kind() == io::ErrorKind::NotFound && !opts.strict => {
+                    status::warn(format_args!("skipping {path}: {err}"));
+                }
+                Err(err) => return Err(err).with_context(|| format!("failed to read {path}")),
+            }
+        }
+
+        if opts.scan {
+            let total = info_span!("scan").in_scope(|| ctx.scan_size(&existing_roots, &filter));
+            ctx.global_progress.set_length(total);
+            ctx.global_progress.set_style(
+                ProgressStyle::with_template(
+                    "{wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, {eta} left)",
+                )
+                .unwrap(),
+            );
+        }
+
+        // A dedicated pool, so that `jobs` bounds the number of files processed at once.
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(opts.jobs.map_or(0, NonZeroUsize::get))
+            .build()
+            .context("failed to start worker threads")?;
+        // Entered on every worker thread, so that spans of files are nested in it.
+        let walk_span = info_span!("walk");
+        let entries = pool.install(|| {
+            let mut entries = existing_roots
+                .par_iter()
+                .flat_map(|it| ctx.walk(it.as_std_path(), &filter).par_bridge())
+                .map(|entry| {
+                    let result = match entry {
+                        Ok((entry, follow)) => {
+                            walk_span.in_scope(|| ctx.snapshot_entry(entry, follow))
+                        }
+                        Err(err) => Err(walk_error(err)),
+                    };
+                    ctx.skip_unreadable(result)
+                })
+                .filter_map(Result::transpose)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            entries.par_sort_unstable_by(|a, b| a.path.cmp(&b.path));
+            anyhow::Ok(entries)
+        })?;
+        drop(walk_span);
+        ctx.global_progress.finish_and_clear();
+
+        let files = entries
+            .iter()
+            .filter(|entry| matches!(entry.ty, EntryType::File { .. }))
+            .count();
+        Span::current().record("files", files);
+        let (new_chunks, bytes_stored) = (
+            counter.new_objects() - objects_before,
+            counter.new_bytes() - bytes_before,
+        );
+
+        let snapshot = SnapshotManifest {
+            version: MANIFEST_VERSION,
+            name: opts.name.clone(),
+            hostname: lock::hostname(),
+            paths: existing_roots,
+            tags: opts.tags.clone(),
+            description: opts.description.clone(),
+            time: SystemTime::now(),
+            chunker: Some(chunker_params),
+            entries,
+        };
+
+        let id = info_span!("write_manifest", entries = snapshot.entries.len()).in_scope(|| {
+            let id = self
+                .objects
+                .store(Bytes::from(snapshot.to_bytes(opts.manifest_format)))?;
+            repo::packed(&self.objects).flush()?;
+            snapshots::write_ref(&self.remote, id)?;
+            anyhow::Ok(id)
+        })?;
+        ctx.journal.remove()?;
+
+        let (verified_chunks, corrupt_chunks) = match opts.verify_after_write {
+            Some(fraction) => info_span!("verify")
+                .in_scope(|| verify_chunks(&self.objects, &snapshot.entries, fraction))?,
+            None => (0, Vec::new()),
+        };
+
+        let result = SnapshotResult {
+            id: id.encode_hex(),
+            parent: parent_id,
+            files,
+            bytes_read: ctx.bytes_read.load(Ordering::Relaxed),
+            bytes_stored,
+            new_chunks,
+            skipped_paths: ctx.skipped_paths.load(Ordering::Relaxed),
+            excluded_paths: ctx.excluded_paths.load(Ordering::Relaxed),
+            unreadable_paths: ctx.unreadable_paths.load(Ordering::Relaxed),
+            verified_chunks,
+            corrupt_chunks: corrupt_chunks.iter().map(|it| it.encode_hex()).collect(),
+            duration: start.elapsed(),
+        };
+        Ok((id, result))
+    }
+}
+
+impl SnapshotResult {
+    /// Fail if any chunk read back with `--verify-after-write` didn't match its hash, reporting
+    /// every such chunk on stderr.
+    fn check_verified(&self) -> anyhow::Result<()> {
+        for hash in &self.corrupt_chunks {
+            status::error(format_args!(
+                "chunk {hash} doesn't match its hash after writing"
+            ));
+        }
+        if !self.corrupt_chunks.is_empty() {
+            bail!(IntegrityError(format!(
+                "{} chunks of snapshot {} failed verification",
+                self.corrupt_chunks.len(),
+                self.id
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub fn snapshot(mut cmd: cli::Snapshot) -> anyhow::Result<()> {
+    let repo = Repository::open_for_writing(
+        &cmd.remote,
+        cmd.identity.as_deref(),
+        &cmd.recipient,
+        cmd.compression.compression(),
+        cmd.limit_upload,
+    )?;
+
+    let mut paths = std::mem::take(&mut cmd.paths);
+    if let Some(source) = &cmd.files_from {
+        paths.extend(read_paths(source, cmd.null)?);
+    }
+    let mut exclude = cmd.exclude;
+    for path in &cmd.exclude_from {
+        exclude.extend(filter::read_patterns(path)?);
+    }
+    let opts = SnapshotOptions {
+        name: cmd.name,
+        tags: cmd.tags,
+        description: cmd.description,
+        parent: cmd.parent,
+        force_rehash: cmd.force_rehash,
+        resume: cmd.resume,
+        chunking: cmd.chunking,
+        force_chunking: cmd.force_chunking,
+        exclude,
+        include: cmd.include,
+        exclude_larger_than: cmd.exclude_larger_than,
+        exclude_caches: cmd.exclude_caches,
+        one_file_system: cmd.one_file_system,
+        follow_symlinks: cmd.follow_symlinks,
+        dereference_root: cmd.dereference_root,
+        xattrs: cmd.xattrs,
+        jobs: cmd.jobs,
+        memory_limit: cmd.memory_limit,
+        strict: cmd.strict,
+        skip_invalid_paths: cmd.skip_invalid_paths,
+        scan: cmd.scan,
+        verify_after_write: cmd.verify_after_write,
+        manifest_format: cmd.manifest_format,
+        break_lock: cmd.lock.break_lock,
+    };
+    let (_, result) = repo.snapshot_with_result(&paths, &opts)?;
+
+    match cmd.output {
+        cli::OutputFormat::Text => {
+            if let Some(parent) = &result.parent {
+                say!("parent: {parent}");
+            }
+            say!("snapshot: {}", result.id);
+            say!(
+                "{} files, {} read, {} stored in {} new chunks, took {:.1?}",
+                result.files,
+                HumanBytes(result.bytes_read),
+                HumanBytes(result.bytes_stored),
+                result.new_chunks,
+                result.duration,
+            );
+            if result.skipped_paths > 0 {
+                say!(
+                    "skipped {} paths that are not valid UTF-8",
+                    result.skipped_paths
+                );
+            }
+            if result.excluded_paths > 0 {
+                say!(
+                    "excluded {} large files and cache directories",
+                    result.excluded_paths
+                );
+            }
+            if result.unreadable_paths > 0 {
+                say!("skipped {} unreadable paths", result.unreadable_paths);
+            }
+            if opts.verify_after_write.is_some() {
+                say!(
+                    "verified {} chunks, {} corrupt",
+                    result.verified_chunks,
+                    result.corrupt_chunks.len()
+                );
+            }
+        }
+        cli::OutputFormat::Json => println!("{}", serde_json::to_string(&result)?),
+    }
+    result.check_verified()
+}
+
+/// Read back a random `fraction` of the distinct chunks referenced by `entries` and check that
+/// they match their hashes. Returns the number of verified chunks and the ones that don't match
+/// (or are missing).
+fn verify_chunks(
+    cas: &repo::Repository,
+    entries: &[EntryManifest],
+    fraction: f64,
+) -> io::Result<(usize, Vec<Output<blake3::Hasher>>)> {
+    let chunks = entries
+        .iter()
+        .filter_map(|entry| match &entry.ty {
+            EntryType::File { content, .. } => Some(content),
+            _ => None,
+        })
+        .flatten()
+        .collect::<HashSet<_>>();
+    let sample = chunks
+        .into_iter()
+        .filter(|_| fraction >= 1.0 || (OsRng.next_u64() as f64) < fraction * u64::MAX as f64)
+        .collect::<Vec<_>>();
+
+    let progress = progress::bar(sample.len() as u64, "{wide_bar} {pos}/{len} chunks");
+    let corrupt = sample
+        .par_iter()
+        .filter_map(|&&hash| {
+            let verified = cas.verify(&hash);
+            progress.inc(1);
+            match verified {
+                Ok(true) => None,
+                Ok(false) => Some(Ok(hash)),
+                Err(err) => Some(Err(err)),
+            }
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    progress.finish_and_clear();
+    Ok((sample.len(), corrupt))
+}
+
+/// Chunking parameters for the new snapshot: the ones of the `reference` snapshot overridden by
+/// `opts`. Without a reference snapshot, the ones of the `resumed` snapshot are used, or the ones
+/// from `config`, with its key opened with `identity`.
+fn chunker_params(
+    opts: &SnapshotOptions,
+    config: &Config,
+    identity: Option<&StaticSecret>,
+    reference: Option<(SnapshotId, ChunkerParams)>,
+    resumed: Option<ChunkerParams>,
+) -> anyhow::Result<ChunkerParams> {
+    let base = match (&reference, resumed) {
+        (Some((_, params)), _) => params.clone(),
+        (None, Some(params)) => params,
+        (None, None) => ChunkerParams {
+            sizes: config.chunker,
+            key: initial_key(config, identity)?,
+        },
+    };
+    let params = ChunkerParams {
+        sizes: opts.chunking.apply(base.sizes),
+        key: base.key,
+    };
+
+    if let Some((id, reference)) = reference
+        && params != reference
+        && !opts.force_chunking
+    {
+        bail!(UsageError(format!(
+            "chunking parameters differ from snapshot {}, so files won't be deduplicated against \
+             it (use --force-chunking to proceed anyway)",
+            id.encode_hex()
+        )));
+    }
+    Ok(params)
+}
+
+/// Gear key of the config, or a random one if the repository was initialized without a key, or if
+/// the key is sealed and there is no `identity` to open it with.
+fn initial_key(config: &Config, identity: Option<&StaticSecret>) -> anyhow::Result<[u8; 16]> {
+    if let Some(key) = &config.chunker_key {
+        if let Some(key) = key
+            .open(identity)
+            .context("failed to open the chunking key of the repository")?
+        {
+            return Ok(key);
+        }
+        status::note(
+            "no identity to open the chunking key of the repository with, so files are not \
+             deduplicated against snapshots chunked with it",
+        );
+    }
+    let mut key = [0u8; 16];
+    OsRng.fill_bytes(&mut key);
+    Ok(key)
+}
+
+/// Read paths separated by newlines (or NULs if `null` is set) from `source`, which is either a
+/// file or `-` for stdin.
+fn read_paths(source: &Utf8Path, null: bool) -> anyhow::Result<Vec<Utf8PathBuf>> {
+    let content = if source == "-" {
+        let mut content = Vec::new();
+        io::stdin()
+            .read_to_end(&mut content)
+            .context("failed to read paths from stdin")?;
+        content
+    } else {
+        std::fs::read(source).with_context(|| format!("failed to read {source}"))?
+    };
+    parse_paths(&content, if null { b'\0' } else { b'\n' })
+}
+
+fn parse_paths(content: &[u8], separator: u8) -> anyhow::Result<Vec<Utf8PathBuf>> {
+    content
+        .split(|&it| it == separator)
+        .filter(|it| !it.is_empty())
+        .map(|it| {
+            let path = std::str::from_utf8(it)
+                .with_context(|| format!("non-UTF-8 path {:?}", String::from_utf8_lossy(it)))?;
+            Ok(Utf8PathBuf::from(path))
+        })
+        .collect()
+}
+
+/// Make `paths` absolute and remove the ones inside other paths, so that every entry is walked
+/// only once.
+fn backup_roots(paths: Vec<Utf8PathBuf>) -> anyhow::Result<Vec<Utf8PathBuf>> {
+    let mut paths = paths
+        .iter()
+        .map(|it| camino::absolute_utf8(it).with_context(|| format!("invalid path {it}")))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // Sorted, every path comes right after its ancestors, or other paths inside them.
+    paths.sort_unstable();
+    let mut roots = Vec::<Utf8PathBuf>::new();
+    for path in paths {
+        if roots.last().is_none_or(|root| !path.starts_with(root)) {
+            roots.push(path);
+        }
+    }
+    Ok(roots)
+}
+
+/// Extended attributes of `path` (of the symlink target if `follow` is set), or `None` if there
+/// are none or the file system doesn't support them.
+fn read_xattrs(path: &Utf8Path, follow: bool) -> anyhow::Result<Option<BTreeMap<String, Vec<u8>>>> {
+    let names = if follow {
+        xattr::list_deref(path)
+    } else {
+        xattr::list(path)
+    };
+    let names = match names {
+        Ok(names) => names,
+        Err(err) if err.kind() == io::ErrorKind::Unsupported => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("failed to list xattrs of {path}")),
+    };
+
+    let mut xattrs = BTreeMap::new();
+    for name in names {
+        let Some(name) = name.to_str() else {
+            status::warn(format_args!("skipping non-UTF-8 xattr {name:?} of {path}"));
+            continue;
+        };
+        let value = if follow {
+            xattr::get_deref(path, name)
+        } else {
+            xattr::get(path, name)
+        };
+        // The attribute may have been removed since listing.
+        if let Some(value) =
+            value.with_context(|| format!("failed to read xattr {name} of {path}"))?
+        {
+            xattrs.insert(name.to_owned(), value);
+        }
+    }
+    Ok((!xattrs.is_empty()).then_some(xattrs))
+}
+
+/// Whether `dir` is marked as a cache directory by a `CACHEDIR.TAG` file.
+fn is_cache_dir(dir: &Path) -> bool {
+    let mut signature = [0; CACHEDIR_TAG_SIGNATURE.len()];
+    File::open(dir.join("CACHEDIR.TAG"))
+        .and_then(|mut file| file.read_exact(&mut signature))
+        .is_ok_and(|()| signature == CACHEDIR_TAG_SIGNATURE)
+}
+
+/// Error of walking the paths to back up, attributed to the path that couldn't be read if any.
+fn walk_error(err: walkdir::Error) -> anyhow::Error {
+    match err.path() {
+        Some(path) if err.io_error().is_some() => {
+            let path = path.to_owned();
+            let source = err.into_io_error().expect("error is an I/O error");
+            UnreadablePath::new(path, source).into()
+        }
+        _ => err.into(),
+    }
+}
+
+/// Metadata of `path`, of the symlink target if `follow` is set.
+fn read_metadata(path: &Utf8Path, follow: bool) -> io::Result<std::fs::Metadata> {
+    if follow {
+        path.metadata()
+    } else {
+        path.symlink_metadata()
+    }
+}
+
+impl SnapshotContext<'_> {
+    /// Total size of the regular files under `roots` that are not excluded, for `--scan`.
+    ///
+    /// This is an estimate: hard links are counted once per link, and files may change before
+    /// they are read. Files reused from the parent snapshot are counted too, as they advance the
+    /// progress when reused.
+    fn scan_size(&self, roots: &[Utf8PathBuf], filter: &PathFilter) -> u64 {
+        roots
+            .iter()
+            .flat_map(|root| {
+                walkdir::WalkDir::new(root)
+                    .same_file_system(self.one_file_system)
+                    .follow_links(self.follow_symlinks)
+                    .follow_root_links(self.follow_symlinks || self.dereference_root)
+                    .into_iter()
+                    .filter_entry(|entry| {
+                        filter.is_included(entry.path())
+                            && !(self.exclude_caches
+                                && entry.file_type().is_dir()
+                                && is_cache_dir(entry.path()))
+                    })
+            })
+            // Unreadable entries are reported when they're backed up.
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|metadata| metadata.size())
+            .filter(|&size| self.exclude_larger_than.is_none_or(|limit| size <= limit))
+            .sum()
+    }
+
+    /// Walk the tree at `root`, skipping excluded paths.
+    fn walk<'s>(&'s self, root: &Path, filter: &'s PathFilter) -> Walk<'s> {
+        let device = root.metadata().ok().map(|it| it.dev());
+        self.walk_from(root, 0, device, 0, filter)
+    }
+
+    /// Walk the tree at `root` from `min_depth`. `root` is on `device`, and is reached through
+    /// `links` followed symlinks.
+    fn walk_from<'s>(
+        &'s self,
+        root: &Path,
+        min_depth: usize,
+        device: Option<u64>,
+        links: usize,
+        filter: &'s PathFilter,
+    ) -> Walk<'s> {
+        let entries = walkdir::WalkDir::new(root)
+            .min_depth(min_depth)
+            // Compares device of every directory with the device of `root`.
+            .same_file_system(self.one_file_system)
+            // Otherwise a symlink given as a path to back up would be stored as a symlink, but
+            // with the content of its target under it.
+            .follow_root_links(self.follow_symlinks || self.dereference_root)
+            .into_iter()
+            // Prune excluded directories without descending into them.
+            .filter_entry(|entry| {
+                filter.is_included(entry.path())
+                    && self.is_valid_path(entry.path())
+                    && !self.is_excluded_cache(entry)
+            });
+        if !self.follow_symlinks {
+            let dereference_root = self.dereference_root;
+            return Box::new(entries.map(move |entry| {
+                entry.map(|entry| {
+                    let follow = dereference_root && entry.depth() == 0 && entry.path_is_symlink();
+                    (entry, follow)
+                })
+            }));
+        }
+
+        Box::new(
+            entries
+                // Directories are yielded before their content, so every directory is visited
+                // before any symlink inside it is followed.
+                .inspect(|entry| {
+                    if let Ok(entry) = entry
+                        && entry.file_type().is_dir()
+                        && let Ok(metadata) = entry.metadata()
+                    {
+                        self.visit(&metadata);
+                    }
+                })
+                .flat_map(move |entry| -> Walk<'s> {
+                    let entry = match entry {
+                        Ok(entry) if entry.path_is_symlink() => entry,
+                        entry => return Box::new(std::iter::once(entry.map(|it| (it, false)))),
+                    };
+                    match self.follow_symlink(entry.path(), device, links) {
+                        // Symlinks given as paths to back up are walked into by `walkdir` itself.
+                        Some(true) if entry.depth() > 0 => {
+                            let target = self.walk_from(entry.path(), 1, device, links + 1, filter);
+                            Box::new(std::iter::once(Ok((entry, true))).chain(target))
+                        }
+                        follow => Box::new(std::iter::once(Ok((entry, follow.is_some())))),
+                    }
+                }),
+        )
+    }
+
+    /// Record the directory with `metadata` as visited, returning whether it wasn't already.
+    fn visit(&self, metadata: &std::fs::Metadata) -> bool {
+        self.visited
+            .lock()
+            .unwrap()
+            .insert((metadata.dev(), metadata.ino()))
+    }
+
+    /// Whether to follow the symlink at `path`, which is reached through `links` followed
+    /// symlinks, and whether it points to a directory on `device`.
+    ///
+    /// Returns `None` if the symlink should be stored as is, `Some(true)` if it points to a
+    /// directory that should be walked, and `Some(false)` if it points to any other file.
+    fn follow_symlink(&self, path: &Path, device: Option<u64>, links: usize) -> Option<bool> {
+        let metadata = match path.metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                self.progress.suspend(|| {
+                    status::note(format_args!(
+                        "{} is a broken symlink ({err}), storing it as is",
+                        path.display()
+                    ))
+                });
+                return None;
+            }
+        };
+        if !metadata.is_dir() {
+            return Some(false);
+        }
+
+        let reason = if links >= MAX_FOLLOWED_SYMLINKS {
+            "it is nested in too many symlinks"
+        } else if self.one_file_system && device != Some(metadata.dev()) {
+            "it points to another file system"
+        } else if !self.visit(&metadata) {
+            "it points to a directory that is already backed up"
+        } else {
+            return Some(true);
+        };
+        self.progress.suspend(|| {
+            status::note(format_args!(
+                "not following symlink {}: {reason}",
+                path.display()
+            ))
+        });
+        None
+    }
+
+    /// Whether `path` is valid UTF-8. Invalid paths are skipped with a warning if
+    /// `--skip-invalid-paths` is set, otherwise they fail the snapshot later.
+    fn is_valid_path(&self, path: &Path) -> bool {
+        if path.to_str().is_some() || !self.skip_invalid_paths {
+            return true;
+        }
+        self.skip_path(path, "its name is not valid UTF-8");
+        false
+    }
+
+    /// Whether `entry` is a cache directory skipped with `--exclude-caches`.
+    fn is_excluded_cache(&self, entry: &walkdir::DirEntry) -> bool {
+        if !self.exclude_caches || !entry.file_type().is_dir() || !is_cache_dir(entry.path()) {
+            return false;
+        }
+        self.excluded_paths.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Skip the entry that failed with `result` if it couldn't be read, unless `--strict` is set.
+    fn skip_unreadable(
+        &self,
+        result: anyhow::Result<Option<EntryManifest>>,
+    ) -> anyhow::Result<Option<EntryManifest>> {
+        let err = match result {
+            Err(err) if !self.strict => err,
+            result => return result,
+        };
+        let Some(unreadable) = err.downcast_ref::<UnreadablePath>() else {
+            return Err(err);
+        };
+        self.progress.suspend(|| {
+            status::warn(format_args!(
+                "skipping {}: {}",
+                unreadable.path.display(),
+                unreadable.source
+            ))
+        });
+        self.unreadable_paths.fetch_add(1, Ordering::Relaxed);
+        Ok(None)
+    }
+
+    fn skip_path(&self, path: &Path, reason: &str) {
+        self.progress
+            .suspend(|| status::warn(format_args!("skipping {}: {reason}", path.display())));
+        self.skipped_paths.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Snapshot a single entry (the target of it if `follow` is set), or `None` if it's skipped.
+    fn snapshot_entry(
+        &self,
+        entry: walkdir::DirEntry,
+        follow: bool,
+    ) -> anyhow::Result<Option<EntryManifest>> {
+        let Ok(path) = Utf8PathBuf::try_from(entry.path().to_path_buf()) else {
+            bail!(
+                "{} is not valid UTF-8 (use --skip-invalid-paths to skip such paths)",
+                entry.path().display()
+            );
+        };
+        let mut metadata =
+            read_metadata(&path, follow).map_err(|err| UnreadablePath::new(&path, err))?;
+
+        let file_type = metadata.file_type();
+        if file_type.is_file()
+            && self
+                .exclude_larger_than
+                .is_some_and(|limit| metadata.size() > limit)
+        {
+            self.excluded_paths.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        }
+        // Whether the file has been read, rather than reused from a previous snapshot.
+        let mut read = false;
+        let ty = if file_type.is_dir() {
+            EntryType::Directory
+        } else if file_type.is_file() {
+            match self.hardlink_target(&path, &metadata) {
+                Some(target) => EntryType::Hardlink { target },
+                None => match self.reused_content(&path, &metadata)? {
+                    Some(ty) => ty,
+                    None => {
+                        read = true;
+                        let inode = (metadata.dev(), metadata.ino());
+                        let (ty, read_metadata) = self
+                            .snapshot_file(&path, metadata, follow)
+                            .inspect_err(|_| {
+                                // Other hard links to the file are read instead of pointing to it.
+                                self.hardlinks.lock().unwrap().remove(&inode);
+                            })?;
+                        metadata = read_metadata;
+                        ty
+                    }
+                },
+            }
+        } else if file_type.is_symlink() {
+            let target = path
+                .read_link()
+                .map_err(|err| UnreadablePath::new(&path, err))?;
+            let target = match Utf8PathBuf::try_from(target) {
+                Ok(target) => target,
+                Err(_) if self.skip_invalid_paths => {
+                    self.skip_path(path.as_std_path(), "its target is not valid UTF-8");
+                    return Ok(None);
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!(
+                            "target of symlink {path} is not valid UTF-8 (use \
+                             --skip-invalid-paths to skip such symlinks)"
+                        )
+                    });
+                }
+            };
+            EntryType::Symlink { target }
+        } else if file_type.is_fifo() {
+            EntryType::Fifo
+        } else if file_type.is_socket() {
+            EntryType::Socket
+        } else if file_type.is_block_device() || file_type.is_char_device() {
+            let kind = if file_type.is_block_device() {
+                DeviceKind::Block
+            } else {
+                DeviceKind::Char
+            };
+            EntryType::Device {
+                kind,
+                rdev: metadata.rdev(),
+            }
+        } else {
+            self.progress
+                .suspend(|| status::warn(format_args!("skipping {path}: unsupported file type")));
+            return Ok(None);
+        };
+
+        let xattrs = if self.xattrs {
+            read_xattrs(&path, follow)?
+        } else {
+            None
+        };
+        let entry = EntryManifest {
+            path,
+            ty,
+            mtime: metadata.modified().ok(),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            mode: Some(metadata.mode()),
+            xattrs,
+        };
+        if read {
+            self.journal.record(entry.clone(), self.out_dir)?;
+        }
+        Ok(Some(entry))
+    }
+
+    /// If the file at `path` is a hard link to an already seen file, return path of that file.
+    fn hardlink_target(
+        &self,
+        path: &Utf8Path,
+        metadata: &std::fs::Metadata,
+    ) -> Option<Utf8PathBuf> {
+        if metadata.nlink() <= 1 {
+            return None;
+        }
+
+        let mut hardlinks = self.hardlinks.lock().unwrap();
+        match hardlinks.entry((metadata.dev(), metadata.ino())) {
+            Entry::Occupied(entry) => Some(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                entry.insert(path.to_owned());
+                None
+            }
+        }
+    }
+
+    /// Content of the file at `path` in the resumed or the parent snapshot, if the file looks
+    /// unchanged since.
+    fn reused_content(
+        &self,
+        path: &Utf8Path,
+        metadata: &std::fs::Metadata,
+    ) -> io::Result<Option<EntryType>> {
+        for previous in [self.resumed.get(path), self.parent.get(path)]
+            .into_iter()
+            .flatten()
+        {
+            if let Some(content) = self.unchanged_content(previous, metadata)? {
+                return Ok(Some(content));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Content of the `previous` entry, if the file with `metadata` looks unchanged since.
+    fn unchanged_content(
+        &self,
+        previous: &EntryManifest,
+        metadata: &std::fs::Metadata,
+    ) -> io::Result<Option<EntryType>> {
+        let EntryType::File {
+            content,
+            size,
+            unstable: false,
+            ..
+        } = &previous.ty
+        else {
+            return Ok(None);
+        };
+        if *size != metadata.size()
+            || previous.mtime.is_none()
+            || previous.mtime != metadata.modified().ok()
+        {
+            return Ok(None);
+        }
+
+        // Chunks might have been pruned since, e.g. if the parent has been forgotten.
+        for hash in content {
+            if !self.out_dir.contains(hash)? {
+                return Ok(None);
+            }
+        }
+
+        self.global_progress.inc(*size);
+        Ok(Some(previous.ty.clone()))
+    }
+
+    /// Read the file at `path` (the target of it if `follow` is set), which had `metadata` before
+    /// reading, reading it again if it changes meanwhile.
+    ///
+    /// Returns the metadata of the file before the last read, which the content corresponds to
+    /// unless the file is marked unstable.
+    fn snapshot_file(
+        &self,
+        path: &Utf8Path,
+        mut metadata: std::fs::Metadata,
+        follow: bool,
+    ) -> anyhow::Result<(EntryType, std::fs::Metadata)> {
+        let mut attempt = 0;
+        loop {
+            let (content, chunk_sizes) = self.read_file(path, metadata.size())?;
+            // Chunked bytes rather than metadata size, which may be stale if the file has changed
+            // while reading.
+            let size = chunk_sizes.iter().sum();
+            let after =
+                read_metadata(path, follow).map_err(|err| UnreadablePath::new(path, err))?;
+            let unstable = size != after.size()
+                || metadata.size() != after.size()
+                || metadata.modified().ok() != after.modified().ok();
+            if unstable && attempt < CHANGED_FILE_RETRIES {
+                self.progress.suspend(|| {
+                    status::note(format_args!("{path} changed while reading, reading again"))
+                });
+                attempt += 1;
+                metadata = after;
+                continue;
+            }
+
+            if unstable {
+                self.progress.suspend(|| {
+                    status::warn(format_args!(
+                        "{path} keeps changing while reading, storing it as unstable"
+                    ))
+                });
+            }
+            let ty = EntryType::File {
+                content,
+                size,
+                chunk_sizes,
+                unstable,
+            };
+            return Ok((ty, metadata));
+        }
+    }
+
+    /// Chunk and store the file at `path` of `size` bytes, returning hashes and sizes of its chunks.
+    #[instrument(level = "debug", skip(self, path), fields(%path, chunks = field::Empty))]
+    fn read_file(
+        &self,
+        path: &Utf8Path,
+        size: u64,
+    ) -> anyhow::Result<(Vec<Output<blake3::Hasher>>, Vec<u64>)> {
+        let name = path.file_name().unwrap_or_default().to_owned();
+        let my_progress = self.progress.add(
+            ProgressBar::new(size)
+                .with_style(
+                    ProgressStyle::with_template(
+                        "{prefix} {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec})",
+                    )
+                    .unwrap(),
+                )
+                .with_message(name.clone())
+                .with_prefix(name),
+        );
+
+        let file = File::open(path).map_err(|err| UnreadablePath::new(path, err))?;
+        let mut source = SourceReader {
+            inner: file,
+            failed: false,
+        };
+        let chunks = chunk_and_store(
+            &self.chunker_config,
+            BufReader::new(&mut source),
+            self.out_dir,
+            &self.store_pool,
+            self.store_workers,
+            self.memory_budget.as_ref(),
+            |len| {
+                my_progress.inc(len);
+                self.global_progress.inc(len);
+            },
+        );
+        let chunks = match chunks {
+            Ok(chunks) => chunks,
+            Err(err) => {
+                self.progress.remove(&my_progress);
+                if source.failed {
+                    return Err(UnreadablePath::new(path, err).into());
+                }
+                return Err(err.into());
+            }
+        };
+
+        Span::current().record("chunks", chunks.len());
+        my_progress.finish();
+        self.progress.remove(&my_progress);
+        self.bytes_read
+            .fetch_add(my_progress.position(), Ordering::Relaxed);
+
+        Ok(chunks.into_iter().unzip())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_paths() {
+        let paths = parse_paths(b"/a b\n/c\n\n", b'\n').unwrap();
+        assert_eq!(paths, ["/a b", "/c"]);
+
+        let paths = parse_paths(b"/a\nb\0/c\0", b'\0').unwrap();
+        assert_eq!(paths, ["/a\nb", "/c"]);
+
+        assert!(parse_paths(b"/\xff\n", b'\n').is_err());
+    }
+
+    #[test]
+    fn test_is_cache_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!is_cache_dir(dir.path()));
+
+        let tag = dir.path().join("CACHEDIR.TAG");
+        std::fs::write(
+            &tag,
+            "Signature: 8a477f597d28d172789f06886806bc55\n# comment\n",
+        )
+        .unwrap();
+        assert!(is_cache_dir(dir.path()));
+
+        std::fs::write(&tag, "Signature: 0000").unwrap();
+        assert!(!is_cache_dir(dir.path()));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let source = dir.join("source");
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        std::fs::write(source.join("a"), b"hello").unwrap();
+        std::fs::write(source.join("sub/b"), vec![7; 100_000]).unwrap();
+        std::os::unix::fs::symlink("a", source.join("link")).unwrap();
+
+        let remote = dir.join("repo");
+        crate::config::init(cli::Init {
+            remote: remote.clone(),
+            encrypted: false,
+            identity: None,
+            recipient: Vec::new(),
+            chunking: cli::ChunkingArgs::default(),
+            pack_size: None,
+        })
+        .unwrap();
+        let repo = Repository::open(&remote, None).unwrap();
+        let opts = SnapshotOptions {
+            name: Some("test".to_owned()),
+            ..SnapshotOptions::default()
+        };
+        let paths = [source.clone()];
+        let id = repo.snapshot(&paths, &opts).unwrap();
+        assert_eq!(snapshots::list(&remote).unwrap(), [id]);
+        let manifest = snapshots::load(&repo.objects, id).unwrap();
+        assert_eq!(manifest.paths, paths);
+        assert!(manifest.hostname.is_some());
+
+        let target = dir.join("target");
+        repo.restore(&id, &target, &RestoreOptions::default())
+            .unwrap();
+        let restored = target.join(source.strip_prefix("/").unwrap());
+        assert_eq!(std::fs::read(restored.join("a")).unwrap(), b"hello");
+        assert_eq!(std::fs::read(restored.join("sub/b")).unwrap(), [7; 100_000]);
+        assert_eq!(
+            std::fs::read_link(restored.join("link")).unwrap(),
+            Path::new("a")
+        );
+
+        // Unchanged files are reused from the parent.
+        let (_, result) = repo.snapshot_with_result(&paths, &opts).unwrap();
+        assert_eq!((result.bytes_read, result.new_chunks), (0, 0));
+    }
+
+    #[test]
+    fn test_snapshot_after_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let source = dir.join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("a"), b"hello").unwrap();
+
+        let remote = dir.join("repo");
+        crate::config::init(cli::Init {
+            remote: remote.clone(),
+            encrypted: false,
+            identity: None,
+            recipient: Vec::new(),
+            chunking: cli::ChunkingArgs::default(),
+            pack_size: None,
+        })
+        .unwrap();
+        let repo = Repository::open(&remote, None).unwrap();
+        let opts = SnapshotOptions::default();
+        let paths = [source.clone()];
+        let first = repo.snapshot(&paths, &opts).unwrap();
+        std::fs::write(source.join("a"), b"hello world").unwrap();
+        repo.snapshot(&paths, &opts).unwrap();
+
+        // Removes the content of the first snapshot while `repo` is open.
+        snapshots::remove_ref(&remote, first).unwrap();
+        crate::prune::prune(cli::Prune {
+            remote: remote.clone(),
+            identity: None,
+            lock: cli::LockArgs { break_lock: false },
+        })
+        .unwrap();
+
+        std::fs::write(source.join("a"), b"hello").unwrap();
+        let id = repo.snapshot(&paths, &opts).unwrap();
+        let target = dir.join("target");
+        repo.restore(&id, &target, &RestoreOptions::default())
+            .unwrap();
+        let restored = target.join(source.strip_prefix("/").unwrap());
+        assert_eq!(std::fs::read(restored.join("a")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_chunker_key_from_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let source = dir.join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("a"), b"hello").unwrap();
+        let identity = dir.join("key");
+        crate::keys::Key::generate().save(&identity).unwrap();
+
+        for encrypted in [false, true] {
+            let remote = dir.join(format!("repo-{encrypted}"));
+            crate::config::init(cli::Init {
+                remote: remote.clone(),
+                encrypted,
+                identity: encrypted.then(|| identity.clone()),
+                recipient: Vec::new(),
+                chunking: cli::ChunkingArgs::default(),
+                pack_size: None,
+            })
+            .unwrap();
+            let repo = Repository::open(&remote, Some(&identity)).unwrap();
+            let key = repo.config.chunker_key.as_ref().unwrap();
+            let key = key.open(repo::identity(&repo.objects)).unwrap();
+            assert!(key.is_some());
+
+            // Kept even if there are no snapshots to inherit it from.
+            let paths = [source.clone()];
+            for _ in 0..2 {
+                let id = repo.snapshot(&paths, &SnapshotOptions::default()).unwrap();
+                let manifest = snapshots::load(&repo.objects, id).unwrap();
+                assert_eq!(Some(manifest.chunker_params().key), key);
+                snapshots::remove_ref(&remote, id).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_dereference_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let source = dir.join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("a"), b"hello").unwrap();
+        std::os::unix::fs::symlink("a", source.join("link")).unwrap();
+        let root = dir.join("root");
+        std::os::unix::fs::symlink(&source, &root).unwrap();
+
+        let remote = dir.join("repo");
+        crate::config::init(cli::Init {
+            remote: remote.clone(),
+            encrypted: false,
+            identity: None,
+            recipient: Vec::new(),
+            chunking: cli::ChunkingArgs::default(),
+            pack_size: None,
+        })
+        .unwrap();
+        let repo = Repository::open(&remote, None).unwrap();
+        let paths = [root.clone()];
+        let restore = |dereference_root| {
+            let opts = SnapshotOptions {
+                dereference_root,
+                ..SnapshotOptions::default()
+            };
+            let id = repo.snapshot(&paths, &opts).unwrap();
+            let target = dir.join(format!("target-{dereference_root}"));
+            repo.restore(&id, &target, &RestoreOptions::default())
+                .unwrap();
+            target.join(root.strip_prefix("/").unwrap())
+        };
+
+        let restored = restore(false);
+        assert_eq!(std::fs::read_link(&restored).unwrap(), source);
+
+        let restored = restore(true);
+        assert!(restored.symlink_metadata().unwrap().is_dir());
+        assert_eq!(std::fs::read(restored.join("a")).unwrap(), b"hello");
+        assert_eq!(
+            std::fs::read_link(restored.join("link")).unwrap(),
+            Path::new("a")
+        );
+    }
+
+    #[test]
+    fn test_skip_unreadable() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let source = dir.join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("a"), b"hello").unwrap();
+
+        let remote = dir.join("repo");
+        crate::config::init(cli::Init {
+            remote: remote.clone(),
+            encrypted: false,
+            identity: None,
+            recipient: Vec::new(),
+            chunking: cli::ChunkingArgs::default(),
+            pack_size: None,
+        })
+        .unwrap();
+        let repo = Repository::open(&remote, None).unwrap();
+        // Reading it fails even as root, as the start of the address space isn't mapped.
+        let mem = Utf8PathBuf::from("/proc/self/mem");
+        let paths = [source.clone(), mem.clone(), dir.join("missing")];
+
+        let strict = SnapshotOptions {
+            strict: true,
+            ..SnapshotOptions::default()
+        };
+        let err = repo.snapshot(&paths[..2], &strict).unwrap_err();
+        assert!(err.is::<UnreadablePath>(), "{err:#}");
+
+        let (id, result) = repo
+            .snapshot_with_result(&paths, &SnapshotOptions::default())
+            .unwrap();
+        assert_eq!(result.unreadable_paths, 1);
+        let manifest = snapshots::load(&repo.objects, id).unwrap();
+        // Missing paths are skipped before the walk, so they aren't recorded.
+        assert_eq!(manifest.paths, [mem, source.clone()]);
+        let paths = manifest
+            .entries
+            .iter()
+            .map(|it| &it.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [&source, &source.join("a")]);
+    }
+
+    #[test]
+    fn test_backup_roots() {
+        let paths = ["/a/b", "/a", "/ab", "/c/d", "/c/d", "/a/b/c"]
+            .map(Utf8PathBuf::from)
+            .to_vec();
+        assert_eq!(backup_roots(paths).unwrap(), ["/a", "/ab", "/c/d"]);
+    }
+}
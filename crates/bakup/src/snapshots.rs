@@ -0,0 +1,211 @@
+//! Snapshot references kept in the `snapshots/` directory of the repository.
+//!
+//! Snapshot manifests are stored in the CAS like any other blob, and the hash of the manifest is
+//! the snapshot ID. Every snapshot has a small reference file `snapshots/<ID>` containing the
+//! hex-encoded ID, which makes the manifest reachable.
+
+use std::io::Write;
+
+use anyhow::{Context, anyhow, bail};
+use camino::Utf8Path;
+use const_hex::ToHexExt;
+use digest::Output;
+
+use crate::{
+    cas::ContentAddressableStorage,
+    manifest::SnapshotManifest,
+    repo::Repository,
+    status::{self, IntegrityError, UsageError},
+};
+
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+
+pub type SnapshotId = Output<blake3::Hasher>;
+
+/// Minimum number of hex digits of a snapshot ID prefix, so that short names aren't mistaken for
+/// prefixes.
+pub const MIN_PREFIX_LEN: usize = 4;
+
+/// IDs of all snapshots in the repository at `remote`.
+///
+/// Invalid references are reported and skipped, so that a single broken snapshot doesn't make
+/// the whole repository unusable.
+pub fn list(remote: &Utf8Path) -> anyhow::Result<Vec<SnapshotId>> {
+    let dir = remote.join(SNAPSHOTS_DIR);
+    let entries = match dir.read_dir_utf8() {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {dir}")),
+    };
+
+    let mut ids = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().starts_with('.') {
+            continue;
+        }
+        match read_ref(entry.path()) {
+            Ok(id) => ids.push(id),
+            Err(err) => status::warn(format_args!("{err:#}")),
+        }
+    }
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
+fn read_ref(path: &Utf8Path) -> anyhow::Result<SnapshotId> {
+    let content = std::fs::read_to_string(path)?;
+    let mut id = SnapshotId::default();
+    const_hex::decode_to_slice(content.trim(), &mut id)
+        .with_context(|| format!("invalid snapshot reference {path}"))?;
+    Ok(id)
+}
+
+/// Add reference to the snapshot `id`, making it visible in the repository at `remote`.
+///
+/// The manifest should be stored before, so the reference never points to a missing manifest.
+pub fn write_ref(remote: &Utf8Path, id: SnapshotId) -> anyhow::Result<()> {
+    let dir = remote.join(SNAPSHOTS_DIR);
+    std::fs::create_dir_all(&dir)?;
+
+    let hex = id.encode_hex();
+    let mut temp = tempfile::Builder::new().prefix(".tmp").tempfile_in(&dir)?;
+    writeln!(temp, "{hex}")?;
+    temp.persist(dir.join(&hex))
+        .with_context(|| format!("failed to write snapshot reference {hex}"))?;
+    Ok(())
+}
+
+/// Remove reference to the snapshot `id`. The manifest and chunks stay in the repository until
+/// pruned.
+pub fn remove_ref(remote: &Utf8Path, id: SnapshotId) -> anyhow::Result<()> {
+    let hex = id.encode_hex();
+    std::fs::remove_file(remote.join(SNAPSHOTS_DIR).join(&hex))
+        .with_context(|| format!("failed to remove snapshot reference {hex}"))
+}
+
+/// Load manifest of the snapshot `id`.
+pub fn load(cas: &Repository, id: SnapshotId) -> anyhow::Result<SnapshotManifest> {
+    let bytes = cas
+        .get(id)
+        .with_context(|| format!("failed to read manifest of snapshot {}", id.encode_hex()))?
+        .ok_or_else(|| {
+            anyhow!(IntegrityError(format!(
+                "manifest of snapshot {} is missing",
+                id.encode_hex()
+            )))
+        })?;
+    SnapshotManifest::from_bytes(&bytes)
+        .with_context(|| format!("invalid manifest of snapshot {}", id.encode_hex()))
+}
+
+/// Find a snapshot by `selector`, which is a snapshot ID, a snapshot name, or a unique prefix of
+/// a snapshot ID (at least [`MIN_PREFIX_LEN`] hex digits). If multiple snapshots have the same
+/// name, the latest one is returned. Names take precedence over ID prefixes.
+pub fn resolve(
+    remote: &Utf8Path,
+    cas: &Repository,
+    selector: &str,
+) -> anyhow::Result<(SnapshotId, SnapshotManifest)> {
+    let ids = list(remote)?;
+    if let Some(&id) = ids.iter().find(|id| id.encode_hex() == selector) {
+        return Ok((id, load(cas, id)?));
+    }
+
+    if let Some(found) = latest(remote, cas, selector)? {
+        return Ok(found);
+    }
+    match find_by_prefix(&ids, selector)? {
+        Some(id) => Ok((id, load(cas, id)?)),
+        None => bail!(UsageError(format!("snapshot {selector:?} not found"))),
+    }
+}
+
+/// The only snapshot among `ids` whose ID starts with `prefix`, if any. Fails if multiple
+/// snapshots match.
+fn find_by_prefix(ids: &[SnapshotId], prefix: &str) -> anyhow::Result<Option<SnapshotId>> {
+    if prefix.len() < MIN_PREFIX_LEN || !prefix.bytes().all(|it| it.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    let prefix = prefix.to_ascii_lowercase();
+    let matching = ids
+        .iter()
+        .filter(|id| id.encode_hex().starts_with(&prefix))
+        .collect::<Vec<_>>();
+    match matching[..] {
+        [] => Ok(None),
+        [&id] => Ok(Some(id)),
+        _ => bail!(UsageError(format!(
+            "ambiguous snapshot ID prefix {prefix}, matching {}",
+            matching
+                .iter()
+                .map(|it| it.encode_hex())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// The latest snapshot named `name`, if any.
+pub fn latest(
+    remote: &Utf8Path,
+    cas: &Repository,
+    name: &str,
+) -> anyhow::Result<Option<(SnapshotId, SnapshotManifest)>> {
+    latest_matching(remote, cas, |manifest| {
+        manifest.name.as_deref() == Some(name)
+    })
+}
+
+/// The latest snapshot in the repository, if any.
+pub fn newest(
+    remote: &Utf8Path,
+    cas: &Repository,
+) -> anyhow::Result<Option<(SnapshotId, SnapshotManifest)>> {
+    latest_matching(remote, cas, |_| true)
+}
+
+fn latest_matching(
+    remote: &Utf8Path,
+    cas: &Repository,
+    predicate: impl Fn(&SnapshotManifest) -> bool,
+) -> anyhow::Result<Option<(SnapshotId, SnapshotManifest)>> {
+    let mut found: Option<(SnapshotId, SnapshotManifest)> = None;
+    for id in list(remote)? {
+        let manifest = load(cas, id)?;
+        if predicate(&manifest) && found.as_ref().is_none_or(|(_, it)| it.time < manifest.time) {
+            found = Some((id, manifest));
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_by_prefix() {
+        let id = |hex: &str| {
+            let mut id = SnapshotId::default();
+            const_hex::decode_to_slice(hex.repeat(32), &mut id).unwrap();
+            id
+        };
+        let ids = [id("ab"), id("ac"), id("12")];
+
+        assert_eq!(find_by_prefix(&ids, "abab").unwrap(), Some(ids[0]));
+        assert_eq!(find_by_prefix(&ids, "ACAC").unwrap(), Some(ids[1]));
+        assert_eq!(find_by_prefix(&ids, "1212121").unwrap(), Some(ids[2]));
+        assert_eq!(find_by_prefix(&ids, "abcd").unwrap(), None);
+        // Too short, or not hex.
+        assert_eq!(find_by_prefix(&ids, "aba").unwrap(), None);
+        assert_eq!(find_by_prefix(&ids, "ababx").unwrap(), None);
+
+        let mut other = id("ab");
+        other[31] ^= 1;
+        let ids = [id("ab"), other];
+        let err = find_by_prefix(&ids, "abab").unwrap_err();
+        assert!(err.to_string().contains("ambiguous"));
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use camino::Utf8PathBuf;
+use const_hex::ToHexExt;
+use digest::Output;
+use indicatif::HumanBytes;
+use rayon::prelude::*;
+
+use crate::{
+    cas::ContentAddressableStorage,
+    cli,
+    lock::RepoLock,
+    manifest::{EntryType, SnapshotManifest},
+    repo, snapshots, status,
+};
+
+/// Usage of the repository by snapshots, collected from their manifests.
+#[derive(Debug, Default)]
+struct Usage {
+    snapshots: usize,
+    /// Number of file entries across all snapshots.
+    files: usize,
+    /// Total size of files across all snapshots, as if they weren't deduplicated.
+    logical_bytes: u64,
+    /// Number of references to every chunk.
+    references: HashMap<Output<blake3::Hasher>, u64>,
+    /// Largest size of every file path in any snapshot.
+    file_sizes: HashMap<Utf8PathBuf, u64>,
+}
+
+impl Usage {
+    fn add(&mut self, manifest: SnapshotManifest) {
+        self.snapshots += 1;
+        for entry in manifest.entries {
+            let EntryType::File { content, size, .. } = entry.ty else {
+                continue;
+            };
+            self.files += 1;
+            self.logical_bytes += size;
+            for hash in content {
+                *self.references.entry(hash).or_default() += 1;
+            }
+            let largest = self.file_sizes.entry(entry.path).or_default();
+            *largest = (*largest).max(size);
+        }
+    }
+
+    /// The `n` largest files, largest first.
+    fn largest_files(&self, n: usize) -> Vec<(&Utf8PathBuf, u64)> {
+        let mut files = self
+            .file_sizes
+            .iter()
+            .map(|(path, &size)| (path, size))
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        files.truncate(n);
+        files
+    }
+
+    /// The `n` most referenced chunks, most referenced first.
+    fn most_referenced(&self, n: usize) -> Vec<(&Output<blake3::Hasher>, u64)> {
+        let mut chunks = self
+            .references
+            .iter()
+            .map(|(hash, &count)| (hash, count))
+            .collect::<Vec<_>>();
+        chunks.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        chunks.truncate(n);
+        chunks
+    }
+}
+
+pub fn stats(cmd: cli::Stats) -> anyhow::Result<()> {
+    let _lock = RepoLock::shared(&cmd.remote, &cmd.lock)?;
+    let cas = repo::open(&cmd.remote, cmd.identity.as_deref())?;
+
+    let mut usage = Usage::default();
+    for id in snapshots::list(&cmd.remote)? {
+        match snapshots::load(&cas, id) {
+            Ok(manifest) => usage.add(manifest),
+            Err(err) => status::warn(format_args!("{err:#}")),
+        }
+    }
+
+    let sizes = usage
+        .references
+        .par_iter()
+        .map(|(hash, _)| Ok((*hash, cas.size(hash)?)))
+        .collect::<std::io::Result<HashMap<_, _>>>()?;
+    let stored_bytes = sizes.values().flatten().sum::<u64>();
+    let missing = sizes.values().filter(|it| it.is_none()).count();
+
+    let mut objects = 0;
+    let mut repository_bytes = 0;
+    for hash in cas.list() {
+        objects += 1;
+        repository_bytes += cas.size(&hash?)?.unwrap_or(0);
+    }
+
+    println!("snapshots: {}", usage.snapshots);
+    println!(
+        "files: {} ({} in total)",
+        usage.files,
+        HumanBytes(usage.logical_bytes)
+    );
+    println!(
+        "unique chunks: {} ({} stored{})",
+        usage.references.len(),
+        HumanBytes(stored_bytes),
+        if missing > 0 {
+            format!(", {missing} missing")
+        } else {
+            String::new()
+        }
+    );
+    if stored_bytes > 0 {
+        println!(
+            "deduplication and compression ratio: {:.2}",
+            usage.logical_bytes as f64 / stored_bytes as f64
+        );
+    }
+    println!(
+        "repository: {objects} objects ({})",
+        HumanBytes(repository_bytes)
+    );
+
+    println!();
+    println!("largest files:");
+    for (path, size) in usage.largest_files(cmd.top) {
+        println!("{:>12}  {path}", HumanBytes(size).to_string());
+    }
+
+    println!();
+    println!("most referenced chunks:");
+    for (hash, count) in usage.most_referenced(cmd.top) {
+        let size = sizes[hash].map_or("missing".to_owned(), |it| HumanBytes(it).to_string());
+        println!("{count:>12}  {}  {size}", hash.encode_hex());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use digest::Digest;
+
+    use super::*;
+    use crate::manifest::{EntryManifest, MANIFEST_VERSION};
+
+    fn file(path: &str, content: &[&[u8]]) -> EntryManifest {
+        EntryManifest {
+            path: path.into(),
+            ty: EntryType::File {
+                content: content.iter().map(blake3::Hasher::digest).collect(),
+                size: content.iter().map(|it| it.len() as u64).sum(),
+                chunk_sizes: content.iter().map(|it| it.len() as u64).collect(),
+                unstable: false,
+            },
+            mtime: None,
+            uid: None,
+            gid: None,
+            mode: None,
+            xattrs: None,
+        }
+    }
+
+    fn manifest(entries: Vec<EntryManifest>) -> SnapshotManifest {
+        SnapshotManifest {
+            version: MANIFEST_VERSION,
+            name: None,
+            hostname: None,
+            paths: Vec::new(),
+            tags: Vec::new(),
+            description: None,
+            time: SystemTime::now(),
+            chunker: None,
+            entries,
+        }
+    }
+
+    #[test]
+    fn test_usage() {
+        let mut usage = Usage::default();
+        usage.add(manifest(vec![
+            file("/a", &[b"aaa", b"bb"]),
+            file("/b", &[b"bb"]),
+        ]));
+        usage.add(manifest(vec![file("/a", &[b"aaa", b"bb", b"c"])]));
+
+        assert_eq!(usage.snapshots, 2);
+        assert_eq!(usage.files, 3);
+        assert_eq!(usage.logical_bytes, 13);
+        assert_eq!(usage.references.len(), 3);
+        assert_eq!(usage.largest_files(1), [(&Utf8PathBuf::from("/a"), 6)]);
+        let most_referenced = usage.most_referenced(2);
+        assert_eq!(
+            most_referenced,
+            [
+                (&blake3::Hasher::digest(b"bb"), 3),
+                (&blake3::Hasher::digest(b"aaa"), 2)
+            ]
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Exit status of commands, for scripts:
+//!
+//! - 0: success.
+//! - 1: failure.
+//! - 2: usage error: invalid arguments, or a repository that can't be used with them (it doesn't
+//!   exist, has an unsupported format, needs a key, or has no such snapshot).
+//! - 3: partial success: the command completed, but skipped something it was asked to process
+//!   (e.g. a file it couldn't read or an unreadable snapshot) or stored a file that kept changing
+//!   while being read, which was reported with a warning.
+//! - 4: integrity failure: objects in the repository are missing or don't match their hashes.
+//!
+//! Warnings that don't leave anything out, like a symlink stored as is, don't change the status.
+
+use std::{
+    fmt,
+    process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Exit status of a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Success = 0,
+    Failure = 1,
+    Usage = 2,
+    Partial = 3,
+    Integrity = 4,
+}
+
+impl From<Status> for ExitCode {
+    fn from(status: Status) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+/// Error that makes the command exit with [`Status::Usage`].
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct UsageError(pub String);
+
+/// Error that makes the command exit with [`Status::Integrity`].
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct IntegrityError(pub String);
+
+/// Whether anything was skipped with [`warn`].
+static PARTIAL: AtomicBool = AtomicBool::new(false);
+
+/// Report on stderr that something is skipped, so that the command exits with
+/// [`Status::Partial`] if it otherwise succeeds.
+pub fn warn(message: impl fmt::Display) {
+    note(message);
+    PARTIAL.store(true, Ordering::Relaxed);
+}
+
+/// Report on stderr something that doesn't leave anything out, so it doesn't change the status.
+pub fn note(message: impl fmt::Display) {
+    eprintln!("warning: {message}");
+}
+
+/// Report on stderr one of the problems that make the command fail, before returning its error.
+pub fn error(message: impl fmt::Display) {
+    eprintln!("error: {message}");
+}
+
+/// Status of a command that finished with `result`. Errors are classified by the first
+/// [`UsageError`] or [`IntegrityError`] in their chain.
+pub fn of(result: &anyhow::Result<()>) -> Status {
+    match result {
+        Ok(()) if PARTIAL.load(Ordering::Relaxed) => Status::Partial,
+        Ok(()) => Status::Success,
+        Err(err) => err
+            .chain()
+            .find_map(|it| {
+                if it.is::<UsageError>() {
+                    Some(Status::Usage)
+                } else if it.is::<IntegrityError>() {
+                    Some(Status::Integrity)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(Status::Failure),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{Context, anyhow};
+
+    use super::*;
+
+    #[test]
+    fn test_of_error() {
+        let status = |err: anyhow::Error| of(&Err(err));
+        assert_eq!(status(anyhow!("failed")), Status::Failure);
+        assert_eq!(
+            status(anyhow!(UsageError("no such snapshot".to_owned()))),
+            Status::Usage
+        );
+        let err = Err::<(), _>(anyhow!(IntegrityError("chunk is missing".to_owned())))
+            .context("failed to restore /a")
+            .unwrap_err();
+        assert_eq!(status(err), Status::Integrity);
+    }
+}